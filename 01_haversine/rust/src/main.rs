@@ -3,14 +3,33 @@ use std::io::BufReader;
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 
 use anyhow::Result;
 use clap::Parser;
 use core_affinity::{set_for_current, CoreId};
+use profiler::{time, Profiler};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
+profiler::zones! {
+    /// Zones timed while computing the haversine distances
+    enum Stats {
+        SerdeJson,
+        SimdJson,
+        Haversine,
+    }
+}
+
+/// Get the wall clock time spent in `zone` of `prof` so far
+fn zone_time(prof: &Profiler<Stats>, frequency: u64, zone: Stats) -> Duration {
+    let report = prof.report(frequency);
+    let cycles = report
+        .zone(profiler::Zones::name(zone))
+        .map_or(0, |zone| zone.inclusive_cycles);
+    report.cycles_to_duration(cycles)
+}
+
 #[derive(Parser)]
 struct CommandLineArgs {
     /// Input file containing pairs of (latitude,longitude) coordinates
@@ -220,16 +239,28 @@ pub fn manual_chunk_parallel(data: Arc<Points>, cores: usize) -> f32 {
 pub fn main() -> Result<()> {
     let args = CommandLineArgs::parse();
 
+    let prof = Profiler::<Stats>::new();
+    let frequency = profiler::estimate_timer_frequency(Duration::from_millis(100));
+    let input_size = std::fs::metadata(&args.input)?.len();
+
     // Time serde_json
-    let start_time = Instant::now();
-    let serde_data = serde_json(&args.input)?;
-    let serde_time = Instant::now() - start_time;
+    let serde_data = time!(
+        prof,
+        Stats::SerdeJson,
+        bytes = input_size,
+        serde_json(&args.input)?
+    );
+    let serde_time = zone_time(&prof, frequency, Stats::SerdeJson);
     println!("Reading via serde_json: {:6.2?}", serde_time);
 
     // Time simd_json
-    let start_time = Instant::now();
-    let simd_data = simd_json(&args.input)?;
-    let simd_time = Instant::now() - start_time;
+    let simd_data = time!(
+        prof,
+        Stats::SimdJson,
+        bytes = input_size,
+        simd_json(&args.input)?
+    );
+    let simd_time = zone_time(&prof, frequency, Stats::SimdJson);
     let speedup = serde_time.as_secs_f32() / simd_time.as_secs_f32();
     println!("Reading via simd_json:  {:6.2?}", simd_time);
     println!("simdjson speedup over serde: {speedup:6.4}x");
@@ -249,14 +280,10 @@ pub fn main() -> Result<()> {
             // Get a reference to the data for this test (only incrementing a ref counter)
             let data = data.clone();
 
-            // Start the timer for this work
-            let start_time = Instant::now();
-
-            // Execute the given tested work
-            let sum: f32 = $work_func(data, $cores);
-
-            // Stop the timer
-            let math_time = Instant::now() - start_time;
+            // Execute the given tested work in its zone
+            let before = zone_time(&prof, frequency, Stats::Haversine);
+            let sum: f32 = time!(prof, Stats::Haversine, $work_func(data, $cores));
+            let math_time = zone_time(&prof, frequency, Stats::Haversine) - before;
 
             // Calculate statistics for this test case
             let total_time = simd_time + math_time;
@@ -304,5 +331,7 @@ pub fn main() -> Result<()> {
     // time_work!(manual_chunk_parallel, 12);
     // time_work!(manual_chunk_parallel, 16);
 
+    prof.report(frequency).print();
+
     Ok(())
 }
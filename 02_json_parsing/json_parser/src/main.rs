@@ -1,5 +1,7 @@
 use clap::Parser;
+use profiler::{time, Profiler};
 use std::path::PathBuf;
+use std::time::Duration;
 
 mod json;
 
@@ -54,8 +56,7 @@ fn format_bytes(num: usize) -> String {
     }
 }
 
-timeloop::impl_enum!(
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+profiler::zones! {
     pub enum HaversineTimers {
         ReadInput,
         ReadAnswer,
@@ -64,14 +65,10 @@ timeloop::impl_enum!(
         CalculateHaversine,
         Drops,
     }
-);
-
-timeloop::create_profiler!(HaversineTimers);
+}
 
 fn main() -> Result<(), Error> {
-    let total_time_start = std::time::Instant::now();
-
-    timeloop::start_profiler!();
+    let prof = Profiler::<HaversineTimers>::new();
     use HaversineTimers::*;
 
     // Parse the command line arguments
@@ -80,7 +77,8 @@ fn main() -> Result<(), Error> {
 
     for _ in 0..iters {
         // Read the given input
-        let data = timeloop::time_work!(
+        let data = time!(
+            prof,
             ReadInput,
             std::fs::read_to_string(&args.input).map_err(Error::Io)?
         );
@@ -90,7 +88,8 @@ fn main() -> Result<(), Error> {
         }
 
         // Get the answer file or look for a `.answer` file from the input `.json` file
-        let answer = timeloop::time_work!(
+        let answer = time!(
+            prof,
             ReadAnswer,
             args.answer
                 .clone()
@@ -112,13 +111,13 @@ fn main() -> Result<(), Error> {
         );
 
         // Parse the given data using the json parser
-        let data = timeloop::time_work!(ParseJson, json::parse(&data).map_err(Error::Json)?);
+        let data = time!(prof, ParseJson, json::parse(&data).map_err(Error::Json)?);
 
         // Retrieve the data from the parsed JSON
-        let pairs = timeloop::time_work!(GetPairs, data["pairs"].as_vec().map_err(Error::Json)?);
+        let pairs = time!(prof, GetPairs, data["pairs"].as_vec().map_err(Error::Json)?);
 
         // Calculate the haversine over the parsed pairs
-        timeloop::time_work!(CalculateHaversine, {
+        time!(prof, CalculateHaversine, {
             let mut sum = 0.0;
             for pair in pairs {
                 let pair = pair.as_map().map_err(Error::Json)?;
@@ -148,16 +147,20 @@ fn main() -> Result<(), Error> {
             }
         });
 
-        timeloop::time_work!(Drops, {
+        time!(prof, Drops, {
             drop(data);
             drop(answer);
         });
     }
 
-    println!("Time elapsed: {:?}", total_time_start.elapsed());
-
     // Print the status of the timers
-    timeloop::print!();
+    let frequency = profiler::estimate_timer_frequency(Duration::from_millis(100));
+    let report = prof.report(frequency);
+    println!(
+        "Time elapsed: {:?}",
+        report.cycles_to_duration(report.total_cycles)
+    );
+    report.print();
 
     Ok(())
 }
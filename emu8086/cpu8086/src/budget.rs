@@ -0,0 +1,70 @@
+//! Limits on how long a program runs
+//!
+//! A runaway program never leaves its loop, so a [`Budget`] caps the number of
+//! instructions and of estimated clock cycles (see [`Instruction::estimated_cycles`]) it
+//! may take. Running out of the budget stops the program with a [`BudgetExhausted`] error.
+//!
+//! The interpreters charge the budget before every instruction. The JIT runs whole blocks
+//! without returning to the host, so it only checks the budget on the host between blocks
+//! and a lane can overrun it by the rest of its last block.
+//!
+//! [`Instruction::estimated_cycles`]: crate::instruction::Instruction::estimated_cycles
+
+use thiserror::Error;
+
+/// The instructions and estimated cycles a program may run for
+///
+/// Checked before every instruction by the interpreters, but only before every block by the
+/// JIT (see the [module documentation](self)).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Budget {
+    /// Maximum number of instructions, unlimited if `None`
+    pub instructions: Option<u64>,
+
+    /// Maximum number of estimated clock cycles, unlimited if `None`
+    pub cycles: Option<u64>,
+}
+
+/// The instructions and estimated cycles spent against a [`Budget`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Spent {
+    /// Number of instructions executed
+    pub instructions: u64,
+
+    /// Estimated clock cycles of the executed instructions
+    pub cycles: u64,
+}
+
+/// A program stopped because it ran out of its [`Budget`]
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+#[error(
+    "Budget exhausted after {} instructions ({} estimated cycles)",
+    .0.instructions,
+    .0.cycles
+)]
+pub struct BudgetExhausted(pub Spent);
+
+impl Budget {
+    /// Returns `true` if `spent` used up this budget
+    pub fn is_exhausted(&self, spent: Spent) -> bool {
+        self.instructions
+            .is_some_and(|max| spent.instructions >= max)
+            || self.cycles.is_some_and(|max| spent.cycles >= max)
+    }
+
+    /// Add `instructions` taking `cycles` to `spent`, unless it already used up this budget
+    pub fn charge(
+        &self,
+        spent: &mut Spent,
+        instructions: u64,
+        cycles: u64,
+    ) -> Result<(), BudgetExhausted> {
+        if self.is_exhausted(*spent) {
+            return Err(BudgetExhausted(*spent));
+        }
+
+        spent.instructions += instructions;
+        spent.cycles += cycles;
+        Ok(())
+    }
+}
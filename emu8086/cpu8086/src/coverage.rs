@@ -0,0 +1,65 @@
+//! Instruction and branch edge coverage of a program
+//!
+//! An [`Emulator`](crate::emu::Emulator) with a [`Coverage`] counts how many times the
+//! instruction at each IP was executed and, for every instruction that can branch, how
+//! many times it went on to each following IP.
+
+use std::collections::BTreeMap;
+
+/// Number of times each instruction and branch edge of a program was executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// Number of times the instruction at each IP was executed
+    hits: Vec<u64>,
+
+    /// Number of times the branch at the first IP continued at the second IP
+    edges: BTreeMap<(u16, u16), u64>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    /// Create a coverage with nothing hit
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; usize::from(u16::MAX) + 1],
+            edges: BTreeMap::new(),
+        }
+    }
+
+    /// Record an execution of the instruction at `ip`
+    pub fn hit(&mut self, ip: u16) {
+        self.hits[usize::from(ip)] += 1;
+    }
+
+    /// Record the branch at `from` continuing at `to`
+    pub fn edge(&mut self, from: u16, to: u16) {
+        *self.edges.entry((from, to)).or_default() += 1;
+    }
+
+    /// Get the number of times the instruction at `ip` was executed
+    pub fn hits(&self, ip: u16) -> u64 {
+        self.hits[usize::from(ip)]
+    }
+
+    /// Get every IP the branch at `from` continued at, with the number of times it did
+    pub fn edges_from(&self, from: u16) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.edges
+            .range((from, 0)..=(from, u16::MAX))
+            .map(|(&(_, to), &count)| (to, count))
+    }
+
+    /// Get the number of distinct instructions executed
+    pub fn instructions(&self) -> usize {
+        self.hits.iter().filter(|&&hits| hits != 0).count()
+    }
+
+    /// Get the number of distinct branch edges taken
+    pub fn edges(&self) -> usize {
+        self.edges.len()
+    }
+}
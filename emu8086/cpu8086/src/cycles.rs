@@ -0,0 +1,264 @@
+//! Estimated 8086 clock cycles of each instruction
+//!
+//! The estimates follow the timings of the 8086 user's manual, including the effective
+//! address calculation of memory operands. They are static: conditional jumps and loops
+//! are counted as taken, shifts by CL and repeated string instructions as a single
+//! iteration, and the extra cycles of word accesses at odd addresses are left out.
+
+use crate::instruction::{Instruction, Operand};
+use crate::memory_operand::{MemoryOperand, MemorySize};
+use crate::register::{Register, SubRegister};
+
+/// Cycles to compute the effective address of `mem`
+fn effective_address(mem: &MemoryOperand) -> u64 {
+    let displacement = mem.displacement.is_some_and(|disp| disp != 0);
+
+    let cycles = match mem.registers {
+        _ if mem.address.is_some() => 6,
+        [Some(_), None] | [None, Some(_)] if displacement => 9,
+        [Some(_), None] | [None, Some(_)] => 5,
+        [Some(base), Some(index)] => {
+            let fast = matches!(
+                (base, index),
+                (Register::Bp, Register::Di) | (Register::Bx, Register::Si)
+            );
+            let cycles = if fast { 7 } else { 8 };
+            if displacement {
+                cycles + 4
+            } else {
+                cycles
+            }
+        }
+        [None, None] => 6,
+    };
+
+    // A segment override prefix takes 2 more cycles
+    cycles + if mem.segment.is_some() { 2 } else { 0 }
+}
+
+/// Returns `true` if `op` is a byte operand
+fn is_byte(op: &Operand) -> bool {
+    match op {
+        Operand::Register(reg) => reg.as_sub_register().1 != SubRegister::Full,
+        Operand::Memory(mem) => mem.size == Some(MemorySize::Byte),
+        Operand::Immediate(_) | Operand::SegmentRegister(_) => false,
+    }
+}
+
+/// Cycles of a two operand instruction given its cost with a register, memory or
+/// immediate source and destination
+struct Costs {
+    reg_reg: u64,
+    reg_mem: u64,
+    mem_reg: u64,
+    reg_imm: u64,
+    mem_imm: u64,
+}
+
+impl Costs {
+    /// Cycles of the instruction from `src` to `dest`
+    fn of(&self, dest: &Operand, src: &Operand) -> u64 {
+        match (dest, src) {
+            (Operand::Memory(mem), Operand::Immediate(_)) => self.mem_imm + effective_address(mem),
+            (Operand::Memory(mem), _) => self.mem_reg + effective_address(mem),
+            (_, Operand::Memory(mem)) => self.reg_mem + effective_address(mem),
+            (_, Operand::Immediate(_)) => self.reg_imm,
+            _ => self.reg_reg,
+        }
+    }
+}
+
+/// Arithmetic and logic instructions writing their destination
+const ALU: Costs = Costs {
+    reg_reg: 3,
+    reg_mem: 9,
+    mem_reg: 16,
+    reg_imm: 4,
+    mem_imm: 17,
+};
+
+/// `cmp`, which only reads its destination
+const CMP: Costs = Costs {
+    reg_reg: 3,
+    reg_mem: 9,
+    mem_reg: 9,
+    reg_imm: 4,
+    mem_imm: 10,
+};
+
+/// `test`, which only reads its destination
+const TEST: Costs = Costs {
+    reg_reg: 3,
+    reg_mem: 9,
+    mem_reg: 9,
+    reg_imm: 5,
+    mem_imm: 11,
+};
+
+/// `mov` between general registers, memory and immediates
+const MOV: Costs = Costs {
+    reg_reg: 2,
+    reg_mem: 8,
+    mem_reg: 9,
+    reg_imm: 4,
+    mem_imm: 10,
+};
+
+/// Cycles of a single operand instruction on `op` costing `reg` on a register, or `mem`
+/// plus the effective address on memory
+fn unary(op: &Operand, reg: u64, mem: u64) -> u64 {
+    match op {
+        Operand::Memory(operand) => mem + effective_address(operand),
+        _ => reg,
+    }
+}
+
+/// Cycles of a multiply or divide of `op`, costing `byte` or `word` on a register and 6
+/// more plus the effective address on memory
+fn multiply(op: &Operand, byte: u64, word: u64) -> u64 {
+    let cycles = if is_byte(op) { byte } else { word };
+    match op {
+        Operand::Memory(mem) => cycles + 6 + effective_address(mem),
+        _ => cycles,
+    }
+}
+
+impl Instruction {
+    /// Estimate the 8086 clock cycles taken by this instruction
+    #[allow(clippy::match_same_arms)]
+    pub fn estimated_cycles(&self) -> u64 {
+        use Instruction::*;
+
+        // Repeated string instructions start with 9 cycles for the prefix
+        let string =
+            |repeat: &Option<_>, once: u64, repeated: u64| repeat.map_or(once, |_| 9 + repeated);
+
+        match self {
+            Mov {
+                dest: Operand::SegmentRegister(_),
+                src,
+            } => unary(src, 2, 8),
+            Mov {
+                dest,
+                src: Operand::SegmentRegister(_),
+            } => unary(dest, 2, 9),
+            Mov { dest, src } => MOV.of(dest, src),
+            Add { dest, src }
+            | Adc { dest, src }
+            | Sub { dest, src }
+            | Sbb { dest, src }
+            | And { dest, src }
+            | Or { dest, src }
+            | Xor { dest, src } => ALU.of(dest, src),
+            Cmp { left, right } => CMP.of(left, right),
+            Test { dest, src } => TEST.of(dest, src),
+            Xchg { left, right } => match (left, right) {
+                (Operand::Memory(mem), _) | (_, Operand::Memory(mem)) => {
+                    17 + effective_address(mem)
+                }
+                _ => 4,
+            },
+            Inc { src } | Dec { src } if is_byte(src) => unary(src, 3, 15),
+            Inc { src } | Dec { src } => unary(src, 2, 15),
+            Neg { src } | Not { src } => unary(src, 3, 16),
+            Push {
+                src: Operand::SegmentRegister(_),
+            } => 10,
+            Push { src } => unary(src, 11, 16),
+            Pop {
+                src: Operand::SegmentRegister(_),
+            } => 8,
+            Pop { src } => unary(src, 8, 17),
+            Shl { src, count }
+            | Sar { src, count }
+            | Shr { src, count }
+            | Rol { src, count }
+            | Ror { src, count }
+            | Rcl { src, count }
+            | Rcr { src, count } => match count {
+                Operand::Immediate(_) => unary(src, 2, 15),
+                _ => unary(src, 8, 20),
+            },
+            Mul { src } => multiply(src, 74, 126),
+            Imul { src } => multiply(src, 89, 141),
+            Div { src } => multiply(src, 85, 153),
+            Idiv { src } => multiply(src, 107, 175),
+            Lea { src, .. } => unary(src, 2, 2),
+            Lds { src, .. } | Les { src, .. } => unary(src, 16, 16),
+            MoveByte { repeat } | MoveWord { repeat } => string(repeat, 18, 17),
+            CmpByte { repeat } | CmpWord { repeat } => string(repeat, 22, 22),
+            ScanByte { repeat } | ScanWord { repeat } => string(repeat, 15, 15),
+            LoadByte { repeat } | LoadWord { repeat } => string(repeat, 12, 13),
+            StoreByte { repeat } | StoreWord { repeat } => string(repeat, 11, 10),
+            Call { dest } => unary(dest, 19, 21),
+            Jump { dest } => unary(dest, 15, 18),
+            Return => 8,
+            ReturnWithOffset { .. } => 12,
+            JumpEqual { .. }
+            | JumpLessThan { .. }
+            | JumpLessThanEqual { .. }
+            | JumpBelow { .. }
+            | JumpBelowEqual { .. }
+            | JumpParityEven { .. }
+            | JumpOverflow { .. }
+            | JumpSign { .. }
+            | JumpParityOdd { .. }
+            | JumpNotEqual { .. }
+            | JumpNotLessThan { .. }
+            | JumpNotLessThanEqual { .. }
+            | JumpNotBelow { .. }
+            | JumpNotBelowEqual { .. }
+            | JumpNotOverflow { .. }
+            | JumpNotSign { .. } => 16,
+            Loop { .. } => 17,
+            LoopWhileZero { .. } | JumpCxZero { .. } => 18,
+            LoopWhileNotZero { .. } => 19,
+            Interrupt { .. } => 51,
+            InterruptOnOverflow => 53,
+            InterruptReturn => 24,
+            In { .. } | Out { .. } => 10,
+            Xlat => 11,
+            Pushf => 10,
+            Popf => 8,
+            Lahf | Sahf | Aaa | Aas | Daa | Das => 4,
+            Aam => 83,
+            Aad => 60,
+            Cwd => 5,
+            Nop | Wait => 3,
+            Cbw | ClearCarry | ComplementCarry | SetCarry | ClearDirection | SetDirection
+            | ClearInterrupt | SetInterrupt | Halt | Lock => 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::decode_instruction;
+    use crate::emu::RegisterState;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_estimated_cycles() {
+        #[rustfmt::skip]
+        let tests: &[(&[u8], u64)] = &[
+            (&[0x89, 0xd8],             2),      // mov ax, bx
+            (&[0xb8, 0x01, 0x00],       4),      // mov ax, 1
+            (&[0x8b, 0x07],             8 + 5),  // mov ax, [bx]
+            (&[0x89, 0x40, 0x02],       9 + 11), // mov [bx + si + 2], ax
+            (&[0x01, 0x00],             16 + 7), // add [bx + si], ax
+            (&[0x26, 0x03, 0x01],       9 + 10), // add ax, es:[bx + di]
+            (&[0x83, 0xf9, 0x01],       4),      // cmp cx, 1
+            (&[0xe2, 0xfe],             17),     // loop $
+            (&[0xf4],                   2),      // hlt
+        ];
+
+        for (bytes, cycles) in tests {
+            let mut memory = Memory::<{ 64 * 1024 }>::new();
+            memory.memory[..bytes.len()].copy_from_slice(bytes);
+            memory.length = bytes.len();
+
+            let instr = decode_instruction(&mut RegisterState::default(), &memory).unwrap();
+            assert_eq!(instr.estimated_cycles(), *cycles, "{instr}");
+        }
+    }
+}
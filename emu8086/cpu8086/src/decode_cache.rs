@@ -0,0 +1,155 @@
+//! A cache of predecoded instructions keyed by CS:IP
+
+use anyhow::Result;
+
+use std::ops::Range;
+
+use crate::const_checks::{is_valid_address_size, If, True};
+use crate::decoder::decode_instruction;
+use crate::emu::RegisterState;
+use crate::instruction::Instruction;
+use crate::memory::Memory;
+
+/// A decoded instruction along with where it was decoded from
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Code segment the instruction was decoded in
+    cs: u16,
+
+    /// Number of bytes the instruction occupies in memory
+    size: u16,
+
+    /// The decoded instruction
+    instr: Instruction,
+}
+
+/// Statistics of a [`DecodeCache`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DecodeCacheStats {
+    /// Number of instructions returned from the cache
+    pub hits: u64,
+
+    /// Number of instructions that had to be decoded
+    pub misses: u64,
+
+    /// Number of cached instructions dropped due to writes into their bytes
+    pub invalidations: u64,
+}
+
+/// A cache of decoded instructions keyed by CS:IP
+///
+/// Writes to memory must be reported via [`DecodeCache::invalidate`] before the next
+/// decode so that self-modifying code sees the newly written bytes.
+pub struct DecodeCache {
+    /// Cached instructions indexed by the IP they were decoded at
+    entries: Vec<Option<Entry>>,
+
+    /// Size of the largest instruction cached so far
+    max_size: usize,
+
+    /// Hit, miss and invalidation counters
+    pub stats: DecodeCacheStats,
+}
+
+impl DecodeCache {
+    /// Create an empty cache for a memory of `size` bytes
+    pub fn new(size: usize) -> Self {
+        Self {
+            entries: vec![None; size],
+            max_size: 0,
+            stats: DecodeCacheStats::default(),
+        }
+    }
+
+    /// Get the instruction at CS:IP, decoding and caching it on a miss.
+    ///
+    /// Like [`decode_instruction`], this advances IP past the returned instruction.
+    pub fn decode<const SIZE: usize>(
+        &mut self,
+        cs: u16,
+        cpu: &mut RegisterState,
+        memory: &Memory<SIZE>,
+    ) -> Result<Instruction>
+    where
+        If<{ is_valid_address_size(SIZE) }>: True,
+    {
+        let ip = cpu.ip();
+
+        if let Some(entry) = &self.entries[usize::from(ip)] {
+            if entry.cs == cs {
+                self.stats.hits += 1;
+                *cpu.ip_mut() = ip.wrapping_add(entry.size);
+                return Ok(entry.instr.clone());
+            }
+        }
+
+        self.stats.misses += 1;
+
+        let instr = decode_instruction(cpu, memory)?;
+        let size = cpu.ip().wrapping_sub(ip);
+        self.max_size = self.max_size.max(usize::from(size));
+
+        self.entries[usize::from(ip)] = Some(Entry {
+            cs,
+            size,
+            instr: instr.clone(),
+        });
+
+        Ok(instr)
+    }
+
+    /// Drop every cached instruction overlapping the `written` range of memory
+    pub fn invalidate(&mut self, written: &Range<usize>) {
+        // An instruction starting up to `max_size - 1` bytes before the write can overlap it
+        let start = written
+            .start
+            .saturating_sub(self.max_size.saturating_sub(1));
+        let end = written.end.min(self.entries.len());
+
+        for (ip, entry) in self.entries[start.min(end)..end].iter_mut().enumerate() {
+            let ip = start + ip;
+            if matches!(entry, Some(e) if ip + usize::from(e.size) > written.start) {
+                *entry = None;
+                self.stats.invalidations += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Address;
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut memory = Memory::<{ 64 * 1024 }>::new();
+        let mut cpu = RegisterState::default();
+        let mut cache = DecodeCache::new(64 * 1024);
+
+        // mov ax, 0x1234
+        memory.memory[..3].copy_from_slice(&[0xb8, 0x34, 0x12]);
+
+        let first = cache.decode(0, &mut cpu, &memory).unwrap();
+        assert_eq!(cpu.ip(), 3);
+
+        *cpu.ip_mut() = 0;
+        assert_eq!(cache.decode(0, &mut cpu, &memory).unwrap(), first);
+        assert_eq!(cache.stats.hits, 1);
+
+        // A different code segment does not hit the cached instruction
+        *cpu.ip_mut() = 0;
+        cache.decode(1, &mut cpu, &memory).unwrap();
+        assert_eq!(cache.stats.misses, 2);
+
+        // Patch the immediate to mov ax, 0x5678
+        memory.write(Address(1), 0x5678_u16).unwrap();
+        cache.invalidate(&memory.take_dirty()[0]);
+        assert_eq!(cache.stats.invalidations, 1);
+
+        *cpu.ip_mut() = 0;
+        let patched = cache.decode(1, &mut cpu, &memory).unwrap();
+        assert_ne!(patched, first);
+        assert_eq!(cache.stats.misses, 3);
+    }
+}
@@ -4,8 +4,10 @@ use anyhow::Result;
 
 use std::path::Path;
 
+use crate::budget::{Budget, Spent};
 use crate::const_checks::{is_valid_address_size, If, True};
-use crate::flags::EFlags;
+use crate::coverage::Coverage;
+use crate::flags::{status_flags, EFlags, FlagOp, STATUS_FLAGS};
 use crate::instruction::{Instruction, Operand};
 use crate::memory::{Address, Memory};
 use crate::memory_operand::{MemoryOperand, MemorySize};
@@ -20,12 +22,21 @@ pub struct Emulator<const MEMORY_SIZE: usize> {
 
     /// Segment registers
     pub segments: [u16; std::mem::variant_count::<SegmentRegister>()],
+
+    /// Limits on the instructions and cycles to run
+    pub budget: Budget,
+
+    /// Instructions and cycles run so far
+    pub spent: Spent,
+
+    /// Instruction and branch edge coverage, recorded if set
+    pub coverage: Option<Coverage>,
 }
 
 /// The register state of the emulator
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct RegisterState {
-    regs: [u16; 10],
+    pub(crate) regs: [u16; 10],
 }
 
 macro_rules! impl_reg {
@@ -114,6 +125,9 @@ where
             memory: Memory::new(),
             registers: RegisterState::default(),
             segments: [0; std::mem::variant_count::<SegmentRegister>()],
+            budget: Budget::default(),
+            spent: Spent::default(),
+            coverage: None,
         }
     }
 
@@ -123,6 +137,9 @@ where
             memory: Memory::from_file(path)?,
             registers: RegisterState::default(),
             segments: [0; std::mem::variant_count::<SegmentRegister>()],
+            budget: Budget::default(),
+            spent: Spent::default(),
+            coverage: None,
         })
     }
 
@@ -155,10 +172,10 @@ where
         let mut addr = address.unwrap_or(0);
 
         if let Some(reg1) = registers[0] {
-            addr += self.get_register_value(&reg1);
+            addr = addr.wrapping_add(self.get_register_value(&reg1));
         }
         if let Some(reg2) = registers[1] {
-            addr += self.get_register_value(&reg2);
+            addr = addr.wrapping_add(self.get_register_value(&reg2));
         }
         if let Some(disp) = displacement {
             addr = addr.wrapping_add_signed(*disp);
@@ -228,29 +245,81 @@ where
         self.registers.regs[dest_reg as usize] = value;
     }
 
-    /// Set the status flags based on the given val
-    pub fn set_status_flags(&mut self, val: u16, arg1: u16, arg2: u16) {
-        let mut new_flags = 0;
-        if val == 0 {
-            new_flags |= EFlags::Zero as u16;
-        }
-        if val & 0x8000 > 0 {
-            new_flags |= EFlags::Sign as u16;
-        }
-        if arg1 & 0x8000 > 0 && arg2 & 0x8000 > 0 && val & 0x8000 == 0 {
-            new_flags |= EFlags::Overflow as u16;
-        }
-        if arg1 & 0x8000 == 0 && arg2 & 0x8000 == 0 && val & 0x8000 > 0 {
-            new_flags |= EFlags::Carry as u16;
-        }
-        if (val & 0xff).count_ones() % 2 == 0 {
-            new_flags |= EFlags::Parity as u16;
-        }
-        if arg1 & 0x80 == 0 && arg2 & 0x80 == 0 && val & 0x80 > 0 {
-            new_flags |= EFlags::Auxillary as u16;
+    /// Set the status flags for `val = arg1 OP arg2`, leaving the other flags untouched
+    pub fn set_status_flags(
+        &mut self,
+        op: FlagOp,
+        val: u16,
+        arg1: u16,
+        arg2: u16,
+        size: MemorySize,
+    ) {
+        let flags = &mut self.registers.regs[Register::Flags as usize];
+        *flags = (*flags & !STATUS_FLAGS) | status_flags(op, val, arg1, arg2, size);
+    }
+
+    /// Get the offset of the conditional jump `instr` and whether it is taken
+    fn jump_condition(&self, instr: &Instruction) -> Option<(i8, bool)> {
+        let less = self.sign_flag() != self.overflow_flag();
+
+        let res = match *instr {
+            Instruction::JumpEqual { offset } => (offset, self.zero_flag()),
+            Instruction::JumpNotEqual { offset } => (offset, !self.zero_flag()),
+            Instruction::JumpBelow { offset } => (offset, self.carry_flag()),
+            Instruction::JumpNotBelow { offset } => (offset, !self.carry_flag()),
+            Instruction::JumpBelowEqual { offset } => {
+                (offset, self.carry_flag() || self.zero_flag())
+            }
+            Instruction::JumpNotBelowEqual { offset } => {
+                (offset, !self.carry_flag() && !self.zero_flag())
+            }
+            Instruction::JumpLessThan { offset } => (offset, less),
+            Instruction::JumpNotLessThan { offset } => (offset, !less),
+            Instruction::JumpLessThanEqual { offset } => (offset, less || self.zero_flag()),
+            Instruction::JumpNotLessThanEqual { offset } => (offset, !less && !self.zero_flag()),
+            Instruction::JumpParityEven { offset } => (offset, self.parity_flag()),
+            Instruction::JumpParityOdd { offset } => (offset, !self.parity_flag()),
+            Instruction::JumpOverflow { offset } => (offset, self.overflow_flag()),
+            Instruction::JumpNotOverflow { offset } => (offset, !self.overflow_flag()),
+            Instruction::JumpSign { offset } => (offset, self.sign_flag()),
+            Instruction::JumpNotSign { offset } => (offset, !self.sign_flag()),
+            Instruction::JumpCxZero { offset } => (offset, self.cx() == 0),
+            _ => return None,
+        };
+
+        Some(res)
+    }
+
+    /// Apply `func` to the `dest` register and `src`, setting the status flags for `op` and
+    /// writing the result back to `dest` if `writes` is set
+    fn execute_alu(
+        &mut self,
+        dest: &Register,
+        src: &Operand,
+        op: FlagOp,
+        writes: bool,
+        func: fn(u16, u16) -> u16,
+    ) -> Result<()> {
+        let (size, mask) = match dest.as_sub_register().1 {
+            SubRegister::Full => (MemorySize::Word, 0xffff),
+            SubRegister::Low | SubRegister::High => (MemorySize::Byte, 0xff),
+        };
+
+        let dest_val = self.get_register_value(dest);
+        let src_val = match src {
+            Operand::Register(src) => self.get_register_value(src),
+            Operand::Immediate(imm) => *imm as u16,
+            Operand::Memory(mem_op) => self.read_memory(mem_op)?,
+            _ => unreachable!(),
+        } & mask;
+
+        let new_val = func(dest_val, src_val) & mask;
+        if writes {
+            self.set_register_value(dest, new_val);
         }
+        self.set_status_flags(op, new_val, dest_val, src_val, size);
 
-        self.registers.regs[Register::Flags as usize] = new_flags;
+        Ok(())
     }
 
     /// Print the CPU state
@@ -311,7 +380,123 @@ where
         }
     }
 
+    /// Charge an instruction taking `cycles` to the budget, failing with
+    /// [`BudgetExhausted`](crate::budget::BudgetExhausted) if it is used up
+    pub fn charge(&mut self, cycles: u64) -> Result<()> {
+        self.budget.charge(&mut self.spent, 1, cycles)?;
+        Ok(())
+    }
+
+    /// Record the coverage of the instruction at `ip` that was just executed, along with
+    /// the edge to the current IP if it is a `branch`
+    pub fn cover(&mut self, ip: u16, branch: bool) {
+        let next = self.ip();
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.hit(ip);
+            if branch {
+                coverage.edge(ip, next);
+            }
+        }
+    }
+
+    /// Execute `instr` decoded at `ip` after charging it to the budget, recording its
+    /// coverage if enabled
+    pub fn step(&mut self, ip: u16, instr: &Instruction) -> Result<()> {
+        self.charge(instr.estimated_cycles())?;
+        self.execute(instr)?;
+        self.cover(ip, instr.ends_basic_block());
+        Ok(())
+    }
+
+    /// Returns `true` if `instr` can be run by [`Emulator::execute`]
+    pub fn supports(instr: &Instruction) -> bool {
+        let full = |reg: &Register| reg.as_sub_register().1 == SubRegister::Full;
+        let alu_src = |src: &Operand| !matches!(src, Operand::SegmentRegister(_));
+
+        match instr {
+            Instruction::JumpEqual { .. }
+            | Instruction::JumpNotEqual { .. }
+            | Instruction::JumpBelow { .. }
+            | Instruction::JumpNotBelow { .. }
+            | Instruction::JumpBelowEqual { .. }
+            | Instruction::JumpNotBelowEqual { .. }
+            | Instruction::JumpLessThan { .. }
+            | Instruction::JumpNotLessThan { .. }
+            | Instruction::JumpLessThanEqual { .. }
+            | Instruction::JumpNotLessThanEqual { .. }
+            | Instruction::JumpParityEven { .. }
+            | Instruction::JumpParityOdd { .. }
+            | Instruction::JumpOverflow { .. }
+            | Instruction::JumpNotOverflow { .. }
+            | Instruction::JumpSign { .. }
+            | Instruction::JumpNotSign { .. }
+            | Instruction::JumpCxZero { .. }
+            | Instruction::Loop { .. }
+            | Instruction::LoopWhileNotZero { .. } => true,
+            Instruction::Mov {
+                dest: Operand::Register(_),
+                src: Operand::Immediate(_) | Operand::Register(_) | Operand::Memory(_),
+            } => true,
+            Instruction::Mov {
+                dest: Operand::SegmentRegister(_),
+                src: Operand::Register(reg),
+            }
+            | Instruction::Mov {
+                dest: Operand::Register(reg),
+                src: Operand::SegmentRegister(_),
+            } => full(reg),
+            Instruction::Mov {
+                dest: Operand::Memory(MemoryOperand {
+                    address: Some(_), ..
+                }),
+                src,
+            } => matches!(src, Operand::Immediate(_)),
+            Instruction::Mov {
+                dest: Operand::Memory(_),
+                src,
+            } => matches!(src, Operand::Immediate(_) | Operand::Register(_)),
+            Instruction::Add {
+                dest: Operand::Register(_),
+                src,
+            }
+            | Instruction::Sub {
+                dest: Operand::Register(_),
+                src,
+            }
+            | Instruction::And {
+                dest: Operand::Register(_),
+                src,
+            }
+            | Instruction::Test {
+                dest: Operand::Register(_),
+                src,
+            }
+            | Instruction::Or {
+                dest: Operand::Register(_),
+                src,
+            }
+            | Instruction::Xor {
+                dest: Operand::Register(_),
+                src,
+            }
+            | Instruction::Cmp {
+                left: Operand::Register(_),
+                right: src,
+            } => alu_src(src),
+            _ => false,
+        }
+    }
+
     pub fn execute(&mut self, instr: &Instruction) -> Result<()> {
+        if let Some((offset, taken)) = self.jump_condition(instr) {
+            if taken {
+                let new_ip = self.ip().wrapping_add_signed(i16::from(offset) - 2);
+                self.set_register_value(&Register::Ip, new_ip);
+            }
+
+            return Ok(());
+        }
+
         match instr {
             Instruction::Mov {
                 dest: Operand::Register(dest),
@@ -394,13 +579,13 @@ where
                         segment: _,
                     }),
             } => {
-                let mut addr = 0;
+                let mut addr = 0_u16;
 
                 if let Some(reg1) = registers[0] {
-                    addr += self.get_register_value(&reg1);
+                    addr = addr.wrapping_add(self.get_register_value(&reg1));
                 }
                 if let Some(reg2) = registers[1] {
-                    addr += self.get_register_value(&reg2);
+                    addr = addr.wrapping_add(self.get_register_value(&reg2));
                 }
                 if let Some(disp) = displacement {
                     addr = addr.wrapping_add_signed(*disp);
@@ -410,7 +595,7 @@ where
 
                 let value = match size {
                     Some(MemorySize::Word) => self.memory.read::<i16>(addr)?,
-                    Some(MemorySize::Byte) => self.memory.read::<i8>(addr)? as i16,
+                    Some(MemorySize::Byte) => self.memory.read::<u8>(addr)? as i16,
                     None => unreachable!(),
                 };
 
@@ -432,92 +617,41 @@ where
 
                 let value = match size {
                     Some(MemorySize::Word) => self.memory.read::<i16>(addr)?,
-                    Some(MemorySize::Byte) => self.memory.read::<i8>(addr)? as i16,
+                    Some(MemorySize::Byte) => self.memory.read::<u8>(addr)? as i16,
                     None => unreachable!(),
                 };
 
                 // Write the value into memory
                 self.set_register_value(dest, value as u16);
             }
-            Instruction::Sub {
+            Instruction::Add {
                 dest: Operand::Register(dest),
-                src: Operand::Register(src),
-            } => {
-                let dest_val = self.get_register_value(dest);
-                let src_val = self.get_register_value(src);
-
-                let new_val = dest_val.wrapping_sub(src_val);
-
-                // Set the status flags based on the resulting value
-                self.set_status_flags(new_val, dest_val, src_val);
-
-                self.set_register_value(dest, new_val);
-            }
+                src,
+            } => self.execute_alu(dest, src, FlagOp::Add, true, u16::wrapping_add)?,
             Instruction::Sub {
                 dest: Operand::Register(dest),
                 src,
-            } => {
-                let dest_val = self.get_register_value(dest);
-
-                let src_val = match src {
-                    Operand::Register(src) => self.get_register_value(src),
-                    Operand::Immediate(imm) => *imm as u16,
-                    _ => unreachable!(),
-                };
-
-                let new_val = dest_val.wrapping_sub(src_val);
-                self.set_register_value(dest, new_val);
-                self.set_status_flags(new_val, dest_val, src_val);
-            }
-            Instruction::Add {
+            } => self.execute_alu(dest, src, FlagOp::Sub, true, u16::wrapping_sub)?,
+            Instruction::Cmp {
+                left: Operand::Register(left),
+                right,
+            } => self.execute_alu(left, right, FlagOp::Sub, false, u16::wrapping_sub)?,
+            Instruction::And {
                 dest: Operand::Register(dest),
                 src,
-            } => {
-                let dest_val = self.get_register_value(dest);
-
-                let src_val = match src {
-                    Operand::Register(src) => self.get_register_value(src),
-                    Operand::Immediate(imm) => *imm as u16,
-                    Operand::Memory(mem_op) => self.read_memory(mem_op)?,
-                    _ => unreachable!(),
-                };
-
-                let new_val = dest_val.wrapping_add(src_val);
-                self.set_register_value(dest, new_val);
-                self.set_status_flags(new_val, dest_val, src_val);
-            }
-            Instruction::Cmp { left, right } => {
-                let dest_val = match left {
-                    Operand::Register(dest) => self.get_register_value(&dest),
-                    _ => unimplemented!(),
-                };
-                let src_val = match right {
-                    Operand::Register(src) => self.get_register_value(&src),
-                    Operand::Immediate(imm) => *imm as u16,
-                    _ => unimplemented!(),
-                };
-
-                let new_val = dest_val.wrapping_sub(src_val);
-                self.set_status_flags(new_val, dest_val, src_val);
-            }
-            Instruction::JumpNotEqual { offset } => {
-                if !self.zero_flag() {
-                    let new_ip = self.ip().wrapping_add_signed(*offset as i16 - 2);
-                    self.set_register_value(&Register::Ip, new_ip);
-                }
-            }
-            Instruction::JumpEqual { offset } => {
-                if self.zero_flag() {
-                    let new_ip = self.ip().wrapping_add_signed(*offset as i16 - 2);
-                    self.set_register_value(&Register::Ip, new_ip);
-                }
-            }
-            Instruction::JumpBelow { offset } => {
-                if self.carry_flag() {
-                    let new_ip = self.ip().wrapping_add_signed(*offset as i16 - 2);
-                    self.set_register_value(&Register::Ip, new_ip);
-                }
-            }
+            } => self.execute_alu(dest, src, FlagOp::Logic, true, |a, b| a & b)?,
+            Instruction::Test {
+                dest: Operand::Register(dest),
+                src,
+            } => self.execute_alu(dest, src, FlagOp::Logic, false, |a, b| a & b)?,
+            Instruction::Or {
+                dest: Operand::Register(dest),
+                src,
+            } => self.execute_alu(dest, src, FlagOp::Logic, true, |a, b| a | b)?,
+            Instruction::Xor {
+                dest: Operand::Register(dest),
+                src,
+            } => self.execute_alu(dest, src, FlagOp::Logic, true, |a, b| a ^ b)?,
             Instruction::Loop { offset } => {
                 let new_cx = self.cx().saturating_sub(1);
                 self.set_register_value(&Register::Cx, new_cx);
@@ -536,13 +670,6 @@ where
                     self.set_register_value(&Register::Ip, new_ip);
                 }
             }
-            Instruction::JumpParityEven { offset } => {
-                if self.parity_flag() {
-                    let new_ip = self.ip().wrapping_add_signed(*offset as i16 - 2);
-                    self.set_register_value(&Register::Ip, new_ip);
-                }
-            }
-
             _ => panic!("Cannot execute: {instr:?}"),
         }
 
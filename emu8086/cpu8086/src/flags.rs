@@ -1,5 +1,7 @@
 //! EFlags implementation
 
+use crate::memory_operand::MemorySize;
+
 pub enum EFlags {
     Carry = (1 << 0),
     Parity = (1 << 2),
@@ -8,3 +10,116 @@ pub enum EFlags {
     Sign = (1 << 7),
     Overflow = (1 << 11),
 }
+
+/// Mask of the status flags written by arithmetic and logic instructions
+pub const STATUS_FLAGS: u16 = EFlags::Carry as u16
+    | EFlags::Parity as u16
+    | EFlags::Auxillary as u16
+    | EFlags::Zero as u16
+    | EFlags::Sign as u16
+    | EFlags::Overflow as u16;
+
+/// The kind of operation producing a result, which decides how the status flags are set
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlagOp {
+    /// `add`: CF is the carry out and OF the signed overflow
+    Add,
+
+    /// `sub` and `cmp`: CF is the borrow and OF the signed overflow
+    Sub,
+
+    /// `and`, `or`, `xor` and `test`: CF, OF and AF are cleared
+    Logic,
+}
+
+/// Compute the status flags of `result = a OP b` for an operation of the given `size`
+///
+/// For byte operations only the low byte of each value is used.
+pub fn status_flags(op: FlagOp, result: u16, a: u16, b: u16, size: MemorySize) -> u16 {
+    let (mask, sign) = match size {
+        MemorySize::Byte => (0xff, 0x80),
+        MemorySize::Word => (0xffff, 0x8000),
+    };
+
+    let (result, a, b) = (result & mask, a & mask, b & mask);
+
+    let mut flags = 0;
+    if result == 0 {
+        flags |= EFlags::Zero as u16;
+    }
+    if result & sign > 0 {
+        flags |= EFlags::Sign as u16;
+    }
+    if (result & 0xff).count_ones() % 2 == 0 {
+        flags |= EFlags::Parity as u16;
+    }
+
+    let (carry, overflow) = match op {
+        FlagOp::Add => (
+            u32::from(a) + u32::from(b) > u32::from(mask),
+            (a ^ result) & (b ^ result) & sign > 0,
+        ),
+        FlagOp::Sub => (b > a, (a ^ b) & (a ^ result) & sign > 0),
+        FlagOp::Logic => return flags,
+    };
+
+    if carry {
+        flags |= EFlags::Carry as u16;
+    }
+    if overflow {
+        flags |= EFlags::Overflow as u16;
+    }
+    if (a ^ b ^ result) & 0x10 > 0 {
+        flags |= EFlags::Auxillary as u16;
+    }
+
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_flags() {
+        let c = EFlags::Carry as u16;
+        let p = EFlags::Parity as u16;
+        let a = EFlags::Auxillary as u16;
+        let z = EFlags::Zero as u16;
+        let s = EFlags::Sign as u16;
+        let o = EFlags::Overflow as u16;
+
+        // add ax, -1 with ax = 4
+        assert_eq!(
+            status_flags(FlagOp::Add, 3, 4, 0xffff, MemorySize::Word),
+            c | p | a
+        );
+
+        // add ax, 1 with ax = 0x7fff
+        let flags = status_flags(FlagOp::Add, 0x8000, 0x7fff, 1, MemorySize::Word);
+        assert_eq!(flags, p | a | s | o);
+
+        // cmp bx, ax with bx = 3, ax = 4
+        assert_eq!(
+            status_flags(FlagOp::Sub, 0xffff, 3, 4, MemorySize::Word),
+            c | p | a | s
+        );
+
+        // sub ax, ax
+        assert_eq!(status_flags(FlagOp::Sub, 0, 5, 5, MemorySize::Word), p | z);
+
+        // sub al, 1 with al = 0x80
+        let flags = status_flags(FlagOp::Sub, 0x7f, 0x80, 1, MemorySize::Byte);
+        assert_eq!(flags, a | o);
+
+        // add al, 0x80 with al = 0x80 ignores the high byte
+        let flags = status_flags(FlagOp::Add, 0x1200, 0x1280, 0x80, MemorySize::Byte);
+        assert_eq!(flags, c | p | z | o);
+
+        // xor ax, 0x8001
+        assert_eq!(
+            status_flags(FlagOp::Logic, 0x8001, 0, 0x8001, MemorySize::Word),
+            s
+        );
+    }
+}
@@ -1075,3 +1075,41 @@ impl Instruction {
     }
 }
 */
+
+impl Instruction {
+    /// Returns `true` if this instruction can transfer control somewhere other than the
+    /// next instruction, ending a basic block
+    pub fn ends_basic_block(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Call { .. }
+                | Instruction::Jump { .. }
+                | Instruction::ReturnWithOffset { .. }
+                | Instruction::Return
+                | Instruction::JumpEqual { .. }
+                | Instruction::JumpLessThan { .. }
+                | Instruction::JumpLessThanEqual { .. }
+                | Instruction::JumpBelow { .. }
+                | Instruction::JumpBelowEqual { .. }
+                | Instruction::JumpParityEven { .. }
+                | Instruction::JumpOverflow { .. }
+                | Instruction::JumpSign { .. }
+                | Instruction::JumpParityOdd { .. }
+                | Instruction::JumpNotEqual { .. }
+                | Instruction::JumpNotLessThan { .. }
+                | Instruction::JumpNotLessThanEqual { .. }
+                | Instruction::JumpNotBelow { .. }
+                | Instruction::JumpNotBelowEqual { .. }
+                | Instruction::JumpNotOverflow { .. }
+                | Instruction::JumpNotSign { .. }
+                | Instruction::Loop { .. }
+                | Instruction::LoopWhileZero { .. }
+                | Instruction::LoopWhileNotZero { .. }
+                | Instruction::JumpCxZero { .. }
+                | Instruction::Interrupt { .. }
+                | Instruction::InterruptOnOverflow
+                | Instruction::InterruptReturn
+                | Instruction::Halt
+        )
+    }
+}
@@ -2,7 +2,11 @@
 #![feature(variant_count)]
 #![allow(incomplete_features)]
 
+pub mod budget;
 pub mod const_checks;
+pub mod coverage;
+mod cycles;
+pub mod decode_cache;
 pub mod decoder;
 pub mod emu;
 pub mod flags;
@@ -10,3 +14,4 @@ pub mod instruction;
 pub mod memory;
 pub mod memory_operand;
 pub mod register;
+pub mod threaded;
@@ -2,7 +2,7 @@ use anyhow::{ensure, Result};
 use thiserror::Error;
 
 use std::mem::size_of;
-use std::ops::{Add, Deref};
+use std::ops::{Add, Deref, Range};
 use std::path::Path;
 
 use crate::const_checks::{is_valid_address_size, If, True};
@@ -13,8 +13,14 @@ pub struct Memory<const SIZE: usize> {
 
     /// Length of valid memory
     pub length: usize,
+
+    /// Ranges of bytes written since the last call to [`Memory::take_dirty`]
+    dirty: Vec<Range<usize>>,
 }
 
+/// Number of separate dirty ranges tracked before new writes are merged into the last one
+const MAX_DIRTY_RANGES: usize = 16;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Attempted to read an out of bounds address: {0:x?}")]
@@ -59,6 +65,7 @@ where
         Memory {
             memory: [0x0_u8; SIZE],
             length: 0,
+            dirty: Vec::new(),
         }
     }
 
@@ -81,6 +88,7 @@ where
         Ok(Memory {
             memory,
             length: data.len(),
+            dirty: Vec::new(),
         })
     }
 
@@ -95,7 +103,12 @@ where
         );
 
         // Read the value from the memory
-        let res = unsafe { *(self.memory[address.0..].as_ptr().cast()) };
+        let res = unsafe {
+            self.memory[address.0..]
+                .as_ptr()
+                .cast::<T>()
+                .read_unaligned()
+        };
 
         Ok(res)
     }
@@ -119,9 +132,31 @@ where
 
         // Write the value from the memory
         unsafe {
-            *(self.memory[start..end_addr].as_mut_ptr().cast()) = value;
+            self.memory[start..end_addr]
+                .as_mut_ptr()
+                .cast::<T>()
+                .write_unaligned(value);
+        }
+
+        // Track distant writes separately so they don't cover all the bytes between them
+        let full = self.dirty.len() >= MAX_DIRTY_RANGES;
+        match self.dirty.last_mut() {
+            Some(last) if full || (last.start <= end_addr && start <= last.end) => {
+                *last = last.start.min(start)..last.end.max(end_addr);
+            }
+            _ => self.dirty.push(start..end_addr),
         }
 
         Ok(())
     }
+
+    /// Get the ranges of bytes written since the last call to [`Memory::take_dirty`]
+    pub fn dirty(&self) -> &[Range<usize>] {
+        &self.dirty
+    }
+
+    /// Get the ranges of bytes written since the last call and reset the tracking
+    pub fn take_dirty(&mut self) -> Vec<Range<usize>> {
+        std::mem::take(&mut self.dirty)
+    }
 }
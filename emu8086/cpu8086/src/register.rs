@@ -141,6 +141,13 @@ pub enum SegmentRegister {
     Ds,
 }
 
+impl SegmentRegister {
+    /// Convert the given segment register, which follows the 16-bit registers
+    pub fn as_zmm(self) -> u8 {
+        self as u8 + Register::Flags.as_zmm() + 1
+    }
+}
+
 /// Error while parsing a segment register
 #[derive(Error, Debug)]
 pub enum SegmentRegisterError {
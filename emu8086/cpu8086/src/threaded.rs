@@ -0,0 +1,450 @@
+//! A basic-block threaded execution engine
+//!
+//! Each basic block is decoded once into a list of handlers with their operands already
+//! resolved. Blocks are cached by the CS:IP they start at and chained by looking up the
+//! block at the IP the previous block left off at.
+
+use anyhow::{ensure, Result};
+
+use std::ops::Range;
+
+use crate::const_checks::{is_valid_address_size, If, True};
+use crate::decoder::decode_instruction;
+use crate::emu::{Emulator, RegisterState};
+use crate::flags::FlagOp;
+use crate::instruction::{Instruction, Operand};
+use crate::memory_operand::MemorySize;
+use crate::register::{Register, SegmentRegister, SubRegister};
+
+/// Maximum number of instructions translated into a single block
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+/// A pre-resolved instruction handler
+type Handler<const MEMORY_SIZE: usize> = Box<dyn Fn(&mut Emulator<MEMORY_SIZE>) -> Result<()>>;
+
+/// A single translated instruction
+struct Op<const MEMORY_SIZE: usize> {
+    /// Handler performing the instruction
+    handler: Handler<MEMORY_SIZE>,
+
+    /// IP of the instruction following this one
+    next_ip: u16,
+
+    /// Set if the handler falls back to [`Emulator::execute`] and might write memory
+    may_write: bool,
+
+    /// Estimated cycles of the instruction, charged to the budget of the emulator
+    cycles: u64,
+
+    /// Set if the instruction can branch, recording an edge in the coverage of the emulator
+    branch: bool,
+}
+
+/// A translated basic block
+struct Block<const MEMORY_SIZE: usize> {
+    /// Code segment the block was translated in
+    cs: u16,
+
+    /// Number of bytes of code covered by this block
+    size: u16,
+
+    /// Translated instructions
+    ops: Vec<Op<MEMORY_SIZE>>,
+}
+
+/// Statistics of a [`ThreadedEngine`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ThreadedStats {
+    /// Number of blocks translated
+    pub blocks_translated: u64,
+
+    /// Number of instructions translated
+    pub instructions_translated: u64,
+
+    /// Number of blocks executed
+    pub blocks_executed: u64,
+
+    /// Number of translated blocks dropped due to writes into their code
+    pub invalidations: u64,
+}
+
+/// Executes an [`Emulator`] one translated basic block at a time
+pub struct ThreadedEngine<const MEMORY_SIZE: usize> {
+    /// Translated blocks indexed by the IP they start at
+    blocks: Vec<Option<Block<MEMORY_SIZE>>>,
+
+    /// Size in bytes of the largest block translated so far
+    max_block_size: usize,
+
+    /// Translation and execution counters
+    pub stats: ThreadedStats,
+}
+
+impl<const MEMORY_SIZE: usize> Default for ThreadedEngine<MEMORY_SIZE>
+where
+    If<{ is_valid_address_size(MEMORY_SIZE) }>: True,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Index into the register file for `reg` if it is a full 16-bit register
+fn full_register(operand: &Operand) -> Option<usize> {
+    match operand {
+        Operand::Register(reg) if reg.as_sub_register().1 == SubRegister::Full => {
+            Some(*reg as usize)
+        }
+        _ => None,
+    }
+}
+
+impl<const MEMORY_SIZE: usize> ThreadedEngine<MEMORY_SIZE>
+where
+    If<{ is_valid_address_size(MEMORY_SIZE) }>: True,
+{
+    /// Create an engine with no translated blocks
+    pub fn new() -> Self {
+        Self {
+            blocks: std::iter::repeat_with(|| None).take(MEMORY_SIZE).collect(),
+            max_block_size: 0,
+            stats: ThreadedStats::default(),
+        }
+    }
+
+    /// Translate the block at the current CS:IP of `emu` unless it is already translated
+    pub fn translate(&mut self, emu: &Emulator<MEMORY_SIZE>) -> Result<()> {
+        let cs = emu.segments[SegmentRegister::Cs as usize];
+        let start = emu.ip();
+
+        if matches!(&self.blocks[usize::from(start)], Some(block) if block.cs == cs) {
+            return Ok(());
+        }
+
+        let mut cpu = RegisterState::default();
+        *cpu.ip_mut() = start;
+
+        let mut ops = Vec::new();
+
+        while ops.len() < MAX_BLOCK_INSTRUCTIONS && usize::from(cpu.ip()) < emu.memory.length {
+            // Stop before an undecodable instruction unless it starts the block, so the
+            // error is raised when execution actually reaches it
+            let instr = match decode_instruction(&mut cpu, &emu.memory) {
+                Ok(instr) => instr,
+                Err(err) if ops.is_empty() => return Err(err),
+                Err(_) => break,
+            };
+
+            let ends_block = instr.ends_basic_block();
+            ops.push(Self::translate_instruction(instr, cpu.ip()));
+
+            if ends_block {
+                break;
+            }
+        }
+
+        ensure!(
+            !ops.is_empty(),
+            "No instructions to translate at {start:#x}"
+        );
+
+        let size = cpu.ip().wrapping_sub(start);
+        self.max_block_size = self.max_block_size.max(usize::from(size));
+
+        self.stats.blocks_translated += 1;
+        self.stats.instructions_translated += ops.len() as u64;
+
+        self.blocks[usize::from(start)] = Some(Block { cs, size, ops });
+
+        Ok(())
+    }
+
+    /// Build the handler for `instr` whose following instruction is at `next_ip`
+    fn translate_instruction(instr: Instruction, next_ip: u16) -> Op<MEMORY_SIZE> {
+        let cycles = instr.estimated_cycles();
+        let branch = instr.ends_basic_block();
+
+        /// Build an [`Op`] from a handler that only touches registers
+        macro_rules! op {
+            (|$emu:ident| $body:expr) => {
+                Op {
+                    handler: Box::new(move |$emu: &mut Emulator<MEMORY_SIZE>| {
+                        $body;
+                        Ok(())
+                    }),
+                    next_ip,
+                    may_write: false,
+                    cycles,
+                    branch,
+                }
+            };
+        }
+
+        /// Build a conditional branch to the baked in target
+        macro_rules! branch {
+            ($offset:expr, |$emu:ident| $cond:expr) => {{
+                let target = next_ip.wrapping_add_signed(i16::from($offset) - 2);
+                op!(|$emu| if $cond {
+                    $emu.registers.regs[Register::Ip as usize] = target;
+                })
+            }};
+        }
+
+        match &instr {
+            Instruction::Mov { dest, src } => match (full_register(dest), src) {
+                (Some(dest), Operand::Immediate(imm)) => {
+                    #[allow(clippy::cast_sign_loss)]
+                    let imm = *imm as u16;
+                    return op!(|emu| emu.registers.regs[dest] = imm);
+                }
+                (Some(dest), src) => {
+                    if let Some(src) = full_register(src) {
+                        return op!(|emu| emu.registers.regs[dest] = emu.registers.regs[src]);
+                    }
+                }
+                _ => {}
+            },
+            Instruction::Add { dest, src }
+            | Instruction::Sub { dest, src }
+            | Instruction::Cmp {
+                left: dest,
+                right: src,
+            } => {
+                let is_add = matches!(instr, Instruction::Add { .. });
+                let writes = !matches!(instr, Instruction::Cmp { .. });
+
+                if let Some(dest) = full_register(dest) {
+                    let apply = move |emu: &mut Emulator<MEMORY_SIZE>, src_val: u16| {
+                        let dest_val = emu.registers.regs[dest];
+                        let new_val = if is_add {
+                            dest_val.wrapping_add(src_val)
+                        } else {
+                            dest_val.wrapping_sub(src_val)
+                        };
+
+                        if writes {
+                            emu.registers.regs[dest] = new_val;
+                        }
+                        let op = if is_add { FlagOp::Add } else { FlagOp::Sub };
+                        emu.set_status_flags(op, new_val, dest_val, src_val, MemorySize::Word);
+                    };
+
+                    if let Operand::Immediate(imm) = src {
+                        #[allow(clippy::cast_sign_loss)]
+                        let imm = *imm as u16;
+                        return op!(|emu| apply(emu, imm));
+                    }
+
+                    if let Some(src) = full_register(src) {
+                        return op!(|emu| apply(emu, emu.registers.regs[src]));
+                    }
+                }
+            }
+            Instruction::JumpNotEqual { offset } => {
+                return branch!(*offset, |emu| !emu.zero_flag())
+            }
+            Instruction::JumpEqual { offset } => return branch!(*offset, |emu| emu.zero_flag()),
+            Instruction::JumpBelow { offset } => return branch!(*offset, |emu| emu.carry_flag()),
+            Instruction::JumpParityEven { offset } => {
+                return branch!(*offset, |emu| emu.parity_flag())
+            }
+            Instruction::Loop { offset } | Instruction::LoopWhileNotZero { offset } => {
+                return branch!(*offset, |emu| {
+                    let cx = &mut emu.registers.regs[Register::Cx as usize];
+                    *cx = cx.saturating_sub(1);
+                    *cx != 0
+                })
+            }
+            _ => {}
+        }
+
+        // Everything else is handled by the interpreter
+        Op {
+            handler: Box::new(move |emu: &mut Emulator<MEMORY_SIZE>| emu.execute(&instr)),
+            next_ip,
+            may_write: true,
+            cycles,
+            branch,
+        }
+    }
+
+    /// Execute the translated block at the current CS:IP of `emu`, returning the number of
+    /// instructions executed.
+    ///
+    /// The block must have been translated with [`ThreadedEngine::translate`]. Execution
+    /// leaves the block early if it writes into its own code, and fails with
+    /// [`BudgetExhausted`](crate::budget::BudgetExhausted) before an instruction once the
+    /// budget of `emu` is used up. The coverage of `emu` is recorded if enabled.
+    pub fn run(&mut self, emu: &mut Emulator<MEMORY_SIZE>) -> Result<u64> {
+        let start = usize::from(emu.ip());
+        let Some(block) = &self.blocks[start] else {
+            anyhow::bail!("No translated block at {start:#x}");
+        };
+
+        self.stats.blocks_executed += 1;
+
+        let code = start..start + usize::from(block.size);
+        let mut executed = 0;
+        let mut ip = emu.ip();
+
+        for op in &block.ops {
+            emu.charge(op.cycles)?;
+            *emu.ip_mut() = op.next_ip;
+            (op.handler)(emu)?;
+            emu.cover(ip, op.branch);
+            ip = op.next_ip;
+            executed += 1;
+
+            // Self-modifying code: stop so the rest of the block is translated again
+            if op.may_write
+                && emu
+                    .memory
+                    .dirty()
+                    .iter()
+                    .any(|w| w.start < code.end && code.start < w.end)
+            {
+                break;
+            }
+        }
+
+        Ok(executed)
+    }
+
+    /// Drop every translated block overlapping the `written` range of memory
+    pub fn invalidate(&mut self, written: &Range<usize>) {
+        // A block starting up to `max_block_size - 1` bytes before the write can overlap it
+        let start = written
+            .start
+            .saturating_sub(self.max_block_size.saturating_sub(1));
+        let end = written.end.min(self.blocks.len());
+
+        for (ip, block) in self.blocks[start.min(end)..end].iter_mut().enumerate() {
+            let ip = start + ip;
+            if matches!(block, Some(b) if ip + usize::from(b.size) > written.start) {
+                *block = None;
+                self.stats.invalidations += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::{BudgetExhausted, Spent};
+    use crate::coverage::Coverage;
+    use crate::memory::Address;
+
+    #[test]
+    fn test_matches_interpreter() {
+        // mov cx, 3; mov ax, 0; add ax, 5; loop -5; cmp ax, 15
+        let code = b"\xb9\x03\x00\xb8\x00\x00\x05\x05\x00\xe2\xfb\x83\xf8\x0f";
+
+        let mut interp = Emulator::<{ 64 * 1024 }>::new();
+        interp.memory.memory[..code.len()].copy_from_slice(code);
+        interp.memory.length = code.len();
+
+        let mut threaded = Emulator::<{ 64 * 1024 }>::new();
+        threaded.memory.memory[..code.len()].copy_from_slice(code);
+        threaded.memory.length = code.len();
+
+        interp.coverage = Some(Coverage::new());
+        threaded.coverage = Some(Coverage::new());
+
+        while usize::from(interp.ip()) < interp.memory.length {
+            let ip = interp.ip();
+            let instr = decode_instruction(&mut interp.registers, &interp.memory).unwrap();
+            interp.step(ip, &instr).unwrap();
+        }
+
+        let mut engine = ThreadedEngine::new();
+        let mut executed = 0;
+        while usize::from(threaded.ip()) < threaded.memory.length {
+            engine.translate(&threaded).unwrap();
+            executed += engine.run(&mut threaded).unwrap();
+        }
+
+        assert_eq!(interp.ax(), 15);
+        assert_eq!(threaded.registers, interp.registers);
+        assert_eq!(executed, 2 + 3 * 2 + 1);
+        assert_eq!(engine.stats.blocks_translated, 3);
+
+        // The loop at 0x09 branches back to 0x06 twice before falling through to 0x0b
+        let coverage = interp.coverage.as_ref().unwrap();
+        assert_eq!(threaded.coverage.as_ref(), Some(coverage));
+        assert_eq!((coverage.hits(0x06), coverage.hits(0x09)), (3, 3));
+        assert_eq!(
+            coverage.edges_from(0x09).collect::<Vec<_>>(),
+            [(0x06, 2), (0x0b, 1)]
+        );
+        assert_eq!((coverage.instructions(), coverage.edges()), (5, 2));
+    }
+
+    #[test]
+    fn test_distant_writes() {
+        let mut emu = Emulator::<{ 64 * 1024 }>::new();
+
+        // mov ax, 1; mov bx, 2 at 0x10
+        let code = b"\xb8\x01\x00\xbb\x02\x00";
+        emu.memory.memory[0x10..0x10 + code.len()].copy_from_slice(code);
+        emu.memory.length = 0x10 + code.len();
+        *emu.ip_mut() = 0x10;
+
+        let mut engine = ThreadedEngine::new();
+        engine.translate(&emu).unwrap();
+
+        // Writes on both sides of the block don't cover the block between them
+        emu.memory.write(Address(0), 0_u16).unwrap();
+        emu.memory.write(Address(0xde89), 0_u16).unwrap();
+        let written = emu.memory.take_dirty();
+        assert_eq!(written, [0..2, 0xde89..0xde8b]);
+
+        for written in &written {
+            engine.invalidate(written);
+        }
+        assert_eq!(engine.stats.invalidations, 0);
+        assert!(emu.memory.dirty().is_empty());
+    }
+
+    #[test]
+    fn test_budget() {
+        // add ax, 1; loop -5, which runs 65535 times from CX = 0xffff
+        let code = b"\x05\x01\x00\xe2\xfb";
+
+        let mut interp = Emulator::<{ 64 * 1024 }>::new();
+        interp.memory.memory[..code.len()].copy_from_slice(code);
+        interp.memory.length = code.len();
+        let mut threaded = Emulator::<{ 64 * 1024 }>::new();
+        threaded.memory.memory[..code.len()].copy_from_slice(code);
+        threaded.memory.length = code.len();
+        *interp.cx_mut() = u16::MAX;
+        *threaded.cx_mut() = u16::MAX;
+
+        interp.budget.instructions = Some(100);
+        let err = loop {
+            let ip = interp.ip();
+            let instr = decode_instruction(&mut interp.registers, &interp.memory).unwrap();
+            if let Err(err) = interp.step(ip, &instr) {
+                break err;
+            }
+        };
+        assert!(err.downcast_ref::<BudgetExhausted>().is_some());
+        assert_eq!(interp.ax(), 50);
+
+        // Each iteration takes 4 + 17 cycles
+        threaded.budget.cycles = Some(10 * 21);
+        let mut engine = ThreadedEngine::new();
+        let err = loop {
+            engine.translate(&threaded).unwrap();
+            if let Err(err) = engine.run(&mut threaded) {
+                break err;
+            }
+        };
+        let spent = Spent {
+            instructions: 20,
+            cycles: 10 * 21,
+        };
+        assert_eq!(err.downcast_ref(), Some(&BudgetExhausted(spent)));
+        assert_eq!(threaded.ax(), 10);
+    }
+}
@@ -0,0 +1,1096 @@
+//! Code generation for hosts with AVX2 but without AVX-512
+//!
+//! A ymm register holds a word of 16 lanes, so each block runs twice: once for lanes 0-15
+//! and once for lanes 16-31. With only 16 ymm registers the 8086 registers stay in the
+//! [`JitEmulatorState`], and each IL instruction loads its operands from the state and
+//! writes its result back to it.
+//!
+//! There are no kmasks in AVX2. The exec mask is instead expanded into a ymm mask with every
+//! word of the executing lanes set, and register writes blend the new value into the old
+//! one with `vpblendvb`. Other masks, such as the lanes where a flag is set, are vectors of
+//! all ones or all zeros words as well. Loads use the AVX2 `vpgatherdd`, but there is no
+//! scatter, so stores loop over the executing lanes one at a time.
+
+use std::collections::HashMap;
+
+use cpu8086::flags::{EFlags, FlagOp};
+use cpu8086::memory_operand::MemorySize;
+use jit_emu::JitEmulatorState;
+
+use crate::regalloc::Constant;
+use crate::utils::{ExecMemory, Protection};
+use crate::{AluOp, AvxOperand, Backend, BytePart, Condition, JitIL, JitMemory, JitRegister};
+use crate::{HostRegister, Mem, RegisterOperand, Trampoline, Ymm, YmmInstruction, YmmOpcode};
+use crate::{Zmm, STATE_REGISTERS};
+
+/// Mask of the executing lanes of the half being run
+const EXEC: Ymm = Ymm(15);
+
+/// Every word zero
+const ZERO: Ymm = Ymm(14);
+
+/// Every word all ones
+const ONES: Ymm = Ymm(13);
+
+/// The ymm registers free for temporaries, ymm0-12
+const TEMPS: u16 = (1 << 13) - 1;
+
+/// Number of lanes in each half of the [`JitEmulatorState`] registers
+const HALF_LANES: usize = 16;
+
+/// A buffer of AVX2 code translated from the IL of each block
+pub struct Avx2Buffer<const N: usize> {
+    /// Mapping where the code is written, writable or executable but never both
+    memory: ExecMemory,
+
+    /// Start of `memory`
+    buffer: *mut u8,
+
+    /// Current offset in `buffer` where new instructions are written
+    pub offset: isize,
+
+    /// Offset in `buffer` of the first block, following the trampoline
+    code_start: isize,
+
+    /// Offset in `buffer` of the constant pool, which grows down from the end of the buffer
+    pool_start: isize,
+
+    /// Offset in the constant pool of each dword written to it
+    pool: HashMap<u32, isize>,
+
+    /// Offset of the word `1 << i` for each lane `i` of a half, used to expand the exec mask
+    lane_bits: isize,
+
+    /// Free temporaries of the instruction being written, one bit per ymm register
+    free: u16,
+
+    /// Half of the lanes the code being written runs for
+    half: usize,
+
+    /// Set when a write didn't fit in the buffer, until the next [`Avx2Buffer::flush`]
+    full: bool,
+}
+
+impl<const N: usize> Default for Avx2Buffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Avx2Buffer<N> {
+    /// Create a new AVX2 buffer of size `N`
+    pub fn new() -> Avx2Buffer<N> {
+        let memory = ExecMemory::new(N);
+        let buffer = memory.as_ptr();
+
+        // Initialize the buffer to ret's
+        unsafe {
+            std::ptr::write_bytes(buffer, 0xc3, N);
+        }
+
+        let mut avx2 = Avx2Buffer {
+            memory,
+            buffer,
+            offset: 0,
+            code_start: 0,
+            pool_start: N as isize,
+            pool: HashMap::new(),
+            lane_bits: 0,
+            free: TEMPS,
+            half: 0,
+            full: false,
+        };
+
+        avx2.write_trampoline();
+        avx2.code_start = avx2.offset;
+        avx2.write_lane_bits();
+        avx2
+    }
+
+    /// Write the word `1 << i` for each lane `i` of a half as a whole vector at the end of
+    /// the buffer
+    fn write_lane_bits(&mut self) {
+        self.pool_start -= 32;
+        self.lane_bits = self.pool_start;
+        for lane in 0..HALF_LANES {
+            // SAFETY: The table is in the buffer and doesn't overlap the code
+            unsafe {
+                let word = self.buffer.offset(self.lane_bits).cast::<u16>().add(lane);
+                std::ptr::write_unaligned(word, 1 << lane);
+            }
+        }
+    }
+
+    /// Returns `true` if a write since the last [`Avx2Buffer::flush`] didn't fit in the
+    /// buffer, so the blocks written since then can't be run
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Discard every block and constant written after the trampoline
+    pub fn flush(&mut self) {
+        self.memory.protect(Protection::ReadWrite);
+
+        // SAFETY: The range is in the buffer, after the trampoline
+        unsafe {
+            std::ptr::write_bytes(
+                self.buffer.offset(self.code_start),
+                0xc3,
+                N - self.code_start as usize,
+            );
+        }
+
+        self.offset = self.code_start;
+        self.pool_start = N as isize;
+        self.pool.clear();
+        self.full = false;
+        self.write_lane_bits();
+    }
+
+    /// Write the entry/exit trampoline, called as a [`Trampoline`]
+    ///
+    /// The registers stay in the [`JitEmulatorState`], so the trampoline only keeps the
+    /// state pointer in rbx and the lane memory in r15 for the code.
+    fn write_trampoline(&mut self) {
+        // push rbx; push rbp; push r12; push r13; push r14; push r15
+        self.write_bytes(&[0x53, 0x55, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+
+        // mov rbx, rdi; mov r15, rdx
+        self.write_bytes(&[0x48, 0x89, 0xfb, 0x49, 0x89, 0xd7]);
+
+        // call rsi
+        self.write_bytes(&[0xff, 0xd6]);
+
+        // vzeroupper
+        self.write_bytes(&[0xc5, 0xf8, 0x77]);
+
+        // pop r15; pop r14; pop r13; pop r12; pop rbp; pop rbx; ret
+        self.write_bytes(&[
+            0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5d, 0x5b, 0xc3,
+        ]);
+    }
+
+    /// Execute the block written at `offset` against the given emulator `state`
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be returned by [`Avx2Buffer::write_block`] on this buffer.
+    pub unsafe fn run_from(&self, offset: isize, state: &mut JitEmulatorState) {
+        assert!(
+            Backend::avx2_supported(),
+            "The AVX2 backend requires a CPU supporting AVX2"
+        );
+        assert!(!self.full, "AVX2 buffer is full");
+        assert!(
+            (self.code_start..self.pool_start).contains(&offset),
+            "AVX2 offset {offset:#x} is outside of the code"
+        );
+
+        self.memory.protect(Protection::ReadExec);
+
+        let memory = state.memory.as_mut_ptr();
+        let trampoline: Trampoline = std::mem::transmute(self.buffer);
+        trampoline(state, self.buffer.offset(offset), memory);
+    }
+
+    /// Get the code written between the `start` and `end` offsets
+    pub fn code_between(&self, start: isize, end: isize) -> &[u8] {
+        assert!(self.code_start <= start && start <= end && end <= self.offset);
+
+        // SAFETY: The range was written by this buffer
+        unsafe { std::slice::from_raw_parts(self.buffer.offset(start), (end - start) as usize) }
+    }
+
+    /// Write the given bytes at the current offset, dropping them and marking the buffer as
+    /// full if they don't fit
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.offset + (bytes.len() as isize) >= self.pool_start {
+            self.full = true;
+            return;
+        }
+
+        self.memory.protect(Protection::ReadWrite);
+
+        // SAFETY: The bytes are in the buffer and don't overlap the constant pool
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                self.buffer.offset(self.offset),
+                bytes.len(),
+            );
+        }
+        self.offset += bytes.len() as isize;
+    }
+
+    /// Assemble and write `instr`
+    fn emit(&mut self, instr: YmmInstruction) {
+        let bytes = instr.assemble();
+        self.write_bytes(bytes.as_slice());
+    }
+
+    /// Write the instruction built by `instr` with the RIP-relative displacement of the
+    /// buffer offset `target`
+    fn emit_rip(&mut self, target: isize, instr: impl Fn(i32) -> YmmInstruction) {
+        // RIP-relative displacements are always a disp32, so the length is known up front
+        let len = instr(0).assemble().as_slice().len() as isize;
+        let disp = i32::try_from(target - (self.offset + len))
+            .expect("Constant pool out of reach of RIP-relative addressing");
+        self.emit(instr(disp));
+    }
+
+    /// `dest = a OP b`
+    fn op3(&mut self, opcode: YmmOpcode, dest: Ymm, a: Ymm, b: Ymm) {
+        self.emit(
+            YmmInstruction::default()
+                .opcode(opcode)
+                .op1(dest)
+                .op2(a)
+                .op3(b),
+        );
+    }
+
+    /// `dest = src` shifted by `imm` with a shift `opcode`
+    fn shift(&mut self, opcode: YmmOpcode, dest: Ymm, src: Ymm, imm: u8) {
+        self.emit(
+            YmmInstruction::default()
+                .opcode(opcode)
+                .op1(dest)
+                .op2(src)
+                .imm(imm),
+        );
+    }
+
+    /// Load `dest` from `offset` in the [`JitEmulatorState`]
+    fn state_load(&mut self, dest: Ymm, offset: isize) {
+        let mem = Mem::base(HostRegister::rbx as u8, offset as i32);
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::Load)
+                .op1(dest)
+                .mem(mem),
+        );
+    }
+
+    /// Store `src` to `offset` in the [`JitEmulatorState`]
+    fn state_store(&mut self, offset: isize, src: Ymm) {
+        let mem = Mem::base(HostRegister::rbx as u8, offset as i32);
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::Store)
+                .op1(src)
+                .mem(mem),
+        );
+    }
+
+    /// Get a free temporary for the instruction being written
+    fn temp(&mut self) -> Ymm {
+        let reg = self.free.trailing_zeros();
+        assert!(reg < 13, "Out of AVX2 temporaries");
+        self.free &= !(1 << reg);
+
+        #[allow(clippy::cast_possible_truncation)]
+        Ymm(reg as u8)
+    }
+
+    /// Free the temporary `reg`
+    fn release(&mut self, reg: Ymm) {
+        if reg.0 < 13 {
+            self.free |= 1 << reg.0;
+        }
+    }
+
+    /// Get the offset of `value` in the constant pool, adding it to the pool on its first
+    /// use
+    fn pool_offset(&mut self, value: Constant) -> isize {
+        if let Some(offset) = self.pool.get(&value.0) {
+            return *offset;
+        }
+
+        let offset = self.pool_start - 4;
+        if self.offset >= offset {
+            // The code using the constant is dropped along with the rest of the block
+            self.full = true;
+            return self.pool_start;
+        }
+
+        self.memory.protect(Protection::ReadWrite);
+
+        // SAFETY: The entry is in the buffer and doesn't overlap the code
+        unsafe {
+            std::ptr::write_unaligned(self.buffer.offset(offset).cast::<u32>(), value.0);
+        }
+
+        self.pool_start = offset;
+        self.pool.insert(value.0, offset);
+        offset
+    }
+
+    /// Get a temporary holding `value` in every dword
+    fn constant(&mut self, value: Constant) -> Ymm {
+        let dest = self.temp();
+        let offset = self.pool_offset(value);
+        self.emit_rip(offset, |disp| {
+            YmmInstruction::default()
+                .opcode(YmmOpcode::BroadcastDwordMem)
+                .op1(dest)
+                .mem(Mem::rip(disp))
+        });
+        dest
+    }
+
+    /// dest = src & `value`
+    fn and_constant(&mut self, dest: Ymm, src: Ymm, value: Constant) {
+        let constant = self.constant(value);
+        self.op3(YmmOpcode::And, dest, src, constant);
+        self.release(constant);
+    }
+
+    /// Set every word of `dest` to all ones where bit `bit` of the word of `src` is set
+    fn bit_mask(&mut self, dest: Ymm, src: Ymm, bit: u8) {
+        self.shift(YmmOpcode::ShiftLeftWordImm, dest, src, 15 - bit);
+        self.shift(YmmOpcode::ShiftRightArithWordImm, dest, dest, 15);
+    }
+
+    /// Get the offset of the register `zmm` of the current half in the [`JitEmulatorState`]
+    fn reg_offset(&self, zmm: Zmm) -> isize {
+        let (_, offset) = STATE_REGISTERS
+            .into_iter()
+            .find(|(reg, _)| reg.as_zmm().0 == zmm.0)
+            .expect("Only the 8086 registers are in the state");
+        offset + self.half as isize * 32
+    }
+
+    /// Get a temporary holding the register `zmm`
+    fn load_reg(&mut self, zmm: Zmm) -> Ymm {
+        let dest = self.temp();
+        self.state_load(dest, self.reg_offset(zmm));
+        dest
+    }
+
+    /// Write `value` to `dest` in the executing lanes, keeping the other byte of the
+    /// register for a byte
+    fn write_reg(&mut self, dest: RegisterOperand, value: Ymm) {
+        let offset = self.reg_offset(dest.zmm);
+        let old = self.temp();
+        self.state_load(old, offset);
+
+        let new = match dest.byte {
+            None => value,
+            Some(part) => {
+                // new = (old & keep) | value moved into its byte
+                let new = self.temp();
+                let keep = match part {
+                    BytePart::Low => {
+                        self.and_constant(new, value, Constant::word(0xff));
+                        0xff00
+                    }
+                    BytePart::High => {
+                        self.shift(YmmOpcode::ShiftLeftWordImm, new, value, 8);
+                        0x00ff
+                    }
+                };
+                let keep = self.constant(Constant::word(keep));
+                self.op3(YmmOpcode::And, keep, keep, old);
+                self.op3(YmmOpcode::Or, new, new, keep);
+                self.release(keep);
+                new
+            }
+        };
+
+        // Only write the executing lanes
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::BlendBytes)
+                .op1(old)
+                .op2(old)
+                .op3(new)
+                .op4(EXEC),
+        );
+        self.state_store(offset, old);
+
+        self.release(old);
+        if new != value {
+            self.release(new);
+        }
+    }
+
+    /// Get a temporary holding `operand` for an operation of the given `size`
+    fn operand(&mut self, operand: AvxOperand, size: MemorySize) -> Ymm {
+        match operand {
+            AvxOperand::Immediate(imm) => {
+                #[allow(clippy::cast_sign_loss)]
+                let imm = match size {
+                    MemorySize::Byte => imm as u16 & 0xff,
+                    MemorySize::Word => imm as u16,
+                };
+                self.constant(Constant::word(imm))
+            }
+            AvxOperand::Zmm(zmm) => self.load_reg(zmm),
+            AvxOperand::Byte(zmm, part) => {
+                let reg = self.load_reg(zmm);
+                match part {
+                    BytePart::Low => self.and_constant(reg, reg, Constant::word(0xff)),
+                    BytePart::High => self.shift(YmmOpcode::ShiftRightWordImm, reg, reg, 8),
+                }
+                reg
+            }
+            AvxOperand::Memory(mem) => self.load(mem),
+        }
+    }
+
+    /// Get a temporary holding the 8086 address `offset` bytes past `mem` in each lane,
+    /// wrapping at 16 bits like the 8086
+    fn address(&mut self, mem: JitMemory, offset: u16) -> Ymm {
+        let addr = self.constant(Constant::word(mem.disp.wrapping_add(offset)));
+        for reg in mem.registers.into_iter().flatten() {
+            let reg = self.load_reg(reg);
+            self.op3(YmmOpcode::Add, addr, addr, reg);
+            self.release(reg);
+        }
+        if let Some(segment) = mem.segment {
+            let base = self.load_reg(segment);
+            self.shift(YmmOpcode::ShiftLeftWordImm, base, base, 4);
+            self.op3(YmmOpcode::Add, addr, addr, base);
+            self.release(base);
+        }
+        addr
+    }
+
+    /// Compute the offset of the byte `offset` bytes past `mem` in the lane memory for each
+    /// lane, returning the dword indices for the low and high 8 lanes of the half
+    fn lane_indices(&mut self, mem: JitMemory, offset: u16) -> (Ymm, Ymm) {
+        let addr = self.address(mem, offset);
+
+        let low = self.temp();
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::ZeroExtendWordToDword)
+                .op1(low)
+                .op2(addr),
+        );
+
+        let high = self.temp();
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::ExtractHalf)
+                .op1(high)
+                .op2(addr)
+                .imm(1),
+        );
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::ZeroExtendWordToDword)
+                .op1(high)
+                .op2(high),
+        );
+        self.release(addr);
+
+        // Offset each lane's address by the start of its memory
+        let lane_offsets = JitEmulatorState::lane_offsets_offset() + self.half as isize * 64;
+        for (index, offset) in [(low, lane_offsets), (high, lane_offsets + 32)] {
+            let mem = Mem::base(HostRegister::rbx as u8, offset as i32);
+            self.emit(
+                YmmInstruction::default()
+                    .opcode(YmmOpcode::AddDword)
+                    .op1(index)
+                    .op2(index)
+                    .mem(mem),
+            );
+        }
+
+        (low, high)
+    }
+
+    /// Get a temporary holding the byte `offset` bytes past `mem` loaded from the memory of
+    /// each lane, zero extended to a word
+    fn load_byte(&mut self, mem: JitMemory, offset: u16) -> Ymm {
+        let (low, high) = self.lane_indices(mem, offset);
+
+        // The gather clears the mask as each element completes
+        let mut gathered = [low, high];
+        for (dest, index) in gathered.iter_mut().zip([low, high]) {
+            let mask = self.temp();
+            self.op3(YmmOpcode::CmpEqWord, mask, mask, mask);
+
+            *dest = self.temp();
+            let vsib = Mem::vsib(HostRegister::r15 as u8, Zmm(index.0));
+            self.emit(
+                YmmInstruction::default()
+                    .opcode(YmmOpcode::GatherDword)
+                    .op1(*dest)
+                    .op2(mask)
+                    .mem(vsib),
+            );
+            self.release(mask);
+            self.release(index);
+        }
+
+        // The low byte of each dword is the loaded value
+        let [low, high] = gathered;
+        let bits = self.constant(Constant::dword(0xff));
+        self.op3(YmmOpcode::And, low, low, bits);
+        self.op3(YmmOpcode::And, high, high, bits);
+        self.release(bits);
+
+        // The pack interleaves the 128-bit lanes of its sources, which the permute undoes
+        self.op3(YmmOpcode::PackDwordToWord, low, low, high);
+        self.release(high);
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::PermuteQwords)
+                .op1(low)
+                .op2(low)
+                .imm(0xd8),
+        );
+
+        low
+    }
+
+    /// Get a temporary holding `mem` loaded from the memory of each lane
+    ///
+    /// The bytes of a word are loaded separately, so a word at 0xffff wraps to offset 0 of
+    /// the lane memory like the 8086 instead of reading the padding after it.
+    fn load(&mut self, mem: JitMemory) -> Ymm {
+        match mem.size {
+            MemorySize::Byte => self.load_byte(mem, 0),
+            MemorySize::Word => {
+                let high = self.load_byte(mem, 1);
+                let low = self.load_byte(mem, 0);
+                self.shift(YmmOpcode::ShiftLeftWordImm, high, high, 8);
+                self.op3(YmmOpcode::Or, low, low, high);
+                self.release(high);
+                low
+            }
+        }
+    }
+
+    /// Store the low byte or word of `src` to `mem` in the memory of each executing lane
+    ///
+    /// The addresses and values are written to the spill area of the state, then each
+    /// executing lane of the half is stored by a scalar loop. The bytes of a word are
+    /// stored separately, so a word at 0xffff wraps to offset 0 of the lane memory.
+    fn store(&mut self, mem: JitMemory, src: Ymm) {
+        let addr = self.address(mem, 0);
+        let spill = JitEmulatorState::spill_offset();
+        self.state_store(spill, addr);
+        self.state_store(spill + 32, src);
+        self.release(addr);
+        self.release(src);
+
+        let disp = |offset: isize| (offset as i32).to_le_bytes();
+
+        // mov edx, dword [rbx + exec_mask]
+        self.write_bytes(&[0x8b, 0x93]);
+        self.write_bytes(&disp(JitEmulatorState::exec_mask_offset()));
+        if self.half == 1 {
+            // shr edx, 16
+            self.write_bytes(&[0xc1, 0xea, 0x10]);
+        }
+
+        // xor ecx, ecx
+        self.write_bytes(&[0x31, 0xc9]);
+        let lane_loop = self.offset;
+
+        // Skip the lanes that aren't executing
+        // bt edx, ecx; jnc skip
+        let (bytes, skip) = match mem.size {
+            MemorySize::Byte => (1, 28),
+            MemorySize::Word => (2, 54),
+        };
+        self.write_bytes(&[0x0f, 0xa3, 0xca, 0x73, skip]);
+
+        // movzx r8d, word [rbx + rcx*2 + spill + 32]
+        self.write_bytes(&[0x44, 0x0f, 0xb7, 0x84, 0x4b]);
+        self.write_bytes(&disp(spill + 32));
+
+        let lane_offsets = JitEmulatorState::lane_offsets_offset() + self.half as isize * 64;
+        for byte in 0..bytes {
+            // movzx eax, word [rbx + rcx*2 + spill]
+            self.write_bytes(&[0x0f, 0xb7, 0x84, 0x4b]);
+            self.write_bytes(&disp(spill));
+
+            if byte == 1 {
+                // The address of the high byte wraps at 16 bits
+                // inc ax; shr r8d, 8
+                self.write_bytes(&[0x66, 0xff, 0xc0, 0x41, 0xc1, 0xe8, 0x08]);
+            }
+
+            // add eax, dword [rbx + rcx*4 + lane_offsets]
+            self.write_bytes(&[0x03, 0x84, 0x8b]);
+            self.write_bytes(&disp(lane_offsets));
+
+            // mov byte [r15 + rax], r8b
+            self.write_bytes(&[0x45, 0x88, 0x04, 0x07]);
+        }
+
+        // inc ecx; cmp ecx, 16; jb lane_loop
+        self.write_bytes(&[0xff, 0xc1, 0x83, 0xf9, 0x10]);
+        let back = i8::try_from(lane_loop - (self.offset + 2)).expect("Store loop too long");
+        self.write_bytes(&[0x72, back.to_le_bytes()[0]]);
+    }
+
+    /// Add `flag` to `flags` in the lanes where `mask` is all ones (or all zeros if `set`
+    /// is false), freeing `mask`
+    fn add_flag(&mut self, flags: Ymm, flag: EFlags, mask: Ymm, set: bool) {
+        let bit = self.constant(Constant::word(flag as u16));
+        let opcode = if set {
+            YmmOpcode::And
+        } else {
+            YmmOpcode::AndNot
+        };
+        self.op3(opcode, bit, mask, bit);
+        self.op3(YmmOpcode::Or, flags, flags, bit);
+        self.release(bit);
+        self.release(mask);
+    }
+
+    /// Compute the `flags` status flags of `result = a OP b` in the executing lanes for an
+    /// operation of the given `size`, leaving the other flags untouched
+    ///
+    /// The operands of byte operations are zero extended bytes, so the result has the byte's
+    /// carry in bit 8 and its sign in bit 7.
+    fn set_status_flags(
+        &mut self,
+        op: FlagOp,
+        flags: u16,
+        result: Ymm,
+        a: Ymm,
+        b: Ymm,
+        size: MemorySize,
+    ) {
+        if flags == 0 {
+            return;
+        }
+
+        let has = |flag: EFlags| flags & flag as u16 > 0;
+        let sign_bit = match size {
+            MemorySize::Byte => 7,
+            MemorySize::Word => 15,
+        };
+
+        // Clear the flags being computed
+        let flags_zmm = JitRegister::flags.as_zmm();
+        let new = self.load_reg(flags_zmm);
+        self.and_constant(new, new, Constant::word(!flags));
+
+        if has(EFlags::Zero) {
+            let mask = self.temp();
+            match size {
+                MemorySize::Byte => {
+                    self.shift(YmmOpcode::ShiftLeftWordImm, mask, result, 8);
+                    self.op3(YmmOpcode::CmpEqWord, mask, mask, ZERO);
+                }
+                MemorySize::Word => self.op3(YmmOpcode::CmpEqWord, mask, result, ZERO),
+            }
+            self.add_flag(new, EFlags::Zero, mask, true);
+        }
+
+        if has(EFlags::Sign) {
+            let mask = self.temp();
+            self.bit_mask(mask, result, sign_bit);
+            self.add_flag(new, EFlags::Sign, mask, true);
+        }
+
+        if has(EFlags::Parity) {
+            // Fold the low byte onto bit 0, which is then set for an odd number of bits
+            let mask = self.temp();
+            let bit = self.temp();
+            self.shift(YmmOpcode::ShiftRightWordImm, mask, result, 4);
+            self.op3(YmmOpcode::Xor, mask, mask, result);
+            self.shift(YmmOpcode::ShiftRightWordImm, bit, mask, 2);
+            self.op3(YmmOpcode::Xor, mask, mask, bit);
+            self.shift(YmmOpcode::ShiftRightWordImm, bit, mask, 1);
+            self.op3(YmmOpcode::Xor, mask, mask, bit);
+            self.release(bit);
+
+            self.bit_mask(mask, mask, 0);
+            self.add_flag(new, EFlags::Parity, mask, false);
+        }
+
+        // Logic operations clear CF, OF and AF
+        if op != FlagOp::Logic {
+            if has(EFlags::Carry) {
+                // There is no unsigned word compare, but `x < y` exactly when the unsigned
+                // maximum of the two isn't `x`
+                let mask = self.temp();
+                match (op, size) {
+                    (FlagOp::Add, MemorySize::Word) => {
+                        self.op3(YmmOpcode::MaxUnsignedWord, mask, result, a);
+                        self.op3(YmmOpcode::CmpEqWord, mask, mask, result);
+                        self.add_flag(new, EFlags::Carry, mask, false);
+                    }
+                    (FlagOp::Add, MemorySize::Byte) => {
+                        self.bit_mask(mask, result, 8);
+                        self.add_flag(new, EFlags::Carry, mask, true);
+                    }
+                    _ => {
+                        self.op3(YmmOpcode::MaxUnsignedWord, mask, a, b);
+                        self.op3(YmmOpcode::CmpEqWord, mask, mask, a);
+                        self.add_flag(new, EFlags::Carry, mask, false);
+                    }
+                }
+            }
+
+            if has(EFlags::Overflow) {
+                // add: (a ^ result) & (b ^ result), sub: (a ^ b) & (a ^ result)
+                let mask = self.temp();
+                let tmp = self.temp();
+                match op {
+                    FlagOp::Add => {
+                        self.op3(YmmOpcode::Xor, mask, a, result);
+                        self.op3(YmmOpcode::Xor, tmp, b, result);
+                    }
+                    _ => {
+                        self.op3(YmmOpcode::Xor, mask, a, b);
+                        self.op3(YmmOpcode::Xor, tmp, a, result);
+                    }
+                }
+                self.op3(YmmOpcode::And, mask, mask, tmp);
+                self.release(tmp);
+
+                self.bit_mask(mask, mask, sign_bit);
+                self.add_flag(new, EFlags::Overflow, mask, true);
+            }
+
+            if has(EFlags::Auxillary) {
+                // Carry or borrow out of bit 3: (a ^ b ^ result) & 0x10
+                let mask = self.temp();
+                self.op3(YmmOpcode::Xor, mask, a, b);
+                self.op3(YmmOpcode::Xor, mask, mask, result);
+                self.bit_mask(mask, mask, 4);
+                self.add_flag(new, EFlags::Auxillary, mask, true);
+            }
+        }
+
+        self.write_reg(flags_zmm.into(), new);
+        self.release(new);
+    }
+
+    /// Write `dest = op1 OP op2` (or only the flags without a `dest`), computing the
+    /// `flags` status flags of the result
+    fn alu(
+        &mut self,
+        op: AluOp,
+        dest: Option<RegisterOperand>,
+        op1: AvxOperand,
+        op2: AvxOperand,
+        flags: u16,
+    ) {
+        let size = match (op1.size(), op2.size()) {
+            (Some(MemorySize::Byte), _) | (_, Some(MemorySize::Byte)) => MemorySize::Byte,
+            _ => MemorySize::Word,
+        };
+        let a = self.operand(op1, size);
+        let b = self.operand(op2, size);
+
+        let result = self.temp();
+        let opcode = match op {
+            AluOp::Add => YmmOpcode::Add,
+            AluOp::Sub => YmmOpcode::Sub,
+            AluOp::And => YmmOpcode::And,
+            AluOp::Or => YmmOpcode::Or,
+            AluOp::Xor => YmmOpcode::Xor,
+        };
+        self.op3(opcode, result, a, b);
+
+        self.set_status_flags(op.flag_op(), flags, result, a, b, size);
+        self.release(a);
+        self.release(b);
+
+        if let Some(dest) = dest {
+            self.write_reg(dest, result);
+        }
+    }
+
+    /// Get a temporary with every word of the lanes where `cond` holds set to all ones
+    fn condition(&mut self, cond: Condition) -> Ymm {
+        use Condition::*;
+
+        if cond == CxZero {
+            let cx = self.load_reg(JitRegister::cx.as_zmm());
+            self.op3(YmmOpcode::CmpEqWord, cx, cx, ZERO);
+            return cx;
+        }
+
+        let flags = self.load_reg(JitRegister::flags.as_zmm());
+        let flag = |this: &mut Self, flag: EFlags| {
+            let mask = this.temp();
+            #[allow(clippy::cast_possible_truncation)]
+            this.bit_mask(mask, flags, (flag as u16).trailing_zeros() as u8);
+            mask
+        };
+        let combine = |this: &mut Self, opcode: YmmOpcode, a: Ymm, b: Ymm| {
+            this.op3(opcode, a, a, b);
+            this.release(b);
+            a
+        };
+
+        let (mask, negate) = match cond {
+            Equal | NotEqual => (flag(self, EFlags::Zero), cond == NotEqual),
+            Below | NotBelow => (flag(self, EFlags::Carry), cond == NotBelow),
+            BelowEqual | NotBelowEqual => {
+                let carry = flag(self, EFlags::Carry);
+                let zero = flag(self, EFlags::Zero);
+                (
+                    combine(self, YmmOpcode::Or, carry, zero),
+                    cond == NotBelowEqual,
+                )
+            }
+            Less | NotLess | LessEqual | NotLessEqual => {
+                // SF != OF, or ZF for the less or equal conditions
+                let sign = flag(self, EFlags::Sign);
+                let overflow = flag(self, EFlags::Overflow);
+                let mut less = combine(self, YmmOpcode::Xor, sign, overflow);
+                if matches!(cond, LessEqual | NotLessEqual) {
+                    let zero = flag(self, EFlags::Zero);
+                    less = combine(self, YmmOpcode::Or, less, zero);
+                }
+                (less, matches!(cond, NotLess | NotLessEqual))
+            }
+            ParityEven | ParityOdd => (flag(self, EFlags::Parity), cond == ParityOdd),
+            Overflow | NotOverflow => (flag(self, EFlags::Overflow), cond == NotOverflow),
+            Sign | NotSign => (flag(self, EFlags::Sign), cond == NotSign),
+            CxZero => unreachable!(),
+        };
+        self.release(flags);
+
+        if negate {
+            self.op3(YmmOpcode::Xor, mask, mask, ONES);
+        }
+        mask
+    }
+
+    /// Set IP to `taken` in the lanes in `mask` and to `not_taken` in the others
+    fn branch(&mut self, mask: Ymm, taken: u16, not_taken: u16) {
+        let ip = self.constant(Constant::word(not_taken));
+        let taken = self.constant(Constant::word(taken));
+        self.emit(
+            YmmInstruction::default()
+                .opcode(YmmOpcode::BlendBytes)
+                .op1(ip)
+                .op2(ip)
+                .op3(taken)
+                .op4(mask),
+        );
+        self.release(taken);
+        self.release(mask);
+
+        self.write_reg(JitRegister::ip.as_zmm().into(), ip);
+        self.release(ip);
+    }
+
+    /// Write the code of `instr` for the current half
+    fn write_instr(&mut self, instr: &JitIL) {
+        match *instr {
+            JitIL::Mov { dest, src } => {
+                let size = match dest.byte {
+                    Some(_) => MemorySize::Byte,
+                    None => MemorySize::Word,
+                };
+                let value = self.operand(src, size);
+                self.write_reg(dest, value);
+            }
+            JitIL::Store { dest, src } => {
+                assert!(
+                    !matches!(src, AvxOperand::Memory(_)),
+                    "Memory to memory store"
+                );
+                let value = self.operand(src, dest.size);
+                self.store(dest, value);
+            }
+            JitIL::Sub {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Sub, Some(dest), op1.into(), op2, flags),
+            JitIL::Add {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Add, Some(dest), op1.into(), op2, flags),
+            JitIL::And {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::And, Some(dest), op1.into(), op2, flags),
+            JitIL::Or {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Or, Some(dest), op1.into(), op2, flags),
+            JitIL::Xor {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Xor, Some(dest), op1.into(), op2, flags),
+            JitIL::Cmp { left, right, flags } => self.alu(AluOp::Sub, None, left, right, flags),
+            JitIL::Test { left, right, flags } => {
+                self.alu(AluOp::And, None, left, right, flags);
+            }
+            JitIL::Branch {
+                cond,
+                taken,
+                not_taken,
+            } => {
+                let mask = self.condition(cond);
+                self.branch(mask, taken, not_taken);
+            }
+            JitIL::Loop {
+                cond,
+                taken,
+                not_taken,
+            } => {
+                // Decrement CX (adding all ones) without touching the flags
+                let cx = self.load_reg(JitRegister::cx.as_zmm());
+                self.op3(YmmOpcode::Add, cx, cx, ONES);
+                self.write_reg(JitRegister::cx.as_zmm().into(), cx);
+
+                let mask = cx;
+                self.op3(YmmOpcode::CmpEqWord, mask, cx, ZERO);
+                self.op3(YmmOpcode::Xor, mask, mask, ONES);
+
+                if let Some(cond) = cond {
+                    let cond = self.condition(cond);
+                    self.op3(YmmOpcode::And, mask, mask, cond);
+                    self.release(cond);
+                }
+
+                self.branch(mask, taken, not_taken);
+            }
+        }
+    }
+
+    /// Write the code of a block made of `il`, returning its offset for
+    /// [`Avx2Buffer::run_from`]
+    ///
+    /// The lanes are independent, so the whole block runs for lanes 0-15 and then again for
+    /// lanes 16-31.
+    pub fn write_block(&mut self, il: &[JitIL]) -> isize {
+        let offset = self.offset;
+
+        for half in 0..2 {
+            self.half = half;
+
+            // Set every word of the executing lanes of the half in EXEC
+            let exec_mask = JitEmulatorState::exec_mask_offset() + half as isize * 2;
+            let mem = Mem::base(HostRegister::rbx as u8, exec_mask as i32);
+            self.emit(
+                YmmInstruction::default()
+                    .opcode(YmmOpcode::BroadcastWordMem)
+                    .op1(EXEC)
+                    .mem(mem),
+            );
+            for opcode in [YmmOpcode::And, YmmOpcode::CmpEqWord] {
+                self.emit_rip(self.lane_bits, |disp| {
+                    YmmInstruction::default()
+                        .opcode(opcode)
+                        .op1(EXEC)
+                        .op2(EXEC)
+                        .mem(Mem::rip(disp))
+                });
+            }
+
+            self.op3(YmmOpcode::Xor, ZERO, ZERO, ZERO);
+            self.op3(YmmOpcode::CmpEqWord, ONES, ONES, ONES);
+
+            for instr in il {
+                self.free = TEMPS;
+                self.write_instr(instr);
+            }
+        }
+
+        // ret
+        self.write_bytes(&[0xc3]);
+        offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::disassemble;
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_vex_ymm() {
+        let instr = |opcode| YmmInstruction::default().opcode(opcode);
+        let rbx = |disp| Mem::base(HostRegister::rbx as u8, disp);
+        for (instr, needed) in [
+            (instr(YmmOpcode::Load).op1(Ymm(3)).mem(rbx(0x40)),                     "vmovdqu ymm3, ymmword ptr [rbx+0x40]"),
+            (instr(YmmOpcode::Store).op1(Ymm(12)).mem(rbx(0x280)),                  "vmovdqu ymmword ptr [rbx+0x280], ymm12"),
+            (instr(YmmOpcode::Add).op1(Ymm(1)).op2(Ymm(2)).op3(Ymm(9)),             "vpaddw ymm1, ymm2, ymm9"),
+            (instr(YmmOpcode::Sub).op1(Ymm(10)).op2(Ymm(0)).op3(Ymm(1)),            "vpsubw ymm10, ymm0, ymm1"),
+            (instr(YmmOpcode::AndNot).op1(Ymm(4)).op2(Ymm(5)).op3(Ymm(4)),          "vpandn ymm4, ymm5, ymm4"),
+            (instr(YmmOpcode::CmpEqWord).op1(Ymm(13)).op2(Ymm(13)).op3(Ymm(13)),    "vpcmpeqw ymm13, ymm13, ymm13"),
+            (instr(YmmOpcode::MaxUnsignedWord).op1(Ymm(2)).op2(Ymm(8)).op3(Ymm(0)), "vpmaxuw ymm2, ymm8, ymm0"),
+            (instr(YmmOpcode::ShiftLeftWordImm).op1(Ymm(11)).op2(Ymm(2)).imm(8),    "vpsllw ymm11, ymm2, 0x8"),
+            (instr(YmmOpcode::ShiftRightWordImm).op1(Ymm(3)).op2(Ymm(9)).imm(4),    "vpsrlw ymm3, ymm9, 0x4"),
+            (instr(YmmOpcode::ShiftRightArithWordImm).op1(Ymm(3)).op2(Ymm(3)).imm(15), "vpsraw ymm3, ymm3, 0xf"),
+            (instr(YmmOpcode::BlendBytes).op1(Ymm(1)).op2(Ymm(1)).op3(Ymm(2)).op4(EXEC), "vpblendvb ymm1, ymm1, ymm2, ymm15"),
+            (instr(YmmOpcode::BroadcastWordMem).op1(EXEC).mem(rbx(0x282)),          "vpbroadcastw ymm15, word ptr [rbx+0x282]"),
+            (instr(YmmOpcode::BroadcastDwordMem).op1(Ymm(5)).mem(Mem::rip(-0x20)),  "vpbroadcastd ymm5, dword ptr [rip-0x20]"),
+            (instr(YmmOpcode::ZeroExtendWordToDword).op1(Ymm(6)).op2(Ymm(12)),      "vpmovzxwd ymm6, xmm12"),
+            (instr(YmmOpcode::ExtractHalf).op1(Ymm(7)).op2(Ymm(1)).imm(1),          "vextracti128 xmm7, ymm1, 0x1"),
+            (instr(YmmOpcode::AddDword).op1(Ymm(6)).op2(Ymm(6)).mem(rbx(0x2c0)),    "vpaddd ymm6, ymm6, ymmword ptr [rbx+0x2c0]"),
+            (instr(YmmOpcode::GatherDword).op1(Ymm(3)).op2(Ymm(4)).mem(Mem::vsib(HostRegister::r15 as u8, Zmm(10))), "vpgatherdd ymm3, dword ptr [r15+ymm10], ymm4"),
+            (instr(YmmOpcode::PackDwordToWord).op1(Ymm(0)).op2(Ymm(0)).op3(Ymm(1)), "vpackusdw ymm0, ymm0, ymm1"),
+            (instr(YmmOpcode::PermuteQwords).op1(Ymm(0)).op2(Ymm(0)).imm(0xd8),     "vpermq ymm0, ymm0, 0xd8"),
+        ] {
+            let bytes = instr.assemble();
+            let bytes = bytes.as_slice();
+            assert_eq!(disassemble(bytes), needed, "{bytes:x?}");
+        }
+    }
+
+    #[test]
+    fn test_store_loop() {
+        let mut avx2 = Avx2Buffer::<4096>::new();
+        let start = avx2.offset;
+        let mem = JitMemory {
+            segment: None,
+            registers: [Some(JitRegister::bx.as_zmm()), None],
+            disp: 0x10,
+            size: MemorySize::Word,
+        };
+        avx2.write_block(&[JitIL::Store {
+            dest: mem,
+            src: AvxOperand::Immediate(-1),
+        }]);
+
+        // Every byte of the block decodes, including the jumps of the store loops
+        let code = avx2.code_between(start, avx2.offset);
+        let mut decoder = iced_x86::Decoder::new(64, code, iced_x86::DecoderOptions::NONE);
+        let mut instrs = Vec::new();
+        while decoder.can_decode() {
+            let instr = decoder.decode();
+            assert!(!instr.is_invalid(), "Invalid encoding at {:#x}", instr.ip());
+            instrs.push(instr);
+        }
+
+        let text: Vec<String> = instrs.iter().map(ToString::to_string).collect();
+        for needed in [
+            "bt edx,ecx",
+            "movzx eax,word ptr [rbx+rcx*2+",
+            "inc ax",
+            "shr r8d,8",
+            "mov [r15+rax],r8b",
+            "shr edx,10h",
+        ] {
+            assert!(
+                text.iter().any(|line| line.starts_with(needed)),
+                "{needed} not in {text:#?}"
+            );
+        }
+
+        // Both jumps of each loop land on instructions
+        let starts: Vec<u64> = instrs.iter().map(iced_x86::Instruction::ip).collect();
+        for instr in instrs.iter().filter(|instr| instr.is_jcc_short()) {
+            assert!(starts.contains(&instr.near_branch_target()), "{instr}");
+        }
+    }
+}
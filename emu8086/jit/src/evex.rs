@@ -1,4 +1,8 @@
-//! Provides a minimal EVEX assembler for AVX512 instructions
+//! Provides a minimal EVEX assembler for AVX512 instructions, along with the VEX encoded
+//! kmask and AVX2 instructions
+
+use crate::il::{BytePart, JitMemory};
+use cpu8086::memory_operand::MemorySize;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Zmm(pub u8);
@@ -18,34 +22,251 @@ impl Zmm {
 #[derive(Debug, Copy, Clone)]
 pub struct Kmask(pub u8);
 
+/// A `[base + disp]`, `[base + zmm*1 + disp]` or `[rip + disp]` memory operand
+#[derive(Debug, Copy, Clone)]
+pub struct Mem {
+    /// Number of the base register (see [`crate::HostRegister`]), or `None` to address
+    /// relative to the next instruction
+    pub base: Option<u8>,
+
+    /// Vector of dword indices for gathers and scatters (VSIB addressing)
+    pub index: Option<Zmm>,
+
+    /// Displacement from the base register or the next instruction
+    pub disp: i32,
+}
+
+impl Mem {
+    /// `[base + disp]`
+    pub fn base(base: u8, disp: i32) -> Self {
+        Self {
+            base: Some(base),
+            index: None,
+            disp,
+        }
+    }
+
+    /// `[base + index*1]` with a vector of dword indices
+    pub fn vsib(base: u8, index: Zmm) -> Self {
+        Self {
+            base: Some(base),
+            index: Some(index),
+            disp: 0,
+        }
+    }
+
+    /// `[rip + disp]`, where `disp` is relative to the end of the instruction
+    pub fn rip(disp: i32) -> Self {
+        Self {
+            base: None,
+            index: None,
+            disp,
+        }
+    }
+}
+
 /// An AVX512 operand
 #[derive(Debug, Copy, Clone)]
 pub enum AvxOperand {
     Zmm(Zmm),
+
+    /// One byte of each word of the register, for the 8-bit registers
+    Byte(Zmm, BytePart),
+
     Immediate(i16),
+    Memory(JitMemory),
+}
+
+impl AvxOperand {
+    /// Get the size of the operand, or `None` for an immediate which takes the size of the
+    /// operation
+    pub fn size(&self) -> Option<MemorySize> {
+        match self {
+            AvxOperand::Zmm(_) => Some(MemorySize::Word),
+            AvxOperand::Byte(..) => Some(MemorySize::Byte),
+            AvxOperand::Immediate(_) => None,
+            AvxOperand::Memory(mem) => Some(mem.size),
+        }
+    }
 }
 
 /// Opcodes for the avx512 instructions we are using
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AvxOpcode {
-    Sub = 0xf9,
-    Mov = 0x6f,
-    Broadcast = 0x7b,
-    SignedCmp = 0x3f,
-    UnsignedCmp = 0x3e,
-    And = 0xdb,
-    Or = 0xeb,
-    Xor = 0xef,
-    Add = 0xfd,
+    Sub,
+    Mov,
+    Store,
+
+    /// vmovdqu16 to memory
+    StoreWords,
+    Broadcast,
+    SignedCmp,
+    UnsignedCmp,
+    And,
+    Or,
+    Xor,
+    Add,
+
+    /// vpaddd
+    AddDword,
+
+    /// vpbroadcastd from a general purpose register
+    BroadcastDword,
+
+    /// vpbroadcastw from memory
+    BroadcastWordMem,
+
+    /// vpbroadcastd from memory
+    BroadcastDwordMem,
+
+    /// vpmovzxwd
+    ZeroExtendWordToDword,
+
+    /// vpmovdw
+    TruncateDwordToWord,
+
+    /// vextracti64x4
+    ExtractHalf,
+
+    /// vinserti64x4
+    InsertHalf,
+
+    /// vpternlogd
+    TernaryLogic,
+
+    /// vpgatherdd
+    GatherDword,
+
+    /// vpscatterdd
+    ScatterDword,
+
+    /// vptestmw
+    TestWord,
+
+    /// vptestnmw
+    TestNotWord,
+
+    /// vpsrlw by an immediate
+    ShiftRightWordImm,
+
+    /// vpsllw by an immediate
+    ShiftLeftWordImm,
+
+    /// vpsraw by an immediate
+    ShiftRightArithWordImm,
+
+    /// vpsllvw, shifting each word by the count in the same word of the second source
+    ShiftLeftWordVar,
+
+    /// vpsrlvw, shifting each word by the count in the same word of the second source
+    ShiftRightWordVar,
+
+    /// vpsravw, shifting each word by the count in the same word of the second source
+    ShiftRightArithWordVar,
+
+    /// vprold by an immediate
+    RotateLeftDwordImm,
+
+    /// vprord by an immediate
+    RotateRightDwordImm,
+
+    /// vpmullw
+    MulLowWord,
+
+    /// vpmulhw
+    MulHighWord,
+
+    /// vpmulhuw
+    MulHighUnsignedWord,
+
+    /// vpblendmw
+    BlendWord,
+
+    /// vpaddb
+    AddByte,
+
+    /// vpsubb
+    SubByte,
+
+    /// vpcmpb
+    SignedCmpByte,
+
+    /// vpcmpub
+    UnsignedCmpByte,
+
+    /// vptestmb
+    TestByte,
+
+    /// vpblendmb
+    BlendByte,
+
+    /// vpbroadcastb from a general purpose register
+    BroadcastByte,
+
+    /// vmovdqu8 between registers
+    MovBytes,
 }
 
 impl AvxOpcode {
+    /// Returns the opcode byte
+    const fn byte(&self) -> u8 {
+        use AvxOpcode::*;
+        match self {
+            Sub => 0xf9,
+            Mov => 0x6f,
+            Store => 0x7f,
+            StoreWords => 0x7f,
+            Broadcast => 0x7b,
+            SignedCmp => 0x3f,
+            UnsignedCmp => 0x3e,
+            And => 0xdb,
+            Or => 0xeb,
+            Xor => 0xef,
+            Add => 0xfd,
+            AddDword => 0xfe,
+            BroadcastDword => 0x7c,
+            BroadcastWordMem => 0x79,
+            BroadcastDwordMem => 0x58,
+            ZeroExtendWordToDword => 0x33,
+            TruncateDwordToWord => 0x33,
+            ExtractHalf => 0x3b,
+            InsertHalf => 0x3a,
+            TernaryLogic => 0x25,
+            GatherDword => 0x90,
+            ScatterDword => 0xa0,
+            TestWord => 0x26,
+            TestNotWord => 0x26,
+            ShiftRightWordImm => 0x71,
+            ShiftLeftWordImm => 0x71,
+            ShiftRightArithWordImm => 0x71,
+            ShiftLeftWordVar => 0x12,
+            ShiftRightWordVar => 0x10,
+            ShiftRightArithWordVar => 0x11,
+            RotateLeftDwordImm => 0x72,
+            RotateRightDwordImm => 0x72,
+            MulLowWord => 0xd5,
+            MulHighWord => 0xe5,
+            MulHighUnsignedWord => 0xe4,
+            BlendWord => 0x66,
+            AddByte => 0xfc,
+            SubByte => 0xf8,
+            SignedCmpByte => 0x3f,
+            UnsignedCmpByte => 0x3e,
+            TestByte => 0x26,
+            BlendByte => 0x66,
+            BroadcastByte => 0x7a,
+            MovBytes => 0x6f,
+        }
+    }
+
     /// Returns the `mmm` field type for the opcode
     const fn mmm(&self) -> PrefixMmm {
         use AvxOpcode::*;
         match self {
             Sub => PrefixMmm::F,
             Mov => PrefixMmm::F,
+            Store => PrefixMmm::F,
+            StoreWords => PrefixMmm::F,
             Broadcast => PrefixMmm::F38,
             SignedCmp => PrefixMmm::F3A,
             UnsignedCmp => PrefixMmm::F3A,
@@ -53,6 +274,48 @@ impl AvxOpcode {
             Or => PrefixMmm::F,
             Xor => PrefixMmm::F,
             And => PrefixMmm::F,
+            AddDword => PrefixMmm::F,
+            BroadcastDword => PrefixMmm::F38,
+            BroadcastWordMem => PrefixMmm::F38,
+            BroadcastDwordMem => PrefixMmm::F38,
+            ZeroExtendWordToDword => PrefixMmm::F38,
+            TruncateDwordToWord => PrefixMmm::F38,
+            ExtractHalf => PrefixMmm::F3A,
+            InsertHalf => PrefixMmm::F3A,
+            TernaryLogic => PrefixMmm::F3A,
+            GatherDword => PrefixMmm::F38,
+            ScatterDword => PrefixMmm::F38,
+            TestWord => PrefixMmm::F38,
+            TestNotWord => PrefixMmm::F38,
+            ShiftRightWordImm => PrefixMmm::F,
+            ShiftLeftWordImm => PrefixMmm::F,
+            ShiftRightArithWordImm => PrefixMmm::F,
+            ShiftLeftWordVar => PrefixMmm::F38,
+            ShiftRightWordVar => PrefixMmm::F38,
+            ShiftRightArithWordVar => PrefixMmm::F38,
+            RotateLeftDwordImm => PrefixMmm::F,
+            RotateRightDwordImm => PrefixMmm::F,
+            MulLowWord => PrefixMmm::F,
+            MulHighWord => PrefixMmm::F,
+            MulHighUnsignedWord => PrefixMmm::F,
+            BlendWord => PrefixMmm::F38,
+            AddByte => PrefixMmm::F,
+            SubByte => PrefixMmm::F,
+            SignedCmpByte => PrefixMmm::F3A,
+            UnsignedCmpByte => PrefixMmm::F3A,
+            TestByte => PrefixMmm::F38,
+            BlendByte => PrefixMmm::F38,
+            BroadcastByte => PrefixMmm::F38,
+            MovBytes => PrefixMmm::F,
+        }
+    }
+
+    /// Returns the `pp` field type for the opcode
+    const fn pp(&self) -> PrefixPp {
+        match self {
+            AvxOpcode::TruncateDwordToWord | AvxOpcode::TestNotWord => PrefixPp::P_F3,
+            AvxOpcode::StoreWords | AvxOpcode::MovBytes => PrefixPp::P_F2,
+            _ => PrefixPp::P_66,
         }
     }
 
@@ -60,9 +323,47 @@ impl AvxOpcode {
     const fn is_wide(&self) -> bool {
         matches!(
             *self,
-            AvxOpcode::SignedCmp | AvxOpcode::Mov | AvxOpcode::UnsignedCmp
+            AvxOpcode::SignedCmp
+                | AvxOpcode::Mov
+                | AvxOpcode::Store
+                | AvxOpcode::StoreWords
+                | AvxOpcode::UnsignedCmp
+                | AvxOpcode::ExtractHalf
+                | AvxOpcode::InsertHalf
+                | AvxOpcode::TestWord
+                | AvxOpcode::TestNotWord
+                | AvxOpcode::ShiftLeftWordVar
+                | AvxOpcode::ShiftRightWordVar
+                | AvxOpcode::ShiftRightArithWordVar
+                | AvxOpcode::BlendWord
         )
     }
+
+    /// Returns `true` if the opcode can broadcast a dword memory operand to every element
+    /// (`{1to16}`). Word element instructions have no embedded broadcast.
+    const fn can_broadcast(&self) -> bool {
+        matches!(
+            *self,
+            AvxOpcode::And
+                | AvxOpcode::Or
+                | AvxOpcode::Xor
+                | AvxOpcode::AddDword
+                | AvxOpcode::TernaryLogic
+        )
+    }
+
+    /// Returns the size of the memory operand, by which an 8-bit displacement is scaled
+    /// (disp8*N, section 2.7.5)
+    const fn disp8_scale(&self, broadcast: bool) -> i32 {
+        use AvxOpcode::*;
+        match self {
+            BroadcastWordMem => 2,
+            BroadcastDwordMem | GatherDword | ScatterDword => 4,
+            ZeroExtendWordToDword | TruncateDwordToWord | ExtractHalf | InsertHalf => 32,
+            _ if broadcast => 4,
+            _ => 64,
+        }
+    }
 }
 
 /// From section 2.3.5 - The VEX prefix
@@ -84,17 +385,57 @@ enum PrefixPp {
 }
 
 /// The return type from an assembly of an AVX512 instruction
-pub enum EvexResult {
-    Bytes6([u8; 6]),
-    Bytes7([u8; 7]),
+pub struct EvexResult {
+    bytes: [u8; 15],
+    len: usize,
 }
 
 impl EvexResult {
-    pub fn as_slice(&self) -> &[u8] {
-        match self {
-            EvexResult::Bytes6(bytes) => bytes,
-            EvexResult::Bytes7(bytes) => bytes,
+    /// Append `bytes` to the assembled instruction
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Append the ModRM byte, SIB byte and displacement of the memory operand `mem`, with
+    /// `reg` in the reg field and displacements that are a multiple of `scale` compressed to
+    /// a disp8 (disp8*N, section 2.7.5)
+    fn push_mem(&mut self, reg: u8, mem: Mem, scale: i32) {
+        // RIP-relative addressing is mod 0b00 with rm 0b101 and always takes a disp32.
+        // Otherwise a zero displacement is omitted (except for rbp/r13, whose mod 0b00
+        // encoding means no base), a multiple of the scale that fits is a disp8 and anything
+        // else is a disp32.
+        let disp8 = mem.disp % scale == 0 && i8::try_from(mem.disp / scale).is_ok();
+        let (mode, disp_len) = match mem.base {
+            None => (0b00, 4),
+            Some(base) if mem.disp == 0 && base & 0b111 != 0b101 => (0b00, 0),
+            Some(_) if disp8 => (0b01, 1),
+            Some(_) => (0b10, 4),
+        };
+
+        // An index register, or rsp/r12 as the base, needs a SIB byte (rm 0b100) with
+        // scale 1. An index of 0b100 without X means no index.
+        let base = mem.base.unwrap_or(0b101) & 0b111;
+        if mem.base.is_some() && (mem.index.is_some() || base == 0b100) {
+            let index = mem.index.map_or(0b100, |index| index.0 & 0b111);
+            let modrm = (mode << 6) | ((reg & 0b111) << 3) | 0b100;
+            let sib = (index << 3) | base;
+            self.push(&[modrm, sib]);
+        } else {
+            let modrm = (mode << 6) | ((reg & 0b111) << 3) | base;
+            self.push(&[modrm]);
         }
+
+        let disp = if disp_len == 1 {
+            mem.disp / scale
+        } else {
+            mem.disp
+        };
+        self.push(&disp.to_le_bytes()[..disp_len]);
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
     }
 }
 
@@ -113,6 +454,8 @@ pub struct Avx512Instruction {
     opcode: Option<AvxOpcode>,
     imm: Option<u8>,
     kmask: Option<Kmask>,
+    mem: Option<Mem>,
+    broadcast: bool,
 }
 
 impl Avx512Instruction {
@@ -146,6 +489,18 @@ impl Avx512Instruction {
         self
     }
 
+    /// Use a memory operand in place of the last register operand
+    pub fn mem(mut self, mem: Mem) -> Self {
+        self.mem = Some(mem);
+        self
+    }
+
+    /// Broadcast the dword memory operand to every dword (`{1to16}`)
+    pub fn broadcast(mut self) -> Self {
+        self.broadcast = true;
+        self
+    }
+
     pub fn assemble(self) -> EvexResult {
         evex(self)
     }
@@ -161,12 +516,21 @@ fn evex(instr: Avx512Instruction) -> EvexResult {
         op3,
         imm,
         kmask,
+        mem,
+        broadcast,
     } = instr;
 
     let op1 = op1.expect("Cannot assemble AVX512 instruction without op1");
-    let op2 = op2.expect("Cannot assemble AVX512 instruction without op2");
     let opcode = opcode.expect("Cannot assemble AVX512 instruction without opcode");
 
+    if let Some(mem) = mem {
+        assert!(op3.is_none(), "Unsupported memory form");
+        return evex_mem(opcode, op1, op2, mem, kmask, imm, broadcast);
+    }
+    assert!(!broadcast, "Only memory operands can be broadcast");
+
+    let op2 = op2.expect("Cannot assemble AVX512 instruction without op2");
+
     // Always 0x62 evex prefix
     let evex_prefix = 0x62;
 
@@ -194,13 +558,9 @@ fn evex(instr: Avx512Instruction) -> EvexResult {
     // W v v v v 1 p p
     // Figure 2-11. Bit Field Layout of the EVEX Prefix
     let w = opcode.is_wide() as u8;
-    let vvvv = if has_three_ops {
-        !op2.0 & 0xf
-    } else {
-        !0 & 0xf
-    };
+    let vvvv = if has_three_ops { !op2.0 & 0xf } else { 0xf };
 
-    let pp = PrefixPp::P_66 as u8;
+    let pp = opcode.pp() as u8;
     let p1 = (w << 7) | (vvvv << 3) | (1 << 2) | pp;
 
     // Construct p2 (the third evex payload byte)
@@ -214,8 +574,8 @@ fn evex(instr: Avx512Instruction) -> EvexResult {
     if has_three_ops {
         vprime = !op2.needs_5_bits() as u8;
     }
-    vprime = vprime & 1;
-    let aaa = kmask.unwrap_or_else(|| Kmask(0)).0;
+    vprime &= 1;
+    let aaa = kmask.unwrap_or(Kmask(0)).0;
     assert!(aaa <= 8, "k value is larger than 8");
 
     let p2 = (z << 7) | (ll << 5) | (b << 4) | (vprime << 3) | aaa;
@@ -226,9 +586,565 @@ fn evex(instr: Avx512Instruction) -> EvexResult {
     let modrm = (3 << 6) | ((r1 & 0b111) << 3) | (r2 & 0b111);
 
     // Return the result of this assembly
+    let mut result = EvexResult {
+        bytes: [0; 15],
+        len: 0,
+    };
+    result.push(&[evex_prefix, p0, p1, p2, opcode.byte(), modrm]);
     if let Some(imm) = imm {
-        EvexResult::Bytes7([evex_prefix, p0, p1, p2, opcode as u8, modrm, imm])
+        result.push(&[imm]);
+    }
+    result
+}
+
+/// Assemble an instruction whose r/m operand is the given memory operand. `vvvv` is the
+/// middle operand of three operand instructions.
+fn evex_mem(
+    opcode: AvxOpcode,
+    reg: Zmm,
+    vvvv: Option<Zmm>,
+    mem: Mem,
+    kmask: Option<Kmask>,
+    imm: Option<u8>,
+    broadcast: bool,
+) -> EvexResult {
+    assert!(
+        vvvv.is_none() || mem.index.is_none(),
+        "VSIB addressing does not take a vvvv operand"
+    );
+    assert!(
+        mem.base.is_some() || mem.index.is_none(),
+        "RIP-relative addressing does not take an index"
+    );
+    assert!(
+        !broadcast || opcode.can_broadcast(),
+        "{opcode:?} has no embedded broadcast"
+    );
+
+    // Payload bytes as described in `evex`. X and V' extend the index register for VSIB
+    // addressing and the base register only needs the B extension bit.
+    let base = mem.base.unwrap_or(0b101);
+    let r = !reg.needs_4_bits() as u8;
+    let x = mem.index.map_or(1, |index| !index.needs_4_bits() as u8);
+    let b = u8::from(base & 0b1000 == 0);
+    let rprime = !reg.needs_5_bits() as u8;
+    let p0 = (r << 7) | (x << 6) | (b << 5) | (rprime << 4) | opcode.mmm() as u8;
+
+    let w = opcode.is_wide() as u8;
+    let vvvv_bits = vvvv.map_or(0b1111, |op| !op.0 & 0xf);
+    let p1 = (w << 7) | (vvvv_bits << 3) | (1 << 2) | opcode.pp() as u8;
+
+    let ll = 2;
+    let vprime = match (vvvv, mem.index) {
+        (Some(op), _) | (_, Some(op)) => !op.needs_5_bits() as u8,
+        (None, None) => 1,
+    };
+    let aaa = kmask.unwrap_or(Kmask(0)).0;
+    let p2 = (ll << 5) | (u8::from(broadcast) << 4) | (vprime << 3) | aaa;
+
+    let mut result = EvexResult {
+        bytes: [0; 15],
+        len: 0,
+    };
+    result.push(&[0x62, p0, p1, p2, opcode.byte()]);
+
+    result.push_mem(reg.0, mem, opcode.disp8_scale(broadcast));
+    if let Some(imm) = imm {
+        result.push(&[imm]);
+    }
+    result
+}
+
+/// Opcodes for the kmask instructions we are using
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KmaskOpcode {
+    /// kandw
+    AndWord,
+
+    /// kandd
+    AndDword,
+
+    /// korw
+    OrWord,
+
+    /// kord
+    OrDword,
+
+    /// kxord
+    XorDword,
+
+    /// kxnorw
+    XnorWord,
+
+    /// kxnord
+    XnorDword,
+
+    /// kmovw from a kmask
+    MovWord,
+
+    /// kmovd from a kmask or memory
+    MovDword,
+
+    /// kortestd, setting ZF if both sources are all zeros and CF if they are all ones
+    OrTestDword,
+
+    /// kshiftrd by an immediate
+    ShiftRightDword,
+}
+
+impl KmaskOpcode {
+    /// Returns the opcode byte
+    const fn byte(&self) -> u8 {
+        use KmaskOpcode::*;
+        match self {
+            AndWord | AndDword => 0x41,
+            OrWord | OrDword => 0x45,
+            XorDword => 0x47,
+            XnorWord | XnorDword => 0x46,
+            MovWord | MovDword => 0x90,
+            OrTestDword => 0x98,
+            ShiftRightDword => 0x31,
+        }
+    }
+
+    /// Returns `true` if the opcode operates on a dword kmask. These use the 0x66 prefix
+    /// and, except for the shifts, VEX.W1.
+    const fn is_dword(&self) -> bool {
+        use KmaskOpcode::*;
+        matches!(
+            self,
+            AndDword | OrDword | XorDword | XnorDword | MovDword | OrTestDword | ShiftRightDword
+        )
+    }
+
+    /// Returns `true` if the opcode takes two source kmasks (VEX.L1)
+    const fn is_logic(&self) -> bool {
+        use KmaskOpcode::*;
+        matches!(
+            self,
+            AndWord | AndDword | OrWord | OrDword | XorDword | XnorWord | XnorDword
+        )
+    }
+}
+
+/// A VEX encoded kmask instruction: `op1 = op2 OP op3` for the logic instructions, and
+/// `op1 = OP op2` (or `OP op1, op2` for kortest) otherwise
+#[derive(Default, Debug, Copy, Clone)]
+pub struct KmaskInstruction {
+    op1: Option<Kmask>,
+    op2: Option<Kmask>,
+    op3: Option<Kmask>,
+    opcode: Option<KmaskOpcode>,
+    imm: Option<u8>,
+    mem: Option<Mem>,
+}
+
+impl KmaskInstruction {
+    pub fn op1(mut self, op1: Kmask) -> Self {
+        self.op1 = Some(op1);
+        self
+    }
+
+    pub fn op2(mut self, op2: Kmask) -> Self {
+        self.op2 = Some(op2);
+        self
+    }
+
+    pub fn op3(mut self, op3: Kmask) -> Self {
+        self.op3 = Some(op3);
+        self
+    }
+
+    pub fn opcode(mut self, opcode: KmaskOpcode) -> Self {
+        self.opcode = Some(opcode);
+        self
+    }
+
+    pub fn imm(mut self, imm: u8) -> Self {
+        self.imm = Some(imm);
+        self
+    }
+
+    /// Use a memory operand in place of the source kmask
+    pub fn mem(mut self, mem: Mem) -> Self {
+        self.mem = Some(mem);
+        self
+    }
+
+    pub fn assemble(self) -> EvexResult {
+        vex(self)
+    }
+}
+
+/// Reference: Section 2.3.5 in Intel® 64 and IA-32 Architectures Software Developer’s Manual
+/// Volume 2 (2A, 2B, 2C, & 2D): Instruction Set Reference, A-Z
+fn vex(instr: KmaskInstruction) -> EvexResult {
+    let KmaskInstruction {
+        op1,
+        op2,
+        op3,
+        opcode,
+        imm,
+        mem,
+    } = instr;
+
+    let op1 = op1.expect("Cannot assemble kmask instruction without op1");
+    let opcode = opcode.expect("Cannot assemble kmask instruction without opcode");
+    assert!(
+        mem.is_none() || opcode == KmaskOpcode::MovDword,
+        "Only kmovd takes a memory operand"
+    );
+    assert!(
+        mem.is_none_or(|mem| mem.index.is_none()),
+        "Kmask instructions don't take an index"
+    );
+
+    // (dst, src1, src2) puts src1 in vvvv, the others only use the reg and r/m fields
+    let (vvvv, rm) = if opcode.is_logic() {
+        let op2 = op2.expect("Cannot assemble kmask instruction without op2");
+        let op3 = op3.expect("Cannot assemble kmask instruction without op3");
+        (op2.0, op3.0)
     } else {
-        EvexResult::Bytes6([evex_prefix, p0, p1, p2, opcode as u8, modrm])
+        (0, op2.map_or(0, |op2| op2.0))
+    };
+
+    let (mmmmm, w, pp) = match opcode {
+        KmaskOpcode::ShiftRightDword => (PrefixMmm::F3A, 0, PrefixPp::P_66),
+        op if op.is_dword() => (PrefixMmm::F, 1, PrefixPp::P_66),
+        _ => (PrefixMmm::F, 0, PrefixPp::None),
+    };
+    let l = u8::from(opcode.is_logic());
+
+    // Kmasks never need the R or X extension bits, only a memory base register can need B
+    let b = mem.map_or(1, |mem| u8::from(mem.base.unwrap_or(0) & 0b1000 == 0));
+    let vvvv = !vvvv & 0xf;
+    let payload = (vvvv << 3) | (l << 2) | pp as u8;
+
+    let mut result = EvexResult {
+        bytes: [0; 15],
+        len: 0,
+    };
+
+    // The two byte VEX prefix implies the 0F map, W0 and no X or B extension
+    let mmmmm = mmmmm as u8;
+    if mmmmm == PrefixMmm::F as u8 && w == 0 && b == 1 {
+        result.push(&[0xc5, 0x80 | payload]);
+    } else {
+        result.push(&[0xc4, 0xc0 | (b << 5) | mmmmm, (w << 7) | payload]);
+    }
+    result.push(&[opcode.byte()]);
+
+    match mem {
+        Some(mem) => result.push_mem(op1.0, mem, 1),
+        None => result.push(&[(3 << 6) | (op1.0 << 3) | rm]),
+    }
+    if let Some(imm) = imm {
+        result.push(&[imm]);
+    }
+    result
+}
+
+/// An AVX2 ymm register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ymm(pub u8);
+
+/// Opcodes for the AVX2 instructions we are using
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum YmmOpcode {
+    /// vmovdqu from memory
+    Load,
+
+    /// vmovdqu to memory
+    Store,
+
+    /// vpaddw
+    Add,
+
+    /// vpsubw
+    Sub,
+
+    /// vpand
+    And,
+
+    /// vpandn, `!op2 & op3`
+    AndNot,
+
+    /// vpor
+    Or,
+
+    /// vpxor
+    Xor,
+
+    /// vpcmpeqw, setting each word to all ones if equal
+    CmpEqWord,
+
+    /// vpmaxuw
+    MaxUnsignedWord,
+
+    /// vpaddd
+    AddDword,
+
+    /// vpsrlw by an immediate
+    ShiftRightWordImm,
+
+    /// vpsraw by an immediate
+    ShiftRightArithWordImm,
+
+    /// vpsllw by an immediate
+    ShiftLeftWordImm,
+
+    /// vpblendvb, taking each byte of `op3` where the top bit of the byte of `op4` is set
+    /// and of `op2` elsewhere
+    BlendBytes,
+
+    /// vpbroadcastw from memory
+    BroadcastWordMem,
+
+    /// vpbroadcastd from memory
+    BroadcastDwordMem,
+
+    /// vpmovzxwd from the low xmm half of a register
+    ZeroExtendWordToDword,
+
+    /// vextracti128 into the low xmm half of a register
+    ExtractHalf,
+
+    /// vpgatherdd with a vector mask
+    GatherDword,
+
+    /// vpackusdw, packing within each 128-bit lane
+    PackDwordToWord,
+
+    /// vpermq by an immediate
+    PermuteQwords,
+}
+
+/// How the operands of a [`YmmInstruction`] map to the ModRM, VEX.vvvv and is4 fields
+enum YmmForm {
+    /// `op1 = op2 OP op3`: reg, vvvv and r/m
+    ThreeOperand,
+
+    /// `op1 = OP op2`: reg and r/m
+    TwoOperand,
+
+    /// `mem = op1`: reg and r/m
+    Store,
+
+    /// `op1 = op2 OP imm` for the shift group: the opcode extension in reg, vvvv and r/m
+    Group(u8),
+
+    /// `op1 = half of op2`: r/m and reg
+    Extract,
+
+    /// `op1 = gather mem with mask op2`: reg, vvvv and VSIB
+    Gather,
+
+    /// `op1 = op4 ? op3 : op2`: reg, vvvv, r/m and is4
+    Blend,
+}
+
+impl YmmOpcode {
+    /// Returns the opcode byte
+    const fn byte(&self) -> u8 {
+        use YmmOpcode::*;
+        match self {
+            Load => 0x6f,
+            Store => 0x7f,
+            Add => 0xfd,
+            Sub => 0xf9,
+            And => 0xdb,
+            AndNot => 0xdf,
+            Or => 0xeb,
+            Xor => 0xef,
+            CmpEqWord => 0x75,
+            MaxUnsignedWord => 0x3e,
+            AddDword => 0xfe,
+            ShiftRightWordImm | ShiftRightArithWordImm | ShiftLeftWordImm => 0x71,
+            BlendBytes => 0x4c,
+            BroadcastWordMem => 0x79,
+            BroadcastDwordMem => 0x58,
+            ZeroExtendWordToDword => 0x33,
+            ExtractHalf => 0x39,
+            GatherDword => 0x90,
+            PackDwordToWord => 0x2b,
+            PermuteQwords => 0x00,
+        }
+    }
+
+    /// Returns the `mmmmm` field type for the opcode
+    const fn mmm(&self) -> PrefixMmm {
+        use YmmOpcode::*;
+        match self {
+            MaxUnsignedWord
+            | BroadcastWordMem
+            | BroadcastDwordMem
+            | ZeroExtendWordToDword
+            | GatherDword
+            | PackDwordToWord => PrefixMmm::F38,
+            BlendBytes | ExtractHalf | PermuteQwords => PrefixMmm::F3A,
+            _ => PrefixMmm::F,
+        }
+    }
+
+    /// Returns the `pp` field type for the opcode
+    const fn pp(&self) -> PrefixPp {
+        match self {
+            YmmOpcode::Load | YmmOpcode::Store => PrefixPp::P_F3,
+            _ => PrefixPp::P_66,
+        }
+    }
+
+    /// Returns `true` if the opcode is a wide instruction (W1 prefix)
+    const fn is_wide(&self) -> bool {
+        matches!(self, YmmOpcode::PermuteQwords)
+    }
+
+    /// Returns how the operands are encoded
+    const fn form(&self) -> YmmForm {
+        use YmmOpcode::*;
+        match self {
+            Load | BroadcastWordMem | BroadcastDwordMem | ZeroExtendWordToDword | PermuteQwords => {
+                YmmForm::TwoOperand
+            }
+            Store => YmmForm::Store,
+            ShiftRightWordImm => YmmForm::Group(2),
+            ShiftRightArithWordImm => YmmForm::Group(4),
+            ShiftLeftWordImm => YmmForm::Group(6),
+            ExtractHalf => YmmForm::Extract,
+            GatherDword => YmmForm::Gather,
+            BlendBytes => YmmForm::Blend,
+            _ => YmmForm::ThreeOperand,
+        }
+    }
+}
+
+/// A VEX encoded AVX2 instruction on ymm registers, with a memory operand in place of the
+/// r/m register operand
+#[derive(Default, Debug, Copy, Clone)]
+pub struct YmmInstruction {
+    op1: Option<Ymm>,
+    op2: Option<Ymm>,
+    op3: Option<Ymm>,
+    op4: Option<Ymm>,
+    opcode: Option<YmmOpcode>,
+    imm: Option<u8>,
+    mem: Option<Mem>,
+}
+
+impl YmmInstruction {
+    pub fn op1(mut self, op1: Ymm) -> Self {
+        self.op1 = Some(op1);
+        self
+    }
+
+    pub fn op2(mut self, op2: Ymm) -> Self {
+        self.op2 = Some(op2);
+        self
+    }
+
+    pub fn op3(mut self, op3: Ymm) -> Self {
+        self.op3 = Some(op3);
+        self
+    }
+
+    pub fn op4(mut self, op4: Ymm) -> Self {
+        self.op4 = Some(op4);
+        self
+    }
+
+    pub fn opcode(mut self, opcode: YmmOpcode) -> Self {
+        self.opcode = Some(opcode);
+        self
+    }
+
+    pub fn imm(mut self, imm: u8) -> Self {
+        self.imm = Some(imm);
+        self
+    }
+
+    /// Use a memory operand in place of the r/m register operand
+    pub fn mem(mut self, mem: Mem) -> Self {
+        self.mem = Some(mem);
+        self
+    }
+
+    pub fn assemble(self) -> EvexResult {
+        vex_ymm(self)
+    }
+}
+
+/// Reference: Section 2.3.5 in Intel® 64 and IA-32 Architectures Software Developer’s Manual
+/// Volume 2 (2A, 2B, 2C, & 2D): Instruction Set Reference, A-Z
+fn vex_ymm(instr: YmmInstruction) -> EvexResult {
+    let YmmInstruction {
+        op1,
+        op2,
+        op3,
+        op4,
+        opcode,
+        imm,
+        mem,
+    } = instr;
+
+    let opcode = opcode.expect("Cannot assemble AVX2 instruction without opcode");
+    let op1 = op1.expect("Cannot assemble AVX2 instruction without op1");
+    let reg_num = |op: Option<Ymm>| op.expect("Missing AVX2 register operand").0;
+
+    // The r/m operand is the last register operand unless a memory operand replaces it
+    let (reg, vvvv, rm, is4) = match opcode.form() {
+        YmmForm::ThreeOperand => (op1.0, Some(reg_num(op2)), op3, None),
+        YmmForm::TwoOperand | YmmForm::Store => (op1.0, None, op2, None),
+        YmmForm::Group(ext) => (ext, Some(op1.0), op2, None),
+        YmmForm::Extract => (reg_num(op2), None, Some(op1), None),
+        YmmForm::Gather => (op1.0, Some(reg_num(op2)), None, None),
+        YmmForm::Blend => (op1.0, Some(reg_num(op2)), op3, Some(reg_num(op4))),
+    };
+    assert!(
+        rm.is_some() != mem.is_some(),
+        "{opcode:?} takes either an r/m register or a memory operand"
+    );
+    assert!(
+        matches!(opcode.form(), YmmForm::Gather) == mem.is_some_and(|mem| mem.index.is_some()),
+        "Only gathers use VSIB addressing"
+    );
+
+    // 7 6 5 4 3 2 1 0     7 6 5 4 3 2 1 0
+    // R X B m m m m m     W v v v v L p p
+    // Figure 2-9. VEX bit fields
+    let r = u8::from(reg & 0b1000 == 0);
+    let (x, b) = match (mem, rm) {
+        (Some(mem), _) => (
+            mem.index.map_or(1, |index| u8::from(index.0 & 0b1000 == 0)),
+            u8::from(mem.base.unwrap_or(0) & 0b1000 == 0),
+        ),
+        (None, Some(rm)) => (1, u8::from(rm.0 & 0b1000 == 0)),
+        (None, None) => unreachable!(),
+    };
+    let w = u8::from(opcode.is_wide());
+    let vvvv = !vvvv.unwrap_or(0) & 0xf;
+    let l = 1;
+
+    let mut result = EvexResult {
+        bytes: [0; 15],
+        len: 0,
+    };
+    result.push(&[
+        0xc4,
+        (r << 7) | (x << 6) | (b << 5) | opcode.mmm() as u8,
+        (w << 7) | (vvvv << 3) | (l << 2) | opcode.pp() as u8,
+        opcode.byte(),
+    ]);
+
+    match (mem, rm) {
+        (Some(mem), _) => result.push_mem(reg, mem, 1),
+        (None, Some(rm)) => result.push(&[(3 << 6) | ((reg & 0b111) << 3) | (rm.0 & 0b111)]),
+        (None, None) => unreachable!(),
+    }
+
+    match (is4, imm) {
+        (Some(is4), _) => result.push(&[is4 << 4]),
+        (None, Some(imm)) => result.push(&[imm]),
+        (None, None) => {}
     }
+    result
 }
@@ -0,0 +1,636 @@
+//! Differential fuzzing of the JIT against the interpreter
+//!
+//! Each case generates a random program from the instructions the JIT supports along with
+//! a random initial state per lane. The program runs once per lane in the
+//! [`Emulator`] and once for all lanes in a [`JitProgram`], and the first register, flag
+//! or memory byte where a lane differs from the interpreter is reported.
+//!
+//! Programs only jump forward so they always end, and every memory operand is preceded by
+//! masking its base register so the accesses stay above the program.
+
+use anyhow::{bail, Context, Result};
+
+use cpu8086::budget::Budget;
+use cpu8086::decoder::decode_instruction;
+use cpu8086::emu::{Emulator, RegisterState};
+use cpu8086::flags::{EFlags, STATUS_FLAGS};
+use cpu8086::register::Register;
+use jit_emu::{Core, CpuState, JitEmulatorState, LaneStatus, MEMORY_SIZE};
+
+use crate::{Backend, JitProgram, Rng};
+
+/// Number of lanes in the [`JitEmulatorState`]
+const LANES: usize = 32;
+
+/// Maximum number of instructions in a generated program
+const MAX_INSTRUCTIONS: usize = 32;
+
+/// Instructions each lane may run before its case is reported as hung. Programs only jump
+/// forward, so a correct engine never runs out of it.
+const BUDGET: Budget = Budget {
+    instructions: Some(4 * MAX_INSTRUCTIONS as u64),
+    cycles: None,
+};
+
+/// Start of the randomized data accessed by memory operands
+const DATA_START: usize = 0x1000;
+
+/// End of the randomized data accessed by memory operands
+const DATA_END: usize = 0x8000;
+
+/// 16-bit registers in the order of their ModRM encoding
+///
+/// With a byte operand size the same encodings select AL, CL, DL, BL, AH, CH, DH and BH.
+const REGISTERS: [Register; 8] = [
+    Register::Ax,
+    Register::Cx,
+    Register::Dx,
+    Register::Bx,
+    Register::Sp,
+    Register::Bp,
+    Register::Si,
+    Register::Di,
+];
+
+/// Base registers usable in a memory operand along with their ModRM r/m field
+const BASE_REGISTERS: [(u8, u8); 4] = [(6, 0b100), (7, 0b101), (5, 0b110), (3, 0b111)];
+
+/// The `/digit` of the generated arithmetic and logic instructions: add, or, and, sub, xor
+/// and cmp
+const ALU_OPS: [u8; 6] = [0, 1, 4, 5, 6, 7];
+
+/// A generated instruction, with registers given by their ModRM encoding
+///
+/// Instructions with a `byte` field operate on the 8-bit registers (and byte memory) when
+/// it is set.
+#[derive(Debug, Copy, Clone)]
+enum FuzzInstr {
+    /// mov reg, imm
+    MovImm { dest: u8, imm: u16, byte: bool },
+
+    /// mov reg, reg
+    MovReg { dest: u8, src: u8, byte: bool },
+
+    /// op reg, reg
+    Alu {
+        op: u8,
+        dest: u8,
+        src: u8,
+        byte: bool,
+    },
+
+    /// op reg, imm
+    AluImm {
+        op: u8,
+        dest: u8,
+        imm: u16,
+        byte: bool,
+    },
+
+    /// op reg, sign extended imm8
+    AluImm8 { op: u8, dest: u8, imm: u8 },
+
+    /// op reg, [base + disp]
+    AluMem {
+        op: u8,
+        dest: u8,
+        base: u8,
+        disp: u16,
+        byte: bool,
+    },
+
+    /// test reg, reg
+    Test { dest: u8, src: u8, byte: bool },
+
+    /// mov reg, [base + disp]
+    Load {
+        dest: u8,
+        base: u8,
+        disp: u16,
+        byte: bool,
+    },
+
+    /// mov [base + disp], reg
+    Store {
+        base: u8,
+        disp: u16,
+        src: u8,
+        byte: bool,
+    },
+
+    /// jcc (or jcxz without a condition) to the instruction at index `target`
+    Jump { cond: Option<u8>, target: usize },
+}
+
+impl FuzzInstr {
+    /// Returns `true` if this instruction reads or writes memory
+    fn accesses_memory(self) -> bool {
+        matches!(
+            self,
+            FuzzInstr::AluMem { .. } | FuzzInstr::Load { .. } | FuzzInstr::Store { .. }
+        )
+    }
+
+    /// Number of bytes of the encoded instruction
+    fn len(self) -> u16 {
+        match self {
+            FuzzInstr::MovReg { .. } | FuzzInstr::Alu { .. } | FuzzInstr::Test { .. } => 2,
+            FuzzInstr::Jump { .. } => 2,
+            FuzzInstr::MovImm { byte, .. } => 3 - u16::from(byte),
+            FuzzInstr::AluImm { byte, .. } => 4 - u16::from(byte),
+            FuzzInstr::AluImm8 { .. } => 3,
+            FuzzInstr::AluMem { .. } | FuzzInstr::Load { .. } | FuzzInstr::Store { .. } => 4,
+        }
+    }
+
+    /// Append the encoded instruction to `bytes`, with the instructions starting at
+    /// `offsets`
+    fn encode(self, index: usize, offsets: &[u16], bytes: &mut Vec<u8>) {
+        let modrm_reg = |reg: u8, rm: u8| 0b1100_0000 | reg << 3 | rm;
+        let modrm_mem = |reg: u8, base: u8| {
+            let rm = BASE_REGISTERS.iter().find(|(r, _)| *r == base).unwrap().1;
+            0b1000_0000 | reg << 3 | rm
+        };
+
+        // The `w` bit selecting a word operand size
+        let w = |byte: bool| u8::from(!byte);
+        // Only the low byte of an immediate is encoded for a byte operand size
+        let push_imm = |bytes: &mut Vec<u8>, imm: u16, byte: bool| {
+            bytes.extend(&imm.to_le_bytes()[..2 - usize::from(byte)]);
+        };
+
+        match self {
+            FuzzInstr::MovImm { dest, imm, byte } => {
+                bytes.push(0xb0 | w(byte) << 3 | dest);
+                push_imm(bytes, imm, byte);
+            }
+            FuzzInstr::MovReg { dest, src, byte } => {
+                bytes.extend([0x8a | w(byte), modrm_reg(dest, src)]);
+            }
+            FuzzInstr::Alu {
+                op,
+                dest,
+                src,
+                byte,
+            } => bytes.extend([op << 3 | 2 | w(byte), modrm_reg(dest, src)]),
+            FuzzInstr::AluImm {
+                op,
+                dest,
+                imm,
+                byte,
+            } => {
+                bytes.extend([0x80 | w(byte), modrm_reg(op, dest)]);
+                push_imm(bytes, imm, byte);
+            }
+            FuzzInstr::AluImm8 { op, dest, imm } => {
+                bytes.extend([0x83, modrm_reg(op, dest), imm]);
+            }
+            FuzzInstr::AluMem {
+                op,
+                dest,
+                base,
+                disp,
+                byte,
+            } => {
+                bytes.extend([op << 3 | 2 | w(byte), modrm_mem(dest, base)]);
+                bytes.extend(disp.to_le_bytes());
+            }
+            FuzzInstr::Test { dest, src, byte } => {
+                bytes.extend([0x84 | w(byte), modrm_reg(src, dest)]);
+            }
+            FuzzInstr::Load {
+                dest,
+                base,
+                disp,
+                byte,
+            } => {
+                bytes.extend([0x8a | w(byte), modrm_mem(dest, base)]);
+                bytes.extend(disp.to_le_bytes());
+            }
+            FuzzInstr::Store {
+                base,
+                disp,
+                src,
+                byte,
+            } => {
+                bytes.extend([0x88 | w(byte), modrm_mem(src, base)]);
+                bytes.extend(disp.to_le_bytes());
+            }
+            FuzzInstr::Jump { cond, target } => {
+                let next = offsets[index] + self.len();
+                #[allow(clippy::cast_possible_truncation)]
+                let rel = offsets[target].wrapping_sub(next) as u8;
+                bytes.extend([cond.map_or(0xe3, |cond| 0x70 + cond), rel]);
+            }
+        }
+    }
+}
+
+/// Generate a random program from `rng`
+fn generate(rng: &mut Rng) -> Vec<u8> {
+    #[allow(clippy::cast_possible_truncation)]
+    let mut pick = |n: usize| (rng.next() % n as u64) as usize;
+
+    let count = 1 + pick(MAX_INSTRUCTIONS);
+    let mut instrs = Vec::with_capacity(count * 2);
+
+    while instrs.len() < count {
+        #[allow(clippy::cast_possible_truncation)]
+        let (dest, src) = (pick(REGISTERS.len()) as u8, pick(REGISTERS.len()) as u8);
+        let op = ALU_OPS[pick(ALU_OPS.len())];
+        #[allow(clippy::cast_possible_truncation)]
+        let imm = rng_u16(pick(u16::MAX as usize + 1), pick(4));
+        // A quarter of the instructions use the 8-bit registers
+        let byte = pick(4) == 0;
+
+        // Memory operands are masked into the data region right before they are used
+        let (base, _) = BASE_REGISTERS[pick(BASE_REGISTERS.len())];
+        #[allow(clippy::cast_possible_truncation)]
+        let disp = (DATA_START + pick(DATA_END / 2 - DATA_START)) as u16 & !1;
+        let mask = FuzzInstr::AluImm {
+            op: 4,
+            dest: base,
+            imm: (DATA_END / 2 - 1) as u16,
+            byte: false,
+        };
+
+        let instr = match pick(10) {
+            0 => FuzzInstr::MovImm { dest, imm, byte },
+            1 => FuzzInstr::MovReg { dest, src, byte },
+            2 => FuzzInstr::Alu {
+                op,
+                dest,
+                src,
+                byte,
+            },
+            3 => FuzzInstr::AluImm {
+                op,
+                dest,
+                imm,
+                byte,
+            },
+            #[allow(clippy::cast_possible_truncation)]
+            4 => FuzzInstr::AluImm8 {
+                op,
+                dest,
+                imm: imm as u8,
+            },
+            5 => FuzzInstr::Test { dest, src, byte },
+            6 | 7 => {
+                // Jumps are patched with their target once every instruction is generated
+                let cond = (pick(17) < 16).then(|| pick(16) as u8);
+                FuzzInstr::Jump { cond, target: 0 }
+            }
+            8 => {
+                instrs.push(mask);
+                match pick(3) {
+                    0 => FuzzInstr::Load {
+                        dest,
+                        base,
+                        disp,
+                        byte,
+                    },
+                    1 => FuzzInstr::Store {
+                        base,
+                        disp,
+                        src,
+                        byte,
+                    },
+                    _ => FuzzInstr::AluMem {
+                        op,
+                        dest,
+                        base,
+                        disp,
+                        byte,
+                    },
+                }
+            }
+            _ => FuzzInstr::AluImm {
+                op,
+                dest,
+                imm,
+                byte,
+            },
+        };
+
+        instrs.push(instr);
+    }
+
+    // Jump forward a few instructions, or to the end of the program. Jumps never land on a
+    // memory access, only on the mask of its base register right before it.
+    let len = instrs.len();
+    for i in 0..len {
+        if let FuzzInstr::Jump { cond, .. } = instrs[i] {
+            let mut target = (i + 1 + pick(8)).min(len);
+            if target < len && instrs[target].accesses_memory() {
+                target -= 1;
+            }
+
+            instrs[i] = FuzzInstr::Jump { cond, target };
+        }
+    }
+
+    let mut offsets = vec![0];
+    for instr in &instrs {
+        offsets.push(offsets.last().unwrap() + instr.len());
+    }
+
+    let mut bytes = Vec::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        instr.encode(i, &offsets, &mut bytes);
+    }
+
+    bytes
+}
+
+/// Turn `val` into an interesting immediate depending on `kind`
+#[allow(clippy::cast_possible_truncation)]
+fn rng_u16(val: usize, kind: usize) -> u16 {
+    let val = val as u16;
+    match kind {
+        0 => val % 16,
+        1 => u16::MAX - val % 16,
+        2 => 0x8000 ^ (val % 16),
+        _ => val,
+    }
+}
+
+/// Get the value of `reg` in the JIT lane state `cpu`
+fn jit_register(cpu: &CpuState, reg: Register) -> u16 {
+    match reg {
+        Register::Ax => cpu.ax,
+        Register::Bx => cpu.bx,
+        Register::Cx => cpu.cx,
+        Register::Dx => cpu.dx,
+        Register::Si => cpu.si,
+        Register::Di => cpu.di,
+        Register::Sp => cpu.sp,
+        Register::Bp => cpu.bp,
+        Register::Ip => cpu.ip,
+        Register::Flags => cpu.flags,
+        _ => unreachable!("{reg:?} is not in the JIT state"),
+    }
+}
+
+/// Set `reg` of `lane` in the JIT state
+fn set_jit_register(state: &mut JitEmulatorState, lane: Core, reg: Register, val: u16) {
+    match reg {
+        Register::Ax => state.set_ax_in(lane, val),
+        Register::Bx => state.set_bx_in(lane, val),
+        Register::Cx => state.set_cx_in(lane, val),
+        Register::Dx => state.set_dx_in(lane, val),
+        Register::Si => state.set_si_in(lane, val),
+        Register::Di => state.set_di_in(lane, val),
+        Register::Sp => state.set_sp_in(lane, val),
+        Register::Bp => state.set_bp_in(lane, val),
+        Register::Ip => state.set_ip_in(lane, val),
+        Register::Flags => state.set_flags_in(lane, val),
+        _ => unreachable!("{reg:?} is not in the JIT state"),
+    }
+}
+
+/// The first difference between a JIT lane and the interpreter
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    /// Seed of the case that generated the program and initial states
+    pub seed: u64,
+
+    /// Lane that differs from the interpreter
+    pub lane: usize,
+
+    /// The register, flag or memory byte that differs
+    pub location: String,
+
+    /// Value computed by the interpreter
+    pub expected: u16,
+
+    /// Value computed by the JIT
+    pub found: u16,
+
+    /// Initial register state of the lane
+    pub initial: RegisterState,
+
+    /// Disassembly of the generated program
+    pub listing: Vec<String>,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Case {:#x} lane {}: {} expected {:#06x} found {:#06x}",
+            self.seed, self.lane, self.location, self.expected, self.found
+        )?;
+
+        let regs = &self.initial;
+        writeln!(
+            f,
+            "Initial state: AX: {:04x} BX: {:04x} CX: {:04x} DX: {:04x} SP: {:04x} BP: {:04x} \
+             SI: {:04x} DI: {:04x} FLAGS: {:04x}",
+            regs.ax(),
+            regs.bx(),
+            regs.cx(),
+            regs.dx(),
+            regs.sp(),
+            regs.bp(),
+            regs.si(),
+            regs.di(),
+            regs.flags()
+        )?;
+
+        for line in &self.listing {
+            writeln!(f, "{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Disassemble `program` with the interpreter's decoder
+fn listing(program: &[u8]) -> Result<Vec<String>> {
+    let mut memory = Box::new(cpu8086::memory::Memory::<{ 64 * 1024 }>::new());
+    memory.memory[..program.len()].copy_from_slice(program);
+    memory.length = program.len();
+
+    let mut cpu = RegisterState::default();
+    let mut lines = Vec::new();
+    while usize::from(cpu.ip()) < program.len() {
+        let ip = cpu.ip();
+        let instr = decode_instruction(&mut cpu, &memory)?;
+        lines.push(format!("{ip:#06x} {instr}"));
+    }
+
+    Ok(lines)
+}
+
+/// Run the case generated from `seed` through the interpreter and the JIT, returning the
+/// first difference
+fn fuzz_case(seed: u64, backend: Backend) -> Result<Option<Mismatch>> {
+    let mut rng = Rng::from_seed(seed);
+    let program = generate(&mut rng);
+
+    let mut state = JitEmulatorState::default();
+    state.memory.load(&program);
+    state.budget = BUDGET;
+
+    let mut interps = Vec::with_capacity(LANES);
+    for lane in 0..LANES {
+        #[allow(clippy::cast_possible_truncation)]
+        let core = Core(lane as u8);
+        let mut emu = Box::new(Emulator::<{ 64 * 1024 }>::new());
+        emu.budget = BUDGET;
+        emu.memory.memory[..program.len()].copy_from_slice(&program);
+        emu.memory.length = program.len();
+
+        for reg in REGISTERS {
+            #[allow(clippy::cast_possible_truncation)]
+            let val = rng.fuzz_u64() as u16;
+            emu.set_register_value(&reg, val);
+            set_jit_register(&mut state, core, reg, val);
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let flags = rng.next() as u16 & STATUS_FLAGS;
+        *emu.flags_mut() = flags;
+        state.set_flags_in(core, flags);
+
+        let data = &mut emu.memory.memory[DATA_START..DATA_END];
+        for chunk in data.chunks_mut(8) {
+            chunk.copy_from_slice(&rng.next().to_le_bytes()[..chunk.len()]);
+        }
+        state.memory.lane_mut(core)[DATA_START..DATA_END].copy_from_slice(data);
+
+        interps.push(emu);
+    }
+
+    let initial: Vec<RegisterState> = interps.iter().map(|emu| emu.registers.clone()).collect();
+
+    // Programs only jump forward, so each instruction runs at most once
+    for emu in &mut interps {
+        while usize::from(emu.ip()) < program.len() {
+            let ip = emu.ip();
+            let instr = decode_instruction(&mut emu.registers, &emu.memory)?;
+            emu.step(ip, &instr)
+                .with_context(|| format!("Interpreter failed in case {seed:#x}: {instr}"))?;
+        }
+    }
+
+    let mut jit = JitProgram::<{ 64 * 1024 }>::new(&program)?;
+    jit.run_on(&mut state, backend)
+        .with_context(|| format!("JIT failed in case {seed:#x}"))?;
+    let hung = (0..LANES).find(|&lane| state.status[lane] == LaneStatus::BudgetExhausted);
+    if let Some(lane) = hung {
+        bail!("JIT lane {lane} ran out of its budget in case {seed:#x}");
+    }
+
+    let mismatch = |lane: usize, location: String, expected: u16, found: u16| {
+        Ok(Some(Mismatch {
+            seed,
+            lane,
+            location,
+            expected,
+            found,
+            initial: initial[lane].clone(),
+            listing: listing(&program)?,
+        }))
+    };
+
+    for (lane, emu) in interps.iter().enumerate() {
+        #[allow(clippy::cast_possible_truncation)]
+        let core = Core(lane as u8);
+        let cpu = state.get_cpu_state(core);
+
+        for reg in REGISTERS.into_iter().chain([Register::Ip]) {
+            let (expected, found) = (emu.get_register_value(&reg), jit_register(&cpu, reg));
+            if expected != found {
+                return mismatch(lane, format!("{reg:?}"), expected, found);
+            }
+        }
+
+        for (flag, name) in [
+            (EFlags::Carry, "CF"),
+            (EFlags::Parity, "PF"),
+            (EFlags::Auxillary, "AF"),
+            (EFlags::Zero, "ZF"),
+            (EFlags::Sign, "SF"),
+            (EFlags::Overflow, "OF"),
+        ] {
+            let bit = flag as u16;
+            let (expected, found) = (emu.flags() & bit, cpu.flags & bit);
+            if expected != found {
+                return mismatch(lane, name.to_string(), expected, found);
+            }
+        }
+
+        if emu.flags() != cpu.flags {
+            return mismatch(lane, "FLAGS".to_string(), emu.flags(), cpu.flags);
+        }
+
+        let expected = &emu.memory.memory[..MEMORY_SIZE];
+        let found = state.memory.lane(core);
+        if let Some(addr) = (0..MEMORY_SIZE).find(|&addr| expected[addr] != found[addr]) {
+            let location = format!("byte [{addr:#06x}]");
+            return mismatch(lane, location, expected[addr].into(), found[addr].into());
+        }
+    }
+
+    Ok(None)
+}
+
+/// Run `cases` fuzz cases with the seeds following `seed` using `backend` for the JIT,
+/// returning the first mismatch between the JIT and the interpreter
+///
+/// A mismatch is reproduced by fuzzing a single case with its [`Mismatch::seed`].
+pub fn fuzz(seed: u64, cases: u64, backend: Backend) -> Result<Option<Mismatch>> {
+    if backend == Backend::Native && !Backend::native_supported() {
+        bail!("Running the JIT natively requires a CPU supporting AVX512BW");
+    }
+    if backend == Backend::Avx2 && !Backend::avx2_supported() {
+        bail!("The AVX2 backend requires a CPU supporting AVX2");
+    }
+
+    for case in 0..cases {
+        if let Some(mismatch) = fuzz_case(seed.wrapping_add(case), backend)? {
+            return Ok(Some(mismatch));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzz_model() {
+        let mismatch = fuzz(0x1234, 20, Backend::Model).unwrap();
+        assert!(mismatch.is_none(), "{}", mismatch.unwrap());
+    }
+
+    #[test]
+    fn test_fuzz_simd() {
+        let mismatch = fuzz(0x9abc, 200, Backend::Simd).unwrap();
+        assert!(mismatch.is_none(), "{}", mismatch.unwrap());
+    }
+
+    #[test]
+    fn test_fuzz_avx2() {
+        if !Backend::avx2_supported() {
+            return;
+        }
+
+        let mismatch = fuzz(0xdef0, 200, Backend::Avx2).unwrap();
+        assert!(mismatch.is_none(), "{}", mismatch.unwrap());
+    }
+
+    #[test]
+    fn test_fuzz_native() {
+        if Backend::detect() != Backend::Native {
+            return;
+        }
+
+        let mismatch = fuzz(0x5678, 200, Backend::Native).unwrap();
+        assert!(mismatch.is_none(), "{}", mismatch.unwrap());
+    }
+}
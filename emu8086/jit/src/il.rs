@@ -1,33 +1,419 @@
 //! The intermediate language for executing avx512 instructions
 use super::Zmm;
 use crate::evex::AvxOperand;
+use cpu8086::flags::{EFlags, STATUS_FLAGS};
+use cpu8086::memory_operand::MemorySize;
+use cpu8086::register::{Register, SegmentRegister, SubRegister};
+
+use std::fmt;
+
+/// The byte of each word accessed by an 8-bit register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BytePart {
+    /// AL, BL, CL and DL
+    Low,
+
+    /// AH, BH, CH and DH
+    High,
+}
+
+/// A register operand: every word of `zmm`, or only one byte of each word for the 8-bit
+/// registers
+#[derive(Debug, Copy, Clone)]
+pub struct RegisterOperand {
+    pub zmm: Zmm,
+
+    /// The byte accessed by an 8-bit register
+    pub byte: Option<BytePart>,
+}
+
+impl From<Zmm> for RegisterOperand {
+    fn from(zmm: Zmm) -> RegisterOperand {
+        RegisterOperand { zmm, byte: None }
+    }
+}
+
+impl fmt::Display for RegisterOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Register::*;
+
+        let segment = [
+            SegmentRegister::Es,
+            SegmentRegister::Cs,
+            SegmentRegister::Ss,
+            SegmentRegister::Ds,
+        ]
+        .into_iter()
+        .find(|reg| reg.as_zmm() == self.zmm.0);
+        if let Some(segment) = segment {
+            return write!(f, "{segment}");
+        }
+
+        let full = [Ax, Bx, Cx, Dx, Si, Di, Sp, Bp, Ip, Flags]
+            .into_iter()
+            .find(|reg| reg.as_zmm() == self.zmm.0);
+        let Some(full) = full else {
+            return write!(f, "zmm{}", self.zmm.0);
+        };
+
+        let sub = match self.byte {
+            None => SubRegister::Full,
+            Some(BytePart::Low) => SubRegister::Low,
+            Some(BytePart::High) => SubRegister::High,
+        };
+        let reg = [full, Al, Ah, Bl, Bh, Cl, Ch, Dl, Dh]
+            .into_iter()
+            .find(|reg| reg.as_sub_register() == (full, sub))
+            .unwrap_or(full);
+
+        write!(f, "{reg}")
+    }
+}
+
+impl From<RegisterOperand> for AvxOperand {
+    fn from(reg: RegisterOperand) -> AvxOperand {
+        match reg.byte {
+            Some(part) => AvxOperand::Byte(reg.zmm, part),
+            None => AvxOperand::Zmm(reg.zmm),
+        }
+    }
+}
+
+/// An 8086 memory operand evaluated per lane as `registers[0] + registers[1] + disp`,
+/// offset by `segment * 16`
+///
+/// Each lane only has 64 KiB of memory, so the segmented address wraps at 16 bits.
+#[derive(Debug, Copy, Clone)]
+pub struct JitMemory {
+    /// Segment register the address is relative to, or none for a flat address
+    pub segment: Option<Zmm>,
+
+    /// Registers holding the base and index of the address
+    pub registers: [Option<Zmm>; 2],
+
+    /// Displacement (or direct address) added to the registers
+    pub disp: u16,
+
+    /// Size of the memory access
+    pub size: MemorySize,
+}
+
+impl fmt::Display for JitMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.size)?;
+        if let Some(segment) = self.segment {
+            write!(f, "{}:", RegisterOperand::from(segment))?;
+        }
+        write!(f, "[")?;
+        for reg in self.registers.into_iter().flatten() {
+            write!(f, "{} + ", RegisterOperand::from(reg))?;
+        }
+        write!(f, "{:#x}]", self.disp)
+    }
+}
+
+impl fmt::Display for AvxOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            AvxOperand::Zmm(zmm) => write!(f, "{}", RegisterOperand::from(zmm)),
+            AvxOperand::Byte(zmm, part) => {
+                let reg = RegisterOperand {
+                    zmm,
+                    byte: Some(part),
+                };
+                write!(f, "{reg}")
+            }
+            AvxOperand::Immediate(imm) => write!(f, "{imm:#x}"),
+            AvxOperand::Memory(mem) => write!(f, "{mem}"),
+        }
+    }
+}
+
+/// Get the registers used by `operand`
+fn operand_registers(operand: &AvxOperand) -> Vec<Zmm> {
+    match operand {
+        AvxOperand::Zmm(zmm) | AvxOperand::Byte(zmm, _) => vec![*zmm],
+        AvxOperand::Immediate(_) => Vec::new(),
+        AvxOperand::Memory(mem) => mem
+            .segment
+            .into_iter()
+            .chain(mem.registers.into_iter().flatten())
+            .collect(),
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum JitIL {
-    /// vmovdqa64
-    Mov { dest: Zmm, src: AvxOperand },
+    /// vmovdqa64, or a gather from each lane's memory
+    Mov {
+        dest: RegisterOperand,
+        src: AvxOperand,
+    },
+
+    /// Gather, merge and scatter `src` into each lane's memory
+    Store { dest: JitMemory, src: AvxOperand },
 
-    /// vpsubw
+    /// vpsubw, computing the `flags` status flags from the result
     Sub {
-        dest: Zmm,
-        op1: Zmm,
+        dest: RegisterOperand,
+        op1: RegisterOperand,
         op2: AvxOperand,
+        flags: u16,
     },
 
-    /// vpaddw
+    /// vpaddw, computing the `flags` status flags from the result
     Add {
-        dest: Zmm,
-        op1: Zmm,
+        dest: RegisterOperand,
+        op1: RegisterOperand,
+        op2: AvxOperand,
+        flags: u16,
+    },
+
+    /// vpandd, computing the `flags` status flags from the result
+    And {
+        dest: RegisterOperand,
+        op1: RegisterOperand,
+        op2: AvxOperand,
+        flags: u16,
+    },
+
+    /// vpord, computing the `flags` status flags from the result
+    Or {
+        dest: RegisterOperand,
+        op1: RegisterOperand,
         op2: AvxOperand,
+        flags: u16,
     },
 
-    /// vpcmpw k, zmm, zmm, imm8
+    /// vpxord, computing the `flags` status flags from the result
+    Xor {
+        dest: RegisterOperand,
+        op1: RegisterOperand,
+        op2: AvxOperand,
+        flags: u16,
+    },
+
+    /// Compute the `flags` status flags of `left - right`
     Cmp {
-        k: Zmm,
         left: AvxOperand,
         right: AvxOperand,
-        op: CmpOp,
+        flags: u16,
+    },
+
+    /// Compute the `flags` status flags of `left & right`
+    Test {
+        left: AvxOperand,
+        right: AvxOperand,
+        flags: u16,
     },
+
+    /// Set IP to `taken` in the lanes where `cond` holds and to `not_taken` in the others
+    Branch {
+        cond: Condition,
+        taken: u16,
+        not_taken: u16,
+    },
+
+    /// Decrement CX and branch to `taken` in the lanes where CX is not zero and the
+    /// optional `cond` holds
+    Loop {
+        cond: Option<Condition>,
+        taken: u16,
+        not_taken: u16,
+    },
+}
+
+/// Condition of a conditional branch, evaluated per lane
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Equal,
+    NotEqual,
+    Below,
+    NotBelow,
+    BelowEqual,
+    NotBelowEqual,
+    Less,
+    NotLess,
+    LessEqual,
+    NotLessEqual,
+    ParityEven,
+    ParityOdd,
+    Overflow,
+    NotOverflow,
+    Sign,
+    NotSign,
+    CxZero,
+}
+
+impl Condition {
+    /// Status flags read when evaluating the condition
+    pub fn flags_read(self) -> u16 {
+        use Condition::*;
+
+        let zero = EFlags::Zero as u16;
+        let carry = EFlags::Carry as u16;
+        let sign = EFlags::Sign as u16;
+        let overflow = EFlags::Overflow as u16;
+
+        match self {
+            Equal | NotEqual => zero,
+            Below | NotBelow => carry,
+            BelowEqual | NotBelowEqual => carry | zero,
+            Less | NotLess => sign | overflow,
+            LessEqual | NotLessEqual => sign | overflow | zero,
+            ParityEven | ParityOdd => EFlags::Parity as u16,
+            Overflow | NotOverflow => overflow,
+            Sign | NotSign => sign,
+            CxZero => 0,
+        }
+    }
+}
+
+impl JitIL {
+    /// Status flags read by this instruction
+    pub fn flags_read(&self) -> u16 {
+        match self {
+            JitIL::Branch { cond, .. } => cond.flags_read(),
+            JitIL::Loop { cond, .. } => cond.map_or(0, Condition::flags_read),
+            _ => 0,
+        }
+    }
+
+    /// Get the 8086 registers read by this instruction, other than FLAGS and IP
+    ///
+    /// Writing a byte of a register keeps its other byte, so the register is also read.
+    pub fn registers_read(&self) -> Vec<Zmm> {
+        match self {
+            JitIL::Mov { dest, src } => {
+                let mut read = operand_registers(src);
+                read.extend(dest.byte.map(|_| dest.zmm));
+                read
+            }
+            JitIL::Store { dest, src } => {
+                let mut read = operand_registers(src);
+                read.extend(operand_registers(&AvxOperand::Memory(*dest)));
+                read
+            }
+            JitIL::Sub { op1, op2, .. }
+            | JitIL::Add { op1, op2, .. }
+            | JitIL::And { op1, op2, .. }
+            | JitIL::Or { op1, op2, .. }
+            | JitIL::Xor { op1, op2, .. } => {
+                let mut read = vec![op1.zmm];
+                read.extend(operand_registers(op2));
+                read
+            }
+            JitIL::Cmp { left, right, .. } | JitIL::Test { left, right, .. } => {
+                let mut read = operand_registers(left);
+                read.extend(operand_registers(right));
+                read
+            }
+            JitIL::Branch { .. } => Vec::new(),
+            JitIL::Loop { .. } => vec![Zmm(Register::Cx.as_zmm())],
+        }
+    }
+
+    /// Get the 8086 register written by this instruction, other than FLAGS and IP
+    pub fn register_written(&self) -> Option<RegisterOperand> {
+        match self {
+            JitIL::Mov { dest, .. }
+            | JitIL::Sub { dest, .. }
+            | JitIL::Add { dest, .. }
+            | JitIL::And { dest, .. }
+            | JitIL::Or { dest, .. }
+            | JitIL::Xor { dest, .. } => Some(*dest),
+            JitIL::Loop { .. } => Some(Zmm(Register::Cx.as_zmm()).into()),
+            JitIL::Store { .. } | JitIL::Cmp { .. } | JitIL::Test { .. } | JitIL::Branch { .. } => {
+                None
+            }
+        }
+    }
+
+    /// Get the status flags computed by this instruction if it writes the status flags
+    pub fn flags_mut(&mut self) -> Option<&mut u16> {
+        match self {
+            JitIL::Sub { flags, .. }
+            | JitIL::Add { flags, .. }
+            | JitIL::And { flags, .. }
+            | JitIL::Or { flags, .. }
+            | JitIL::Xor { flags, .. }
+            | JitIL::Cmp { flags, .. }
+            | JitIL::Test { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JitIL {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitIL::Mov { dest, src } => write!(f, "mov {dest}, {src}")?,
+            JitIL::Store { dest, src } => write!(f, "mov {dest}, {src}")?,
+            JitIL::Sub { dest, op1, op2, .. } => write!(f, "sub {dest}, {op1}, {op2}")?,
+            JitIL::Add { dest, op1, op2, .. } => write!(f, "add {dest}, {op1}, {op2}")?,
+            JitIL::And { dest, op1, op2, .. } => write!(f, "and {dest}, {op1}, {op2}")?,
+            JitIL::Or { dest, op1, op2, .. } => write!(f, "or {dest}, {op1}, {op2}")?,
+            JitIL::Xor { dest, op1, op2, .. } => write!(f, "xor {dest}, {op1}, {op2}")?,
+            JitIL::Cmp { left, right, .. } => write!(f, "cmp {left}, {right}")?,
+            JitIL::Test { left, right, .. } => write!(f, "test {left}, {right}")?,
+            JitIL::Branch {
+                cond,
+                taken,
+                not_taken,
+            } => write!(f, "branch {cond:?} {taken:#x} else {not_taken:#x}")?,
+            JitIL::Loop {
+                cond,
+                taken,
+                not_taken,
+            } => {
+                write!(f, "loop ")?;
+                if let Some(cond) = cond {
+                    write!(f, "{cond:?} ")?;
+                }
+                write!(f, "{taken:#x} else {not_taken:#x}")?;
+            }
+        }
+
+        // List the computed status flags
+        let mut instr = *self;
+        let flags = instr.flags_mut().map_or(0, |flags| *flags);
+        if flags != 0 {
+            write!(f, " ; ")?;
+            for (flag, name) in [
+                (EFlags::Carry, 'C'),
+                (EFlags::Parity, 'P'),
+                (EFlags::Auxillary, 'A'),
+                (EFlags::Zero, 'Z'),
+                (EFlags::Sign, 'S'),
+                (EFlags::Overflow, 'O'),
+            ] {
+                if flags & flag as u16 > 0 {
+                    write!(f, "{name}")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Only compute the status flags that are read before being overwritten, treating the
+/// `live_out` flags as read after the last of the `instrs`
+///
+/// Every instruction writing the status flags writes all of them, so a flag is dead once a
+/// later instruction writes the flags before any instruction reads it.
+pub fn eliminate_dead_flags<'a>(
+    instrs: impl DoubleEndedIterator<Item = &'a mut JitIL>,
+    live_out: u16,
+) {
+    let mut live = live_out;
+    for instr in instrs.rev() {
+        if let Some(flags) = instr.flags_mut() {
+            *flags &= live;
+            live &= !STATUS_FLAGS;
+        }
+        live |= instr.flags_read();
+    }
 }
 
 // CASE (imm8[2:0]) OF
@@ -77,6 +463,15 @@ macro_rules! vpaddw {
             .op2($op2)
             .op3($op3)
     };
+
+    ($op1:expr, $op2:expr, $op3:expr, $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::Add)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .kmask($k)
+    };
 }
 
 #[macro_export]
@@ -91,8 +486,312 @@ macro_rules! vpcmpw {
     };
 }
 
+#[macro_export]
+macro_rules! vpcmpuw {
+    ($op1:expr, $op2:expr, $op3:expr, $cmp:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::UnsignedCmp)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .imm($cmp as u8)
+    };
+}
+
+/// vptestmw k, zmm, zmm
+#[macro_export]
+macro_rules! vptestmw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::TestWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vptestnmw k, zmm, zmm
+#[macro_export]
+macro_rules! vptestnmw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::TestNotWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpsrlw zmm, zmm, imm8
+#[macro_export]
+macro_rules! vpsrlw {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        // The destination is encoded in vvvv and the reg field holds the /2 opcode extension
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ShiftRightWordImm)
+            .op1(Zmm(2))
+            .op2($op1)
+            .op3($op2)
+            .imm($imm)
+    };
+}
+
+/// vpsllw zmm, zmm, imm8
+#[macro_export]
+macro_rules! vpsllw {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        // The destination is encoded in vvvv and the reg field holds the /6 opcode extension
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ShiftLeftWordImm)
+            .op1(Zmm(6))
+            .op2($op1)
+            .op3($op2)
+            .imm($imm)
+    };
+}
+
+/// vpsraw zmm, zmm, imm8
+#[macro_export]
+macro_rules! vpsraw {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        // The destination is encoded in vvvv and the reg field holds the /4 opcode extension
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ShiftRightArithWordImm)
+            .op1(Zmm(4))
+            .op2($op1)
+            .op3($op2)
+            .imm($imm)
+    };
+}
+
+/// vpsllvw zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpsllvw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ShiftLeftWordVar)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpsrlvw zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpsrlvw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ShiftRightWordVar)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpsravw zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpsravw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ShiftRightArithWordVar)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vprold zmm, zmm, imm8
+#[macro_export]
+macro_rules! vprold {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        // The destination is encoded in vvvv and the reg field holds the /1 opcode extension
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::RotateLeftDwordImm)
+            .op1(Zmm(1))
+            .op2($op1)
+            .op3($op2)
+            .imm($imm)
+    };
+}
+
+/// vprord zmm, zmm, imm8
+#[macro_export]
+macro_rules! vprord {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        // The destination is encoded in vvvv and the reg field holds the /0 opcode extension
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::RotateRightDwordImm)
+            .op1(Zmm(0))
+            .op2($op1)
+            .op3($op2)
+            .imm($imm)
+    };
+}
+
+/// vpmullw zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpmullw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::MulLowWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpmulhw zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpmulhw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::MulHighWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpmulhuw zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpmulhuw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::MulHighUnsignedWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpblendmw zmm{k}, zmm, zmm
+#[macro_export]
+macro_rules! vpblendmw {
+    ($op1:expr, $op2:expr, $op3:expr, $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BlendWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .kmask($k)
+    };
+}
+
+/// vpaddb zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpaddb {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::AddByte)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpsubb zmm, zmm, zmm
+#[macro_export]
+macro_rules! vpsubb {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::SubByte)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpcmpb k, zmm, zmm, imm8
+#[macro_export]
+macro_rules! vpcmpb {
+    ($op1:expr, $op2:expr, $op3:expr, $cmp:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::SignedCmpByte)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .imm($cmp as u8)
+    };
+}
+
+/// vpcmpub k, zmm, zmm, imm8
+#[macro_export]
+macro_rules! vpcmpub {
+    ($op1:expr, $op2:expr, $op3:expr, $cmp:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::UnsignedCmpByte)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .imm($cmp as u8)
+    };
+}
+
+/// vptestmb k, zmm, zmm
+#[macro_export]
+macro_rules! vptestmb {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::TestByte)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// vpblendmb zmm{k}, zmm, zmm
+#[macro_export]
+macro_rules! vpblendmb {
+    ($op1:expr, $op2:expr, $op3:expr, $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BlendByte)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .kmask($k)
+    };
+}
+
+/// vpbroadcastb zmm, r32
+#[macro_export]
+macro_rules! vpbroadcastb {
+    ($op1:expr, $reg:ident) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BroadcastByte)
+            .op1($op1)
+            .op2(HostRegister::$reg.as_zmm())
+    };
+}
+
+/// vmovdqu8 zmm{k}, zmm
+#[macro_export]
+macro_rules! vmovdqu8 {
+    ($op1:expr, $op2:expr, $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::MovBytes)
+            .op1($op1)
+            .op2($op2)
+            .kmask($k)
+    };
+}
+
 #[macro_export]
 macro_rules! vpbroadcastw {
+    ($op1:expr, [rip + $disp:expr]) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BroadcastWordMem)
+            .op1($op1)
+            .mem(Mem::rip($disp))
+    };
+
+    ($op1:expr, [rip + $disp:expr], $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BroadcastWordMem)
+            .op1($op1)
+            .mem(Mem::rip($disp))
+            .kmask($k)
+    };
+
     ($op1:expr, $reg:ident) => {
         Avx512Instruction::default()
             .opcode(AvxOpcode::Broadcast)
@@ -119,6 +818,38 @@ macro_rules! vpmovdqa64 {
     };
 }
 
+#[macro_export]
+macro_rules! vmovdqa64_load {
+    ($op1:expr, [$base:ident + $disp:expr]) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::Mov)
+            .op1($op1)
+            .mem(Mem::base(HostRegister::$base as u8, $disp as i32))
+    };
+}
+
+#[macro_export]
+macro_rules! vmovdqa64_store {
+    ([$base:ident + $disp:expr], $op1:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::Store)
+            .op1($op1)
+            .mem(Mem::base(HostRegister::$base as u8, $disp as i32))
+    };
+}
+
+/// vmovdqu16 [base + disp]{k}, zmm
+#[macro_export]
+macro_rules! vmovdqu16_store {
+    ([$base:ident + $disp:expr], $op1:expr, $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::StoreWords)
+            .op1($op1)
+            .mem(Mem::base(HostRegister::$base as u8, $disp as i32))
+            .kmask($k)
+    };
+}
+
 #[macro_export]
 macro_rules! vpxorq {
     ($op:expr) => {
@@ -130,6 +861,17 @@ macro_rules! vpxorq {
     };
 }
 
+#[macro_export]
+macro_rules! vpxord {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::Xor)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
 #[macro_export]
 macro_rules! vporw {
     ($op1:expr, $op2:expr, $op3:expr) => {
@@ -152,6 +894,14 @@ macro_rules! vporw {
 
 #[macro_export]
 macro_rules! vpandw {
+    ($op1:expr, $op2:expr, [rip + $disp:expr]) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::And)
+            .op1($op1)
+            .op2($op2)
+            .mem(Mem::rip($disp))
+    };
+
     ($op1:expr, $op2:expr, $op3:expr) => {
         Avx512Instruction::default()
             .opcode(AvxOpcode::And)
@@ -169,3 +919,261 @@ macro_rules! vpandw {
             .kmask($k)
     };
 }
+
+#[macro_export]
+macro_rules! vpaddd {
+    ($op1:expr, $op2:expr, [$base:ident + $disp:expr]) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::AddDword)
+            .op1($op1)
+            .op2($op2)
+            .mem(Mem::base(HostRegister::$base as u8, $disp as i32))
+    };
+}
+
+#[macro_export]
+macro_rules! vpbroadcastd {
+    ($op1:expr, [rip + $disp:expr]) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BroadcastDwordMem)
+            .op1($op1)
+            .mem(Mem::rip($disp))
+    };
+
+    ($op1:expr, $reg:ident) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::BroadcastDword)
+            .op1($op1)
+            .op2(HostRegister::$reg.as_zmm())
+    };
+}
+
+/// vpmovzxwd zmm, ymm
+#[macro_export]
+macro_rules! vpmovzxwd {
+    ($op1:expr, $op2:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ZeroExtendWordToDword)
+            .op1($op1)
+            .op2($op2)
+    };
+}
+
+/// vpmovdw ymm, zmm
+#[macro_export]
+macro_rules! vpmovdw {
+    ($op1:expr, $op2:expr) => {
+        // The destination is encoded in the r/m field
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::TruncateDwordToWord)
+            .op1($op2)
+            .op2($op1)
+    };
+}
+
+/// vextracti64x4 ymm, zmm, imm8
+#[macro_export]
+macro_rules! vextracti64x4 {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        // The destination is encoded in the r/m field
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ExtractHalf)
+            .op1($op2)
+            .op2($op1)
+            .imm($imm)
+    };
+}
+
+/// vinserti64x4 zmm, zmm, ymm, imm8
+#[macro_export]
+macro_rules! vinserti64x4 {
+    ($op1:expr, $op2:expr, $op3:expr, $imm:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::InsertHalf)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .imm($imm)
+    };
+}
+
+#[macro_export]
+macro_rules! vpternlogd {
+    ($op1:expr, $op2:expr, [rip + $disp:expr], $imm:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::TernaryLogic)
+            .op1($op1)
+            .op2($op2)
+            .mem(Mem::rip($disp))
+            .imm($imm)
+    };
+
+    ($op1:expr, $op2:expr, $op3:expr, $imm:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::TernaryLogic)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+            .imm($imm)
+    };
+}
+
+/// vpgatherdd zmm{k}, [base + zmm*1]
+#[macro_export]
+macro_rules! vpgatherdd {
+    ($op1:expr, [$base:ident + $index:expr], $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::GatherDword)
+            .op1($op1)
+            .mem(Mem::vsib(HostRegister::$base as u8, $index))
+            .kmask($k)
+    };
+}
+
+/// vpscatterdd [base + zmm*1]{k}, zmm
+#[macro_export]
+macro_rules! vpscatterdd {
+    ([$base:ident + $index:expr], $op1:expr, $k:expr) => {
+        Avx512Instruction::default()
+            .opcode(AvxOpcode::ScatterDword)
+            .op1($op1)
+            .mem(Mem::vsib(HostRegister::$base as u8, $index))
+            .kmask($k)
+    };
+}
+
+/// kandw k, k, k
+#[macro_export]
+macro_rules! kandw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::AndWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// kandd k, k, k
+#[macro_export]
+macro_rules! kandd {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::AndDword)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// korw k, k, k
+#[macro_export]
+macro_rules! korw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::OrWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// kord k, k, k
+#[macro_export]
+macro_rules! kord {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::OrDword)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// kxord k, k, k
+#[macro_export]
+macro_rules! kxord {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::XorDword)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// kxnorw k, k, k
+#[macro_export]
+macro_rules! kxnorw {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::XnorWord)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// kxnord k, k, k
+#[macro_export]
+macro_rules! kxnord {
+    ($op1:expr, $op2:expr, $op3:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::XnorDword)
+            .op1($op1)
+            .op2($op2)
+            .op3($op3)
+    };
+}
+
+/// kmovw k, k
+#[macro_export]
+macro_rules! kmovw {
+    ($op1:expr, $op2:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::MovWord)
+            .op1($op1)
+            .op2($op2)
+    };
+}
+
+/// kmovd k, [base + disp] or kmovd k, k
+#[macro_export]
+macro_rules! kmovd {
+    ($op1:expr, [$base:ident + $disp:expr]) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::MovDword)
+            .op1($op1)
+            .mem(Mem::base(HostRegister::$base as u8, $disp as i32))
+    };
+
+    ($op1:expr, $op2:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::MovDword)
+            .op1($op1)
+            .op2($op2)
+    };
+}
+
+/// kortestd k, k
+#[macro_export]
+macro_rules! kortestd {
+    ($op1:expr, $op2:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::OrTestDword)
+            .op1($op1)
+            .op2($op2)
+    };
+}
+
+/// kshiftrd k, k, imm8
+#[macro_export]
+macro_rules! kshiftrd {
+    ($op1:expr, $op2:expr, $imm:expr) => {
+        KmaskInstruction::default()
+            .opcode(KmaskOpcode::ShiftRightDword)
+            .op1($op1)
+            .op2($op2)
+            .imm($imm)
+    };
+}
@@ -1,16 +1,164 @@
 #![feature(portable_simd)]
+#![feature(generic_const_exprs)]
+#![allow(incomplete_features)]
 
 mod utils;
-use utils::alloc_rwx;
+use utils::{ExecMemory, Protection};
 
 mod il;
-pub use il::{CmpOp, JitIL};
+pub use il::{eliminate_dead_flags, BytePart, CmpOp, Condition, JitIL, JitMemory, RegisterOperand};
+
+mod program;
+pub use program::{translatable, JitProgram, JitProgramStats, TranslatedInstruction};
+
+mod model;
+
+mod rng;
+pub use rng::Rng;
+
+mod fuzz;
+pub use fuzz::{fuzz, Mismatch};
+
+mod passes;
+pub use passes::{optimize, Pass, PASSES};
+
+mod simd;
+
+mod avx2;
+pub use avx2::Avx2Buffer;
+
+mod listing;
+pub use listing::{HostInstruction, Listing, ListingEntry, ListingFormat};
+
+mod regalloc;
+use regalloc::{Allocation, Constant, ScratchAllocator};
 
 mod evex;
-pub use evex::{Avx512Instruction, AvxOpcode, AvxOperand, Kmask, Zmm};
+pub use evex::{
+    Avx512Instruction, AvxOpcode, AvxOperand, Kmask, KmaskInstruction, KmaskOpcode, Mem, Ymm,
+    YmmInstruction, YmmOpcode, Zmm,
+};
+
+use std::collections::HashMap;
 
-use cpu8086::flags::EFlags;
+use cpu8086::flags::{EFlags, FlagOp, STATUS_FLAGS};
 use cpu8086::instruction::{Instruction, Operand};
+use cpu8086::memory_operand::{MemoryOperand, MemorySize};
+use cpu8086::register::{Register, SegmentRegister, SubRegister};
+use jit_emu::JitEmulatorState;
+
+/// Signature of the trampoline written at the start of every [`JitBuffer`]
+type Trampoline =
+    unsafe extern "sysv64" fn(state: *mut JitEmulatorState, code: *const u8, memory: *mut u8);
+
+/// Registers loaded from and saved to the [`JitEmulatorState`] by the trampoline
+const STATE_REGISTERS: [(JitRegister, isize); 14] = [
+    (JitRegister::ax, JitEmulatorState::ax_offset()),
+    (JitRegister::bx, JitEmulatorState::bx_offset()),
+    (JitRegister::cx, JitEmulatorState::cx_offset()),
+    (JitRegister::dx, JitEmulatorState::dx_offset()),
+    (JitRegister::si, JitEmulatorState::si_offset()),
+    (JitRegister::di, JitEmulatorState::di_offset()),
+    (JitRegister::sp, JitEmulatorState::sp_offset()),
+    (JitRegister::bp, JitEmulatorState::bp_offset()),
+    (JitRegister::ip, JitEmulatorState::ip_offset()),
+    (JitRegister::flags, JitEmulatorState::flags_offset()),
+    (JitRegister::es, JitEmulatorState::es_offset()),
+    (JitRegister::cs, JitEmulatorState::cs_offset()),
+    (JitRegister::ss, JitEmulatorState::ss_offset()),
+    (JitRegister::ds, JitEmulatorState::ds_offset()),
+];
+
+/// Kmask holding the lanes executing the JIT code (see [`JitEmulatorState::exec_mask`])
+const EXEC_KMASK: Kmask = Kmask(1);
+
+/// How JIT code is executed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// Run the code on the host CPU, which must support AVX512BW
+    Native,
+
+    /// Evaluate the code in a software model of the emitted instructions
+    Model,
+
+    /// Interpret the IL of each block over `std::simd` vectors without running any JIT code
+    Simd,
+
+    /// Run code generated from the IL of each block for AVX2 on the host CPU
+    Avx2,
+}
+
+impl Backend {
+    /// Run natively if the host CPU supports AVX512BW, with the AVX2 code if it only supports
+    /// AVX2, and in the model otherwise
+    pub fn detect() -> Backend {
+        if Backend::native_supported() {
+            Backend::Native
+        } else if Backend::avx2_supported() {
+            Backend::Avx2
+        } else {
+            Backend::Model
+        }
+    }
+
+    /// Returns `true` if the host CPU can run the JIT code natively
+    pub fn native_supported() -> bool {
+        #[cfg(target_arch = "x86_64")]
+        return std::arch::is_x86_feature_detected!("avx512bw");
+
+        #[cfg(not(target_arch = "x86_64"))]
+        false
+    }
+
+    /// Returns `true` if the host CPU can run the [`Backend::Avx2`] code
+    pub fn avx2_supported() -> bool {
+        #[cfg(target_arch = "x86_64")]
+        return std::arch::is_x86_feature_detected!("avx2");
+
+        #[cfg(not(target_arch = "x86_64"))]
+        false
+    }
+}
+
+/// Arithmetic and logic operations written by [`JitBuffer::alu`]
+#[derive(Debug, Copy, Clone)]
+enum AluOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+}
+
+impl AluOp {
+    /// Get how the status flags are computed for this operation
+    fn flag_op(self) -> FlagOp {
+        match self {
+            AluOp::Add => FlagOp::Add,
+            AluOp::Sub => FlagOp::Sub,
+            AluOp::And | AluOp::Or | AluOp::Xor => FlagOp::Logic,
+        }
+    }
+
+    /// Compute `a OP b`
+    fn apply(self, a: u16, b: u16) -> u16 {
+        match self {
+            AluOp::Add => a.wrapping_add(b),
+            AluOp::Sub => a.wrapping_sub(b),
+            AluOp::And => a & b,
+            AluOp::Or => a | b,
+            AluOp::Xor => a ^ b,
+        }
+    }
+
+    /// Get the second operand leaving the first operand unchanged
+    fn identity(self) -> u16 {
+        match self {
+            AluOp::And => 0xffff,
+            AluOp::Add | AluOp::Sub | AluOp::Or | AluOp::Xor => 0,
+        }
+    }
+}
 
 /// Enum used to identify the zmm register for each 8086 register
 /// Example::
@@ -30,6 +178,10 @@ pub enum JitRegister {
     bp,
     ip,
     flags,
+    es,
+    cs,
+    ss,
+    ds,
 }
 
 impl JitRegister {
@@ -67,30 +219,43 @@ impl HostRegister {
 }
 
 pub struct JitBuffer<const N: usize> {
-    /// RWX allocation where the JIT instructions are written
+    /// Mapping where the JIT instructions are written, writable or executable but never both
+    memory: ExecMemory,
+
+    /// Start of `memory`
     buffer: *mut u8,
 
     /// Current offset in `buffer` where new instructions are written
     pub offset: isize,
 
-    /// Scratch registers available to be allocated during the JIT
-    scratch_registers: [Zmm; 8],
+    /// Offset in `buffer` of the first JIT instruction, following the trampoline
+    code_start: isize,
+
+    /// Allocator of the scratch registers and kmasks used while writing an instruction
+    scratch: ScratchAllocator,
 
-    /// The current index for allocating a new scratch register
-    scratch_registers_index: usize,
+    /// Offset in `buffer` of the constant pool, which grows down from the end of the buffer
+    pool_start: isize,
 
-    /// Scratch kmask to be allocated during the JIT
-    scratch_kmask: [Kmask; 4],
+    /// Offset in the constant pool of each dword written to it
+    pool: HashMap<u32, isize>,
 
-    /// The current index for allocating a new scratch kmask
-    scratch_kmask_index: usize,
+    /// Set when a write didn't fit in the buffer, until the next [`JitBuffer::flush`]
+    full: bool,
+}
+
+impl<const N: usize> Default for JitBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<const N: usize> JitBuffer<N> {
     /// Create a new JIT buffer of size `N`
     pub fn new() -> JitBuffer<N> {
-        // Alloc N bytes as RWX for the JIT buffer
-        let buffer = alloc_rwx(N);
+        // Map N writable bytes for the JIT buffer
+        let memory = ExecMemory::new(N);
+        let buffer = memory.as_ptr();
 
         // Initialize the JIT buffer to ret's
         unsafe {
@@ -98,23 +263,102 @@ impl<const N: usize> JitBuffer<N> {
         }
 
         // Return the created JitBuffer
-        JitBuffer {
+        let mut jit = JitBuffer {
+            memory,
             buffer,
             offset: 0,
-            scratch_registers: [
-                Zmm(24),
-                Zmm(25),
-                Zmm(26),
-                Zmm(27),
-                Zmm(28),
-                Zmm(29),
-                Zmm(30),
-                Zmm(31),
-            ],
-            scratch_registers_index: 0,
-            scratch_kmask: [Kmask(3), Kmask(4), Kmask(5), Kmask(6)],
-            scratch_kmask_index: 0,
+            code_start: 0,
+            scratch: ScratchAllocator::new(
+                &[24, 25, 26, 27, 28, 29, 30, 31].map(Zmm),
+                &[3, 4, 5, 6].map(Kmask),
+            ),
+            pool_start: N as isize,
+            pool: HashMap::new(),
+            full: false,
+        };
+
+        // The trampoline lives at the start of the buffer, followed by the JIT code
+        jit.write_trampoline();
+        jit.code_start = jit.offset;
+
+        jit
+    }
+
+    /// Write the entry/exit trampoline used by [`JitBuffer::run`]
+    ///
+    /// Called as a [`Trampoline`]: `rdi` is the [`JitEmulatorState`], `rsi` is the JIT
+    /// code to call and `rdx` is the lane memory. The emulator state is loaded into the zmm
+    /// registers, the code is called and the zmm registers of the lanes in the state's
+    /// `exec_mask` are saved back to the state.
+    fn write_trampoline(&mut self) {
+        // Preserve the callee-saved host registers
+        // push rbx; push rbp; push r12; push r13; push r14; push r15
+        self.write_bytes(&[0x53, 0x55, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+
+        // Keep the state pointer in rbx and the lane memory in r15 for the JIT code
+        // mov rbx, rdi; mov r15, rdx
+        self.write_bytes(&[0x48, 0x89, 0xfb, 0x49, 0x89, 0xd7]);
+
+        // Load the executing lanes into the exec kmask
+        let exec_mask = JitEmulatorState::exec_mask_offset();
+        let bytes = kmovd!(EXEC_KMASK, [rbx + exec_mask]).assemble();
+        self.write_bytes(bytes.as_slice());
+
+        // Restore the 8086 context via the avx512 registers
+        for (reg, offset) in STATE_REGISTERS {
+            let bytes = vmovdqa64_load!(reg.as_zmm(), [rbx + offset]).assemble();
+            self.write_bytes(bytes.as_slice());
         }
+
+        // call rsi
+        self.write_bytes(&[0xff, 0xd6]);
+
+        // Save the 8086 context of the executing lanes from the avx512 registers
+        for (reg, offset) in STATE_REGISTERS {
+            let bytes = vmovdqu16_store!([rbx + offset], reg.as_zmm(), EXEC_KMASK).assemble();
+            self.write_bytes(bytes.as_slice());
+        }
+
+        // Avoid AVX-SSE transition penalties in the host code
+        // vzeroupper
+        self.write_bytes(&[0xc5, 0xf8, 0x77]);
+
+        // pop r15; pop r14; pop r13; pop r12; pop rbp; pop rbx; ret
+        self.write_bytes(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5d, 0x5b]);
+        self.ret();
+    }
+
+    /// Execute all JIT code written so far against the given emulator `state`
+    pub fn run(&self, state: &mut JitEmulatorState) {
+        // SAFETY: The code start is always the boundary of the first JIT instruction
+        unsafe { self.run_from(self.code_start, state) }
+    }
+
+    /// Execute the JIT code starting at `offset` against the given emulator `state`
+    ///
+    /// The code runs until the first `ret`, which is the unwritten part of the buffer if no
+    /// other `ret` was written.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be the start of a block (see [`JitBuffer::begin_block`]) written into
+    /// this buffer.
+    pub unsafe fn run_from(&self, offset: isize, state: &mut JitEmulatorState) {
+        assert!(
+            Backend::native_supported(),
+            "The JIT requires a CPU supporting AVX512BW"
+        );
+        assert!(!self.full, "JIT buffer is full");
+        assert!(
+            (self.code_start..self.pool_start).contains(&offset),
+            "JIT offset {offset:#x} is outside of the code"
+        );
+
+        self.memory.protect(Protection::ReadExec);
+
+        let memory = state.memory.as_mut_ptr();
+        let trampoline: Trampoline = std::mem::transmute(self.buffer);
+        trampoline(state, self.buffer.offset(offset), memory);
     }
 
     /// Get the pointer to the JIT buffer
@@ -122,19 +366,172 @@ impl<const N: usize> JitBuffer<N> {
         self.buffer
     }
 
-    /// Get the next available scratch register
+    /// Returns `true` if a write since the last [`JitBuffer::flush`] didn't fit in the
+    /// buffer
+    ///
+    /// The writes that don't fit are dropped, so the code written since the last flush
+    /// can't be run.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// Discard every block and constant written after the trampoline
+    pub fn flush(&mut self) {
+        self.memory.protect(Protection::ReadWrite);
+
+        // SAFETY: The range is in the buffer, after the trampoline
+        unsafe {
+            std::ptr::write_bytes(
+                self.buffer.offset(self.code_start),
+                0xc3,
+                N - self.code_start as usize,
+            );
+        }
+
+        self.offset = self.code_start;
+        self.pool_start = N as isize;
+        self.pool.clear();
+        self.full = false;
+    }
+
+    /// Start a new block made of `instrs`, which are written next with
+    /// [`JitBuffer::write_instr`]
+    ///
+    /// Broadcast constants are kept in the scratch registers for the rest of the block, so
+    /// the written code must only be entered at the start of a block.
+    pub fn begin_block(&mut self, instrs: &[JitIL]) {
+        let uses = instrs.iter().map(Self::constants).collect();
+        self.scratch.begin_block(uses);
+    }
+
+    /// Get a free scratch register for a temporary of the [`JitIL`] instruction being
+    /// written
+    ///
+    /// The register is free again at the end of the instruction or once it is released.
     pub fn next_scratch_reg(&mut self) -> Zmm {
-        let reg = self.scratch_registers[self.scratch_registers_index];
-        self.scratch_registers_index =
-            (self.scratch_registers_index + 1) % self.scratch_registers.len();
-        reg
+        let alloc = self.scratch.alloc_temp();
+        self.spill(alloc);
+        alloc.reg
     }
 
-    /// Get the next available scratch kmask
+    /// Get a free scratch kmask for the [`JitIL`] instruction being written
+    ///
+    /// The kmask is free again at the end of the instruction or once it is released.
     pub fn next_scratch_kmask(&mut self) -> Kmask {
-        let reg = self.scratch_kmask[self.scratch_kmask_index];
-        self.scratch_kmask_index = (self.scratch_kmask_index + 1) % self.scratch_kmask.len();
-        reg
+        self.scratch.alloc_kmask()
+    }
+
+    /// Free the temporary or unpin the constant in `reg` before the end of the instruction
+    fn release(&mut self, reg: Zmm) {
+        self.scratch.release(reg);
+    }
+
+    /// Free the scratch `kmask` before the end of the instruction
+    fn release_kmask(&mut self, kmask: Kmask) {
+        self.scratch.release_kmask(kmask);
+    }
+
+    /// Store the constant evicted from the register of `alloc` to its spill slot
+    fn spill(&mut self, alloc: Allocation) {
+        if let Some(slot) = alloc.spill {
+            let offset = JitEmulatorState::spill_offset() + slot as isize * 64;
+            let bytes = vmovdqa64_store!([rbx + offset], alloc.reg).assemble();
+            self.write_bytes(bytes.as_slice());
+        }
+    }
+
+    /// Get a scratch register holding `value`, reusing the register of an earlier
+    /// instruction in the block if it is still cached
+    fn constant(&mut self, value: Constant) -> Zmm {
+        if let Some(reg) = self.scratch.find_constant(value) {
+            return reg;
+        }
+
+        let spill_slot = self.scratch.spill_slot(value);
+        let alloc = self.scratch.alloc_constant(value);
+        self.spill(alloc);
+
+        match spill_slot {
+            Some(slot) => {
+                let offset = JitEmulatorState::spill_offset() + slot as isize * 64;
+                let bytes = vmovdqa64_load!(alloc.reg, [rbx + offset]).assemble();
+                self.write_bytes(bytes.as_slice());
+            }
+            None => self.broadcast(alloc.reg, value),
+        }
+
+        alloc.reg
+    }
+
+    /// Broadcast `value` to `dest`
+    fn broadcast(&mut self, dest: Zmm, value: Constant) {
+        match value.as_word() {
+            Some(0) => self.clear_zmm(dest),
+            #[allow(clippy::cast_possible_wrap)]
+            Some(word) => self.mov_imm(dest, word as i16),
+            None => self.mov_imm_dword(dest, value.0),
+        }
+    }
+
+    /// Set every word of `dest` to `imm`, copying it from a scratch register if the
+    /// constant is cached
+    fn mov_constant(&mut self, dest: Zmm, imm: u16) {
+        let value = Constant::word(imm);
+        if self.scratch.is_cached(value) {
+            let src = self.constant(value);
+            self.mov(dest, src);
+            self.release(src);
+        } else {
+            self.broadcast(dest, value);
+        }
+    }
+
+    /// Get the offset of `value` in the constant pool, adding it to the pool on its first
+    /// use
+    ///
+    /// Each entry is a single dword. Word constants are stored as the word repeated in the
+    /// dword, so the same entry serves word broadcasts, dword broadcasts and `{1to16}`
+    /// operands of dword instructions (word instructions have no embedded broadcast).
+    fn pool_offset(&mut self, value: Constant) -> isize {
+        if let Some(offset) = self.pool.get(&value.0) {
+            return *offset;
+        }
+
+        let offset = self.pool_start - 4;
+        if self.offset >= offset {
+            // The code using the constant is dropped along with the rest of the block
+            self.full = true;
+            return self.pool_start;
+        }
+
+        self.memory.protect(Protection::ReadWrite);
+
+        // SAFETY: The entry is in the buffer and doesn't overlap the code
+        unsafe {
+            std::ptr::write_unaligned(self.buffer.offset(offset).cast::<u32>(), value.0);
+        }
+
+        self.pool_start = offset;
+        self.pool.insert(value.0, offset);
+        offset
+    }
+
+    /// Write the instruction built by `instr` with the RIP-relative displacement of `value`
+    /// in the constant pool
+    fn write_pool_instr(&mut self, value: Constant, instr: impl Fn(i32) -> Avx512Instruction) {
+        let offset = self.pool_offset(value);
+
+        // RIP-relative displacements are always a disp32, so the length is known up front
+        let len = instr(0).assemble().as_slice().len() as isize;
+        let disp = i32::try_from(offset - (self.offset + len))
+            .expect("Constant pool out of reach of RIP-relative addressing");
+        let bytes = instr(disp).assemble();
+        self.write_bytes(bytes.as_slice());
+    }
+
+    /// dest = src && `value`, read from the constant pool with an embedded broadcast
+    fn and_constant(&mut self, dest: Zmm, src: Zmm, value: Constant) {
+        self.write_pool_instr(value, |disp| vpandw!(dest, src, [rip + disp]).broadcast());
     }
 
     /// Write the given byte into the JIT stream at the current byte offset
@@ -143,117 +540,343 @@ impl<const N: usize> JitBuffer<N> {
         self._write_instr(&instr);
     }
 
+    /// Broadcast the word `imm` from the constant pool to every word of `dest`
     pub fn mov_imm(&mut self, dest: Zmm, imm: i16) {
-        // Assemble: mov esi, VAL
-        let bytes = [0xbe];
-        self.write_bytes(&bytes);
-        self.write_bytes(&(imm as u32).to_le_bytes());
-
-        let instr = vpbroadcastw!(dest, rsi);
-        let bytes = instr.assemble();
-        self.write_bytes(&bytes.as_slice());
+        #[allow(clippy::cast_sign_loss)]
+        let value = Constant::word(imm as u16);
+        self.write_pool_instr(value, |disp| vpbroadcastw!(dest, [rip + disp]));
     }
 
     /// Move immediate using the k opmask
     pub fn mov_imm_with_kmask(&mut self, dest: Zmm, imm: i16, kmask: Kmask) {
-        // Assemble: mov esi, VAL
-        let bytes = [0xbe];
-        self.write_bytes(&bytes);
-        self.write_bytes(&(imm as u32).to_le_bytes());
-
-        let instr = vpbroadcastw!(dest, rsi, kmask);
-        let bytes = instr.assemble();
-        self.write_bytes(&bytes.as_slice());
+        #[allow(clippy::cast_sign_loss)]
+        let value = Constant::word(imm as u16);
+        self.write_pool_instr(value, |disp| vpbroadcastw!(dest, [rip + disp], kmask));
     }
 
     pub fn mov(&mut self, dest: Zmm, src: Zmm) {
         let bytes = vpmovdqa64!(dest, src).assemble();
-        self.write_bytes(&bytes.as_slice());
+        self.write_bytes(bytes.as_slice());
     }
 
     /// dest = op1 - op2
     pub fn sub(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
         let bytes = vpsubw!(dest, op1, op2).assemble();
-        self.write_bytes(&bytes.as_slice());
+        self.write_bytes(bytes.as_slice());
+    }
 
-        // Update status bits based on the result
-        self.set_zero_flag_if_needed(dest);
-        self.set_sign_flag_if_needed(dest);
+    /// dest = op1 + op2
+    pub fn add(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
+        let bytes = vpaddw!(dest, op1, op2).assemble();
+        self.write_bytes(bytes.as_slice());
     }
 
-    /// Set the Zero Flag in the EFLAGS register if `dest` is zero
-    pub fn set_zero_flag_if_needed(&mut self, dest: Zmm) {
-        // Clear the existing zero bit
-        let tmp_zmm = self.next_scratch_reg();
-        self.mov_imm(tmp_zmm, !(EFlags::Zero as i16));
-        let flags = JitRegister::flags.as_zmm();
-        self.and(flags, flags, tmp_zmm);
+    /// dest = op1 || op2
+    pub fn or(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
+        let bytes = vporw!(dest, op1, op2).assemble();
+        self.write_bytes(bytes.as_slice());
+    }
 
-        // Fill a ZMM with zero
-        let zero_zmm = self.next_scratch_reg();
-        self.clear_zmm(zero_zmm);
+    /// dest = op1 && op2
+    pub fn and(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
+        let bytes = vpandw!(dest, op1, op2).assemble();
+        self.write_bytes(bytes.as_slice());
+    }
 
-        let kmask = self.next_scratch_kmask();
-        let tmp_flag_zmm = self.next_scratch_reg();
-        self.cmp(Zmm(kmask.0), dest, zero_zmm, CmpOp::Equal);
-        self.mov_imm_with_kmask(tmp_flag_zmm, EFlags::Zero as i16, kmask);
-        let flags = JitRegister::flags.as_zmm();
-        self.or_with_kmask(flags, flags, tmp_flag_zmm, kmask);
+    /// dest = op1 ^ op2
+    pub fn xor(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
+        let bytes = vpxord!(dest, op1, op2).assemble();
+        self.write_bytes(bytes.as_slice());
     }
 
-    /// Set the Sign Flag in the EFLAGS register if `dest` is less than zero
-    pub fn set_sign_flag_if_needed(&mut self, dest: Zmm) {
-        // Clear the existing sign bit
-        let tmp_zmm = self.next_scratch_reg();
-        self.mov_imm(tmp_zmm, !(EFlags::Sign as i16));
-        let flags = JitRegister::flags.as_zmm();
-        self.and(flags, flags, tmp_zmm);
+    /// dest = src >> imm for each word
+    pub fn shift_right(&mut self, dest: Zmm, src: Zmm, imm: u8) {
+        let bytes = vpsrlw!(dest, src, imm).assemble();
+        self.write_bytes(bytes.as_slice());
+    }
 
-        // Fill a ZMM with zero
-        let zero_zmm = self.next_scratch_reg();
-        self.clear_zmm(zero_zmm);
+    /// dest = src << imm for each word
+    pub fn shift_left(&mut self, dest: Zmm, src: Zmm, imm: u8) {
+        let bytes = vpsllw!(dest, src, imm).assemble();
+        self.write_bytes(bytes.as_slice());
+    }
 
-        let kmask = self.next_scratch_kmask();
-        let tmp_flag_zmm = self.next_scratch_reg();
-        self.cmp(Zmm(kmask.0), dest, zero_zmm, CmpOp::LessThan);
-        self.mov_imm_with_kmask(tmp_flag_zmm, EFlags::Sign as i16, kmask);
+    /// Zero extend the `part` byte of each word of `src` into `dest`
+    fn read_byte(&mut self, dest: Zmm, src: Zmm, part: BytePart) {
+        match part {
+            BytePart::Low => self.and_constant(dest, src, Constant::word(0xff)),
+            BytePart::High => self.shift_right(dest, src, 8),
+        }
+    }
+
+    /// Write the low byte of each word of `value` into the `part` byte of each word of
+    /// `dest`, keeping its other byte
+    fn write_byte(&mut self, dest: Zmm, part: BytePart, value: Zmm) {
+        let (value, keep) = match part {
+            BytePart::Low => (value, 0xff00),
+            BytePart::High => {
+                let shifted = self.next_scratch_reg();
+                self.shift_left(shifted, value, 8);
+                (shifted, 0x00ff)
+            }
+        };
+
+        // dest = keep ? dest : value
+        self.write_pool_instr(Constant::word(keep), |disp| {
+            vpternlogd!(dest, value, [rip + disp], 0xe4).broadcast()
+        });
+        self.release(value);
+    }
+
+    /// Set `kmask` in the lanes where any of the `bits` of `src` are set (or all of them
+    /// are clear if `set` is false)
+    fn test_bits(&mut self, kmask: Kmask, src: Zmm, bits: u16, set: bool) {
+        let k = Zmm(kmask.0);
+        let bits = self.constant(Constant::word(bits));
+        let bytes = if set {
+            vptestmw!(k, src, bits)
+        } else {
+            vptestnmw!(k, src, bits)
+        }
+        .assemble();
+        self.write_bytes(bytes.as_slice());
+        self.release(bits);
+    }
 
+    /// Add the `flag` bit to FLAGS in the lanes in `kmask`
+    ///
+    /// The flag must already be clear, so adding it sets it without a word granular OR.
+    fn add_flag(&mut self, flag: EFlags, kmask: Kmask) {
         let flags = JitRegister::flags.as_zmm();
-        self.or_with_kmask(flags, flags, tmp_flag_zmm, kmask);
+        let bit = self.constant(Constant::word(flag as u16));
+        let bytes = vpaddw!(flags, flags, bit, kmask).assemble();
+        self.write_bytes(bytes.as_slice());
+        self.release(bit);
     }
 
-    /// dest = op1 + op2
-    pub fn add(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
-        let bytes = vpaddw!(dest, op1, op2).assemble();
-        self.write_bytes(&bytes.as_slice());
+    /// Get the constants used to compute the `flags` status flags of an `op` operation of
+    /// the given `size`
+    fn status_flag_constants(op: FlagOp, flags: u16, size: MemorySize) -> Vec<Constant> {
+        if flags == 0 {
+            return Vec::new();
+        }
+
+        // Word results are compared against zero, byte results test their bits instead
+        let byte = size == MemorySize::Byte;
+        let mut used = if byte {
+            Vec::new()
+        } else {
+            vec![Constant::word(0)]
+        };
 
-        // Update status bits based on the result
-        self.set_zero_flag_if_needed(dest);
-        self.set_sign_flag_if_needed(dest);
+        // Each flag along with the bits of the byte it tests
+        let mut computed = vec![
+            (EFlags::Zero, 0xff),
+            (EFlags::Sign, 0x80),
+            (EFlags::Parity, 1),
+        ];
+        if op != FlagOp::Logic {
+            let carry = if op == FlagOp::Add { 0x100 } else { 0 };
+            computed.extend([
+                (EFlags::Carry, carry),
+                (EFlags::Overflow, 0x80),
+                (EFlags::Auxillary, 0x10),
+            ]);
+        }
+
+        let tested_bits = EFlags::Parity as u16 | EFlags::Auxillary as u16;
+        for (flag, bits) in computed {
+            let flag = flag as u16;
+            if flags & flag == 0 {
+                continue;
+            }
+            if bits != 0 && (byte || flag & tested_bits > 0) {
+                used.push(Constant::word(bits));
+            }
+            used.push(Constant::word(flag));
+        }
+
+        used
     }
 
-    /// dest = op1 || op2
-    pub fn or(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
-        let bytes = vporw!(dest, op1, op2).assemble();
-        self.write_bytes(&bytes.as_slice());
+    /// Compute the `flags` status flags of `result = a OP b` in every lane for an operation
+    /// of the given `size`, leaving the other flags untouched
+    ///
+    /// The operands of byte operations are zero extended bytes, so the result has the byte's
+    /// carry in bit 8 and its sign in bit 7.
+    pub fn set_status_flags(
+        &mut self,
+        op: FlagOp,
+        flags: u16,
+        result: Zmm,
+        a: Zmm,
+        b: Zmm,
+        size: MemorySize,
+    ) {
+        if flags == 0 {
+            return;
+        }
+
+        let has = |flag: EFlags| flags & flag as u16 > 0;
+
+        let kmask = self.next_scratch_kmask();
+        let k = Zmm(kmask.0);
+
+        // Clear the flags being computed
+        let flags_zmm = JitRegister::flags.as_zmm();
+        self.and_constant(flags_zmm, flags_zmm, Constant::word(!flags));
+
+        // Word results are compared against zero, byte results test their bits instead
+        let zero = (size == MemorySize::Word).then(|| self.constant(Constant::word(0)));
+
+        if has(EFlags::Zero) {
+            match zero {
+                Some(zero) => self.cmp(k, result, zero, CmpOp::Equal),
+                None => self.test_bits(kmask, result, 0xff, false),
+            }
+            self.add_flag(EFlags::Zero, kmask);
+        }
+
+        if has(EFlags::Sign) {
+            match zero {
+                Some(zero) => self.cmp(k, result, zero, CmpOp::LessThan),
+                None => self.test_bits(kmask, result, 0x80, true),
+            }
+            self.add_flag(EFlags::Sign, kmask);
+        }
+
+        if has(EFlags::Parity) {
+            // Fold the low byte onto bit 0, which is then set for an odd number of bits
+            let tmp = self.next_scratch_reg();
+            let bit = self.next_scratch_reg();
+            self.shift_right(tmp, result, 4);
+            self.xor(tmp, tmp, result);
+            self.shift_right(bit, tmp, 2);
+            self.xor(tmp, tmp, bit);
+            self.shift_right(bit, tmp, 1);
+            self.xor(tmp, tmp, bit);
+            self.release(bit);
+
+            self.test_bits(kmask, tmp, 1, false);
+            self.release(tmp);
+            self.add_flag(EFlags::Parity, kmask);
+        }
+
+        // Logic operations clear CF, OF and AF
+        if op == FlagOp::Logic {
+            return;
+        }
+
+        if has(EFlags::Carry) {
+            // A carry wraps the sum below `a` (or out of bit 7 for bytes), a borrow happens
+            // when `b` is above `a`
+            match (op, zero) {
+                (FlagOp::Add, Some(_)) => {
+                    let bytes = vpcmpuw!(k, result, a, CmpOp::LessThan).assemble();
+                    self.write_bytes(bytes.as_slice());
+                }
+                (FlagOp::Add, None) => self.test_bits(kmask, result, 0x100, true),
+                _ => {
+                    let bytes = vpcmpuw!(k, a, b, CmpOp::LessThan).assemble();
+                    self.write_bytes(bytes.as_slice());
+                }
+            }
+            self.add_flag(EFlags::Carry, kmask);
+        }
+
+        if has(EFlags::Overflow) {
+            // add: (a ^ result) & (b ^ result), sub: (a ^ b) & (a ^ result)
+            let logic = match op {
+                FlagOp::Add => 0x42,
+                _ => 0x18,
+            };
+            let tmp = self.next_scratch_reg();
+            self.mov(tmp, a);
+            let bytes = vpternlogd!(tmp, b, result, logic).assemble();
+            self.write_bytes(bytes.as_slice());
+            match zero {
+                Some(zero) => self.cmp(k, tmp, zero, CmpOp::LessThan),
+                None => self.test_bits(kmask, tmp, 0x80, true),
+            }
+            self.release(tmp);
+            self.add_flag(EFlags::Overflow, kmask);
+        }
+
+        if has(EFlags::Auxillary) {
+            // Carry or borrow out of bit 3: (a ^ b ^ result) & 0x10
+            let tmp = self.next_scratch_reg();
+            self.mov(tmp, a);
+            let bytes = vpternlogd!(tmp, b, result, 0x96).assemble();
+            self.write_bytes(bytes.as_slice());
+            self.test_bits(kmask, tmp, EFlags::Auxillary as u16, true);
+            self.release(tmp);
+            self.add_flag(EFlags::Auxillary, kmask);
+        }
     }
 
-    /// dest = op1 && op2
-    pub fn and(&mut self, dest: Zmm, op1: Zmm, op2: Zmm) {
-        let bytes = vpandw!(dest, op1, op2).assemble();
-        self.write_bytes(&bytes.as_slice());
+    /// Get the size of an operation on `op1` and `op2`, which is a byte operation if either
+    /// operand is a byte
+    fn operation_size(op1: &AvxOperand, op2: &AvxOperand) -> MemorySize {
+        if op1.size() == Some(MemorySize::Byte) || op2.size() == Some(MemorySize::Byte) {
+            MemorySize::Byte
+        } else {
+            MemorySize::Word
+        }
     }
 
-    /// dest = op1 || op2
-    pub fn or_with_kmask(&mut self, dest: Zmm, op1: Zmm, op2: Zmm, kmask: Kmask) {
-        let bytes = vporw!(dest, op1, op2, kmask).assemble();
-        self.write_bytes(&bytes.as_slice());
+    /// Truncate an immediate `operand` of a byte operation to its low byte
+    fn sized(operand: AvxOperand, size: MemorySize) -> AvxOperand {
+        match (operand, size) {
+            (AvxOperand::Immediate(imm), MemorySize::Byte) => AvxOperand::Immediate(imm & 0xff),
+            _ => operand,
+        }
+    }
+
+    /// Write `dest = op1 OP op2` (or only the flags without a `dest`), computing the
+    /// `flags` status flags of the result
+    fn alu(
+        &mut self,
+        op: AluOp,
+        dest: Option<RegisterOperand>,
+        op1: AvxOperand,
+        op2: AvxOperand,
+        flags: u16,
+    ) {
+        let size = Self::operation_size(&op1, &op2);
+        let op1 = self.operand_to_register(Self::sized(op1, size));
+        let op2 = self.operand_to_register(Self::sized(op2, size));
+
+        // The flags need the original operands and bytes are merged into their word, so
+        // only write `dest` directly for a word without flags
+        let result = match dest {
+            Some(RegisterOperand { zmm, byte: None }) if flags == 0 => zmm,
+            _ => self.next_scratch_reg(),
+        };
+
+        match op {
+            AluOp::Add => self.add(result, op1, op2),
+            AluOp::Sub => self.sub(result, op1, op2),
+            AluOp::And => self.and(result, op1, op2),
+            AluOp::Or => self.or(result, op1, op2),
+            AluOp::Xor => self.xor(result, op1, op2),
+        }
+
+        self.set_status_flags(op.flag_op(), flags, result, op1, op2, size);
+
+        match dest {
+            Some(RegisterOperand {
+                zmm,
+                byte: Some(part),
+            }) => self.write_byte(zmm, part, result),
+            Some(RegisterOperand { zmm, .. }) if zmm.0 != result.0 => self.mov(zmm, result),
+            _ => {}
+        }
     }
 
     /// k = left [`CmpOp`] right
     pub fn cmp(&mut self, k: Zmm, left: Zmm, right: Zmm, op: CmpOp) {
         let bytes = vpcmpw!(k, left, right, op).assemble();
-        self.write_bytes(&bytes.as_slice());
+        self.write_bytes(bytes.as_slice());
     }
 
     /// Write a `ret` instruction
@@ -263,66 +886,519 @@ impl<const N: usize> JitBuffer<N> {
 
     pub fn clear_zmm(&mut self, dest: Zmm) {
         let bytes = vpxorq!(dest).assemble();
-        self.write_bytes(&bytes.as_slice());
+        self.write_bytes(bytes.as_slice());
+    }
+
+    /// Get the constants used to evaluate `operand`
+    fn operand_constants(operand: &AvxOperand) -> Vec<Constant> {
+        match operand {
+            // Bytes are extracted with a mask from the constant pool or a shift
+            AvxOperand::Zmm(_) | AvxOperand::Byte(..) => Vec::new(),
+            #[allow(clippy::cast_sign_loss)]
+            AvxOperand::Immediate(imm) => vec![Constant::word(*imm as u16)],
+            AvxOperand::Memory(mem) => Self::memory_constants(mem),
+        }
     }
 
     /// Get the register holding the value in the operand
     /// AvxOperand::Zmm(zmm) -> Return the given register
+    /// AvxOperand::Byte(zmm, part) ->
+    ///    Zero extend the byte of each word into a scratch register and return this register
     /// AvxOperand::Immediate(imm) ->
-    ///    Broadcast this immediate to a scratch register and return this register
+    ///    Get the scratch register holding the broadcast immediate
+    /// AvxOperand::Memory(mem) ->
+    ///    Load the memory of each lane into a scratch register and return this register
     pub fn operand_to_register(&mut self, operand: AvxOperand) -> Zmm {
         // Get the op2 based on
         match operand {
             AvxOperand::Zmm(zmm) => zmm,
-            AvxOperand::Immediate(imm) => {
-                // If there is an immediate operand, broadcast it to the scratch register
-                // and use the scratch register to do this operation
+            AvxOperand::Byte(zmm, part) => {
+                let byte = self.next_scratch_reg();
+                self.read_byte(byte, zmm, part);
+                byte
+            }
+            #[allow(clippy::cast_sign_loss)]
+            AvxOperand::Immediate(imm) => self.constant(Constant::word(imm as u16)),
+            AvxOperand::Memory(mem) => {
                 let new_src = self.next_scratch_reg();
-                self.mov_imm(new_src, imm);
+                self.load(new_src, mem);
                 new_src
             }
         }
     }
 
+    /// Set every bit of the given kmask
+    pub fn fill_kmask(&mut self, kmask: Kmask) {
+        let bytes = kxnorw!(kmask, kmask, kmask).assemble();
+        self.write_bytes(bytes.as_slice());
+    }
+
+    /// Copy the exec kmask bits of lanes 0-15 (`half` 0) or 16-31 (`half` 1) into the low
+    /// bits of `kmask` for use with dword gathers and scatters
+    fn exec_kmask_half(&mut self, kmask: Kmask, half: usize) {
+        let bytes = if half == 0 {
+            kmovw!(kmask, EXEC_KMASK)
+        } else {
+            kshiftrd!(kmask, EXEC_KMASK, 16)
+        }
+        .assemble();
+        self.write_bytes(bytes.as_slice());
+    }
+
+    /// dest = op1 OP op2 for a kmask logic opcode
+    fn kmask_op(&mut self, op: KmaskOpcode, dest: Kmask, op1: Kmask, op2: Kmask) {
+        let bytes = KmaskInstruction::default()
+            .opcode(op)
+            .op1(dest)
+            .op2(op1)
+            .op3(op2)
+            .assemble();
+        self.write_bytes(bytes.as_slice());
+    }
+
+    /// Get a kmask of the lanes where any of the `bits` in FLAGS are set (or all of them
+    /// are clear if `set` is false)
+    fn test_flags(&mut self, bits: u16, set: bool) -> Kmask {
+        let tmp = self.next_scratch_reg();
+        self.and_constant(tmp, JitRegister::flags.as_zmm(), Constant::word(bits));
+
+        let zero = self.constant(Constant::word(0));
+        let kmask = self.next_scratch_kmask();
+        let op = if set { CmpOp::NotEqual } else { CmpOp::Equal };
+        self.cmp(Zmm(kmask.0), tmp, zero, op);
+        self.release(zero);
+        self.release(tmp);
+        kmask
+    }
+
+    /// Get a kmask of the lanes where `cond` holds
+    fn condition_kmask(&mut self, cond: Condition) -> Kmask {
+        use Condition::*;
+
+        let flag = |flag: EFlags| flag as u16;
+        match cond {
+            Equal => self.test_flags(flag(EFlags::Zero), true),
+            NotEqual => self.test_flags(flag(EFlags::Zero), false),
+            Below => self.test_flags(flag(EFlags::Carry), true),
+            NotBelow => self.test_flags(flag(EFlags::Carry), false),
+            BelowEqual => self.test_flags(flag(EFlags::Carry) | flag(EFlags::Zero), true),
+            NotBelowEqual => self.test_flags(flag(EFlags::Carry) | flag(EFlags::Zero), false),
+            ParityEven => self.test_flags(flag(EFlags::Parity), true),
+            ParityOdd => self.test_flags(flag(EFlags::Parity), false),
+            Overflow => self.test_flags(flag(EFlags::Overflow), true),
+            NotOverflow => self.test_flags(flag(EFlags::Overflow), false),
+            Sign => self.test_flags(flag(EFlags::Sign), true),
+            NotSign => self.test_flags(flag(EFlags::Sign), false),
+            Less | NotLess => {
+                // SF != OF
+                let sign = self.test_flags(flag(EFlags::Sign), true);
+                let overflow = self.test_flags(flag(EFlags::Overflow), true);
+                let op = if cond == Less {
+                    KmaskOpcode::XorDword
+                } else {
+                    KmaskOpcode::XnorDword
+                };
+                self.kmask_op(op, sign, sign, overflow);
+                self.release_kmask(overflow);
+                sign
+            }
+            LessEqual => {
+                // ZF || SF != OF
+                let less = self.condition_kmask(Less);
+                let zero = self.test_flags(flag(EFlags::Zero), true);
+                self.kmask_op(KmaskOpcode::OrDword, less, less, zero);
+                self.release_kmask(zero);
+                less
+            }
+            NotLessEqual => {
+                // !ZF && SF == OF
+                let not_less = self.condition_kmask(NotLess);
+                let not_zero = self.test_flags(flag(EFlags::Zero), false);
+                self.kmask_op(KmaskOpcode::AndDword, not_less, not_less, not_zero);
+                self.release_kmask(not_zero);
+                not_less
+            }
+            CxZero => {
+                let zero = self.constant(Constant::word(0));
+                let kmask = self.next_scratch_kmask();
+                self.cmp(Zmm(kmask.0), JitRegister::cx.as_zmm(), zero, CmpOp::Equal);
+                self.release(zero);
+                kmask
+            }
+        }
+    }
+
+    /// Set IP to `taken` in the lanes in `kmask` and to `not_taken` in the others
+    fn branch(&mut self, kmask: Kmask, taken: u16, not_taken: u16) {
+        let ip = JitRegister::ip.as_zmm();
+        self.mov_constant(ip, not_taken);
+        #[allow(clippy::cast_possible_wrap)]
+        self.mov_imm_with_kmask(ip, taken as i16, kmask);
+    }
+
+    /// Broadcast the dword `imm` from the constant pool to every dword of `dest`
+    pub fn mov_imm_dword(&mut self, dest: Zmm, imm: u32) {
+        let value = Constant::dword(imm);
+        self.write_pool_instr(value, |disp| vpbroadcastd!(dest, [rip + disp]));
+    }
+
+    /// Zero extend the words of `src` into dwords, returning the registers holding the
+    /// extended lanes 0-15 and 16-31
+    fn zero_extend_words(&mut self, src: Zmm) -> (Zmm, Zmm) {
+        let low = self.next_scratch_reg();
+        let bytes = vpmovzxwd!(low, src).assemble();
+        self.write_bytes(bytes.as_slice());
+
+        let high = self.next_scratch_reg();
+        let bytes = vextracti64x4!(high, src, 1).assemble();
+        self.write_bytes(bytes.as_slice());
+        let bytes = vpmovzxwd!(high, high).assemble();
+        self.write_bytes(bytes.as_slice());
+
+        (low, high)
+    }
+
+    /// Get the constants used to load from or store to `mem`, in the order they are used
+    fn memory_constants(mem: &JitMemory) -> Vec<Constant> {
+        match mem.size {
+            MemorySize::Byte => vec![Constant::word(mem.disp)],
+            // The high byte is accessed first
+            MemorySize::Word => vec![
+                Constant::word(mem.disp.wrapping_add(1)),
+                Constant::word(mem.disp),
+            ],
+        }
+    }
+
+    /// Compute the offset of the byte `offset` bytes past `mem` in the lane memory for each
+    /// lane, returning the dword indices for lanes 0-15 and 16-31
+    fn lane_indices(&mut self, mem: JitMemory, offset: u16) -> (Zmm, Zmm) {
+        // Evaluate the 8086 address, wrapping at 16 bits like the 8086
+        let low = self.next_scratch_reg();
+        let disp = self.constant(Constant::word(mem.disp.wrapping_add(offset)));
+        let mut addr = disp;
+        for reg in mem.registers.into_iter().flatten() {
+            let bytes = vpaddw!(low, addr, reg).assemble();
+            self.write_bytes(bytes.as_slice());
+            addr = low;
+        }
+        if addr.0 == disp.0 {
+            self.mov(low, disp);
+        }
+        self.release(disp);
+
+        // Offset the address by the start of its segment, wrapping at the 64K of lane
+        // memory so segments 0x1000 apart alias
+        if let Some(segment) = mem.segment {
+            let base = self.next_scratch_reg();
+            self.shift_left(base, segment, 4);
+            self.add(low, low, base);
+            self.release(base);
+        }
+
+        // Zero extend the addresses in place to keep the scratch registers free
+        let high = self.next_scratch_reg();
+        let bytes = vextracti64x4!(high, low, 1).assemble();
+        self.write_bytes(bytes.as_slice());
+        for half in [low, high] {
+            let bytes = vpmovzxwd!(half, half).assemble();
+            self.write_bytes(bytes.as_slice());
+        }
+
+        // Offset each lane's address by the start of its memory
+        let lane_offsets = JitEmulatorState::lane_offsets_offset();
+        let bytes = vpaddd!(low, low, [rbx + lane_offsets]).assemble();
+        self.write_bytes(bytes.as_slice());
+        let bytes = vpaddd!(high, high, [rbx + lane_offsets + 64]).assemble();
+        self.write_bytes(bytes.as_slice());
+
+        (low, high)
+    }
+
+    /// Gather the dword at each of the `low` and `high` lane indices
+    fn gather(&mut self, low: Zmm, high: Zmm) -> (Zmm, Zmm) {
+        // The gather clears the kmask as each element completes
+        let kmask = self.next_scratch_kmask();
+        let mut result = [low, high];
+
+        for (dest, index) in result.iter_mut().zip([low, high]) {
+            *dest = self.next_scratch_reg();
+            self.fill_kmask(kmask);
+            let bytes = vpgatherdd!(*dest, [r15 + index], kmask).assemble();
+            self.write_bytes(bytes.as_slice());
+        }
+
+        self.release_kmask(kmask);
+        (result[0], result[1])
+    }
+
+    /// Load the byte `offset` bytes past `mem` from the memory of each lane into the low
+    /// byte of each word of `dest`, clearing the high byte
+    fn load_byte(&mut self, dest: Zmm, mem: JitMemory, offset: u16) {
+        let (index_low, index_high) = self.lane_indices(mem, offset);
+        let (low, high) = self.gather(index_low, index_high);
+        self.release(index_low);
+        self.release(index_high);
+
+        // The low byte of each dword is the loaded value
+        for half in [low, high] {
+            self.and_constant(half, half, Constant::dword(0xff));
+            let bytes = vpmovdw!(half, half).assemble();
+            self.write_bytes(bytes.as_slice());
+        }
+
+        let bytes = vinserti64x4!(dest, low, high, 1).assemble();
+        self.write_bytes(bytes.as_slice());
+        self.release(low);
+        self.release(high);
+    }
+
+    /// Load `mem` from the memory of each lane into `dest`
+    ///
+    /// The bytes of a word are loaded separately, so a word at 0xffff wraps to offset 0 of
+    /// the lane memory like the 8086 instead of reading the padding after it.
+    pub fn load(&mut self, dest: Zmm, mem: JitMemory) {
+        match mem.size {
+            MemorySize::Byte => self.load_byte(dest, mem, 0),
+            MemorySize::Word => {
+                // The high byte is loaded first, since `dest` may be used by the address
+                let high = self.next_scratch_reg();
+                self.load_byte(high, mem, 1);
+                self.load_byte(dest, mem, 0);
+                self.shift_left(high, high, 8);
+                self.or(dest, dest, high);
+                self.release(high);
+            }
+        }
+    }
+
+    /// Store the low byte of `src` to the byte `offset` bytes past `mem` in the memory of
+    /// each executing lane
+    ///
+    /// There is no byte scatter, so the dword at each address is gathered, the new byte is
+    /// merged into it and the dword is scattered back.
+    fn store_byte(&mut self, mem: JitMemory, offset: u16, src: Zmm) {
+        let (low, high) = self.lane_indices(mem, offset);
+        let (new_low, new_high) = self.zero_extend_words(src);
+        self.release(src);
+        let (old_low, old_high) = self.gather(low, high);
+
+        // old = keep ? old : new
+        let keep = Constant::dword(0xffff_ff00);
+        let kmask = self.next_scratch_kmask();
+        let halves = [(old_low, new_low, low), (old_high, new_high, high)];
+        for (half, (old, new, index)) in halves.into_iter().enumerate() {
+            self.write_pool_instr(keep, |disp| {
+                vpternlogd!(old, new, [rip + disp], 0xe4).broadcast()
+            });
+
+            // Only write the memory of the executing lanes
+            self.exec_kmask_half(kmask, half);
+            let bytes = vpscatterdd!([r15 + index], old, kmask).assemble();
+            self.write_bytes(bytes.as_slice());
+        }
+
+        self.release_kmask(kmask);
+        for reg in [low, high, old_low, old_high, new_low, new_high] {
+            self.release(reg);
+        }
+    }
+
+    /// Store the low byte or word of `src` to `mem` in the memory of each executing lane
+    ///
+    /// The bytes of a word are stored separately, so a word at 0xffff wraps to offset 0 of
+    /// the lane memory like the 8086 instead of writing the padding after it.
+    pub fn store(&mut self, mem: JitMemory, src: Zmm) {
+        if mem.size == MemorySize::Word {
+            let high = self.next_scratch_reg();
+            self.shift_right(high, src, 8);
+            self.store_byte(mem, 1, high);
+        }
+        self.store_byte(mem, 0, src);
+    }
+
+    /// Get the constants written by the lowering of `instr`, in the order they are used
+    ///
+    /// Only used to decide which cached constants are kept, so a missing constant is
+    /// broadcast again instead of being reused.
+    fn constants(instr: &JitIL) -> Vec<Constant> {
+        let alu = |op: FlagOp, op1: &AvxOperand, op2: &AvxOperand, flags: u16| {
+            let size = Self::operation_size(op1, op2);
+            let mut used = Self::operand_constants(&Self::sized(*op1, size));
+            used.extend(Self::operand_constants(&Self::sized(*op2, size)));
+            used.extend(Self::status_flag_constants(op, flags, size));
+            used
+        };
+
+        match instr {
+            JitIL::Mov {
+                dest: RegisterOperand { byte: Some(_), .. },
+                src,
+            } => Self::operand_constants(&Self::sized(*src, MemorySize::Byte)),
+            JitIL::Mov { src, .. } => Self::operand_constants(src),
+            JitIL::Store { dest, src } => {
+                let mut used = Self::operand_constants(src);
+                used.extend(Self::memory_constants(dest));
+                used
+            }
+            JitIL::Sub {
+                op1, op2, flags, ..
+            } => alu(FlagOp::Sub, &(*op1).into(), op2, *flags),
+            JitIL::Add {
+                op1, op2, flags, ..
+            } => alu(FlagOp::Add, &(*op1).into(), op2, *flags),
+            JitIL::And {
+                op1, op2, flags, ..
+            }
+            | JitIL::Or {
+                op1, op2, flags, ..
+            }
+            | JitIL::Xor {
+                op1, op2, flags, ..
+            } => alu(FlagOp::Logic, &(*op1).into(), op2, *flags),
+            JitIL::Cmp { left, right, flags } => alu(FlagOp::Sub, left, right, *flags),
+            JitIL::Test { left, right, flags } => alu(FlagOp::Logic, left, right, *flags),
+            // Conditions compare against zero, the flag bits are read from the constant pool
+            JitIL::Branch { not_taken, .. } => {
+                vec![Constant::word(0), Constant::word(*not_taken)]
+            }
+            JitIL::Loop { not_taken, .. } => vec![
+                Constant::word(1),
+                Constant::word(0),
+                Constant::word(*not_taken),
+            ],
+        }
+    }
+
     /// Internal function to write the given [`JitIL`] instruction into the JIT stream
     fn _write_instr(&mut self, instr: &JitIL) {
         match instr {
-            JitIL::Mov { dest, src } => {
+            JitIL::Mov {
+                dest:
+                    RegisterOperand {
+                        zmm: dest,
+                        byte: Some(part),
+                    },
+                src,
+            } => {
+                let src = self.operand_to_register(Self::sized(*src, MemorySize::Byte));
+                self.write_byte(*dest, *part, src);
+            }
+            JitIL::Mov {
+                dest: RegisterOperand { zmm: dest, .. },
+                src,
+            } => {
                 match src {
                     AvxOperand::Immediate(imm) => {
                         // Broadcast the given immediate to
-                        self.mov_imm(*dest, *imm);
+                        #[allow(clippy::cast_sign_loss)]
+                        self.mov_constant(*dest, *imm as u16);
                     }
                     AvxOperand::Zmm(src) => {
                         self.mov(*dest, *src);
                     }
+                    AvxOperand::Byte(src, part) => {
+                        self.read_byte(*dest, *src, *part);
+                    }
+                    AvxOperand::Memory(mem) => {
+                        self.load(*dest, *mem);
+                    }
                 }
             }
-            JitIL::Sub { dest, op1, op2 } => {
-                let op2 = self.operand_to_register(*op2);
-                self.sub(*dest, *op1, op2);
+            JitIL::Store { dest, src } => {
+                assert!(
+                    !matches!(src, AvxOperand::Memory(_)),
+                    "Memory to memory store"
+                );
+                let src = self.operand_to_register(*src);
+                self.store(*dest, src);
             }
-            JitIL::Add { dest, op1, op2 } => {
-                let op2 = self.operand_to_register(*op2);
-                self.add(*dest, *op1, op2);
+            JitIL::Sub {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Sub, Some(*dest), (*op1).into(), *op2, *flags),
+            JitIL::Add {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Add, Some(*dest), (*op1).into(), *op2, *flags),
+            JitIL::And {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::And, Some(*dest), (*op1).into(), *op2, *flags),
+            JitIL::Or {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Or, Some(*dest), (*op1).into(), *op2, *flags),
+            JitIL::Xor {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Xor, Some(*dest), (*op1).into(), *op2, *flags),
+            JitIL::Cmp { left, right, flags } => {
+                self.alu(AluOp::Sub, None, *left, *right, *flags);
             }
-            JitIL::Cmp { left, right, .. } => {
-                let left = self.operand_to_register(*left);
-                let right = self.operand_to_register(*right);
-                let tmp_dest = self.next_scratch_reg();
-                self.sub(tmp_dest, left, right);
+            JitIL::Test { left, right, flags } => {
+                self.alu(AluOp::And, None, *left, *right, *flags);
+            }
+            JitIL::Branch {
+                cond,
+                taken,
+                not_taken,
+            } => {
+                let kmask = self.condition_kmask(*cond);
+                self.branch(kmask, *taken, *not_taken);
+            }
+            JitIL::Loop {
+                cond,
+                taken,
+                not_taken,
+            } => {
+                // Decrement CX without touching the flags
+                let cx = JitRegister::cx.as_zmm();
+                let one = self.constant(Constant::word(1));
+                let bytes = vpsubw!(cx, cx, one).assemble();
+                self.write_bytes(bytes.as_slice());
+                self.release(one);
+
+                let zero = self.constant(Constant::word(0));
+                let kmask = self.next_scratch_kmask();
+                self.cmp(Zmm(kmask.0), cx, zero, CmpOp::NotEqual);
+                self.release(zero);
+
+                if let Some(cond) = cond {
+                    let cond = self.condition_kmask(*cond);
+                    self.kmask_op(KmaskOpcode::AndDword, kmask, kmask, cond);
+                }
+
+                self.branch(kmask, *taken, *not_taken);
             }
         };
+
+        // Every temporary is free again for the next instruction
+        self.scratch.finish_instr();
     }
 
     /// Write the given bytes into the JIT stream at the current byte offset
+    ///
+    /// At least one `ret` is always left between the code and the constant pool. Bytes that
+    /// don't fit are dropped and mark the buffer as full (see [`JitBuffer::is_full`]).
     pub fn write_bytes(&mut self, bytes: &[u8]) {
-        assert!(
-            self.offset + bytes.len() as isize <= N as isize,
-            "OOB write of JIT buffer"
-        );
+        if self.offset + (bytes.len() as isize) >= self.pool_start {
+            self.full = true;
+            return;
+        }
 
         // Write the given bytes into the JIT buffer at the current offset
+        self.memory.protect(Protection::ReadWrite);
         unsafe {
             let curr_buffer = self.buffer.offset(self.offset);
             let data = std::slice::from_raw_parts_mut(curr_buffer, bytes.len());
@@ -341,7 +1417,9 @@ impl<const N: usize> JitBuffer<N> {
         let mut offset = 0;
 
         for _ in 0..n {
-            let mut decoder = Decoder::new(64, &data[offset..], DecoderOptions::NONE);
+            // Constant pool operands are shown at their offset in the buffer
+            let mut decoder =
+                Decoder::with_ip(64, &data[offset..], offset as u64, DecoderOptions::NONE);
 
             let mut instr = Instruction::default();
             decoder.decode_out(&mut instr);
@@ -369,6 +1447,15 @@ impl<const N: usize> JitBuffer<N> {
 
     /// Get the disassembly instruction at the given offset in the JIT buffer
     pub fn get_disassembly_between(&self, start: isize, end: isize) -> Vec<String> {
+        self.get_instructions_between(start, end)
+            .into_iter()
+            .map(|instr| instr.text)
+            .collect()
+    }
+
+    /// Get the offset, bytes and disassembly of each instruction between the given offsets
+    /// in the JIT buffer
+    pub fn get_instructions_between(&self, start: isize, end: isize) -> Vec<HostInstruction> {
         assert!(start < end);
 
         use iced_x86::{Decoder, DecoderOptions, FastFormatter, Instruction};
@@ -381,7 +1468,9 @@ impl<const N: usize> JitBuffer<N> {
                 std::slice::from_raw_parts_mut(self.buffer.offset(offset), N - offset as usize)
             };
             let mut output = String::new();
-            let mut decoder = Decoder::new(64, &data, DecoderOptions::NONE);
+            // Constant pool operands are shown at their offset in the buffer
+            #[allow(clippy::cast_sign_loss)]
+            let mut decoder = Decoder::with_ip(64, data, offset as u64, DecoderOptions::NONE);
 
             let mut instr = Instruction::default();
             decoder.decode_out(&mut instr);
@@ -399,10 +1488,14 @@ impl<const N: usize> JitBuffer<N> {
             // Format the instruction into the output
             formatter.format(&instr, &mut output);
 
+            result.push(HostInstruction {
+                offset,
+                bytes: data[..instr.len()].to_vec(),
+                text: output,
+            });
+
             // Increase the offset past the decoded instruction
             offset += instr.len() as isize;
-
-            result.push(output)
         }
 
         result
@@ -412,18 +1505,76 @@ impl<const N: usize> JitBuffer<N> {
 impl From<Operand> for AvxOperand {
     fn from(op: Operand) -> AvxOperand {
         match op {
-            Operand::Register(dest) => AvxOperand::Zmm(Zmm(dest.as_zmm())),
+            Operand::Register(_) | Operand::SegmentRegister(_) => RegisterOperand::from(op).into(),
             Operand::Immediate(imm) => AvxOperand::Immediate(imm),
-            _ => unimplemented!("{op:?}"),
+            Operand::Memory(mem) => AvxOperand::Memory(mem.into()),
         }
     }
 }
 
-impl From<Operand> for Zmm {
-    fn from(op: Operand) -> Zmm {
+impl From<MemoryOperand> for JitMemory {
+    fn from(mem: MemoryOperand) -> JitMemory {
+        // Addresses based on BP are relative to SS unless overridden, every other one to DS
+        let segment = mem
+            .segment
+            .unwrap_or(if mem.registers[0] == Some(Register::Bp) {
+                SegmentRegister::Ss
+            } else {
+                SegmentRegister::Ds
+            });
+
+        let disp = mem
+            .address
+            .unwrap_or(0)
+            .wrapping_add_signed(mem.displacement.unwrap_or(0));
+
+        JitMemory {
+            segment: Some(Zmm(segment.as_zmm())),
+            registers: mem.registers.map(|reg| reg.map(|reg| Zmm(reg.as_zmm()))),
+            disp,
+            size: mem.size.unwrap_or(MemorySize::Word),
+        }
+    }
+}
+
+impl From<Operand> for RegisterOperand {
+    fn from(op: Operand) -> RegisterOperand {
         match op {
-            Operand::Register(dest) => Zmm(dest.as_zmm()),
-            _ => unimplemented!("Zmm {op:?}"),
+            Operand::Register(reg) => {
+                // The 8-bit registers are a byte of the 16-bit register's zmm
+                let (reg, sub) = reg.as_sub_register();
+                let byte = match sub {
+                    SubRegister::Full => None,
+                    SubRegister::Low => Some(BytePart::Low),
+                    SubRegister::High => Some(BytePart::High),
+                };
+
+                RegisterOperand {
+                    zmm: Zmm(reg.as_zmm()),
+                    byte,
+                }
+            }
+            Operand::SegmentRegister(reg) => Zmm(reg.as_zmm()).into(),
+            _ => unimplemented!("Register {op:?}"),
+        }
+    }
+}
+
+impl JitIL {
+    /// Returns `true` if `instr` has a translation with [`JitIL::from`]
+    pub fn supports(instr: &Instruction) -> bool {
+        // The arithmetic instructions write their result to a register
+        let register =
+            |op: &Operand| matches!(op, Operand::Register(_) | Operand::SegmentRegister(_));
+
+        match instr {
+            Instruction::Mov { .. } | Instruction::Cmp { .. } | Instruction::Test { .. } => true,
+            Instruction::Sub { dest, .. }
+            | Instruction::Add { dest, .. }
+            | Instruction::And { dest, .. }
+            | Instruction::Or { dest, .. }
+            | Instruction::Xor { dest, .. } => register(dest),
+            _ => false,
         }
     }
 }
@@ -431,6 +1582,13 @@ impl From<Operand> for Zmm {
 impl From<Instruction> for JitIL {
     fn from(instr: Instruction) -> JitIL {
         match instr {
+            Instruction::Mov {
+                dest: Operand::Memory(dest),
+                src,
+            } => JitIL::Store {
+                dest: dest.into(),
+                src: src.into(),
+            },
             Instruction::Mov { dest, src } => JitIL::Mov {
                 dest: dest.into(),
                 src: src.into(),
@@ -439,17 +1597,41 @@ impl From<Instruction> for JitIL {
                 dest: dest.into(),
                 op1: dest.into(),
                 op2: src.into(),
+                flags: STATUS_FLAGS,
             },
             Instruction::Add { dest, src } => JitIL::Add {
                 dest: dest.into(),
                 op1: dest.into(),
                 op2: src.into(),
+                flags: STATUS_FLAGS,
+            },
+            Instruction::And { dest, src } => JitIL::And {
+                dest: dest.into(),
+                op1: dest.into(),
+                op2: src.into(),
+                flags: STATUS_FLAGS,
+            },
+            Instruction::Or { dest, src } => JitIL::Or {
+                dest: dest.into(),
+                op1: dest.into(),
+                op2: src.into(),
+                flags: STATUS_FLAGS,
+            },
+            Instruction::Xor { dest, src } => JitIL::Xor {
+                dest: dest.into(),
+                op1: dest.into(),
+                op2: src.into(),
+                flags: STATUS_FLAGS,
             },
             Instruction::Cmp { left, right } => JitIL::Cmp {
-                k: Zmm(4),
                 left: left.into(),
                 right: right.into(),
-                op: CmpOp::Equal,
+                flags: STATUS_FLAGS,
+            },
+            Instruction::Test { dest, src } => JitIL::Test {
+                left: dest.into(),
+                right: src.into(),
+                flags: STATUS_FLAGS,
             },
             _ => unimplemented!("Impl JitIL for {instr:x?}"),
         }
@@ -459,29 +1641,621 @@ impl From<Instruction> for JitIL {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use cpu8086::*;
-    use std::arch::asm;
+
+    /// Get every backend the host can run
+    pub(crate) fn backends() -> Vec<Backend> {
+        let mut backends = vec![Backend::Model, Backend::Simd];
+        if Backend::native_supported() {
+            backends.push(Backend::Native);
+        }
+        if Backend::avx2_supported() {
+            backends.push(Backend::Avx2);
+        }
+        backends
+    }
+
+    /// Run the JIT code of `il` on `backend` against `state`
+    fn run_il(il: &[JitIL], state: &mut JitEmulatorState, backend: Backend) {
+        match backend {
+            Backend::Native | Backend::Model => {
+                let mut jit = JitBuffer::<4096>::new();
+                for instr in il {
+                    jit.write_instr(*instr);
+                }
+                run_buffer(&jit, state, backend);
+            }
+            Backend::Simd => simd::run_block(il, state),
+            Backend::Avx2 => {
+                let mut avx2 = Avx2Buffer::<4096>::new();
+                let offset = avx2.write_block(il);
+                // SAFETY: The offset was returned by `write_block`
+                unsafe { avx2.run_from(offset, state) };
+            }
+        }
+    }
+
+    /// Run the code written to `jit` natively or in the model
+    fn run_buffer<const N: usize>(
+        jit: &JitBuffer<N>,
+        state: &mut JitEmulatorState,
+        backend: Backend,
+    ) {
+        match backend {
+            Backend::Native => jit.run(state),
+            Backend::Model => jit.model_from(jit.code_start, state).unwrap(),
+            _ => unreachable!("{backend:?} doesn't run a JitBuffer"),
+        }
+    }
+
+    /// Decode `bytes` with iced-x86, checking that they are exactly one valid instruction
+    pub(crate) fn disassemble(bytes: &[u8]) -> String {
+        use iced_x86::{Decoder, DecoderOptions, FastFormatter};
+
+        let mut decoder = Decoder::new(64, bytes, DecoderOptions::NONE);
+        let instr = decoder.decode();
+        assert!(
+            !instr.is_invalid() && instr.len() == bytes.len(),
+            "Invalid encoding {bytes:x?}"
+        );
+
+        let mut formatter = FastFormatter::new();
+        formatter.options_mut().set_uppercase_hex(false);
+        formatter.options_mut().set_use_hex_prefix(true);
+        formatter.options_mut().set_always_show_memory_size(true);
+        formatter
+            .options_mut()
+            .set_space_after_operand_separator(true);
+        formatter.options_mut().set_rip_relative_addresses(true);
+
+        let mut output = String::new();
+        formatter.format(&instr, &mut output);
+        output
+    }
 
     #[rustfmt::skip]
     #[test]
     fn test_evex() {
-        for (instr, needed) in [
-            (vpsubw!(Zmm(1), Zmm(2), Zmm(1)),        vec![0x62, 0xf1, 0x6d, 0x48, 0xf9, 0xc9]),
-            (vpaddw!(Zmm(1), Zmm(2), Zmm(1)),        vec![0x62, 0xf1, 0x6d, 0x48, 0xfd, 0xc9]),
-            (vpcmpw!(Zmm(1), Zmm(8), Zmm(7), CmpOp::Equal), vec![0x62, 0xf3, 0xbd, 0x48, 0x3f, 0xcf, 0x00]),
-            (vpbroadcastw!(Zmm(1), rsi),  vec![0x62, 0xf2, 0x7d, 0x48, 0x7b, 0xce]),
-            (vpbroadcastw!(Zmm(1), rax),  vec![0x62, 0xf2, 0x7d, 0x48, 0x7b, 0xc8]),
-            (vpbroadcastw!(Zmm(1), rcx),  vec![0x62, 0xf2, 0x7d, 0x48, 0x7b, 0xc9]),
-            (vpmovdqa64!(Zmm(1), Zmm(2)), vec![0x62, 0xf1, 0xfd, 0x48, 0x6f, 0xca]),
-            (vpmovdqa64!(Zmm(2), Zmm(1)), vec![0x62, 0xf1, 0xfd, 0x48, 0x6f, 0xd1]),
+        for (bytes, needed) in [
+            (vpsubw!(Zmm(1), Zmm(2), Zmm(1)).assemble(),                             "vpsubw zmm1, zmm2, zmm1"),
+            (vpaddw!(Zmm(1), Zmm(2), Zmm(1)).assemble(),                             "vpaddw zmm1, zmm2, zmm1"),
+            (vpaddw!(Zmm(10), Zmm(10), Zmm(24), Kmask(3)).assemble(),                "vpaddw zmm10{k3}, zmm10, zmm24"),
+            (vpcmpw!(Zmm(1), Zmm(8), Zmm(7), CmpOp::Equal).assemble(),               "vpcmpeqw k1, zmm8, zmm7"),
+            (vpcmpuw!(Zmm(3), Zmm(30), Zmm(1), CmpOp::LessThan).assemble(),          "vpcmpltuw k3, zmm30, zmm1"),
+            (vptestmw!(Zmm(4), Zmm(26), Zmm(24)).assemble(),                         "vptestmw k4, zmm26, zmm24"),
+            (vptestnmw!(Zmm(5), Zmm(26), Zmm(24)).assemble(),                        "vptestnmw k5, zmm26, zmm24"),
+            (vpbroadcastw!(Zmm(1), rsi).assemble(),                                  "vpbroadcastw zmm1, esi"),
+            (vpbroadcastw!(Zmm(1), rax).assemble(),                                  "vpbroadcastw zmm1, eax"),
+            (vpbroadcastw!(Zmm(1), rcx).assemble(),                                  "vpbroadcastw zmm1, ecx"),
+            (vpbroadcastd!(Zmm(30), rsi).assemble(),                                 "vpbroadcastd zmm30, esi"),
+            (vpmovdqa64!(Zmm(1), Zmm(2)).assemble(),                                 "vmovdqa64 zmm1, zmm2"),
+            (vpmovdqa64!(Zmm(2), Zmm(1)).assemble(),                                 "vmovdqa64 zmm2, zmm1"),
+            (vmovdqa64_load!(Zmm(17), [r13 + 0x40]).assemble(),                      "vmovdqa64 zmm17, zmmword ptr [r13+0x40]"),
+            (vmovdqa64_store!([rbx + -8], Zmm(9)).assemble(),                        "vmovdqa64 zmmword ptr [rbx-0x8], zmm9"),
+            (vmovdqa64_load!(Zmm(2), [rdi + 0x1000]).assemble(),                     "vmovdqa64 zmm2, zmmword ptr [rdi+0x1000]"),
+            (vmovdqa64_load!(Zmm(1), [r12 + 0x40]).assemble(),                       "vmovdqa64 zmm1, zmmword ptr [r12+0x40]"),
+            (vmovdqa64_load!(Zmm(3), [rbx + 0x2000]).assemble(),                     "vmovdqa64 zmm3, zmmword ptr [rbx+0x2000]"),
+            (vmovdqa64_load!(Zmm(3), [rbx + -0x2000]).assemble(),                    "vmovdqa64 zmm3, zmmword ptr [rbx-0x2000]"),
+            (vmovdqa64_load!(Zmm(3), [rbp + 0]).assemble(),                          "vmovdqa64 zmm3, zmmword ptr [rbp]"),
+            (vmovdqa64_load!(Zmm(3), [rsp + 0]).assemble(),                          "vmovdqa64 zmm3, zmmword ptr [rsp]"),
+            (vmovdqu16_store!([rbx + 0x80], Zmm(10), Kmask(1)).assemble(),           "vmovdqu16 zmmword ptr [rbx+0x80]{k1}, zmm10"),
+            (vpaddd!(Zmm(24), Zmm(25), [rbx + 0x280]).assemble(),                    "vpaddd zmm24, zmm25, zmmword ptr [rbx+0x280]"),
+            (vpaddd!(Zmm(24), Zmm(25), [rbx + 0x1fc]).broadcast().assemble(),        "vpaddd zmm24, zmm25, dword bcst [rbx+0x1fc]"),
+            (vpbroadcastd!(Zmm(26), [rip + 0x1234]).assemble(),                      "vpbroadcastd zmm26, dword ptr [rip+0x1234]"),
+            (vpbroadcastw!(Zmm(9), [rip + -0x20], Kmask(4)).assemble(),              "vpbroadcastw zmm9{k4}, word ptr [rip-0x20]"),
+            (vpandw!(Zmm(10), Zmm(10), [rip + 0x100]).broadcast().assemble(),        "vpandd zmm10, zmm10, dword bcst [rip+0x100]"),
+            (vpternlogd!(Zmm(25), Zmm(27), [rip + -4], 0xe4).broadcast().assemble(), "vpternlogd zmm25, zmm27, dword bcst [rip-0x4], 0xe4"),
+            (vpxorq!(Zmm(24)).assemble(),                                            "vpxord zmm24, zmm24, zmm24"),
+            (vpxord!(Zmm(26), Zmm(26), Zmm(30)).assemble(),                          "vpxord zmm26, zmm26, zmm30"),
+            (vporw!(Zmm(10), Zmm(10), Zmm(25), Kmask(5)).assemble(),                 "vpord zmm10{k5}, zmm10, zmm25"),
+            (vpandw!(Zmm(10), Zmm(10), Zmm(31)).assemble(),                          "vpandd zmm10, zmm10, zmm31"),
+            (vpmovzxwd!(Zmm(26), Zmm(17)).assemble(),                                "vpmovzxwd zmm26, ymm17"),
+            (vpmovdw!(Zmm(27), Zmm(9)).assemble(),                                   "vpmovdw ymm27, zmm9"),
+            (vextracti64x4!(Zmm(28), Zmm(2), 1).assemble(),                          "vextracti64x4 ymm28, zmm2, 0x1"),
+            (vinserti64x4!(Zmm(3), Zmm(24), Zmm(31), 1).assemble(),                  "vinserti64x4 zmm3, zmm24, ymm31, 0x1"),
+            (vpternlogd!(Zmm(24), Zmm(25), Zmm(26), 0xe4).assemble(),                "vpternlogd zmm24, zmm25, zmm26, 0xe4"),
+            (vpgatherdd!(Zmm(25), [r15 + Zmm(24)], Kmask(3)).assemble(),             "vpgatherdd zmm25{k3}, dword ptr [r15+zmm24]"),
+            (vpscatterdd!([r15 + Zmm(30)], Zmm(29), Kmask(6)).assemble(),            "vpscatterdd dword ptr [r15+zmm30]{k6}, zmm29"),
+            (vpsrlw!(Zmm(26), Zmm(30), 4).assemble(),                                "vpsrlw zmm26, zmm30, 0x4"),
+            (vpsrlw!(Zmm(9), Zmm(1), 2).assemble(),                                  "vpsrlw zmm9, zmm1, 0x2"),
+            (vpsllw!(Zmm(26), Zmm(30), 1).assemble(),                                "vpsllw zmm26, zmm30, 0x1"),
+            (vpsraw!(Zmm(9), Zmm(17), 15).assemble(),                                "vpsraw zmm9, zmm17, 0xf"),
+            (vpsllvw!(Zmm(1), Zmm(2), Zmm(3)).assemble(),                            "vpsllvw zmm1, zmm2, zmm3"),
+            (vpsrlvw!(Zmm(24), Zmm(8), Zmm(30)).assemble(),                          "vpsrlvw zmm24, zmm8, zmm30"),
+            (vpsravw!(Zmm(9), Zmm(25), Zmm(4)).assemble(),                           "vpsravw zmm9, zmm25, zmm4"),
+            (vprold!(Zmm(26), Zmm(1), 8).assemble(),                                 "vprold zmm26, zmm1, 0x8"),
+            (vprord!(Zmm(3), Zmm(29), 16).assemble(),                                "vprord zmm3, zmm29, 0x10"),
+            (vpmullw!(Zmm(1), Zmm(2), Zmm(27)).assemble(),                           "vpmullw zmm1, zmm2, zmm27"),
+            (vpmulhw!(Zmm(24), Zmm(1), Zmm(2)).assemble(),                           "vpmulhw zmm24, zmm1, zmm2"),
+            (vpmulhuw!(Zmm(8), Zmm(31), Zmm(16)).assemble(),                         "vpmulhuw zmm8, zmm31, zmm16"),
+            (vpblendmw!(Zmm(9), Zmm(9), Zmm(24), Kmask(3)).assemble(),               "vpblendmw zmm9{k3}, zmm9, zmm24"),
+            (vpblendmb!(Zmm(1), Zmm(1), Zmm(30), Kmask(6)).assemble(),               "vpblendmb zmm1{k6}, zmm1, zmm30"),
+            (vpaddb!(Zmm(1), Zmm(1), Zmm(25)).assemble(),                            "vpaddb zmm1, zmm1, zmm25"),
+            (vpsubb!(Zmm(28), Zmm(3), Zmm(4)).assemble(),                            "vpsubb zmm28, zmm3, zmm4"),
+            (vpcmpb!(Zmm(3), Zmm(1), Zmm(24), CmpOp::LessThan).assemble(),           "vpcmpltb k3, zmm1, zmm24"),
+            (vpcmpub!(Zmm(5), Zmm(26), Zmm(2), CmpOp::NotEqual).assemble(),          "vpcmpnequb k5, zmm26, zmm2"),
+            (vptestmb!(Zmm(6), Zmm(1), Zmm(31)).assemble(),                          "vptestmb k6, zmm1, zmm31"),
+            (vpbroadcastb!(Zmm(25), rsi).assemble(),                                 "vpbroadcastb zmm25, esi"),
+            (vmovdqu8!(Zmm(1), Zmm(26), Kmask(4)).assemble(),                        "vmovdqu8 zmm1{k4}, zmm26"),
+            (kandw!(Kmask(3), Kmask(4), Kmask(5)).assemble(),                        "kandw k3, k4, k5"),
+            (kandd!(Kmask(3), Kmask(3), Kmask(6)).assemble(),                        "kandd k3, k3, k6"),
+            (korw!(Kmask(1), Kmask(2), Kmask(7)).assemble(),                         "korw k1, k2, k7"),
+            (kord!(Kmask(5), Kmask(5), Kmask(4)).assemble(),                         "kord k5, k5, k4"),
+            (kxord!(Kmask(3), Kmask(3), Kmask(4)).assemble(),                        "kxord k3, k3, k4"),
+            (kxnorw!(Kmask(3), Kmask(3), Kmask(3)).assemble(),                       "kxnorw k3, k3, k3"),
+            (kxnord!(Kmask(6), Kmask(3), Kmask(5)).assemble(),                       "kxnord k6, k3, k5"),
+            (kmovw!(Kmask(4), Kmask(1)).assemble(),                                  "kmovw k4, k1"),
+            (kmovd!(Kmask(5), Kmask(1)).assemble(),                                  "kmovd k5, k1"),
+            (kmovd!(Kmask(1), [rbx + 0x300]).assemble(),                             "kmovd k1, dword ptr [rbx+0x300]"),
+            (kmovd!(Kmask(1), [r13 + 8]).assemble(),                                 "kmovd k1, dword ptr [r13+0x8]"),
+            (kortestd!(Kmask(1), Kmask(3)).assemble(),                               "kortestd k1, k3"),
+            (kshiftrd!(Kmask(6), Kmask(1), 16).assemble(),                           "kshiftrd k6, k1, 0x10"),
         ] {
-            let bytes = instr.assemble();
-            let instr2 = instr.clone();
-            assert!(
-                bytes.as_slice() == needed,
-                "{instr2:?} | {:x?} {needed:x?}",
-                bytes.as_slice(),
-            );
+            let bytes = bytes.as_slice();
+            assert_eq!(disassemble(bytes), needed, "{bytes:x?}");
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let il = [
+            JitIL::Mov {
+                dest: JitRegister::ax.as_zmm().into(),
+                src: AvxOperand::Immediate(0x42),
+            },
+            JitIL::Add {
+                dest: JitRegister::cx.as_zmm().into(),
+                op1: JitRegister::cx.as_zmm().into(),
+                op2: AvxOperand::Zmm(JitRegister::bx.as_zmm()),
+                flags: STATUS_FLAGS,
+            },
+        ];
+
+        for backend in backends() {
+            let mut state = JitEmulatorState::default();
+            state.set_bx(0x1234);
+            state.set_cx_in(jit_emu::Core(3), 7);
+            run_il(&il, &mut state, backend);
+
+            for core in 0..32 {
+                let cpu = state.get_cpu_state(jit_emu::Core(core));
+                assert_eq!(cpu.ax, 0x42, "{backend:?} core {core}");
+                assert_eq!(cpu.bx, 0x1234, "{backend:?} core {core}");
+                let cx = if core == 3 { 0x123b } else { 0x1234 };
+                assert_eq!(cpu.cx, cx, "{backend:?} core {core}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_xor_execute() {
+        let mov = |imm| JitIL::Mov {
+            dest: JitRegister::ax.as_zmm().into(),
+            src: AvxOperand::Immediate(imm),
+        };
+
+        // Only native execution maps the buffer executable, the model just reads it
+        let buffer_backends = backends()
+            .into_iter()
+            .filter(|backend| matches!(backend, Backend::Native | Backend::Model));
+        for backend in buffer_backends {
+            let mut state = JitEmulatorState::default();
+            let mut jit = JitBuffer::<1024>::new();
+            assert_eq!(jit.memory.protection(), Protection::ReadWrite);
+
+            jit.write_instr(mov(1));
+            run_buffer(&jit, &mut state, backend);
+            if backend == Backend::Native {
+                assert_eq!(jit.memory.protection(), Protection::ReadExec);
+            }
+
+            // Fill the buffer, dropping the writes that don't fit
+            while !jit.is_full() {
+                jit.write_instr(mov(2));
+            }
+            assert_eq!(jit.memory.protection(), Protection::ReadWrite);
+
+            jit.flush();
+            assert!(!jit.is_full());
+            assert_eq!(jit.offset, jit.code_start);
+
+            jit.write_instr(mov(3));
+            run_buffer(&jit, &mut state, backend);
+            assert_eq!(state.get_cpu_state(jit_emu::Core(0)).ax, 3, "{backend:?}");
+        }
+    }
+
+    #[test]
+    fn test_lane_memory() {
+        use jit_emu::Core;
+
+        let bx = Some(JitRegister::bx.as_zmm());
+        let si = Some(JitRegister::si.as_zmm());
+        let byte = JitMemory {
+            segment: None,
+            registers: [bx, None],
+            disp: 0x70,
+            size: MemorySize::Byte,
+        };
+        let il = [
+            // mov ax, [bx + si + 2]
+            JitIL::Mov {
+                dest: JitRegister::ax.as_zmm().into(),
+                src: AvxOperand::Memory(JitMemory {
+                    segment: None,
+                    registers: [bx, si],
+                    disp: 2,
+                    size: MemorySize::Word,
+                }),
+            },
+            // mov [0xfff0], ax
+            JitIL::Store {
+                dest: JitMemory {
+                    segment: None,
+                    registers: [None, None],
+                    disp: 0xfff0,
+                    size: MemorySize::Word,
+                },
+                src: AvxOperand::Zmm(JitRegister::ax.as_zmm()),
+            },
+            // mov byte [bx + 0x70], 0x7f
+            JitIL::Store {
+                dest: byte,
+                src: AvxOperand::Immediate(0x7f),
+            },
+            // mov cx, byte [bx + 0x70]
+            JitIL::Mov {
+                dest: JitRegister::cx.as_zmm().into(),
+                src: AvxOperand::Memory(byte),
+            },
+        ];
+
+        for backend in backends() {
+            let mut state = JitEmulatorState::default();
+            state.set_bx(0x10);
+            for core in 0..32 {
+                // Each lane reads a different address holding a different value
+                state.set_si_in(Core(core), u16::from(core) * 2);
+                let addr = 0x12 + usize::from(core) * 2;
+                let memory = state.memory.lane_mut(Core(core));
+                memory[addr..addr + 2].copy_from_slice(&(0x1000 + u16::from(core)).to_le_bytes());
+                memory[0xfff2] = 0xaa;
+                memory[0x81] = 0x55;
+            }
+
+            run_il(&il, &mut state, backend);
+
+            for core in 0..32 {
+                let cpu = state.get_cpu_state(Core(core));
+                assert_eq!(cpu.ax, 0x1000 + u16::from(core), "{backend:?} core {core}");
+                assert_eq!(cpu.cx, 0x7f, "{backend:?} core {core}");
+
+                let memory = state.memory.lane(Core(core));
+                assert_eq!(
+                    memory[0xfff0..0xfff3],
+                    [core, 0x10, 0xaa],
+                    "{backend:?} core {core}"
+                );
+                assert_eq!(memory[0x80..0x82], [0x7f, 0x55], "{backend:?} core {core}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_status_flags() {
+        use cpu8086::flags::{status_flags, FlagOp};
+        use jit_emu::Core;
+
+        // Operand pairs covering carries, borrows, overflows and nibble carries
+        const VALUES: [(u16, u16); 32] = [
+            (0, 0),
+            (4, 0xffff),
+            (0x7fff, 1),
+            (0x8000, 1),
+            (3, 4),
+            (0xffff, 0xffff),
+            (0x8000, 0x8000),
+            (0x0f, 1),
+            (0x10, 1),
+            (0x1234, 0x1234),
+            (0xff, 0x01),
+            (0x7f, 0x80),
+            (0x8001, 0x7fff),
+            (1, 0x8000),
+            (0xfffe, 2),
+            (0x00f0, 0x0f0f),
+            (0x5555, 0xaaaa),
+            (0xaaaa, 0x5555),
+            (0x0100, 0x00ff),
+            (2, 3),
+            (0x4000, 0x4000),
+            (0xc000, 0x4000),
+            (0x0008, 0x0008),
+            (0x00ff, 0x00ff),
+            (0x8000, 0x7fff),
+            (0x7fff, 0x8000),
+            (0x0001, 0xffff),
+            (0x1000, 0x0fff),
+            (0x0f0f, 0xf0f0),
+            (0x00aa, 0x0055),
+            (0x00c3, 0x0001),
+            (0x6000, 0x3000),
+        ];
+
+        // An instruction of AX and BX, its flag operation, the result it computes and
+        // whether it writes AX
+        type FlagCase = (JitIL, FlagOp, fn(u16, u16) -> u16, bool);
+
+        let ax = JitRegister::ax.as_zmm();
+        let bx = AvxOperand::Zmm(JitRegister::bx.as_zmm());
+        let instrs: [FlagCase; 7] = [
+            (
+                JitIL::Add {
+                    dest: ax.into(),
+                    op1: ax.into(),
+                    op2: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Add,
+                u16::wrapping_add,
+                true,
+            ),
+            (
+                JitIL::Sub {
+                    dest: ax.into(),
+                    op1: ax.into(),
+                    op2: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Sub,
+                u16::wrapping_sub,
+                true,
+            ),
+            (
+                JitIL::And {
+                    dest: ax.into(),
+                    op1: ax.into(),
+                    op2: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Logic,
+                |a, b| a & b,
+                true,
+            ),
+            (
+                JitIL::Or {
+                    dest: ax.into(),
+                    op1: ax.into(),
+                    op2: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Logic,
+                |a, b| a | b,
+                true,
+            ),
+            (
+                JitIL::Xor {
+                    dest: ax.into(),
+                    op1: ax.into(),
+                    op2: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Logic,
+                |a, b| a ^ b,
+                true,
+            ),
+            (
+                JitIL::Cmp {
+                    left: AvxOperand::Zmm(ax),
+                    right: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Sub,
+                u16::wrapping_sub,
+                false,
+            ),
+            (
+                JitIL::Test {
+                    left: AvxOperand::Zmm(ax),
+                    right: bx,
+                    flags: STATUS_FLAGS,
+                },
+                FlagOp::Logic,
+                |a, b| a & b,
+                false,
+            ),
+        ];
+
+        for (instr, op, func, writes) in instrs {
+            for backend in backends() {
+                let mut state = JitEmulatorState::default();
+                for (core, (a, b)) in VALUES.into_iter().enumerate() {
+                    let core = Core(core as u8);
+                    state.set_ax_in(core, a);
+                    state.set_bx_in(core, b);
+
+                    // Flags other than the status flags are left untouched
+                    state.set_flags_in(core, 0x0200 | STATUS_FLAGS);
+                }
+
+                run_il(&[instr], &mut state, backend);
+
+                for (core, (a, b)) in VALUES.into_iter().enumerate() {
+                    let cpu = state.get_cpu_state(Core(core as u8));
+                    let result = func(a, b);
+                    let flags = status_flags(op, result, a, b, MemorySize::Word);
+
+                    assert_eq!(
+                        cpu.ax,
+                        if writes { result } else { a },
+                        "{backend:?} {instr:?} {a:#x} {b:#x}"
+                    );
+                    assert_eq!(
+                        cpu.flags,
+                        0x0200 | flags,
+                        "{backend:?} {instr:?} {a:#x} {b:#x}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_byte_registers() {
+        use cpu8086::flags::{status_flags, FlagOp};
+        use jit_emu::Core;
+
+        let ax = JitRegister::ax.as_zmm();
+        let bx = JitRegister::bx.as_zmm();
+        let ah = RegisterOperand {
+            zmm: ax,
+            byte: Some(BytePart::High),
+        };
+        let bh = RegisterOperand {
+            zmm: bx,
+            byte: Some(BytePart::High),
+        };
+
+        // Spread the bytes of each lane to cover carries, overflows and nibble carries
+        let values = |core: u16| (core.wrapping_mul(0x2b17), core.wrapping_mul(0x1d35));
+
+        let il = [
+            // add ah, bl
+            JitIL::Add {
+                dest: ah,
+                op1: ah,
+                op2: AvxOperand::Byte(bx, BytePart::Low),
+                flags: STATUS_FLAGS,
+            },
+            // mov bh, 0x80 with the immediate sign extended to a word
+            JitIL::Mov {
+                dest: bh,
+                src: AvxOperand::Immediate(-0x80),
+            },
+        ];
+
+        for backend in backends() {
+            let mut state = JitEmulatorState::default();
+            for core in 0..32 {
+                let (a, b) = values(core);
+                state.set_ax_in(Core(core as u8), a);
+                state.set_bx_in(Core(core as u8), b);
+            }
+
+            run_il(&il, &mut state, backend);
+
+            for core in 0..32 {
+                let (a, b) = values(core);
+                let (high, low) = (a >> 8, b & 0xff);
+                let result = (high + low) & 0xff;
+
+                let cpu = state.get_cpu_state(Core(core as u8));
+                assert_eq!(cpu.ax, result << 8 | a & 0xff, "{backend:?} {a:#x} {b:#x}");
+                assert_eq!(cpu.bx, 0x8000 | b & 0xff, "{backend:?} {a:#x} {b:#x}");
+
+                let flags = status_flags(FlagOp::Add, result, high, low, MemorySize::Byte);
+                assert_eq!(cpu.flags, flags, "{backend:?} {a:#x} {b:#x}");
+            }
         }
     }
+
+    #[test]
+    fn test_dead_flags() {
+        let ax = JitRegister::ax.as_zmm();
+        let one = AvxOperand::Immediate(1);
+        let sub = JitIL::Sub {
+            dest: ax.into(),
+            op1: ax.into(),
+            op2: one,
+            flags: STATUS_FLAGS,
+        };
+        let cmp = JitIL::Cmp {
+            left: AvxOperand::Zmm(ax),
+            right: one,
+            flags: STATUS_FLAGS,
+        };
+        let branch = JitIL::Branch {
+            cond: Condition::Less,
+            taken: 0,
+            not_taken: 2,
+        };
+
+        // sub; cmp; jl: only the cmp's SF and OF are read
+        let mut instrs = [sub, cmp, branch];
+        eliminate_dead_flags(instrs.iter_mut(), 0);
+        let flags: Vec<_> = instrs.iter_mut().map(|i| i.flags_mut().copied()).collect();
+        let less = EFlags::Sign as u16 | EFlags::Overflow as u16;
+        assert_eq!(flags, [Some(0), Some(less), None]);
+
+        // Flags live after the last instruction are kept
+        let mut instrs = [sub, sub];
+        eliminate_dead_flags(instrs.iter_mut(), EFlags::Zero as u16);
+        let flags: Vec<_> = instrs.iter_mut().map(|i| i.flags_mut().copied()).collect();
+        assert_eq!(flags, [Some(0), Some(EFlags::Zero as u16)]);
+    }
+
+    #[test]
+    fn test_constant_cache() {
+        let ax = JitRegister::ax.as_zmm();
+        let sub = JitIL::Sub {
+            dest: ax.into(),
+            op1: ax.into(),
+            op2: AvxOperand::Immediate(1),
+            flags: EFlags::Zero as u16 | EFlags::Sign as u16,
+        };
+
+        let mut jit = JitBuffer::<4096>::new();
+        jit.begin_block(&[sub, sub]);
+        jit.write_instr(sub);
+        let second = jit.offset;
+        jit.write_instr(sub);
+
+        // The second sub reuses every constant broadcast by the first one
+        let code = jit.get_disassembly_between(second, jit.offset);
+        assert!(
+            code.iter().all(|instr| !instr.contains("broadcast")),
+            "{code:#?}"
+        );
+
+        // A new block doesn't reuse the constants of the previous block
+        jit.begin_block(&[sub]);
+        let start = jit.offset;
+        jit.write_instr(sub);
+        let code = jit.get_disassembly_between(start, jit.offset);
+        assert!(code.iter().any(|instr| instr.contains("broadcast")));
+    }
+
+    #[test]
+    fn test_constant_pool() {
+        let ax = JitRegister::ax.as_zmm();
+        let flags = EFlags::Zero as u16 | EFlags::Sign as u16;
+        let sub = JitIL::Sub {
+            dest: ax.into(),
+            op1: ax.into(),
+            op2: AvxOperand::Immediate(1),
+            flags,
+        };
+
+        let mut jit = JitBuffer::<4096>::new();
+        jit.begin_block(&[sub]);
+        jit.write_instr(sub);
+        let entries = jit.pool.len();
+
+        // A later block finds all of its constants in the pool
+        jit.begin_block(&[sub]);
+        let start = jit.offset;
+        jit.write_instr(sub);
+        assert_eq!(jit.pool.len(), entries);
+
+        // No immediate goes through a general purpose register and the flags are cleared
+        // with the mask broadcast straight from the pool
+        let mask = jit.pool[&Constant::word(!flags).0];
+        let code = jit.get_disassembly_between(start, jit.offset);
+        assert!(code.iter().all(|instr| !instr.contains("esi")), "{code:#?}");
+        assert!(
+            code.iter()
+                .any(|instr| instr.starts_with("vpandd zmm10")
+                    && instr.contains(&format!("{mask:#x}]"))),
+            "{code:#?}"
+        );
+    }
 }
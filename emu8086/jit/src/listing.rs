@@ -0,0 +1,347 @@
+//! Annotated listing of the host code each 8086 instruction was translated into
+//!
+//! Each translated 8086 instruction is shown next to its host instructions, along with the
+//! bytes and offsets of both. Code written once per block, such as the final `ret`, isn't
+//! part of any instruction and isn't listed.
+
+use std::fmt::Write;
+
+/// Output format of [`Listing::render`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ListingFormat {
+    /// Aligned plain text columns
+    Text,
+
+    /// A Markdown table
+    Markdown,
+
+    /// An HTML table
+    Html,
+}
+
+/// A host instruction in a [`crate::JitBuffer`]
+#[derive(Debug, Clone)]
+pub struct HostInstruction {
+    /// Offset of the instruction in the buffer
+    pub offset: isize,
+
+    /// Encoded bytes of the instruction
+    pub bytes: Vec<u8>,
+
+    /// Disassembly of the instruction
+    pub text: String,
+}
+
+/// An 8086 instruction and the host instructions it was translated into
+#[derive(Debug, Clone)]
+pub struct ListingEntry {
+    /// IP of the instruction
+    pub ip: u16,
+
+    /// Encoded bytes of the instruction
+    pub bytes: Vec<u8>,
+
+    /// Disassembly of the instruction
+    pub instr: String,
+
+    /// The host code of the instruction, empty if it was optimized away
+    pub host: Vec<HostInstruction>,
+}
+
+impl ListingEntry {
+    /// Size in bytes of the host code of the instruction
+    pub fn host_size(&self) -> usize {
+        self.host.iter().map(|instr| instr.bytes.len()).sum()
+    }
+}
+
+/// Every translated instruction of a [`crate::JitProgram`] in translation order
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub entries: Vec<ListingEntry>,
+}
+
+/// Format `bytes` as space separated hex
+fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    hex.join(" ")
+}
+
+/// Escape the HTML special characters of `text`
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Listing {
+    /// Total number of host instructions
+    pub fn host_instructions(&self) -> usize {
+        self.entries.iter().map(|entry| entry.host.len()).sum()
+    }
+
+    /// Total size in bytes of the host code
+    pub fn host_size(&self) -> usize {
+        self.entries.iter().map(ListingEntry::host_size).sum()
+    }
+
+    /// Total size in bytes of the 8086 code
+    pub fn size(&self) -> usize {
+        self.entries.iter().map(|entry| entry.bytes.len()).sum()
+    }
+
+    /// Get the one line summary of the code sizes
+    pub fn summary(&self) -> String {
+        let instructions = self.entries.len();
+        let host_instructions = self.host_instructions();
+        let (size, host_size) = (self.size(), self.host_size());
+
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = |host: usize, guest: usize| host as f64 / guest.max(1) as f64;
+
+        format!(
+            "{instructions} 8086 instructions ({size} bytes) translated into \
+             {host_instructions} host instructions ({host_size} bytes), {:.1} host \
+             instructions per instruction and {:.1} host bytes per byte",
+            ratio(host_instructions, instructions),
+            ratio(host_size, size)
+        )
+    }
+
+    /// Render the listing in the given `format`
+    pub fn render(&self, format: ListingFormat) -> String {
+        match format {
+            ListingFormat::Text => self.render_text(),
+            ListingFormat::Markdown => self.render_markdown(),
+            ListingFormat::Html => self.render_html(),
+        }
+    }
+
+    /// Render the 8086 and host code side by side in aligned columns
+    fn render_text(&self) -> String {
+        let mut out = String::new();
+        let columns = |out: &mut String, left: [&str; 5], right: [&str; 3]| {
+            let [ip, bytes, instr, count, size] = left;
+            let [offset, host_bytes, host] = right;
+            let line = format!(
+                "{ip:6} {bytes:17} {instr:24} {count:>5} {size:>4} | {offset:6} {host_bytes:32} {host}"
+            );
+            let _ = writeln!(out, "{}", line.trim_end());
+        };
+
+        columns(
+            &mut out,
+            ["IP", "Bytes", "8086", "Count", "Size"],
+            ["Offset", "Bytes", "Host"],
+        );
+
+        for entry in &self.entries {
+            let _ = writeln!(out, "{}", "-".repeat(100));
+
+            let ip = format!("{:#06x}", entry.ip);
+            let bytes = hex(&entry.bytes);
+            let count = entry.host.len().to_string();
+            let size = entry.host_size().to_string();
+            let left = [&*ip, &*bytes, &*entry.instr, &*count, &*size];
+
+            if entry.host.is_empty() {
+                columns(&mut out, left, ["", "", ""]);
+            }
+
+            for (i, host) in entry.host.iter().enumerate() {
+                let left = if i == 0 { left } else { [""; 5] };
+                let offset = format!("{:#06x}", host.offset);
+                columns(&mut out, left, [&offset, &hex(&host.bytes), &host.text]);
+            }
+        }
+
+        let _ = writeln!(out, "{}", "-".repeat(100));
+        let _ = writeln!(out, "{}", self.summary());
+        out
+    }
+
+    /// Render a Markdown table with a row per host instruction
+    fn render_markdown(&self) -> String {
+        let mut out = String::new();
+        let code = |text: &str| {
+            if text.is_empty() {
+                String::new()
+            } else {
+                format!("`{}`", text.replace('|', "\\|"))
+            }
+        };
+
+        let _ = writeln!(
+            out,
+            "| IP | Bytes | 8086 | Count | Size | Offset | Bytes | Host |"
+        );
+        let _ = writeln!(out, "|---|---|---|--:|--:|---|---|---|");
+
+        for entry in &self.entries {
+            let left = format!(
+                "| {} | {} | {} | {} | {} |",
+                code(&format!("{:#06x}", entry.ip)),
+                code(&hex(&entry.bytes)),
+                code(&entry.instr),
+                entry.host.len(),
+                entry.host_size()
+            );
+
+            if entry.host.is_empty() {
+                let _ = writeln!(out, "{left} | | |");
+            }
+
+            for (i, host) in entry.host.iter().enumerate() {
+                let left = if i == 0 { &*left } else { "| | | | | |" };
+                let _ = writeln!(
+                    out,
+                    "{left} {} | {} | {} |",
+                    code(&format!("{:#06x}", host.offset)),
+                    code(&hex(&host.bytes)),
+                    code(&host.text)
+                );
+            }
+        }
+
+        let _ = writeln!(out);
+        let _ = writeln!(out, "{}", self.summary());
+        out
+    }
+
+    /// Render an HTML table with a body per 8086 instruction
+    fn render_html(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "<table class=\"listing\">");
+        let _ = writeln!(
+            out,
+            "<thead><tr><th>IP</th><th>Bytes</th><th>8086</th><th>Count</th><th>Size</th>\
+             <th>Offset</th><th>Bytes</th><th>Host</th></tr></thead>"
+        );
+
+        for entry in &self.entries {
+            let _ = writeln!(out, "<tbody>");
+
+            let rows = entry.host.len().max(1);
+            let left = format!(
+                "<td rowspan=\"{rows}\"><code>{:#06x}</code></td>\
+                 <td rowspan=\"{rows}\"><code>{}</code></td>\
+                 <td rowspan=\"{rows}\"><code>{}</code></td>\
+                 <td rowspan=\"{rows}\">{}</td><td rowspan=\"{rows}\">{}</td>",
+                entry.ip,
+                hex(&entry.bytes),
+                escape_html(&entry.instr),
+                entry.host.len(),
+                entry.host_size()
+            );
+
+            if entry.host.is_empty() {
+                let _ = writeln!(out, "<tr>{left}<td></td><td></td><td></td></tr>");
+            }
+
+            for (i, host) in entry.host.iter().enumerate() {
+                let left = if i == 0 { &*left } else { "" };
+                let _ = writeln!(
+                    out,
+                    "<tr>{left}<td><code>{:#06x}</code></td><td><code>{}</code></td>\
+                     <td><code>{}</code></td></tr>",
+                    host.offset,
+                    hex(&host.bytes),
+                    escape_html(&host.text)
+                );
+            }
+
+            let _ = writeln!(out, "</tbody>");
+        }
+
+        let _ = writeln!(out, "</table>");
+        let _ = writeln!(out, "<p>{}</p>", escape_html(&self.summary()));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing() -> Listing {
+        let host = |offset, bytes: &[u8], text: &str| HostInstruction {
+            offset,
+            bytes: bytes.to_vec(),
+            text: text.to_string(),
+        };
+
+        Listing {
+            entries: vec![
+                ListingEntry {
+                    ip: 0,
+                    bytes: vec![0xbc, 0xe6, 0x03],
+                    instr: "mov sp, 0x3e6".to_string(),
+                    host: vec![host(
+                        0x40,
+                        &[0x62, 0xf2, 0x7d, 0x48, 0x79, 0x3d, 0xb2, 0xff, 0x00, 0x00],
+                        "vpbroadcastw zmm7, word ptr [0xfffc]",
+                    )],
+                },
+                ListingEntry {
+                    ip: 3,
+                    bytes: vec![0x39, 0xe5],
+                    instr: "cmp bp, sp".to_string(),
+                    host: Vec::new(),
+                },
+                ListingEntry {
+                    ip: 5,
+                    bytes: vec![0x01, 0xd8],
+                    instr: "add ax, bx".to_string(),
+                    host: vec![
+                        host(
+                            0x4a,
+                            &[0x62, 0xf1, 0x75, 0x49, 0xfd, 0xca],
+                            "vpaddw zmm1{k1}, zmm1, zmm2",
+                        ),
+                        host(0x50, &[0xc3], "ret"),
+                    ],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = listing();
+        assert_eq!(listing.host_instructions(), 3);
+        assert_eq!(listing.host_size(), 17);
+        assert_eq!(listing.size(), 7);
+        assert_eq!(
+            listing.summary(),
+            "3 8086 instructions (7 bytes) translated into 3 host instructions (17 bytes), \
+             1.0 host instructions per instruction and 2.4 host bytes per byte"
+        );
+
+        let text = listing.render(ListingFormat::Text);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[4],
+            "0x0003 39 e5             cmp bp, sp                   0    0 |"
+        );
+        assert_eq!(
+            lines[6],
+            "0x0005 01 d8             add ax, bx                   2    7 | \
+             0x004a 62 f1 75 49 fd ca                vpaddw zmm1{k1}, zmm1, zmm2"
+        );
+        assert_eq!(lines[7], format!("{:61}| 0x0050 {:32} ret", "", "c3"));
+
+        let markdown = listing.render(ListingFormat::Markdown);
+        assert!(markdown.contains(
+            "| `0x0003` | `39 e5` | `cmp bp, sp` | 0 | 0 | | | |\n\
+             | `0x0005` | `01 d8` | `add ax, bx` | 2 | 7 | `0x004a` | `62 f1 75 49 fd ca` | \
+             `vpaddw zmm1{k1}, zmm1, zmm2` |\n\
+             | | | | | | `0x0050` | `c3` | `ret` |\n"
+        ));
+
+        let html = listing.render(ListingFormat::Html);
+        assert_eq!(html.matches("<tbody>").count(), 3);
+        assert_eq!(html.matches("<tr>").count(), 5);
+        assert!(html.contains("<td rowspan=\"2\"><code>add ax, bx</code></td>"));
+    }
+}
@@ -0,0 +1,463 @@
+//! A software model of the instructions emitted by the JIT
+//!
+//! The JIT code, including the trampoline, is decoded with iced-x86 and each instruction is
+//! evaluated against modelled zmm, k and general purpose registers. Memory operands access
+//! the real [`JitEmulatorState`], lane memory and constant pool, so the model checks the
+//! generated code on hosts without AVX-512.
+
+use anyhow::{bail, ensure, Result};
+use iced_x86::{Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
+
+use jit_emu::JitEmulatorState;
+
+use crate::JitBuffer;
+
+/// Maximum number of instructions evaluated by a single call to the model
+const MAX_STEPS: usize = 1_000_000;
+
+/// A zmm register as its 64 bytes
+type Vector = [u8; 64];
+
+/// Get the `size` byte element `index` of `vec`
+fn element(vec: &Vector, size: usize, index: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes[..size].copy_from_slice(&vec[index * size..(index + 1) * size]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Set the `size` byte element `index` of `vec` to `val`
+fn set_element(vec: &mut Vector, size: usize, index: usize, val: u32) {
+    vec[index * size..(index + 1) * size].copy_from_slice(&val.to_le_bytes()[..size]);
+}
+
+/// Register state of the modelled CPU
+struct Model {
+    /// Vector registers
+    zmm: [Vector; 32],
+
+    /// Opmask registers
+    k: [u64; 8],
+
+    /// General purpose registers in their encoding order
+    gpr: [u64; 16],
+
+    /// Return addresses and values pushed on the stack
+    stack: Vec<u64>,
+}
+
+impl Model {
+    /// Read `N` bytes of host memory at `addr`
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be valid for reads of `N` bytes
+    unsafe fn read<const N: usize>(addr: u64) -> [u8; N] {
+        std::ptr::read_unaligned(addr as *const [u8; N])
+    }
+
+    /// Write `bytes` to host memory at `addr`
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be valid for writes of `N` bytes
+    unsafe fn write<const N: usize>(addr: u64, bytes: [u8; N]) {
+        std::ptr::write_unaligned(addr as *mut [u8; N], bytes);
+    }
+
+    /// Get the value of a general purpose register of any size
+    fn gpr(&self, reg: Register) -> u64 {
+        let val = self.gpr[reg.full_register().number()];
+        match reg.size() {
+            8 => val,
+            size => val & ((1 << (size * 8)) - 1),
+        }
+    }
+
+    /// Get the address of the memory operand of `instr`
+    fn address(&self, instr: &Instruction) -> u64 {
+        // The displacement of a RIP-relative operand is already the absolute address
+        let mut addr = instr.memory_displacement64();
+        if !matches!(instr.memory_base(), Register::None | Register::RIP) {
+            addr = addr.wrapping_add(self.gpr(instr.memory_base()));
+        }
+        addr
+    }
+
+    /// Get the address of each dword element of the gather or scatter `instr` in its
+    /// write mask, clearing the mask like the CPU does as each element completes
+    fn vsib_addresses(&mut self, instr: &Instruction) -> Vec<(usize, u64)> {
+        let base = self.address(instr);
+        let index = self.zmm[instr.memory_index().number()];
+        let scale = u64::from(instr.memory_index_scale());
+        let mask = std::mem::take(&mut self.k[instr.op_mask().number()]);
+
+        (0..16)
+            .filter(|i| mask & (1 << i) > 0)
+            .map(|i| {
+                #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+                let offset = i64::from(element(&index, 4, i) as i32) as u64;
+                (i, base.wrapping_add(offset.wrapping_mul(scale)))
+            })
+            .collect()
+    }
+
+    /// Get the vector register or memory operand `op` of `instr`
+    fn vector(&self, instr: &Instruction, op: u32) -> Result<Vector> {
+        match instr.op_kind(op) {
+            OpKind::Register => Ok(self.zmm[instr.op_register(op).number()]),
+            // SAFETY: Embedded broadcasts only read dwords of the constant pool
+            OpKind::Memory if instr.is_broadcast() => {
+                let val: [u8; 4] = unsafe { Self::read(self.address(instr)) };
+                Ok(std::array::from_fn(|i| val[i % 4]))
+            }
+            // SAFETY: Memory operands of the JIT code only address the emulator state
+            OpKind::Memory => Ok(unsafe { Self::read(self.address(instr)) }),
+            kind => bail!("Unsupported vector operand {kind:?} in {instr}"),
+        }
+    }
+
+    /// Get the opmask register `op` of `instr`
+    fn kmask(&self, instr: &Instruction, op: u32) -> u64 {
+        self.k[instr.op_register(op).number()]
+    }
+
+    /// Get the write mask of `instr` for `count` elements
+    fn write_mask(&self, instr: &Instruction, count: usize) -> u64 {
+        let all = if count == 64 {
+            u64::MAX
+        } else {
+            (1 << count) - 1
+        };
+        match instr.op_mask() {
+            Register::None => all,
+            mask => self.k[mask.number()] & all,
+        }
+    }
+
+    /// Write the `size` byte elements of `val` to the destination register of `instr`,
+    /// merging in only the elements in its write mask. Writes to a ymm zero the upper half.
+    fn write_vector(&mut self, instr: &Instruction, size: usize, val: &Vector) -> Result<()> {
+        ensure!(
+            !instr.zeroing_masking(),
+            "Unsupported zeroing masking in {instr}"
+        );
+
+        let dest = instr.op0_register();
+        let count = if dest.is_ymm() { 32 } else { 64 } / size;
+        let mask = self.write_mask(instr, count);
+
+        let zmm = &mut self.zmm[dest.number()];
+        for i in (0..count).filter(|i| mask & (1 << i) > 0) {
+            let elem = i * size..(i + 1) * size;
+            zmm[elem.clone()].copy_from_slice(&val[elem]);
+        }
+
+        if dest.is_ymm() {
+            zmm[32..].fill(0);
+        }
+
+        Ok(())
+    }
+
+    /// Evaluate `func` on each `size` byte element of the two sources of `instr`
+    fn binary(
+        &mut self,
+        instr: &Instruction,
+        size: usize,
+        func: impl Fn(u32, u32) -> u32,
+    ) -> Result<()> {
+        let (a, b) = (self.vector(instr, 1)?, self.vector(instr, 2)?);
+        let mut res = [0; 64];
+        for i in 0..64 / size {
+            set_element(
+                &mut res,
+                size,
+                i,
+                func(element(&a, size, i), element(&b, size, i)),
+            );
+        }
+        self.write_vector(instr, size, &res)
+    }
+
+    /// Set the destination opmask of `instr` to the words of its sources where `func` holds
+    fn compare(&mut self, instr: &Instruction, func: impl Fn(u16, u16) -> bool) -> Result<()> {
+        let (a, b) = (self.vector(instr, 1)?, self.vector(instr, 2)?);
+        let mut res = 0;
+        for i in 0..32 {
+            #[allow(clippy::cast_possible_truncation)]
+            if func(element(&a, 2, i) as u16, element(&b, 2, i) as u16) {
+                res |= 1 << i;
+            }
+        }
+        self.k[instr.op0_register().number()] = res & self.write_mask(instr, 32);
+        Ok(())
+    }
+
+    /// Set the destination opmask of `instr` to `func` of its source opmasks
+    fn kmask_logic(&mut self, instr: &Instruction, bits: u32, func: fn(u64, u64) -> u64) {
+        let res = func(self.kmask(instr, 1), self.kmask(instr, 2));
+        self.k[instr.op0_register().number()] = res & ((1 << bits) - 1);
+    }
+
+    /// Evaluate a single instruction, returning the address of the next one or `None` once
+    /// the outermost `ret` is reached
+    #[allow(clippy::too_many_lines, clippy::cast_possible_truncation)]
+    fn step(&mut self, instr: &Instruction) -> Result<Option<u64>> {
+        let next = instr.next_ip();
+
+        match instr.mnemonic() {
+            Mnemonic::Push => self.stack.push(self.gpr(instr.op0_register())),
+            Mnemonic::Pop => {
+                let Some(val) = self.stack.pop() else {
+                    bail!("Stack underflow at {instr}");
+                };
+                self.gpr[instr.op0_register().number()] = val;
+            }
+            Mnemonic::Mov => {
+                // Writes to a 32-bit register zero extend into the 64-bit register
+                let val = match instr.op1_kind() {
+                    OpKind::Register => self.gpr(instr.op1_register()),
+                    OpKind::Immediate32 => u64::from(instr.immediate32()),
+                    kind => bail!("Unsupported mov operand {kind:?} in {instr}"),
+                };
+                self.gpr[instr.op0_register().full_register().number()] = val;
+            }
+            Mnemonic::Call => {
+                self.stack.push(next);
+                return Ok(Some(self.gpr(instr.op0_register())));
+            }
+            Mnemonic::Ret => return Ok(self.stack.pop()),
+            Mnemonic::Vzeroupper => {
+                for zmm in &mut self.zmm {
+                    zmm[16..].fill(0);
+                }
+            }
+
+            Mnemonic::Kmovd => {
+                // SAFETY: The trampoline only loads the exec mask from the emulator state
+                let bytes: [u8; 4] = unsafe { Self::read(self.address(instr)) };
+                self.k[instr.op0_register().number()] = u64::from(u32::from_le_bytes(bytes));
+            }
+            Mnemonic::Kmovw => {
+                self.k[instr.op0_register().number()] = self.kmask(instr, 1) & 0xffff;
+            }
+            Mnemonic::Kxnorw => self.kmask_logic(instr, 16, |a, b| !(a ^ b)),
+            Mnemonic::Kandd => self.kmask_logic(instr, 32, |a, b| a & b),
+            Mnemonic::Kord => self.kmask_logic(instr, 32, |a, b| a | b),
+            Mnemonic::Kxord => self.kmask_logic(instr, 32, |a, b| a ^ b),
+            Mnemonic::Kxnord => self.kmask_logic(instr, 32, |a, b| !(a ^ b)),
+            Mnemonic::Kshiftrd => {
+                let src = self.kmask(instr, 1) & 0xffff_ffff;
+                let shift = u32::from(instr.immediate8());
+                self.k[instr.op0_register().number()] = src.checked_shr(shift).unwrap_or(0);
+            }
+
+            Mnemonic::Vmovdqa64 if instr.op0_kind() == OpKind::Memory => {
+                let src = self.zmm[instr.op1_register().number()];
+
+                // SAFETY: The JIT code only spills into the emulator state
+                unsafe { Self::write(self.address(instr), src) };
+            }
+            Mnemonic::Vmovdqa64 => {
+                let src = self.vector(instr, 1)?;
+                self.write_vector(instr, 8, &src)?;
+            }
+            Mnemonic::Vmovdqu16 => {
+                // Only used to store the executing lanes back to the state
+                ensure!(instr.op0_kind() == OpKind::Memory, "Unsupported {instr}");
+                let addr = self.address(instr);
+                let src = self.zmm[instr.op1_register().number()];
+                let mask = self.write_mask(instr, 32);
+
+                for i in (0..32).filter(|i| mask & (1 << i) > 0) {
+                    let word = [src[i * 2], src[i * 2 + 1]];
+
+                    // SAFETY: The trampoline only stores into the emulator state
+                    unsafe { Self::write(addr + i as u64 * 2, word) };
+                }
+            }
+            Mnemonic::Vpbroadcastw | Mnemonic::Vpbroadcastd => {
+                let size = if instr.mnemonic() == Mnemonic::Vpbroadcastw {
+                    2
+                } else {
+                    4
+                };
+                let val = match instr.op1_kind() {
+                    // SAFETY: Broadcasts from memory only read the constant pool
+                    OpKind::Memory => {
+                        u32::from_le_bytes(unsafe { Self::read(self.address(instr)) })
+                    }
+                    _ => self.gpr(instr.op1_register()) as u32,
+                };
+                let mut res = [0; 64];
+                for i in 0..64 / size {
+                    set_element(&mut res, size, i, val);
+                }
+                self.write_vector(instr, size, &res)?;
+            }
+            Mnemonic::Vpaddw => self.binary(instr, 2, |a, b| a.wrapping_add(b))?,
+            Mnemonic::Vpsubw => self.binary(instr, 2, |a, b| a.wrapping_sub(b))?,
+            Mnemonic::Vpaddd => self.binary(instr, 4, u32::wrapping_add)?,
+            Mnemonic::Vpandd => self.binary(instr, 4, |a, b| a & b)?,
+            Mnemonic::Vpord => self.binary(instr, 4, |a, b| a | b)?,
+            Mnemonic::Vpxord | Mnemonic::Vpxorq => self.binary(instr, 4, |a, b| a ^ b)?,
+            Mnemonic::Vpcmpw | Mnemonic::Vpcmpuw => {
+                let signed = instr.mnemonic() == Mnemonic::Vpcmpw;
+                let pred = instr.immediate8();
+                self.compare(instr, |a, b| {
+                    let ord = if signed {
+                        (a as i16).cmp(&(b as i16))
+                    } else {
+                        a.cmp(&b)
+                    };
+                    match pred {
+                        0 => ord.is_eq(),
+                        1 => ord.is_lt(),
+                        2 => ord.is_le(),
+                        3 => false,
+                        4 => ord.is_ne(),
+                        5 => ord.is_ge(),
+                        6 => ord.is_gt(),
+                        _ => true,
+                    }
+                })?;
+            }
+            Mnemonic::Vptestmw => self.compare(instr, |a, b| a & b != 0)?,
+            Mnemonic::Vptestnmw => self.compare(instr, |a, b| a & b == 0)?,
+            Mnemonic::Vpsrlw | Mnemonic::Vpsllw => {
+                let src = self.vector(instr, 1)?;
+                let shift = u32::from(instr.immediate8());
+                let mut res = [0; 64];
+                for i in 0..32 {
+                    let val = element(&src, 2, i);
+                    let val = if shift >= 16 {
+                        0
+                    } else if instr.mnemonic() == Mnemonic::Vpsrlw {
+                        val >> shift
+                    } else {
+                        val << shift
+                    };
+                    set_element(&mut res, 2, i, val);
+                }
+                self.write_vector(instr, 2, &res)?;
+            }
+            Mnemonic::Vpternlogd => {
+                let a = self.zmm[instr.op0_register().number()];
+                let (b, c) = (self.vector(instr, 1)?, self.vector(instr, 2)?);
+                let imm = instr.immediate8();
+
+                let mut res = [0; 64];
+                for (i, res) in res.iter_mut().enumerate() {
+                    for bit in 0..8 {
+                        let index = ((a[i] >> bit) & 1) << 2
+                            | ((b[i] >> bit) & 1) << 1
+                            | ((c[i] >> bit) & 1);
+                        *res |= ((imm >> index) & 1) << bit;
+                    }
+                }
+                self.write_vector(instr, 4, &res)?;
+            }
+            Mnemonic::Vpmovzxwd => {
+                let src = self.vector(instr, 1)?;
+                let mut res = [0; 64];
+                for i in 0..16 {
+                    set_element(&mut res, 4, i, element(&src, 2, i));
+                }
+                self.write_vector(instr, 4, &res)?;
+            }
+            Mnemonic::Vpmovdw => {
+                let src = self.vector(instr, 1)?;
+                let mut res = [0; 64];
+                for i in 0..16 {
+                    set_element(&mut res, 2, i, element(&src, 4, i) & 0xffff);
+                }
+                self.write_vector(instr, 2, &res)?;
+            }
+            Mnemonic::Vextracti64x4 => {
+                let src = self.vector(instr, 1)?;
+                let half = usize::from(instr.immediate8() & 1) * 32;
+                let mut res = [0; 64];
+                res[..32].copy_from_slice(&src[half..half + 32]);
+                self.write_vector(instr, 8, &res)?;
+            }
+            Mnemonic::Vinserti64x4 => {
+                let mut res = self.vector(instr, 1)?;
+                let src = self.vector(instr, 2)?;
+                let half = usize::from(instr.immediate8() & 1) * 32;
+                res[half..half + 32].copy_from_slice(&src[..32]);
+                self.write_vector(instr, 8, &res)?;
+            }
+            Mnemonic::Vpgatherdd => {
+                let mut dest = self.zmm[instr.op0_register().number()];
+                for (i, addr) in self.vsib_addresses(instr) {
+                    // SAFETY: Gathers only address the lane memory
+                    let val = unsafe { Self::read(addr) };
+                    set_element(&mut dest, 4, i, u32::from_le_bytes(val));
+                }
+                self.zmm[instr.op0_register().number()] = dest;
+            }
+            Mnemonic::Vpscatterdd => {
+                let src = self.zmm[instr.op1_register().number()];
+                for (i, addr) in self.vsib_addresses(instr) {
+                    // SAFETY: Scatters only address the lane memory
+                    unsafe { Self::write(addr, element(&src, 4, i).to_le_bytes()) };
+                }
+            }
+            _ => bail!("Instruction not supported by the model: {instr}"),
+        }
+
+        Ok(Some(next))
+    }
+}
+
+impl<const N: usize> JitBuffer<N> {
+    /// Evaluate the JIT code starting at `offset` against the given emulator `state` in
+    /// the software model instead of running it on the CPU
+    ///
+    /// Like [`JitBuffer::run_from`], the code is entered through the trampoline and runs
+    /// until the first `ret`.
+    pub fn model_from(&self, offset: isize, state: &mut JitEmulatorState) -> Result<()> {
+        ensure!(!self.full, "JIT buffer is full");
+        ensure!(
+            (self.code_start..self.pool_start).contains(&offset),
+            "JIT offset {offset:#x} is outside of the code"
+        );
+
+        // SAFETY: The buffer is an allocation of N bytes
+        let code = unsafe { std::slice::from_raw_parts(self.buffer, N) };
+        let start = self.buffer as u64;
+        let mut decoder = Decoder::with_ip(64, code, start, DecoderOptions::NONE);
+
+        let mut model = Model {
+            zmm: [[0; 64]; 32],
+            k: [0; 8],
+            gpr: [0; 16],
+            stack: Vec::new(),
+        };
+
+        // Arguments of the trampoline
+        model.gpr[Register::RDX.number()] = state.memory.as_mut_ptr() as u64;
+        model.gpr[Register::RDI.number()] = std::ptr::addr_of_mut!(*state) as u64;
+        model.gpr[Register::RSI.number()] = start + offset as u64;
+
+        let mut ip = start;
+        for _ in 0..MAX_STEPS {
+            decoder.set_position((ip - start) as usize)?;
+            decoder.set_ip(ip);
+
+            let instr = decoder.decode();
+            ensure!(
+                !instr.is_invalid(),
+                "Invalid JIT instruction at {:#x}",
+                ip - start
+            );
+
+            match model.step(&instr)? {
+                Some(next) => ip = next,
+                None => return Ok(()),
+            }
+        }
+
+        bail!("JIT code did not return after {MAX_STEPS} instructions")
+    }
+}
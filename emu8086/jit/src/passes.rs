@@ -0,0 +1,457 @@
+//! Optimization passes over the IL of a block
+//!
+//! A block is translated into one optional [`JitIL`] instruction per 8086 instruction. The
+//! passes rewrite or remove these instructions in place, so the code of every 8086
+//! instruction stays attributed to it in the listing. The [`PASSES`] run in order:
+//!
+//! * Dead flags: only compute the status flags read before being overwritten, and remove
+//!   the comparisons left without any
+//! * Constants: fold instructions whose operands are known into a broadcast of the result
+//!   and drop arithmetic with an identity operand
+//! * Zero vectors: read zero operands from a register known to be zero instead of
+//!   broadcasting another zero vector
+//! * Redundant moves: drop moves of a value already in the destination and writes that are
+//!   overwritten before being read
+//!
+//! Every register and status flag is treated as read after the block, and an instruction
+//! computing status flags is never removed.
+
+use cpu8086::flags::STATUS_FLAGS;
+
+use crate::{eliminate_dead_flags, AluOp, AvxOperand, BytePart, JitIL, RegisterOperand, Zmm};
+
+/// Number of zmm registers tracked by the passes
+const REGISTERS: usize = 32;
+
+/// An optimization pass over the IL of a block
+pub type Pass = fn(&mut [Option<JitIL>]);
+
+/// The passes run over every block in order, along with their names
+pub const PASSES: [(&str, Pass); 4] = [
+    ("dead flags", dead_flags),
+    ("constants", fold_constants),
+    ("zero vectors", reuse_zero_vectors),
+    ("redundant moves", remove_redundant_moves),
+];
+
+/// Run the [`PASSES`] over `block`, calling `dump` with the name and IL of the block
+/// before the first pass (named "input") and after each pass
+pub fn optimize(block: &mut [Option<JitIL>], mut dump: impl FnMut(&str, &[Option<JitIL>])) {
+    dump("input", block);
+    for (name, pass) in PASSES {
+        pass(block);
+        dump(name, block);
+    }
+}
+
+/// Split an arithmetic or logic `instr` into its operation, destination, operands and
+/// computed status flags
+fn alu(instr: &JitIL) -> Option<(AluOp, RegisterOperand, AvxOperand, AvxOperand, u16)> {
+    let (op, dest, op1, op2, flags) = match *instr {
+        JitIL::Add {
+            dest,
+            op1,
+            op2,
+            flags,
+        } => (AluOp::Add, dest, op1, op2, flags),
+        JitIL::Sub {
+            dest,
+            op1,
+            op2,
+            flags,
+        } => (AluOp::Sub, dest, op1, op2, flags),
+        JitIL::And {
+            dest,
+            op1,
+            op2,
+            flags,
+        } => (AluOp::And, dest, op1, op2, flags),
+        JitIL::Or {
+            dest,
+            op1,
+            op2,
+            flags,
+        } => (AluOp::Or, dest, op1, op2, flags),
+        JitIL::Xor {
+            dest,
+            op1,
+            op2,
+            flags,
+        } => (AluOp::Xor, dest, op1, op2, flags),
+        _ => return None,
+    };
+
+    Some((op, dest, op1.into(), op2, flags))
+}
+
+/// Get the status flags computed by `instr`
+fn flags_written(instr: &JitIL) -> u16 {
+    let mut instr = *instr;
+    instr.flags_mut().map_or(0, |flags| *flags)
+}
+
+/// Get the `part` byte of `word`
+fn byte(word: u16, part: BytePart) -> u16 {
+    match part {
+        BytePart::Low => word & 0xff,
+        BytePart::High => word >> 8,
+    }
+}
+
+/// Returns `true` if both operands are the same register (or the same byte of it)
+fn same_register(op1: &AvxOperand, op2: &AvxOperand) -> bool {
+    match (op1, op2) {
+        (AvxOperand::Zmm(a), AvxOperand::Zmm(b)) => a.0 == b.0,
+        (AvxOperand::Byte(a, a_part), AvxOperand::Byte(b, b_part)) => {
+            a.0 == b.0 && a_part == b_part
+        }
+        _ => false,
+    }
+}
+
+/// The words known to be in each register at a point of the block
+struct KnownValues([Option<u16>; REGISTERS]);
+
+impl KnownValues {
+    fn new() -> Self {
+        Self([None; REGISTERS])
+    }
+
+    /// Get the value of `operand` if it is known, zero extended for a byte
+    fn get(&self, operand: &AvxOperand) -> Option<u16> {
+        match *operand {
+            #[allow(clippy::cast_sign_loss)]
+            AvxOperand::Immediate(imm) => Some(imm as u16),
+            AvxOperand::Zmm(zmm) => self.0[usize::from(zmm.0)],
+            AvxOperand::Byte(zmm, part) => self.0[usize::from(zmm.0)].map(|word| byte(word, part)),
+            AvxOperand::Memory(_) => None,
+        }
+    }
+
+    /// Get the word in `dest` after writing `value` to it, if it is known
+    ///
+    /// Only the low byte of `value` is written to a byte, so the other byte of the register
+    /// must be known.
+    fn merge(&self, dest: RegisterOperand, value: u16) -> Option<u16> {
+        let old = self.0[usize::from(dest.zmm.0)];
+        match dest.byte {
+            None => Some(value),
+            Some(BytePart::Low) => old.map(|old| old & 0xff00 | value & 0xff),
+            Some(BytePart::High) => old.map(|old| old & 0x00ff | (value & 0xff) << 8),
+        }
+    }
+
+    /// Get the word `instr` writes to its destination register, if it is known
+    fn result(&self, instr: &JitIL) -> Option<u16> {
+        let dest = instr.register_written()?;
+        let value = match *instr {
+            JitIL::Mov { src, .. } => self.get(&src)?,
+            _ => {
+                let (op, _, op1, op2, _) = alu(instr)?;
+                if matches!(op, AluOp::Sub | AluOp::Xor) && same_register(&op1, &op2) {
+                    // x - x and x ^ x are zero whatever x is
+                    0
+                } else {
+                    op.apply(self.get(&op1)?, self.get(&op2)?)
+                }
+            }
+        };
+
+        self.merge(dest, value)
+    }
+
+    /// Get a register known to be zero
+    fn zero_register(&self) -> Option<Zmm> {
+        #[allow(clippy::cast_possible_truncation)]
+        (0..REGISTERS)
+            .find(|&reg| self.0[reg] == Some(0))
+            .map(|reg| Zmm(reg as u8))
+    }
+
+    /// Update the known values after `instr`
+    fn update(&mut self, instr: &JitIL) {
+        if let Some(dest) = instr.register_written() {
+            self.0[usize::from(dest.zmm.0)] = self.result(instr);
+        }
+    }
+}
+
+/// Only compute the status flags that are read before being overwritten, removing the
+/// comparisons that no longer compute any
+fn dead_flags(block: &mut [Option<JitIL>]) {
+    eliminate_dead_flags(block.iter_mut().flatten(), STATUS_FLAGS);
+
+    for slot in block.iter_mut() {
+        if let Some(JitIL::Cmp { flags: 0, .. } | JitIL::Test { flags: 0, .. }) = slot {
+            *slot = None;
+        }
+    }
+}
+
+/// Replace the instructions without status flags whose result is known by a broadcast of
+/// the result, and remove the ones leaving their destination unchanged
+fn fold_constants(block: &mut [Option<JitIL>]) {
+    let mut known = KnownValues::new();
+
+    for slot in block.iter_mut() {
+        let Some(instr) = slot else {
+            continue;
+        };
+
+        if let Some((op, dest, op1, op2, 0)) = alu(instr) {
+            // Only the low byte of the operand is used by byte operations
+            let mask = if dest.byte.is_some() { 0xff } else { 0xffff };
+            let identity = known.get(&op2).map(|value| value & mask) == Some(op.identity() & mask);
+            if identity && same_register(&op1, &dest.into()) {
+                *slot = None;
+                continue;
+            }
+
+            // Zero the destination without reading it, even if its value isn't known
+            if matches!(op, AluOp::Sub | AluOp::Xor) && same_register(&op1, &op2) {
+                *instr = JitIL::Mov {
+                    dest,
+                    src: AvxOperand::Immediate(0),
+                };
+            }
+        }
+
+        let broadcast = matches!(
+            instr,
+            JitIL::Mov {
+                dest: RegisterOperand { byte: None, .. },
+                src: AvxOperand::Immediate(_),
+            }
+        );
+
+        if flags_written(instr) == 0 && !broadcast {
+            if let (Some(dest), Some(value)) = (instr.register_written(), known.result(instr)) {
+                #[allow(clippy::cast_possible_wrap)]
+                let src = AvxOperand::Immediate(value as i16);
+                *instr = JitIL::Mov {
+                    dest: dest.zmm.into(),
+                    src,
+                };
+            }
+        }
+
+        known.update(instr);
+    }
+}
+
+/// Read the zero operands from a register known to be zero, so no zero vector is
+/// broadcast for them
+fn reuse_zero_vectors(block: &mut [Option<JitIL>]) {
+    let mut known = KnownValues::new();
+
+    for instr in block.iter_mut().flatten() {
+        if let Some(zero) = known.zero_register() {
+            let operands = match instr {
+                JitIL::Sub { op2, .. }
+                | JitIL::Add { op2, .. }
+                | JitIL::And { op2, .. }
+                | JitIL::Or { op2, .. }
+                | JitIL::Xor { op2, .. } => vec![op2],
+                JitIL::Cmp { left, right, .. } | JitIL::Test { left, right, .. } => {
+                    vec![left, right]
+                }
+                JitIL::Store { src, .. } => vec![src],
+                _ => Vec::new(),
+            };
+
+            for operand in operands {
+                if matches!(operand, AvxOperand::Immediate(0)) {
+                    *operand = AvxOperand::Zmm(zero);
+                }
+            }
+        }
+
+        known.update(instr);
+    }
+}
+
+/// Remove moves of a value already in the destination, and the instructions without
+/// status flags whose destination is overwritten before being read
+fn remove_redundant_moves(block: &mut [Option<JitIL>]) {
+    let mut known = KnownValues::new();
+
+    // Registers known to hold the same word as another register
+    let mut copies: [Option<u8>; REGISTERS] = [None; REGISTERS];
+
+    for slot in block.iter_mut() {
+        let Some(instr) = slot else {
+            continue;
+        };
+
+        if let JitIL::Mov { dest, src } = *instr {
+            let held = known.get(&dest.into());
+            let value = known.get(&src).map(|value| match dest.byte {
+                Some(_) => value & 0xff,
+                None => value,
+            });
+            let copied = match (dest.byte, src) {
+                (None, AvxOperand::Zmm(src)) => {
+                    src.0 == dest.zmm.0
+                        || copies[usize::from(dest.zmm.0)] == Some(src.0)
+                        || copies[usize::from(src.0)] == Some(dest.zmm.0)
+                }
+                (Some(_), src) => same_register(&dest.into(), &src),
+                _ => false,
+            };
+
+            if copied || (held.is_some() && held == value) {
+                *slot = None;
+                continue;
+            }
+        }
+
+        if let Some(dest) = instr.register_written() {
+            let reg = dest.zmm.0;
+            copies[usize::from(reg)] = None;
+            for copy in &mut copies {
+                if *copy == Some(reg) {
+                    *copy = None;
+                }
+            }
+
+            if let JitIL::Mov {
+                dest: RegisterOperand { byte: None, .. },
+                src: AvxOperand::Zmm(src),
+            } = instr
+            {
+                copies[usize::from(reg)] = Some(src.0);
+            }
+        }
+
+        known.update(instr);
+    }
+
+    // Walk backwards to find the registers read later in the block
+    let mut live = [true; REGISTERS];
+    for slot in block.iter_mut().rev() {
+        let Some(instr) = slot else {
+            continue;
+        };
+
+        if let Some(dest) = instr.register_written() {
+            let reg = usize::from(dest.zmm.0);
+
+            // The loop also branches, so it always stays
+            if !live[reg] && !matches!(instr, JitIL::Loop { .. }) {
+                match alu(instr) {
+                    // Only the flags of a subtraction or and are needed
+                    Some((AluOp::Sub, _, left, right, flags)) if flags != 0 => {
+                        *instr = JitIL::Cmp { left, right, flags };
+                    }
+                    Some((AluOp::And, _, left, right, flags)) if flags != 0 => {
+                        *instr = JitIL::Test { left, right, flags };
+                    }
+                    Some((.., flags)) if flags != 0 => {}
+                    _ => {
+                        *slot = None;
+                        continue;
+                    }
+                }
+            }
+
+            // Writing a byte keeps the other byte, which is read below
+            if dest.byte.is_none() {
+                live[reg] = false;
+            }
+        }
+
+        for reg in instr.registers_read() {
+            live[usize::from(reg.0)] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JitRegister;
+
+    #[test]
+    fn test_passes() {
+        let ax = JitRegister::ax.as_zmm();
+        let bx = JitRegister::bx.as_zmm();
+        let cx = JitRegister::cx.as_zmm();
+        let dx = JitRegister::dx.as_zmm();
+        let mov = |dest: Zmm, src: AvxOperand| {
+            Some(JitIL::Mov {
+                dest: dest.into(),
+                src,
+            })
+        };
+        let add = |dest: Zmm, src: AvxOperand| {
+            Some(JitIL::Add {
+                dest: dest.into(),
+                op1: dest.into(),
+                op2: src,
+                flags: STATUS_FLAGS,
+            })
+        };
+
+        let mut block = [
+            // cmp ax, bx is dead as its flags are overwritten by the add below
+            Some(JitIL::Cmp {
+                left: AvxOperand::Zmm(ax),
+                right: AvxOperand::Zmm(bx),
+                flags: STATUS_FLAGS,
+            }),
+            // mov ax, 5; add ax, 3 folds into mov ax, 8, which is overwritten below
+            mov(ax, AvxOperand::Immediate(5)),
+            add(ax, AvxOperand::Immediate(3)),
+            // add cx, 0 without live flags leaves cx unchanged
+            mov(bx, AvxOperand::Immediate(0)),
+            add(cx, AvxOperand::Immediate(0)),
+            // sub dx, dx without live flags zeroes dx and cmp cx, 0 reads the zero in bx
+            Some(JitIL::Sub {
+                dest: dx.into(),
+                op1: dx.into(),
+                op2: AvxOperand::Zmm(dx),
+                flags: STATUS_FLAGS,
+            }),
+            Some(JitIL::Cmp {
+                left: AvxOperand::Zmm(cx),
+                right: AvxOperand::Immediate(0),
+                flags: STATUS_FLAGS,
+            }),
+            // mov ax, bx propagates the zero in bx, and moving it again is redundant
+            mov(ax, AvxOperand::Zmm(bx)),
+            mov(ax, AvxOperand::Zmm(bx)),
+        ];
+
+        let mut dumps = Vec::new();
+        optimize(&mut block, |name, _| dumps.push(name.to_string()));
+        assert_eq!(
+            dumps,
+            [
+                "input",
+                "dead flags",
+                "constants",
+                "zero vectors",
+                "redundant moves"
+            ]
+        );
+
+        let block: Vec<_> = block
+            .iter()
+            .map(|instr| instr.map(|instr| instr.to_string()))
+            .collect();
+        assert_eq!(
+            block,
+            [
+                None,
+                None,
+                None,
+                Some("mov bx, 0x0".to_string()),
+                None,
+                Some("mov dx, 0x0".to_string()),
+                Some("cmp cx, bx ; CPAZSO".to_string()),
+                Some("mov ax, 0x0".to_string()),
+                None,
+            ]
+        );
+    }
+}
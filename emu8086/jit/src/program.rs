@@ -0,0 +1,1161 @@
+//! Runs a whole 8086 program on every lane with divergent control flow
+//!
+//! The program is translated one basic block at a time and every lane keeps its own CS:IP.
+//! Each step runs the block at the lowest linear address `CS * 16 + IP` of the running
+//! lanes with only the lanes at that address and in the same code segment enabled in the
+//! exec kmask. Lanes that branched ahead wait until the lanes behind
+//! them catch up, so the lanes reconverge after both sides of a branch. A lane stops once
+//! its linear address leaves the program, it executes `hlt`, it reaches an instruction
+//! that can't be translated, which faults it, or it used up the budget of the state before
+//! a block. Each lane's [`LaneStatus`] records why it stopped, and the blocks it ran are
+//! recorded in the [`BlockCoverage`](jit_emu::BlockCoverage) of the state.
+//!
+//! Each lane runs the code in its own memory, so the program must also be loaded into the
+//! lane memory. A block only runs for the lanes whose memory still holds the bytes it was
+//! translated from. When none of the lanes at a block do, the blocks translated from the
+//! bytes that changed are invalidated and the code of the first lane is translated instead.
+//! Writes by a block into its own code are seen from the next block on.
+//!
+//! When the [`JitBuffer`] is full, every translated block is flushed and translation starts
+//! over in the empty buffer.
+
+use anyhow::{ensure, Result};
+
+use std::ops::Range;
+
+use cpu8086::decoder::decode_instruction;
+use cpu8086::emu::RegisterState;
+use cpu8086::instruction::{Instruction, Operand};
+use cpu8086::memory::Memory;
+use cpu8086::register::SegmentRegister;
+use jit_emu::{Core, Fault, JitEmulatorState, LaneStatus, MEMORY_SIZE};
+
+use crate::{optimize, simd, Avx2Buffer, AvxOperand, Backend, Condition, JitBuffer, JitIL};
+use crate::{JitRegister, Listing, ListingEntry};
+
+/// Maximum number of instructions translated into a single block
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+/// Number of lanes in the [`JitEmulatorState`]
+const LANES: usize = 32;
+
+/// Memory holding the program being translated
+type CodeMemory = Memory<{ 64 * 1024 }>;
+
+/// Load `code` at address 0 of a new [`CodeMemory`]
+fn code_memory(code: &[u8]) -> Box<CodeMemory> {
+    let mut memory = Box::new(CodeMemory::new());
+    memory.memory[..code.len()].copy_from_slice(code);
+    memory.length = code.len();
+    memory
+}
+
+/// Bytes the decoder may read for a single instruction, including its prefixes
+const FETCH_WINDOW: usize = 16;
+
+/// Decode the instruction at the IP of `cpu` in `code`, failing with
+/// [`OutOfBoundsRead`](cpu8086::memory::Error::OutOfBoundsRead) if it runs past the end of
+/// the program
+fn decode(cpu: &mut RegisterState, code: &CodeMemory) -> Result<Instruction> {
+    let ip = cpu.ip();
+    let start = usize::from(ip);
+
+    // The decoder indexes the memory directly, so decode the last bytes of the memory from
+    // a zero padded copy to keep it from reading past the end
+    let instr = if start + FETCH_WINDOW <= code.memory.len() {
+        decode_instruction(cpu, code)?
+    } else {
+        let tail = code_memory(&code.memory[start..]);
+        let mut tail_cpu = RegisterState::default();
+        let instr = decode_instruction(&mut tail_cpu, &tail)?;
+        *cpu.ip_mut() = ip.wrapping_add(tail_cpu.ip());
+        instr
+    };
+
+    let end = start + usize::from(cpu.ip().wrapping_sub(ip));
+    ensure!(
+        end <= code.length,
+        cpu8086::memory::Error::OutOfBoundsRead(cpu8086::memory::Address(start))
+    );
+
+    Ok(instr)
+}
+
+/// Get the fault of a lane fetching an instruction that failed to decode with `err`
+fn decode_fault(err: &anyhow::Error) -> Fault {
+    match err.downcast_ref::<cpu8086::memory::Error>() {
+        Some(cpu8086::memory::Error::OutOfBoundsRead(_)) => Fault::OutOfBounds,
+        None => Fault::UnsupportedInstruction,
+    }
+}
+
+/// Returns `true` if `instr` can be translated into a block
+pub fn translatable(instr: &Instruction) -> bool {
+    branch(instr, 0).is_some()
+        || matches!(instr, Instruction::Halt)
+        || (!instr.ends_basic_block() && JitIL::supports(instr))
+}
+
+/// Statistics of a [`JitProgram`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct JitProgramStats {
+    /// Number of blocks translated
+    pub blocks_translated: u64,
+
+    /// Number of instructions translated
+    pub instructions_translated: u64,
+
+    /// Number of blocks executed, each by one or more lanes
+    pub blocks_executed: u64,
+
+    /// Number of instructions executed summed over the lanes executing them
+    pub lane_instructions: u64,
+
+    /// Number of blocks invalidated after a lane wrote into their code
+    pub blocks_invalidated: u64,
+
+    /// Number of times every block was flushed from a full buffer
+    pub flushes: u64,
+}
+
+/// An 8086 instruction along with the JIT code it was translated into
+#[derive(Debug, Clone)]
+pub struct TranslatedInstruction {
+    /// IP of the instruction
+    pub ip: u16,
+
+    /// The decoded instruction
+    pub instr: Instruction,
+
+    /// Encoded bytes of the instruction
+    pub bytes: Vec<u8>,
+
+    /// Offsets of the instruction's code in the [`JitBuffer`]
+    pub jit: Range<isize>,
+}
+
+/// A translated basic block
+#[derive(Debug, Copy, Clone)]
+struct Block {
+    /// Offset of the block's code in the [`JitBuffer`]
+    offset: isize,
+
+    /// Start and end of the block's IL in [`JitProgram::il`]
+    il: (usize, usize),
+
+    /// Start and end of the 8086 code the block was translated from
+    code: (usize, usize),
+
+    /// Code segment the IPs of the block are relative to
+    cs: u16,
+
+    /// Number of instructions in the block
+    instructions: u64,
+
+    /// Estimated 8086 cycles of the instructions in the block
+    cycles: u64,
+
+    /// Set if the block ends with `hlt`
+    halts: bool,
+
+    /// Set if the lanes fault at the start of the block instead of running it
+    fault: Option<Fault>,
+}
+
+/// An 8086 program translated into a [`JitBuffer`] on demand
+pub struct JitProgram<const N: usize> {
+    /// Buffer holding the translated blocks
+    jit: JitBuffer<N>,
+
+    /// The program being translated
+    code: Box<CodeMemory>,
+
+    /// Translated blocks indexed by the linear address they start at
+    blocks: Vec<Option<Block>>,
+
+    /// Every translated instruction in translation order
+    instructions: Vec<TranslatedInstruction>,
+
+    /// The optimized IL of every translated block, run by [`Backend::Simd`]
+    il: Vec<JitIL>,
+
+    /// Buffer holding the AVX2 code of the blocks run by [`Backend::Avx2`], created on first
+    /// use
+    avx2: Option<Avx2Buffer<N>>,
+
+    /// Offset in `avx2` of the code of each block indexed by the linear address it starts
+    /// at
+    avx2_blocks: Vec<Option<isize>>,
+
+    /// Translation and execution counters
+    pub stats: JitProgramStats,
+
+    /// Lines of the IL of each translated block before and after each optimization pass,
+    /// if enabled
+    il_dump: Option<Vec<String>>,
+}
+
+/// Get the JIT branch for the conditional jump or loop `instr` whose following instruction
+/// is at `next_ip`
+fn branch(instr: &Instruction, next_ip: u16) -> Option<JitIL> {
+    use Condition::*;
+
+    let target = |offset: i8| next_ip.wrapping_add_signed(i16::from(offset) - 2);
+    let jump = |offset: i8, cond: Condition| JitIL::Branch {
+        cond,
+        taken: target(offset),
+        not_taken: next_ip,
+    };
+    let repeat = |offset: i8, cond: Option<Condition>| JitIL::Loop {
+        cond,
+        taken: target(offset),
+        not_taken: next_ip,
+    };
+
+    let il = match *instr {
+        Instruction::JumpEqual { offset } => jump(offset, Equal),
+        Instruction::JumpNotEqual { offset } => jump(offset, NotEqual),
+        Instruction::JumpBelow { offset } => jump(offset, Below),
+        Instruction::JumpNotBelow { offset } => jump(offset, NotBelow),
+        Instruction::JumpBelowEqual { offset } => jump(offset, BelowEqual),
+        Instruction::JumpNotBelowEqual { offset } => jump(offset, NotBelowEqual),
+        Instruction::JumpLessThan { offset } => jump(offset, Less),
+        Instruction::JumpNotLessThan { offset } => jump(offset, NotLess),
+        Instruction::JumpLessThanEqual { offset } => jump(offset, LessEqual),
+        Instruction::JumpNotLessThanEqual { offset } => jump(offset, NotLessEqual),
+        Instruction::JumpParityEven { offset } => jump(offset, ParityEven),
+        Instruction::JumpParityOdd { offset } => jump(offset, ParityOdd),
+        Instruction::JumpOverflow { offset } => jump(offset, Overflow),
+        Instruction::JumpNotOverflow { offset } => jump(offset, NotOverflow),
+        Instruction::JumpSign { offset } => jump(offset, Sign),
+        Instruction::JumpNotSign { offset } => jump(offset, NotSign),
+        Instruction::JumpCxZero { offset } => jump(offset, CxZero),
+        Instruction::Loop { offset } => repeat(offset, None),
+        Instruction::LoopWhileZero { offset } => repeat(offset, Some(Equal)),
+        Instruction::LoopWhileNotZero { offset } => repeat(offset, Some(NotEqual)),
+        _ => return None,
+    };
+
+    Some(il)
+}
+
+impl<const N: usize> JitProgram<N> {
+    /// Create a program executing `code` loaded at address 0
+    pub fn new(code: &[u8]) -> Result<Self> {
+        ensure!(
+            code.len() <= MEMORY_SIZE,
+            "Program of {} bytes is larger than the {MEMORY_SIZE} bytes of memory",
+            code.len()
+        );
+
+        Ok(Self {
+            jit: JitBuffer::new(),
+            code: code_memory(code),
+            blocks: vec![None; code.len()],
+            avx2: None,
+            avx2_blocks: vec![None; code.len()],
+            instructions: Vec::new(),
+            il: Vec::new(),
+            stats: JitProgramStats::default(),
+            il_dump: None,
+        })
+    }
+
+    /// Record the IL of each block translated from now on before and after each
+    /// optimization pass
+    pub fn enable_il_dump(&mut self) {
+        self.il_dump.get_or_insert_with(Vec::new);
+    }
+
+    /// Get the lines of the IL dump, empty unless enabled by [`JitProgram::enable_il_dump`]
+    pub fn il_dump(&self) -> &[String] {
+        self.il_dump.as_deref().unwrap_or_default()
+    }
+
+    /// Get the buffer holding the translated code
+    pub fn jit(&self) -> &JitBuffer<N> {
+        &self.jit
+    }
+
+    /// Get every translated instruction in translation order
+    pub fn instructions(&self) -> &[TranslatedInstruction] {
+        &self.instructions
+    }
+
+    /// Get the listing of every translated instruction next to its code in the
+    /// [`JitBuffer`]
+    pub fn listing(&self) -> Listing {
+        let entries = self
+            .instructions
+            .iter()
+            .map(|translated| ListingEntry {
+                ip: translated.ip,
+                bytes: translated.bytes.clone(),
+                instr: translated.instr.to_string(),
+                host: if translated.jit.is_empty() {
+                    Vec::new()
+                } else {
+                    self.jit
+                        .get_instructions_between(translated.jit.start, translated.jit.end)
+                },
+            })
+            .collect();
+
+        Listing { entries }
+    }
+
+    /// Get the block starting at the linear address `start` with the code segment `cs`,
+    /// translating it if needed
+    fn block(&mut self, start: u16, cs: u16) -> Result<Block> {
+        if let Some(block) = self.blocks[usize::from(start)].filter(|block| block.cs == cs) {
+            return Ok(block);
+        }
+
+        let block = self.translate(start, cs)?;
+        if !self.jit.is_full() {
+            return Ok(block);
+        }
+
+        // Start over in an empty buffer
+        self.flush();
+        let block = self.translate(start, cs)?;
+        ensure!(
+            !self.jit.is_full(),
+            "Block at {start:#x} does not fit in the {N} bytes JIT buffer"
+        );
+
+        Ok(block)
+    }
+
+    /// Discard every translated block
+    fn flush(&mut self) {
+        self.jit.flush();
+        if let Some(avx2) = &mut self.avx2 {
+            avx2.flush();
+        }
+
+        self.blocks.fill(None);
+        self.avx2_blocks.fill(None);
+        self.instructions.clear();
+        self.il.clear();
+        self.stats.flushes += 1;
+    }
+
+    /// Get the lanes of `exec_mask` whose memory holds the code `block` was translated from
+    fn matching_lanes(&self, block: Block, exec_mask: u32, state: &JitEmulatorState) -> u32 {
+        let (start, end) = block.code;
+        let code = &self.code.memory[start..end];
+
+        #[allow(clippy::cast_possible_truncation)]
+        (0..LANES)
+            .filter(|&lane| exec_mask & (1 << lane) != 0)
+            .filter(|&lane| state.memory.lane(Core(lane as u8))[start..end] == *code)
+            .fold(0, |mask, lane| mask | (1 << lane))
+    }
+
+    /// Replace the program with the code in the `memory` of a lane, invalidating the blocks
+    /// translated from bytes that changed
+    fn reload_code(&mut self, memory: &[u8]) {
+        for ip in 0..self.blocks.len() {
+            let Some(block) = self.blocks[ip] else {
+                continue;
+            };
+
+            let (start, end) = block.code;
+            if memory[start..end] != self.code.memory[start..end] {
+                self.blocks[ip] = None;
+                self.avx2_blocks[ip] = None;
+                self.stats.blocks_invalidated += 1;
+            }
+        }
+
+        self.code.memory.copy_from_slice(memory);
+    }
+
+    /// Get the block starting at `start` in the code segment `cs` for the lanes in
+    /// `exec_mask`, along with the lanes whose memory holds the code it was translated from
+    ///
+    /// If none of the lanes hold that code anymore, the block is translated again from the
+    /// code of the first lane. The lanes left out run the block once the others moved on.
+    fn block_for_lanes(
+        &mut self,
+        start: u16,
+        cs: u16,
+        exec_mask: u32,
+        state: &JitEmulatorState,
+    ) -> Result<(Block, u32)> {
+        let block = self.block(start, cs)?;
+        let matching = self.matching_lanes(block, exec_mask, state);
+        if matching != 0 {
+            return Ok((block, matching));
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let lane = Core(exec_mask.trailing_zeros() as u8);
+        self.reload_code(state.memory.lane(lane));
+
+        let block = self.block(start, cs)?;
+        let matching = self.matching_lanes(block, exec_mask, state);
+        debug_assert!(
+            matching & (1 << *lane) != 0,
+            "Lane {lane:?} runs other code"
+        );
+
+        Ok((block, matching))
+    }
+
+    /// Get the AVX2 code of the block starting at `start`, writing it if needed
+    fn avx2_block(&mut self, start: u16, block: Block) -> Result<isize> {
+        if let Some(offset) = self.avx2_blocks[usize::from(start)] {
+            return Ok(offset);
+        }
+
+        let il = &self.il[block.il.0..block.il.1];
+        let avx2 = self.avx2.get_or_insert_with(Avx2Buffer::new);
+        let mut offset = avx2.write_block(il);
+        if avx2.is_full() {
+            // The native blocks stay, only the AVX2 code starts over
+            avx2.flush();
+            self.avx2_blocks.fill(None);
+            offset = avx2.write_block(il);
+            ensure!(
+                !avx2.is_full(),
+                "Block at {start:#x} does not fit in the {N} bytes AVX2 buffer"
+            );
+        }
+
+        self.avx2_blocks[usize::from(start)] = Some(offset);
+        Ok(offset)
+    }
+
+    /// Translate the block starting at the linear address `start` with the code segment `cs`
+    fn translate(&mut self, start: u16, cs: u16) -> Result<Block> {
+        // Decode from the linear address, converting the IPs back to offsets in `cs`. Like
+        // every linear address in the lane memory, the base of `cs` wraps at 64K.
+        let base = cs << 4;
+        let mut cpu = RegisterState::default();
+        *cpu.ip_mut() = start;
+
+        // Decode the whole block first so only the flags read later are computed
+        let mut decoded = Vec::new();
+        let mut halts = false;
+        let mut end_ip = None;
+        let mut block_end;
+
+        loop {
+            let ip = cpu.ip().wrapping_sub(base);
+            let linear = cpu.ip();
+            let instr = match decode(&mut cpu, &self.code) {
+                Ok(instr) if translatable(&instr) => instr,
+                res => {
+                    let fault = res
+                        .map_or_else(|err| decode_fault(&err), |_| Fault::UnsupportedInstruction);
+
+                    // The lanes run the instructions before the fault first, and only fault
+                    // once they reach it
+                    if decoded.is_empty() {
+                        let len = cpu.ip().wrapping_sub(linear).max(1);
+                        return Ok(self.fault_block(start, cs, len, fault));
+                    }
+
+                    end_ip = Some(ip);
+                    block_end = linear;
+                    break;
+                }
+            };
+            let next_ip = cpu.ip().wrapping_sub(base);
+            let linear_end = cpu.ip();
+            let ends_block = instr.ends_basic_block();
+
+            // Moving into CS changes where the next instruction is fetched from
+            let writes_cs = matches!(
+                instr,
+                Instruction::Mov {
+                    dest: Operand::SegmentRegister(SegmentRegister::Cs),
+                    ..
+                }
+            );
+
+            // Branches set the IP of each lane, every other block end continues after the
+            // last instruction
+            let branch = branch(&instr, next_ip);
+            let il = match (branch, &instr) {
+                (Some(il), _) => Some(il),
+                (None, Instruction::Halt) => {
+                    halts = true;
+                    None
+                }
+                (None, _) => Some(JitIL::from(instr.clone())),
+            };
+
+            decoded.push((ip, instr, il));
+            block_end = linear_end;
+
+            let last = ends_block
+                || writes_cs
+                || decoded.len() == MAX_BLOCK_INSTRUCTIONS
+                || usize::from(linear_end) >= self.code.length;
+
+            if last {
+                if branch.is_none() {
+                    end_ip = Some(next_ip);
+                }
+                break;
+            }
+        }
+
+        // Instructions removed by the passes are left without any code
+        let mut optimized: Vec<Option<JitIL>> = decoded.iter().map(|(_, _, il)| *il).collect();
+        optimize(&mut optimized, |pass, block| {
+            let Some(dump) = &mut self.il_dump else {
+                return;
+            };
+
+            dump.push(format!("== {start:#06x} {pass} =="));
+            for ((ip, ..), il) in decoded.iter().zip(block) {
+                match il {
+                    Some(il) => dump.push(format!("{ip:#06x} {il}")),
+                    None => dump.push(format!("{ip:#06x} -")),
+                }
+            }
+        });
+
+        for ((.., il), optimized) in decoded.iter_mut().zip(optimized) {
+            *il = optimized;
+        }
+
+        // Without a branch, the block ends by moving every lane to the next instruction
+        #[allow(clippy::cast_possible_wrap)]
+        let end = end_ip.map(|end_ip| JitIL::Mov {
+            dest: JitRegister::ip.as_zmm().into(),
+            src: AvxOperand::Immediate(end_ip as i16),
+        });
+
+        let offset = self.jit.offset;
+        let instructions = decoded.len();
+        let cycles = decoded
+            .iter()
+            .map(|(_, instr, _)| instr.estimated_cycles())
+            .sum();
+
+        let block_il: Vec<JitIL> = decoded
+            .iter()
+            .filter_map(|(_, _, il)| *il)
+            .chain(end)
+            .collect();
+        self.jit.begin_block(&block_il);
+
+        let il_range = (self.il.len(), self.il.len() + block_il.len());
+        self.il.extend(block_il);
+
+        // Each instruction ends where the next one starts
+        let ends: Vec<u16> = decoded
+            .iter()
+            .skip(1)
+            .map(|(ip, ..)| ip.wrapping_add(base))
+            .chain([block_end])
+            .collect();
+
+        for (i, ((ip, instr, il), next)) in decoded.into_iter().zip(ends).enumerate() {
+            let jit_start = self.jit.offset;
+            let linear = ip.wrapping_add(base);
+            let bytes = (0..next.wrapping_sub(linear))
+                .map(|i| self.code.memory[usize::from(linear.wrapping_add(i))])
+                .collect();
+
+            if let Some(il) = il {
+                self.jit.write_instr(il);
+            }
+
+            if let Some(end) = end.filter(|_| i == instructions - 1) {
+                self.jit.write_instr(end);
+            }
+
+            self.instructions.push(TranslatedInstruction {
+                ip,
+                instr,
+                bytes,
+                jit: jit_start..self.jit.offset,
+            });
+        }
+
+        self.jit.ret();
+
+        self.stats.blocks_translated += 1;
+        self.stats.instructions_translated += instructions as u64;
+
+        let code_start = usize::from(start);
+        let code_end = (code_start + usize::from(block_end.wrapping_sub(start))).min(MEMORY_SIZE);
+
+        let block = Block {
+            offset,
+            il: il_range,
+            code: (code_start, code_end),
+            cs,
+            instructions: instructions as u64,
+            cycles,
+            halts,
+            fault: None,
+        };
+        self.blocks[usize::from(start)] = Some(block);
+        self.avx2_blocks[usize::from(start)] = None;
+
+        Ok(block)
+    }
+
+    /// Record that the lanes at the linear address `start` with the code segment `cs` fault
+    /// on the `len` bytes there
+    fn fault_block(&mut self, start: u16, cs: u16, len: u16, fault: Fault) -> Block {
+        let code_start = usize::from(start);
+        let block = Block {
+            offset: self.jit.offset,
+            il: (self.il.len(), self.il.len()),
+            code: (code_start, (code_start + usize::from(len)).min(MEMORY_SIZE)),
+            cs,
+            instructions: 0,
+            cycles: 0,
+            halts: false,
+            fault: Some(fault),
+        };
+        self.blocks[code_start] = Some(block);
+        self.avx2_blocks[code_start] = None;
+
+        block
+    }
+
+    /// Run every lane of `state` until none of them is running, returning the number of
+    /// instructions executed (counting an instruction executed by several lanes at once
+    /// only once).
+    ///
+    /// The blocks run on the [`Backend::detect`]ed backend of the host, or evaluate their IL
+    /// with [`Backend::Simd`] on hosts without AVX2.
+    pub fn run(&mut self, state: &mut JitEmulatorState) -> Result<u64> {
+        let backend = match Backend::detect() {
+            Backend::Model => Backend::Simd,
+            backend => backend,
+        };
+        self.run_on(state, backend)
+    }
+
+    /// Like [`JitProgram::run`], but execute the translated blocks using `backend`
+    ///
+    /// The [`Budget`](cpu8086::budget::Budget) of `state` is checked on the host before each
+    /// block, not inside the JIT code, so a lane stops with
+    /// [`LaneStatus::BudgetExhausted`] only at the first block boundary after it ran out.
+    /// It can overrun the budget by the rest of that block, at most 63 instructions.
+    pub fn run_on(&mut self, state: &mut JitEmulatorState, backend: Backend) -> Result<u64> {
+        let mut executed = 0;
+
+        loop {
+            // Each lane fetches from the linear address of its CS:IP, wrapping at 64K
+            let css = state.cs.to_array();
+            let ips = (state.ip + (state.cs << 4)).to_array();
+
+            // Lanes whose CS:IP left the program have nothing left to run
+            let running_mask = state.running_mask();
+            let running = (0..LANES).filter(|&lane| running_mask & (1 << lane) != 0);
+            let exited = running
+                .clone()
+                .filter(|&lane| usize::from(ips[lane]) >= self.code.length)
+                .fold(0, |mask, lane| mask | (1 << lane));
+            state.set_status(exited, LaneStatus::Exited);
+            let running = running.filter(|&lane| exited & (1 << lane) == 0);
+
+            // Reconverge by always running the lanes furthest behind
+            let Some(target) = running.clone().map(|lane| ips[lane]).min() else {
+                break;
+            };
+
+            // Lanes reaching the same code through different segments run it separately
+            let mut at_target = running.filter(|&lane| ips[lane] == target).peekable();
+            let cs = css[*at_target.peek().expect("The target is the IP of a lane")];
+            let exec_mask = at_target
+                .filter(|&lane| css[lane] == cs)
+                .fold(0, |mask, lane| mask | (1 << lane));
+
+            // The budget is checked at block boundaries, so a lane can overrun it by the
+            // rest of its last block
+            let exhausted = (0..LANES)
+                .filter(|&lane| exec_mask & (1 << lane) != 0)
+                .filter(|&lane| state.budget.is_exhausted(state.spent[lane]))
+                .fold(0, |mask, lane| mask | (1 << lane));
+            if exhausted != 0 {
+                state.set_status(exhausted, LaneStatus::BudgetExhausted);
+                continue;
+            }
+
+            let (block, exec_mask) = self.block_for_lanes(target, cs, exec_mask, state)?;
+            state.exec_mask = exec_mask;
+
+            if let Some(fault) = block.fault {
+                state.set_status(exec_mask, LaneStatus::Faulted(fault));
+                continue;
+            }
+
+            match backend {
+                // SAFETY: Every block is written after a `begin_block`
+                Backend::Native => unsafe { self.jit.run_from(block.offset, state) },
+                Backend::Model => self.jit.model_from(block.offset, state)?,
+                Backend::Simd => simd::run_block(&self.il[block.il.0..block.il.1], state),
+                Backend::Avx2 => {
+                    let offset = self.avx2_block(target, block)?;
+                    let avx2 = self
+                        .avx2
+                        .as_ref()
+                        .expect("AVX2 buffer is created with the block");
+
+                    // SAFETY: The offset was returned by `write_block`
+                    unsafe { avx2.run_from(offset, state) }
+                }
+            }
+
+            if block.halts {
+                state.set_status(exec_mask, LaneStatus::Halted);
+            }
+
+            state.coverage.record(exec_mask, target);
+            for lane in (0..LANES).filter(|&lane| exec_mask & (1 << lane) != 0) {
+                let spent = &mut state.spent[lane];
+                spent.instructions += block.instructions;
+                spent.cycles += block.cycles;
+            }
+
+            self.stats.blocks_executed += 1;
+            self.stats.lane_instructions += block.instructions * u64::from(exec_mask.count_ones());
+            executed += block.instructions;
+        }
+
+        state.exec_mask = u32::MAX;
+
+        Ok(executed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::backends;
+    use cpu8086::budget::Spent;
+    use jit_emu::{CpuState, LaneInput};
+
+    #[test]
+    fn test_divergent_lanes() {
+        #[rustfmt::skip]
+        let code = [
+            0xb8, 0x00, 0x00,       // 0x00: mov ax, 0
+            0x89, 0xca,             // 0x03: mov dx, cx
+            0x83, 0xea, 0x0a,       // 0x05: sub dx, 10
+            0x78, 0x03,             // 0x08: js 0x0d
+            0x05, 0xe8, 0x03,       // 0x0a: add ax, 1000
+            0xe3, 0x04,             // 0x0d: jcxz 0x13
+            0x01, 0xc8,             // 0x0f: add ax, cx
+            0xe2, 0xfc,             // 0x11: loop 0x0f
+            0x89, 0x06, 0x00, 0x01, // 0x13: mov [0x100], ax
+            0xf4,                   // 0x17: hlt
+        ];
+
+        for backend in backends() {
+            let mut state = JitEmulatorState::default();
+            state.memory.load(&code);
+            for core in 0..32 {
+                state.set_cx_in(Core(core), u16::from(core));
+            }
+
+            let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+            let executed = program.run_on(&mut state, backend).unwrap();
+
+            // Lanes with a smaller CX leave the loop early, leaving their slots idle
+            let lane_instructions = program.stats.lane_instructions;
+            assert!(lane_instructions > executed && lane_instructions < executed * 32);
+
+            for core in 0..32 {
+                let cx = u16::from(core);
+                let expected = if cx >= 10 { 1000 } else { 0 } + cx * (cx + 1) / 2;
+
+                let cpu = state.get_cpu_state(Core(core));
+                assert_eq!(cpu.ax, expected, "{backend:?} core {core}");
+                assert_eq!(cpu.cx, 0, "{backend:?} core {core}");
+                assert_eq!(cpu.ip, 0x18, "{backend:?} core {core}");
+
+                let memory = state.memory.lane(Core(core));
+                assert_eq!(
+                    memory[0x100..0x102],
+                    expected.to_le_bytes(),
+                    "{backend:?} core {core}"
+                );
+            }
+
+            // The blocks of the loop are shared by every lane instead of translated per lane
+            assert_eq!(program.stats.blocks_translated, 5);
+        }
+    }
+
+    /// Run a loop writing the low byte of each lane's DX over the immediate of its
+    /// `mov ax, 1` in a buffer of `N` bytes on `backend`
+    fn run_self_modifying<const N: usize>(backend: Backend) -> (JitEmulatorState, JitProgramStats) {
+        #[rustfmt::skip]
+        let code = [
+            0xb9, 0x02, 0x00,       // 0x00: mov cx, 2
+            0x74, 0x00,             // 0x03: je 0x05
+            0xb8, 0x01, 0x00,       // 0x05: mov ax, 1
+            0x01, 0xc3,             // 0x08: add bx, ax
+            0x88, 0x16, 0x06, 0x00, // 0x0a: mov [0x0006], dl
+            0xe2, 0xf5,             // 0x0e: loop 0x05
+            0xf4,                   // 0x10: hlt
+        ];
+
+        let mut state = JitEmulatorState::default();
+        state.memory.load(&code);
+        for core in 0..32 {
+            state.set_dx_in(Core(core), u16::from(core) + 2);
+        }
+
+        let mut program = JitProgram::<N>::new(&code).unwrap();
+        program.run_on(&mut state, backend).unwrap();
+        (state, program.stats)
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        for backend in backends() {
+            let (state, stats) = run_self_modifying::<{ 64 * 1024 }>(backend);
+            for core in 0..32 {
+                // The second iteration adds the byte written by the first one
+                let cpu = state.get_cpu_state(Core(core));
+                assert_eq!(cpu.ax, u16::from(core) + 2, "{backend:?} core {core}");
+                assert_eq!(cpu.bx, u16::from(core) + 3, "{backend:?} core {core}");
+                assert_eq!(cpu.ip, 0x11, "{backend:?} core {core}");
+            }
+
+            // Every lane wrote different code, so the loop body is translated again for each
+            assert_eq!(stats.blocks_invalidated, 32, "{backend:?}");
+            assert_eq!(stats.flushes, 0, "{backend:?}");
+
+            // The same program still runs when its blocks don't all fit in the buffer
+            let (small, stats) = run_self_modifying::<2048>(backend);
+            for core in 0..32 {
+                let cpu = small.get_cpu_state(Core(core));
+                assert_eq!(
+                    cpu,
+                    state.get_cpu_state(Core(core)),
+                    "{backend:?} core {core}"
+                );
+            }
+            assert!(stats.flushes > 0, "{backend:?}");
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        #[rustfmt::skip]
+        let code = [
+            0x8b, 0x06, 0x00, 0x01, // 0x00: mov ax, [0x100]
+            0x01, 0xd8,             // 0x04: add ax, bx
+            0x89, 0x06, 0x02, 0x01, // 0x06: mov [0x102], ax
+            0xf4,                   // 0x0a: hlt
+        ];
+
+        for backend in backends() {
+            // Each lane adds its own BX to its own word at 0x100
+            let mut state = JitEmulatorState::with_inputs(&code, |core| LaneInput {
+                cpu: CpuState {
+                    bx: u16::from(*core),
+                    ..CpuState::default()
+                },
+                memory: vec![(0x100, (u16::from(*core) * 0x100).to_le_bytes().to_vec())],
+            });
+
+            let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+            program.run_on(&mut state, backend).unwrap();
+
+            let results = state.results(0x100..0x104);
+            assert_eq!(results.lanes.len(), 32);
+            for lane in &results.lanes {
+                let core = u16::from(lane.core.0);
+                let sum = core * 0x100 + core;
+                assert_eq!(lane.cpu.ax, sum, "{backend:?} {:?}", lane.core);
+                assert_eq!(
+                    lane.memory[2..],
+                    sum.to_le_bytes(),
+                    "{backend:?} {:?}",
+                    lane.core
+                );
+            }
+
+            let table = results.table();
+            let row = table.lines().nth(4).unwrap();
+            assert_eq!(
+                row,
+                "   3 0303 0003 0000 0000 0000 0000 0000 0000 000b  0004 0000 0000 0000 0000 | \
+                 00 03 03 03 | halted"
+            );
+            assert!(results
+                .csv()
+                .contains("\n3,771,3,0,0,0,0,0,0,11,4,0,0,0,0,halted,00030303\n"));
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        #[rustfmt::skip]
+        let code = [
+            0x8e, 0xd8,             // 0x00: mov ds, ax
+            0xbb, 0x10, 0x00,       // 0x02: mov bx, 0x10
+            0x89, 0x0f,             // 0x05: mov [bx], cx
+            0x26, 0x89, 0x17,       // 0x07: mov es:[bx], dx
+            0x89, 0x5e, 0x02,       // 0x0a: mov [bp + 2], bx
+            0x8c, 0xde,             // 0x0d: mov si, ds
+            0xf4,                   // 0x0f: hlt
+        ];
+
+        for backend in backends() {
+            // Each lane gets its own data segment, and the last lane fetches the program
+            // through CS:IP 0xffff:0x0010 instead of 0000:0000
+            let mut state = JitEmulatorState::with_inputs(&code, |core| LaneInput {
+                cpu: CpuState {
+                    ax: u16::from(*core),
+                    cx: 0x100 + u16::from(*core),
+                    dx: 0xbeef,
+                    es: 0x80,
+                    ss: 0x40,
+                    cs: if *core == 31 { 0xffff } else { 0 },
+                    ip: if *core == 31 { 0x10 } else { 0 },
+                    ..CpuState::default()
+                },
+                memory: Vec::new(),
+            });
+
+            let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+            program.run_on(&mut state, backend).unwrap();
+
+            for core in 0..32 {
+                let cpu = state.get_cpu_state(Core(core));
+                let ds = u16::from(core);
+                assert_eq!((cpu.ds, cpu.si), (ds, ds), "{backend:?} core {core}");
+                let ip = if core == 31 { 0x20 } else { 0x10 };
+                assert_eq!(cpu.ip, ip, "{backend:?} core {core}");
+
+                let memory = state.memory.lane(Core(core));
+                let data = usize::from(ds) * 16 + 0x10;
+                let cx = (0x100 + u16::from(core)).to_le_bytes();
+                assert_eq!(memory[data..data + 2], cx, "{backend:?} core {core}");
+                assert_eq!(
+                    memory[0x810..0x812],
+                    [0xef, 0xbe],
+                    "{backend:?} core {core}"
+                );
+                assert_eq!(
+                    memory[0x402..0x404],
+                    [0x10, 0x00],
+                    "{backend:?} core {core}"
+                );
+            }
+
+            // The lane in another code segment runs its own translation of the block
+            assert_eq!(program.stats.blocks_translated, 2, "{backend:?}");
+        }
+    }
+
+    #[test]
+    fn test_segment_wrap() {
+        #[rustfmt::skip]
+        let code = [
+            0x89, 0x0f,             // 0x00: mov [bx], cx
+            0xf4,                   // 0x02: hlt
+        ];
+
+        // Lane `i` writes through DS=i*0x1000+1, which wraps to the linear address 0x10
+        // of the 64K lane memory for every lane
+        let mut state = JitEmulatorState::with_inputs(&code, |core| LaneInput {
+            cpu: CpuState {
+                cx: 0xbeef,
+                ds: u16::from(*core).wrapping_mul(0x1000) + 1,
+                ..CpuState::default()
+            },
+            memory: Vec::new(),
+        });
+
+        let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+        program.run_on(&mut state, Backend::Model).unwrap();
+
+        for core in 0..32 {
+            let memory = state.memory.lane(Core(core));
+            assert_eq!(memory[0x10..0x12], [0xef, 0xbe], "Core {core}");
+            assert_eq!(state.status(Core(core)), LaneStatus::Halted, "Core {core}");
+        }
+    }
+
+    #[test]
+    fn test_word_wrap() {
+        #[rustfmt::skip]
+        let code = [
+            0x8b, 0x07,             // 0x00: mov ax, [bx]
+            0x89, 0x0f,             // 0x02: mov [bx], cx
+            0xf4,                   // 0x04: hlt
+        ];
+
+        for backend in backends() {
+            // The high byte of a word at 0xffff is the first byte of the lane memory
+            let mut state = JitEmulatorState::with_inputs(&code, |core| LaneInput {
+                cpu: CpuState {
+                    bx: 0xffff,
+                    cx: 0xbe00 + u16::from(*core),
+                    ..CpuState::default()
+                },
+                memory: vec![(0xffff, vec![*core])],
+            });
+
+            let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+            program.run_on(&mut state, backend).unwrap();
+
+            for core in 0..32 {
+                let cpu = state.get_cpu_state(Core(core));
+                assert_eq!(cpu.ax, 0x8b00 + u16::from(core), "{backend:?} core {core}");
+
+                let memory = state.memory.lane(Core(core));
+                assert_eq!(memory[0xffff], core, "{backend:?} core {core}");
+                assert_eq!(memory[0], 0xbe, "{backend:?} core {core}");
+                assert_eq!(
+                    state.status(Core(core)),
+                    LaneStatus::Halted,
+                    "{backend:?} core {core}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_lane_status() {
+        #[rustfmt::skip]
+        let code = [
+            0xb8, 0x01, 0x00,       // 0x00: mov ax, 1
+            0xe3, 0x06,             // 0x03: jcxz 0x0b
+            0x83, 0xf9, 0x01,       // 0x05: cmp cx, 1
+            0x74, 0x02,             // 0x08: je 0x0c
+            0x50,                   // 0x0a: push ax
+            0xf4,                   // 0x0b: hlt
+        ];
+
+        for backend in backends() {
+            let mut state = JitEmulatorState::default();
+            state.memory.load(&code);
+            for core in 0..32 {
+                state.set_cx_in(Core(core), u16::from(core));
+            }
+
+            let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+            program.run_on(&mut state, backend).unwrap();
+
+            // Lane 0 halts, lane 1 jumps past the end and the others fault on the `push`
+            assert_eq!(state.status(Core(0)), LaneStatus::Halted);
+            assert_eq!(state.status(Core(1)), LaneStatus::Exited);
+            for core in 2..32 {
+                let fault = LaneStatus::Faulted(Fault::UnsupportedInstruction);
+                assert_eq!(state.status(Core(core)), fault, "{backend:?} core {core}");
+
+                let cpu = state.get_cpu_state(Core(core));
+                assert_eq!((cpu.ax, cpu.ip), (1, 0x0a), "{backend:?} core {core}");
+            }
+
+            // The block faulting on the `push` never ran, so it isn't covered
+            assert_eq!(
+                state.coverage.blocks(Core(0)).collect::<Vec<_>>(),
+                [0x00, 0x0b]
+            );
+            assert_eq!(
+                state.coverage.blocks(Core(2)).collect::<Vec<_>>(),
+                [0x00, 0x05]
+            );
+            assert_eq!(state.coverage.lanes(0x05), !1);
+            assert_eq!(state.coverage.lanes(0x0a), 0);
+
+            // Stopped lanes stay stopped
+            assert_eq!(state.running_mask(), 0);
+            assert_eq!(program.run_on(&mut state, backend).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_fault_out_of_bounds() {
+        // `mov ax, imm16` cut off by the end of the program, both in the middle of the
+        // memory and at the very end of it
+        let mut full = vec![0x90; 64 * 1024];
+        full[0xffff] = 0xb8;
+        let programs = [(vec![0x90, 0xb8, 0x01], 1), (full, 0xffff)];
+
+        for backend in backends() {
+            for (code, ip) in &programs {
+                let ip = *ip;
+                let mut state = JitEmulatorState::with_inputs(code, |_| LaneInput {
+                    cpu: CpuState {
+                        ip,
+                        ..CpuState::default()
+                    },
+                    memory: Vec::new(),
+                });
+
+                let mut program = JitProgram::<{ 64 * 1024 }>::new(code).unwrap();
+                program.run_on(&mut state, backend).unwrap();
+
+                for core in 0..32 {
+                    let fault = LaneStatus::Faulted(Fault::OutOfBounds);
+                    assert_eq!(
+                        state.status(Core(core)),
+                        fault,
+                        "{backend:?} {ip:#x} core {core}"
+                    );
+                    assert_eq!(
+                        state.get_cpu_state(Core(core)).ip,
+                        ip,
+                        "{backend:?} {ip:#x} core {core}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        #[rustfmt::skip]
+        let code = [
+            0x05, 0x01, 0x00,       // 0x00: add ax, 1
+            0xe2, 0xfb,             // 0x03: loop 0x00
+            0xf4,                   // 0x05: hlt
+        ];
+
+        for backend in backends() {
+            // Lane 0 leaves the loop right away, the others would run it 65535 times
+            let mut state = JitEmulatorState::default();
+            state.memory.load(&code);
+            for core in 0..32 {
+                state.set_cx_in(Core(core), if core == 0 { 1 } else { u16::MAX });
+            }
+            state.budget.instructions = Some(100);
+
+            let mut program = JitProgram::<{ 64 * 1024 }>::new(&code).unwrap();
+            program.run_on(&mut state, backend).unwrap();
+
+            assert_eq!(state.status(Core(0)), LaneStatus::Halted);
+            assert_eq!(state.get_cpu_state(Core(0)).ax, 1);
+            for core in 1..32 {
+                assert_eq!(state.status(Core(core)), LaneStatus::BudgetExhausted);
+                assert_eq!(
+                    state.get_cpu_state(Core(core)).ax,
+                    50,
+                    "{backend:?} core {core}"
+                );
+
+                // Each iteration takes 4 + 17 cycles
+                let spent = Spent {
+                    instructions: 100,
+                    cycles: 50 * 21,
+                };
+                assert_eq!(
+                    state.spent[usize::from(core)],
+                    spent,
+                    "{backend:?} core {core}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Allocation of the scratch registers used while lowering [`JitIL`] instructions
+//!
+//! Temporaries live until the end of the instruction they were allocated for. Broadcast
+//! constants stay in their register for the rest of the block, so later instructions reuse
+//! them instead of broadcasting them again. When every register is taken, the cached
+//! constant whose next use in the block is furthest away is evicted, and spilled to the
+//! spill area of the [`JitEmulatorState`] if a later instruction still uses it.
+//!
+//! [`JitIL`]: crate::JitIL
+//! [`JitEmulatorState`]: jit_emu::JitEmulatorState
+
+use jit_emu::SPILL_SLOTS;
+
+use crate::{Kmask, Zmm};
+
+/// A value broadcast to every dword of a vector register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Constant(pub u32);
+
+impl Constant {
+    /// The word `val` in every word
+    pub fn word(val: u16) -> Self {
+        Self(u32::from(val) * 0x1_0001)
+    }
+
+    /// The dword `val` in every dword
+    pub fn dword(val: u32) -> Self {
+        Self(val)
+    }
+
+    /// Get the word in every word if this is a word constant
+    pub fn as_word(self) -> Option<u16> {
+        #[allow(clippy::cast_possible_truncation)]
+        let word = self.0 as u16;
+        (Self::word(word) == self).then_some(word)
+    }
+}
+
+/// What a scratch register holds
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Slot {
+    /// Nothing, the register can be allocated
+    Free,
+
+    /// A temporary of the instruction being lowered
+    Temp,
+
+    /// A cached constant, which can't be evicted while pinned by any of the `pins` of the
+    /// instruction being lowered
+    Constant { value: Constant, pins: u8 },
+}
+
+/// A register handed out by the [`ScratchAllocator`]
+#[derive(Debug, Copy, Clone)]
+pub struct Allocation {
+    /// The allocated register
+    pub reg: Zmm,
+
+    /// The constant previously in `reg` is still used later in the block and must be
+    /// stored to this spill slot before `reg` is written
+    pub spill: Option<usize>,
+}
+
+/// Allocator of the scratch zmm registers and kmasks
+#[derive(Debug)]
+pub struct ScratchAllocator {
+    /// Each scratch register and what it currently holds
+    regs: Vec<(Zmm, Slot)>,
+
+    /// Each scratch kmask and whether it is allocated
+    kmasks: Vec<(Kmask, bool)>,
+
+    /// Constants used by each instruction of the current block
+    uses: Vec<Vec<Constant>>,
+
+    /// Index in `uses` of the instruction being lowered
+    current: usize,
+
+    /// Constant stored in each used spill slot
+    spilled: Vec<Constant>,
+}
+
+impl ScratchAllocator {
+    /// Create an allocator of the given scratch registers and kmasks
+    pub fn new(regs: &[Zmm], kmasks: &[Kmask]) -> Self {
+        Self {
+            regs: regs.iter().map(|reg| (*reg, Slot::Free)).collect(),
+            kmasks: kmasks.iter().map(|kmask| (*kmask, false)).collect(),
+            uses: Vec::new(),
+            current: 0,
+            spilled: Vec::new(),
+        }
+    }
+
+    /// Start a new block whose instructions use the given constants. Nothing cached by the
+    /// previous block is reused, since the block can be entered from anywhere.
+    pub fn begin_block(&mut self, uses: Vec<Vec<Constant>>) {
+        for (_, slot) in &mut self.regs {
+            *slot = Slot::Free;
+        }
+        for (_, used) in &mut self.kmasks {
+            *used = false;
+        }
+
+        self.uses = uses;
+        self.current = 0;
+        self.spilled.clear();
+    }
+
+    /// Free the temporaries of the instruction that was just lowered and move on to the
+    /// next instruction
+    pub fn finish_instr(&mut self) {
+        for (_, slot) in &mut self.regs {
+            match slot {
+                Slot::Temp => *slot = Slot::Free,
+                Slot::Constant { pins, .. } => *pins = 0,
+                Slot::Free => {}
+            }
+        }
+        for (_, used) in &mut self.kmasks {
+            *used = false;
+        }
+
+        self.current += 1;
+    }
+
+    /// Get the index of the next instruction after the current one using `value`
+    fn next_use(&self, value: Constant) -> Option<usize> {
+        let later = self.uses.get(self.current + 1..)?;
+        later
+            .iter()
+            .position(|uses| uses.contains(&value))
+            .map(|i| self.current + 1 + i)
+    }
+
+    /// Returns `true` if a register caches `value`
+    pub fn is_cached(&self, value: Constant) -> bool {
+        self.regs
+            .iter()
+            .any(|(_, slot)| matches!(slot, Slot::Constant { value: val, .. } if *val == value))
+    }
+
+    /// Get the register caching `value`, pinning it until it is released
+    pub fn find_constant(&mut self, value: Constant) -> Option<Zmm> {
+        self.regs.iter_mut().find_map(|(reg, slot)| match slot {
+            Slot::Constant { value: val, pins } if *val == value => {
+                *pins += 1;
+                Some(*reg)
+            }
+            _ => None,
+        })
+    }
+
+    /// Get the spill slot holding `value`, if it was spilled in this block
+    pub fn spill_slot(&self, value: Constant) -> Option<usize> {
+        self.spilled.iter().position(|val| *val == value)
+    }
+
+    /// Find a register for a new value, evicting a cached constant if none is free
+    fn alloc(&mut self, new: Slot) -> Allocation {
+        if let Some(index) = self.regs.iter().position(|(_, slot)| *slot == Slot::Free) {
+            self.regs[index].1 = new;
+            return Allocation {
+                reg: self.regs[index].0,
+                spill: None,
+            };
+        }
+
+        // Evict the constant that is needed again the latest, preferring constants that
+        // aren't needed again at all
+        let (index, value, next_use) = self
+            .regs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, slot))| match *slot {
+                Slot::Constant { value, pins: 0 } => Some((i, value, self.next_use(value))),
+                _ => None,
+            })
+            .max_by_key(|(_, _, next_use)| next_use.map_or(usize::MAX, |i| i))
+            .expect("Out of scratch registers: all hold values of the current instruction");
+
+        // Constants never change, so a constant spilled before is still in its spill slot.
+        // Without a free slot the constant is broadcast again when it is next used.
+        let mut spill = None;
+        if next_use.is_some()
+            && self.spill_slot(value).is_none()
+            && self.spilled.len() < SPILL_SLOTS
+        {
+            spill = Some(self.spilled.len());
+            self.spilled.push(value);
+        }
+
+        self.regs[index].1 = new;
+        Allocation {
+            reg: self.regs[index].0,
+            spill,
+        }
+    }
+
+    /// Allocate a register for a temporary of the current instruction
+    pub fn alloc_temp(&mut self) -> Allocation {
+        self.alloc(Slot::Temp)
+    }
+
+    /// Allocate a register that will cache `value`, pinned until it is released
+    pub fn alloc_constant(&mut self, value: Constant) -> Allocation {
+        self.alloc(Slot::Constant { value, pins: 1 })
+    }
+
+    /// Release `reg` before the end of the current instruction. A temporary is freed and
+    /// a constant stays cached, but can be evicted again once every pin is released.
+    pub fn release(&mut self, reg: Zmm) {
+        let Some((_, slot)) = self.regs.iter_mut().find(|(r, _)| r.0 == reg.0) else {
+            return;
+        };
+
+        match slot {
+            Slot::Temp => *slot = Slot::Free,
+            Slot::Constant { pins, .. } => *pins = pins.saturating_sub(1),
+            Slot::Free => {}
+        }
+    }
+
+    /// Allocate a kmask for the current instruction
+    pub fn alloc_kmask(&mut self) -> Kmask {
+        let (kmask, used) = self
+            .kmasks
+            .iter_mut()
+            .find(|(_, used)| !*used)
+            .expect("Out of scratch kmasks");
+        *used = true;
+        *kmask
+    }
+
+    /// Free `kmask` before the end of the current instruction
+    pub fn release_kmask(&mut self, kmask: Kmask) {
+        if let Some((_, used)) = self.kmasks.iter_mut().find(|(k, _)| k.0 == kmask.0) {
+            *used = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eviction() {
+        let regs = [Zmm(24), Zmm(25)];
+        let mut alloc = ScratchAllocator::new(&regs, &[Kmask(3)]);
+
+        let (one, two, three) = (Constant::word(1), Constant::word(2), Constant::word(3));
+        alloc.begin_block(vec![vec![one, two], vec![three], vec![one], vec![two]]);
+
+        // Instruction 0 caches both constants
+        assert!(alloc.alloc_constant(one).spill.is_none());
+        assert!(alloc.alloc_constant(two).spill.is_none());
+        alloc.finish_instr();
+
+        // Instruction 1 evicts `two`, which is used after `one`, into the first spill slot
+        assert!(alloc.find_constant(three).is_none());
+        let three_reg = alloc.alloc_constant(three);
+        assert_eq!(three_reg.reg.0, 25);
+        assert_eq!(three_reg.spill, Some(0));
+        alloc.finish_instr();
+
+        // Instruction 2 still has `one` cached
+        assert_eq!(alloc.find_constant(one).map(|reg| reg.0), Some(24));
+        alloc.finish_instr();
+
+        // Instruction 3 evicts the dead `three` for a temporary and reloads `two`
+        assert_eq!(alloc.spill_slot(two), Some(0));
+        let temp = alloc.alloc_temp();
+        assert_eq!((temp.reg.0, temp.spill), (25, None));
+        alloc.release(temp.reg);
+        assert_eq!(alloc.alloc_constant(two).reg.0, 25);
+    }
+
+    #[test]
+    #[should_panic(expected = "Out of scratch registers")]
+    fn test_exhausted() {
+        let mut alloc = ScratchAllocator::new(&[Zmm(24)], &[]);
+        alloc.begin_block(Vec::new());
+        let reg = alloc.alloc_constant(Constant::word(1)).reg;
+
+        // A constant shared by two users stays pinned until both release it
+        alloc.find_constant(Constant::word(1));
+        alloc.release(reg);
+        alloc.alloc_temp();
+    }
+}
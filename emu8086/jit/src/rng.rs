@@ -0,0 +1,179 @@
+//! `RomuDuoJr` pseudo random number generator implementation seeded by Lehmer64 seeded
+//! by the profiler timer
+//!
+//!
+//!
+//! ```test
+//! Reference: https://www.romu-random.org/code.c
+//!
+//! //===== RomuDuoJr ==================================================================
+//! //
+//! // The fastest generator using 64-bit arith., but not suited for huge jobs.
+//! // Est. capacity = 2^51 bytes. Register pressure = 4. State size = 128 bits.
+//!
+//! uint64_t xState, yState;  // set to nonzero seed
+//!
+//! uint64_t romuDuoJr_random () {
+//!    uint64_t xp = xState;
+//!    xState = 15241094284759029579u * yState;
+//!    yState = yState - xp;  yState = ROTL(yState,27);
+//!    return xp;
+//! }
+//! ```
+
+/// `RomuDuoJr` pseudo random number generator
+pub struct Rng {
+    /// Internal x state
+    xstate: u64,
+
+    /// Internal y state
+    ystate: u64,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Rng::new()
+    }
+}
+
+impl Rng {
+    /// Creates a new `RandRomu` rng initialized with values from Lehmer64 initialized
+    /// with the profiler timer
+    #[must_use]
+    pub fn new() -> Rng {
+        // Generate the random state from Lehmer64
+        let mut lehmer64 = Lehmer64::new();
+        let mut res = Rng {
+            xstate: lehmer64.rand_u64(),
+            ystate: lehmer64.rand_u64(),
+        };
+
+        // Cycle through to create some chaos
+        for _ in 0..92 {
+            let _ = res.next();
+        }
+
+        res
+    }
+
+    /// Create an [`Rng`] seeded with the given seed value
+    ///
+    /// The seed is spread over the Lehmer64 state by `SplitMix64`, so small and zero seeds
+    /// still give a well mixed state.
+    pub fn from_seed(seed: u64) -> Rng {
+        // Generate the random state from Lehmer64
+        let mut lehmer64 = Lehmer64::from_seed(splitmix128(seed));
+        let mut res = Rng {
+            xstate: lehmer64.rand_u64(),
+            ystate: lehmer64.rand_u64(),
+        };
+
+        // Cycle through to create some chaos
+        for _ in 0..92 {
+            let _ = res.next();
+        }
+
+        res
+    }
+
+    /// Get the next random number
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> u64 {
+        let xp = self.xstate;
+        self.xstate = 15_241_094_284_759_029_579_u64.wrapping_mul(self.ystate);
+        self.ystate = self.ystate.wrapping_sub(xp);
+        self.ystate = self.ystate.rotate_left(27);
+        xp
+    }
+
+    /// Provides a u64 useful for fuzzing
+    #[allow(clippy::cast_possible_truncation, clippy::cast_lossless, dead_code)]
+    pub fn fuzz_u64(&mut self) -> u64 {
+        let val = self.next();
+        match self.next() % 16 {
+            0 => val as u8 as u64,
+            1 => val as u16 as u64,
+            2 => val as u32 as u64,
+            3 => (u8::MAX - (val as u8 % 16)) as u64,
+            4 | 6 | 8 | 10 => val % 16,
+            5 => (u16::MAX - (val as u16 % 16)) as u64,
+            7 => (u32::MAX - (val as u32 % 16)) as u64,
+            9 => u64::MAX - (val % 16),
+            _ => val,
+        }
+    }
+}
+
+/// Spread `seed` over 128 bits with two steps of `SplitMix64`
+fn splitmix128(seed: u64) -> u128 {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    (u128::from(next()) << 64) | u128::from(next())
+}
+
+/// Rng seeded with the profiler timer that is generated using Lehmer64
+pub struct Lehmer64 {
+    /// Internal state
+    value: u128,
+}
+
+impl Default for Lehmer64 {
+    fn default() -> Self {
+        let mut res = Lehmer64::from_seed(splitmix128(profiler::read_timer()));
+
+        // Cycle through to create some chaos
+        for _ in 0..123 {
+            let _ = res.rand_u64();
+        }
+
+        res
+    }
+}
+
+impl Lehmer64 {
+    /// Create a new `Lehmer64` rng seeded by the profiler timer
+    #[must_use]
+    pub fn new() -> Lehmer64 {
+        Lehmer64::default()
+    }
+
+    /// Create an [`Lehmer64`] seeded with the given seed value
+    ///
+    /// The state is made odd, since an even state loses a low bit with every step and a zero
+    /// state stays zero.
+    #[must_use]
+    pub fn from_seed(seed: u128) -> Lehmer64 {
+        Lehmer64 { value: seed | 1 }
+    }
+
+    /// Get the next random number
+    #[allow(clippy::cast_possible_truncation)]
+    fn rand_u64(&mut self) -> u64 {
+        self.value = self.value.wrapping_mul(0xda94_2042_e4dd_58b5);
+        (self.value >> 64) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_seed() {
+        // A zero seed still gives a generator that doesn't get stuck at zero
+        let mut rng = Rng::from_seed(0);
+        let values: Vec<u64> = (0..4).map(|_| rng.next()).collect();
+        assert!(values.iter().all(|val| *val != 0), "{values:x?}");
+        assert_ne!(values[0], values[1]);
+
+        // Different seeds give different sequences
+        assert_ne!(Rng::from_seed(1).next(), Rng::from_seed(2).next());
+    }
+}
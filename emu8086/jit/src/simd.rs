@@ -0,0 +1,374 @@
+//! A portable backend interpreting the IL over `std::simd` vectors
+//!
+//! Each 8086 register is a `u16x32` holding the register of every lane, the same layout as
+//! the [`JitEmulatorState`], and every [`JitIL`] instruction is evaluated on all lanes at
+//! once. This runs the vectorized emulator on hosts without AVX-512 and gives the native
+//! code an oracle evaluated straight from the IL it was lowered from.
+//!
+//! Like the JIT code, every lane evaluates the block and only the registers of the lanes in
+//! the exec mask are written back, while stores only write the memory of those lanes. The
+//! word at the last address of a lane wraps around to address 0, where the native code
+//! accesses the padding between the lane memories instead.
+
+use std::simd::prelude::*;
+
+use cpu8086::flags::{EFlags, FlagOp};
+use cpu8086::memory_operand::MemorySize;
+use jit_emu::{Core, JitEmulatorState, MEMORY_SIZE};
+
+use crate::{AluOp, AvxOperand, BytePart, Condition, JitIL, JitMemory, JitRegister};
+use crate::{RegisterOperand, Zmm};
+
+/// Number of lanes in the [`JitEmulatorState`]
+const LANES: usize = 32;
+
+/// Number of registers addressed by the IL
+const REGISTERS: usize = 32;
+
+/// Get the 8086 registers of `state` along with their register in the IL
+fn state_registers(state: &mut JitEmulatorState) -> [(JitRegister, &mut u16x32); 14] {
+    [
+        (JitRegister::ax, &mut state.ax),
+        (JitRegister::bx, &mut state.bx),
+        (JitRegister::cx, &mut state.cx),
+        (JitRegister::dx, &mut state.dx),
+        (JitRegister::si, &mut state.si),
+        (JitRegister::di, &mut state.di),
+        (JitRegister::sp, &mut state.sp),
+        (JitRegister::bp, &mut state.bp),
+        (JitRegister::ip, &mut state.ip),
+        (JitRegister::flags, &mut state.flags),
+        (JitRegister::es, &mut state.es),
+        (JitRegister::cs, &mut state.cs),
+        (JitRegister::ss, &mut state.ss),
+        (JitRegister::ds, &mut state.ds),
+    ]
+}
+
+/// Get a mask of the lanes where any of the `bits` of `value` are set
+fn any_set(value: u16x32, bits: u16) -> mask16x32 {
+    (value & u16x32::splat(bits)).simd_ne(u16x32::splat(0))
+}
+
+/// Registers of every lane while evaluating a block
+struct SimdState<'a> {
+    /// Every register addressed by the IL, indexed by its zmm number
+    regs: [u16x32; REGISTERS],
+
+    /// The emulator state the block runs against
+    state: &'a mut JitEmulatorState,
+}
+
+impl SimdState<'_> {
+    /// Get the register `zmm` of every lane
+    fn reg(&self, zmm: Zmm) -> u16x32 {
+        self.regs[usize::from(zmm.0)]
+    }
+
+    /// Get the `part` byte of `zmm`, zero extended
+    fn read_byte(&self, zmm: Zmm, part: BytePart) -> u16x32 {
+        match part {
+            BytePart::Low => self.reg(zmm) & u16x32::splat(0xff),
+            BytePart::High => self.reg(zmm) >> u16x32::splat(8),
+        }
+    }
+
+    /// Write `value` to `dest`, keeping the other byte of the register for a byte
+    fn write(&mut self, dest: RegisterOperand, value: u16x32) {
+        let old = self.reg(dest.zmm);
+        self.regs[usize::from(dest.zmm.0)] = match dest.byte {
+            None => value,
+            Some(BytePart::Low) => old & u16x32::splat(0xff00) | value & u16x32::splat(0xff),
+            Some(BytePart::High) => old & u16x32::splat(0x00ff) | value << u16x32::splat(8),
+        };
+    }
+
+    /// Get the address of `mem` in each lane, wrapping at 16 bits like the 8086
+    fn address(&self, mem: &JitMemory) -> u16x32 {
+        let base = mem
+            .segment
+            .map_or(u16x32::splat(0), |segment| self.reg(segment) << 4);
+        mem.registers
+            .into_iter()
+            .flatten()
+            .fold(base + u16x32::splat(mem.disp), |addr, reg| {
+                addr + self.reg(reg)
+            })
+    }
+
+    /// Load `mem` from the memory of each lane, zero extending bytes
+    fn load(&self, mem: &JitMemory) -> u16x32 {
+        let addrs = self.address(mem).to_array();
+        u16x32::from_array(std::array::from_fn(|lane| {
+            #[allow(clippy::cast_possible_truncation)]
+            let memory = self.state.memory.lane(Core(lane as u8));
+            let addr = usize::from(addrs[lane]);
+            match mem.size {
+                MemorySize::Byte => u16::from(memory[addr]),
+                MemorySize::Word => {
+                    u16::from_le_bytes([memory[addr], memory[(addr + 1) % MEMORY_SIZE]])
+                }
+            }
+        }))
+    }
+
+    /// Store the low byte or word of `value` to `mem` in the memory of the lanes in `exec`
+    fn store(&mut self, mem: &JitMemory, value: u16x32, exec: u32) {
+        let addrs = self.address(mem).to_array();
+        let values = value.to_array();
+        for lane in (0..LANES).filter(|lane| exec & (1 << lane) > 0) {
+            #[allow(clippy::cast_possible_truncation)]
+            let memory = self.state.memory.lane_mut(Core(lane as u8));
+            let addr = usize::from(addrs[lane]);
+            let [low, high] = values[lane].to_le_bytes();
+            memory[addr] = low;
+            if mem.size == MemorySize::Word {
+                memory[(addr + 1) % MEMORY_SIZE] = high;
+            }
+        }
+    }
+
+    /// Get the value of `operand` in every lane for an operation of the given `size`
+    fn operand(&self, operand: &AvxOperand, size: MemorySize) -> u16x32 {
+        match *operand {
+            #[allow(clippy::cast_sign_loss)]
+            AvxOperand::Immediate(imm) => match size {
+                MemorySize::Byte => u16x32::splat(imm as u16 & 0xff),
+                MemorySize::Word => u16x32::splat(imm as u16),
+            },
+            AvxOperand::Zmm(zmm) => self.reg(zmm),
+            AvxOperand::Byte(zmm, part) => self.read_byte(zmm, part),
+            AvxOperand::Memory(mem) => self.load(&mem),
+        }
+    }
+
+    /// Compute the `flags` status flags of `result = a OP b` in every lane for an operation
+    /// of the given `size`, leaving the other flags untouched
+    ///
+    /// The operands of byte operations are zero extended bytes, so the result has the byte's
+    /// carry in bit 8 and its sign in bit 7.
+    fn set_status_flags(
+        &mut self,
+        op: FlagOp,
+        flags: u16,
+        result: u16x32,
+        a: u16x32,
+        b: u16x32,
+        size: MemorySize,
+    ) {
+        if flags == 0 {
+            return;
+        }
+
+        let (bits, sign) = match size {
+            MemorySize::Byte => (0xff, 0x80),
+            MemorySize::Word => (0xffff, 0x8000),
+        };
+
+        // Fold the low byte onto bit 0, which is then set for an odd number of bits
+        let mut parity = result ^ (result >> u16x32::splat(4));
+        parity ^= parity >> u16x32::splat(2);
+        parity ^= parity >> u16x32::splat(1);
+
+        let mut computed = vec![
+            (EFlags::Zero, !any_set(result, bits)),
+            (EFlags::Sign, any_set(result, sign)),
+            (EFlags::Parity, !any_set(parity, 1)),
+        ];
+
+        // Logic operations clear CF, OF and AF
+        if op != FlagOp::Logic {
+            let carry = match (op, size) {
+                (FlagOp::Add, MemorySize::Word) => result.simd_lt(a),
+                (FlagOp::Add, MemorySize::Byte) => any_set(result, 0x100),
+                _ => a.simd_lt(b),
+            };
+            let overflow = match op {
+                FlagOp::Add => (a ^ result) & (b ^ result),
+                _ => (a ^ b) & (a ^ result),
+            };
+
+            computed.extend([
+                (EFlags::Carry, carry),
+                (EFlags::Overflow, any_set(overflow, sign)),
+                (
+                    EFlags::Auxillary,
+                    any_set(a ^ b ^ result, EFlags::Auxillary as u16),
+                ),
+            ]);
+        }
+
+        let flags_zmm = JitRegister::flags.as_zmm();
+        let mut value = self.reg(flags_zmm) & u16x32::splat(!flags);
+        for (flag, set) in computed {
+            let bit = flag as u16 & flags;
+            value |= set.select(u16x32::splat(bit), u16x32::splat(0));
+        }
+        self.regs[usize::from(flags_zmm.0)] = value;
+    }
+
+    /// Evaluate `dest = op1 OP op2` (or only the flags without a `dest`), computing the
+    /// `flags` status flags of the result
+    fn alu(
+        &mut self,
+        op: AluOp,
+        dest: Option<RegisterOperand>,
+        op1: AvxOperand,
+        op2: AvxOperand,
+        flags: u16,
+    ) {
+        let size = match (op1.size(), op2.size()) {
+            (Some(MemorySize::Byte), _) | (_, Some(MemorySize::Byte)) => MemorySize::Byte,
+            _ => MemorySize::Word,
+        };
+        let a = self.operand(&op1, size);
+        let b = self.operand(&op2, size);
+
+        let result = match op {
+            AluOp::Add => a + b,
+            AluOp::Sub => a - b,
+            AluOp::And => a & b,
+            AluOp::Or => a | b,
+            AluOp::Xor => a ^ b,
+        };
+
+        self.set_status_flags(op.flag_op(), flags, result, a, b, size);
+
+        if let Some(dest) = dest {
+            self.write(dest, result);
+        }
+    }
+
+    /// Get a mask of the lanes where `cond` holds
+    fn condition(&self, cond: Condition) -> mask16x32 {
+        use Condition::*;
+
+        let flags = self.reg(JitRegister::flags.as_zmm());
+        let flag = |flag: EFlags| any_set(flags, flag as u16);
+        let less = flag(EFlags::Sign) ^ flag(EFlags::Overflow);
+
+        match cond {
+            Equal => flag(EFlags::Zero),
+            NotEqual => !flag(EFlags::Zero),
+            Below => flag(EFlags::Carry),
+            NotBelow => !flag(EFlags::Carry),
+            BelowEqual => flag(EFlags::Carry) | flag(EFlags::Zero),
+            NotBelowEqual => !(flag(EFlags::Carry) | flag(EFlags::Zero)),
+            Less => less,
+            NotLess => !less,
+            LessEqual => less | flag(EFlags::Zero),
+            NotLessEqual => !(less | flag(EFlags::Zero)),
+            ParityEven => flag(EFlags::Parity),
+            ParityOdd => !flag(EFlags::Parity),
+            Overflow => flag(EFlags::Overflow),
+            NotOverflow => !flag(EFlags::Overflow),
+            Sign => flag(EFlags::Sign),
+            NotSign => !flag(EFlags::Sign),
+            CxZero => self.reg(JitRegister::cx.as_zmm()).simd_eq(u16x32::splat(0)),
+        }
+    }
+
+    /// Set IP to `taken` in the lanes in `mask` and to `not_taken` in the others
+    fn branch(&mut self, mask: mask16x32, taken: u16, not_taken: u16) {
+        let ip = mask.select(u16x32::splat(taken), u16x32::splat(not_taken));
+        self.regs[usize::from(JitRegister::ip.as_zmm().0)] = ip;
+    }
+
+    /// Evaluate `instr` in every lane
+    fn eval(&mut self, instr: &JitIL) {
+        match *instr {
+            JitIL::Mov { dest, src } => {
+                let size = match dest.byte {
+                    Some(_) => MemorySize::Byte,
+                    None => MemorySize::Word,
+                };
+                let value = self.operand(&src, size);
+                self.write(dest, value);
+            }
+            JitIL::Store { dest, src } => {
+                assert!(
+                    !matches!(src, AvxOperand::Memory(_)),
+                    "Memory to memory store"
+                );
+                let value = self.operand(&src, dest.size);
+                self.store(&dest, value, self.state.exec_mask);
+            }
+            JitIL::Sub {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Sub, Some(dest), op1.into(), op2, flags),
+            JitIL::Add {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Add, Some(dest), op1.into(), op2, flags),
+            JitIL::And {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::And, Some(dest), op1.into(), op2, flags),
+            JitIL::Or {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Or, Some(dest), op1.into(), op2, flags),
+            JitIL::Xor {
+                dest,
+                op1,
+                op2,
+                flags,
+            } => self.alu(AluOp::Xor, Some(dest), op1.into(), op2, flags),
+            JitIL::Cmp { left, right, flags } => self.alu(AluOp::Sub, None, left, right, flags),
+            JitIL::Test { left, right, flags } => {
+                self.alu(AluOp::And, None, left, right, flags);
+            }
+            JitIL::Branch {
+                cond,
+                taken,
+                not_taken,
+            } => self.branch(self.condition(cond), taken, not_taken),
+            JitIL::Loop {
+                cond,
+                taken,
+                not_taken,
+            } => {
+                // Decrement CX without touching the flags
+                let cx = JitRegister::cx.as_zmm();
+                self.regs[usize::from(cx.0)] = self.reg(cx) - u16x32::splat(1);
+
+                let mut mask = self.reg(cx).simd_ne(u16x32::splat(0));
+                if let Some(cond) = cond {
+                    mask &= self.condition(cond);
+                }
+                self.branch(mask, taken, not_taken);
+            }
+        }
+    }
+}
+
+/// Evaluate the IL of a block in every lane of `state`, only writing the registers and
+/// memory of the lanes in [`JitEmulatorState::exec_mask`]
+pub fn run_block(il: &[JitIL], state: &mut JitEmulatorState) {
+    let mut regs = [u16x32::splat(0); REGISTERS];
+    for (reg, value) in state_registers(state) {
+        regs[usize::from(reg.as_zmm().0)] = *value;
+    }
+
+    let mut simd = SimdState { regs, state };
+    for instr in il {
+        simd.eval(instr);
+    }
+
+    // Save the registers of the executing lanes
+    let SimdState { regs, state } = simd;
+    let exec_mask = state.exec_mask;
+    let exec = mask16x32::from_array(std::array::from_fn(|lane| exec_mask & (1 << lane) > 0));
+    for (reg, value) in state_registers(state) {
+        *value = exec.select(regs[usize::from(reg.as_zmm().0)], *value);
+    }
+}
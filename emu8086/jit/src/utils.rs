@@ -1,37 +1,116 @@
 //! Utilities for the JIT
 
-/// Allocate a Read|Write|Exec buffer used for the JIT buffer
-pub fn alloc_rwx(size: usize) -> *mut u8 {
-    extern "C" {
-        fn mmap(
-            addr: *mut u8,
-            length: usize,
-            prot: i32,
-            flags: i32,
-            fd: i32,
-            offset: usize,
-        ) -> *mut u8;
+use std::cell::Cell;
+
+extern "C" {
+    fn mmap(addr: *mut u8, length: usize, prot: i32, flags: i32, fd: i32, offset: usize)
+        -> *mut u8;
+    fn mprotect(addr: *mut u8, length: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, length: usize) -> i32;
+}
+
+const PROT_EXEC: i32 = 4;
+const PROT_READ: i32 = 2;
+const PROT_WRITE: i32 = 1;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_PRIVATE: i32 = 2;
+
+/// Value returned by `mmap` on failure
+const MAP_FAILED: *mut u8 = usize::MAX as *mut u8;
+
+/// Protection of an [`ExecMemory`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protection {
+    /// Read and write, while code is being written
+    ReadWrite,
+
+    /// Read and execute, while code is being run
+    ReadExec,
+}
+
+impl Protection {
+    /// Get the `PROT_*` flags of the protection
+    fn flags(self) -> i32 {
+        match self {
+            Protection::ReadWrite => PROT_READ | PROT_WRITE,
+            Protection::ReadExec => PROT_READ | PROT_EXEC,
+        }
     }
+}
+
+/// A mapping holding JIT code, which is never writable and executable at the same time
+///
+/// The mapping starts writable. It is switched to executable before running code and back
+/// to writable before writing more, and unmapped on drop.
+pub struct ExecMemory {
+    /// Start of the mapping
+    ptr: *mut u8,
+
+    /// Size of the mapping in bytes
+    size: usize,
+
+    /// Current protection of the mapping
+    protection: Cell<Protection>,
+}
+
+impl ExecMemory {
+    /// Map `size` bytes of writable memory
+    pub fn new(size: usize) -> ExecMemory {
+        // SAFETY: A new anonymous mapping doesn't alias any memory
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                size,
+                Protection::ReadWrite.flags(),
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert!(
+            ptr != MAP_FAILED && !ptr.is_null(),
+            "Failed to allocate buffer of size {size}"
+        );
 
-    const PROT_EXEC: i32 = 4;
-    const PROT_READ: i32 = 2;
-    const PROT_WRITE: i32 = 1;
-    const PROT_RWX: i32 = PROT_EXEC | PROT_READ | PROT_WRITE;
-    const MAP_ANONYMOUS: i32 = 0x20;
-    const MAP_PRIVATE: i32 = 2;
-
-    // Return an RWX Priv/Anon allocated buffer of `size` bytes
-    unsafe {
-        let res = mmap(
-            std::ptr::null_mut(),
+        ExecMemory {
+            ptr,
             size,
-            PROT_RWX,
-            MAP_PRIVATE | MAP_ANONYMOUS,
-            -1,
-            0,
+            protection: Cell::new(Protection::ReadWrite),
+        }
+    }
+
+    /// Get the start of the mapping
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Get the current protection of the mapping
+    #[cfg(test)]
+    pub fn protection(&self) -> Protection {
+        self.protection.get()
+    }
+
+    /// Change the protection of the whole mapping, if it isn't already `protection`
+    pub fn protect(&self, protection: Protection) {
+        if self.protection.get() == protection {
+            return;
+        }
+
+        // SAFETY: The range is exactly the mapping
+        let res = unsafe { mprotect(self.ptr, self.size, protection.flags()) };
+        assert!(
+            res == 0,
+            "Failed to change the JIT buffer to {protection:?}"
         );
-        assert!(!res.is_null(), "Failed to allocate buffer of size {size}");
+        self.protection.set(protection);
+    }
+}
 
-        res
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        // SAFETY: The mapping is owned by this allocation and no longer used
+        unsafe {
+            munmap(self.ptr, self.size);
+        }
     }
 }
@@ -0,0 +1,191 @@
+//! Running a batch of different inputs, one per lane
+//!
+//! Every lane runs the same program, but each one can start from its own registers and
+//! memory. After the run, the registers and a range of the memory of every lane are
+//! collected into a table of results.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::{Core, CpuState, JitEmulatorState, LaneStatus, LANES, MEMORY_SIZE};
+
+/// The initial state of one lane of a batch
+#[derive(Debug, Clone, Default)]
+pub struct LaneInput {
+    /// Registers of the lane
+    pub cpu: CpuState,
+
+    /// Bytes written into the memory of the lane, each at its address
+    pub memory: Vec<(u16, Vec<u8>)>,
+}
+
+/// The final state of one lane of a batch
+#[derive(Debug, Clone)]
+pub struct LaneResult {
+    /// The lane
+    pub core: Core,
+
+    /// Registers of the lane
+    pub cpu: CpuState,
+
+    /// Why the lane stopped, or [`LaneStatus::Running`] if it didn't
+    pub status: LaneStatus,
+
+    /// The collected range of the memory of the lane
+    pub memory: Vec<u8>,
+}
+
+/// The final state of every lane of a batch (see [`JitEmulatorState::results`])
+#[derive(Debug, Clone)]
+pub struct BatchResults {
+    /// Range of the memory collected from each lane
+    pub memory: Range<usize>,
+
+    /// The result of every lane
+    pub lanes: Vec<LaneResult>,
+}
+
+impl JitEmulatorState {
+    /// Create a state with `program` loaded into every lane, and each lane then initialized
+    /// from its `input`
+    pub fn with_inputs(program: &[u8], mut input: impl FnMut(Core) -> LaneInput) -> Self {
+        let mut state = Self::default();
+        state.memory.load(program);
+
+        for core in 0..LANES {
+            let core = Core(core);
+            state.set_lane_input(core, &input(core));
+        }
+
+        state
+    }
+
+    /// Set the registers of `core` and write the memory of its `input`
+    pub fn set_lane_input(&mut self, core: Core, input: &LaneInput) {
+        self.set_cpu_state(core, &input.cpu);
+
+        let memory = self.memory.lane_mut(core);
+        for (address, bytes) in &input.memory {
+            let start = usize::from(*address);
+            assert!(
+                start + bytes.len() <= MEMORY_SIZE,
+                "{} bytes at {address:#x} do not fit in the memory of {core:?}",
+                bytes.len()
+            );
+            memory[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    /// Collect the registers and the given `memory` range of every lane
+    pub fn results(&self, memory: Range<usize>) -> BatchResults {
+        assert!(
+            memory.start <= memory.end && memory.end <= MEMORY_SIZE,
+            "Memory range {memory:#x?} is outside of the lane memory"
+        );
+
+        let lanes = (0..LANES)
+            .map(|core| {
+                let core = Core(core);
+                LaneResult {
+                    core,
+                    cpu: self.get_cpu_state(core),
+                    status: self.status(core),
+                    memory: self.memory.lane(core)[memory.clone()].to_vec(),
+                }
+            })
+            .collect();
+
+        BatchResults { memory, lanes }
+    }
+}
+
+impl BatchResults {
+    /// Format the results as a table with a row per lane
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+
+        let _ = write!(
+            out,
+            "Lane   AX   BX   CX   DX   SI   DI   SP   BP   IP FLAGS   ES   CS   SS   DS"
+        );
+        if !self.memory.is_empty() {
+            let _ = write!(
+                out,
+                " | Memory {:#06x}..{:#06x}",
+                self.memory.start, self.memory.end
+            );
+        }
+        let _ = writeln!(out, " | Status");
+
+        for lane in &self.lanes {
+            let CpuState {
+                ax,
+                bx,
+                cx,
+                dx,
+                si,
+                di,
+                sp,
+                bp,
+                ip,
+                flags,
+                es,
+                cs,
+                ss,
+                ds,
+            } = lane.cpu;
+
+            let _ = write!(
+                out,
+                "{:4} {ax:04x} {bx:04x} {cx:04x} {dx:04x} {si:04x} {di:04x} {sp:04x} {bp:04x} \
+                 {ip:04x}  {flags:04x} {es:04x} {cs:04x} {ss:04x} {ds:04x}",
+                lane.core.0
+            );
+            if !self.memory.is_empty() {
+                let _ = write!(out, " | {}", hex(&lane.memory));
+            }
+            let _ = writeln!(out, " | {}", lane.status);
+        }
+
+        out
+    }
+
+    /// Format the results as CSV with a row per lane and the memory as a hex string
+    pub fn csv(&self) -> String {
+        let mut out =
+            String::from("lane,ax,bx,cx,dx,si,di,sp,bp,ip,flags,es,cs,ss,ds,status,memory\n");
+
+        for lane in &self.lanes {
+            let cpu = lane.cpu;
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                lane.core.0,
+                cpu.ax,
+                cpu.bx,
+                cpu.cx,
+                cpu.dx,
+                cpu.si,
+                cpu.di,
+                cpu.sp,
+                cpu.bp,
+                cpu.ip,
+                cpu.flags,
+                cpu.es,
+                cpu.cs,
+                cpu.ss,
+                cpu.ds,
+                lane.status,
+                hex(&lane.memory).replace(' ', "")
+            );
+        }
+
+        out
+    }
+}
+
+/// Format `bytes` as space separated hex
+fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    hex.join(" ")
+}
@@ -0,0 +1,84 @@
+//! Which blocks each lane executed
+//!
+//! Every lane has a bitmap with a bit per linear address, set once the lane ran the block
+//! starting at that address. The bitmaps are kept across runs, so they can be compared
+//! between inputs to find the ones reaching new code.
+
+use crate::{Core, LANES};
+
+/// Number of linear addresses tracked in the bitmap of each lane
+const ADDRESSES: usize = u16::MAX as usize + 1;
+
+/// Number of words in the bitmap of each lane
+const WORDS: usize = ADDRESSES / 64;
+
+/// The blocks executed by each lane, one bitmap per lane
+#[derive(Clone, PartialEq, Eq)]
+pub struct BlockCoverage {
+    /// The bitmap of lane `i` is the `WORDS` words starting at `i * WORDS`
+    bits: Vec<u64>,
+}
+
+impl Default for BlockCoverage {
+    fn default() -> Self {
+        Self {
+            bits: vec![0; usize::from(LANES) * WORDS],
+        }
+    }
+}
+
+impl std::fmt::Debug for BlockCoverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let blocks: Vec<usize> = (0..LANES).map(|core| self.count(Core(core))).collect();
+        f.debug_struct("BlockCoverage")
+            .field("blocks", &blocks)
+            .finish()
+    }
+}
+
+impl BlockCoverage {
+    /// Get the bitmap of `core`
+    pub fn lane(&self, core: Core) -> &[u64] {
+        let start = usize::from(*core) * WORDS;
+        &self.bits[start..start + WORDS]
+    }
+
+    /// Record that every lane in `mask` ran the block at linear `address`
+    pub fn record(&mut self, mask: u32, address: u16) {
+        let (word, bit) = (usize::from(address) / 64, address % 64);
+        for lane in 0..usize::from(LANES) {
+            if mask & (1 << lane) != 0 {
+                self.bits[lane * WORDS + word] |= 1 << bit;
+            }
+        }
+    }
+
+    /// Get the mask of the lanes that ran the block at linear `address`
+    pub fn lanes(&self, address: u16) -> u32 {
+        let (word, bit) = (usize::from(address) / 64, address % 64);
+        (0..LANES)
+            .filter(|&core| self.lane(Core(core))[word] & (1 << bit) != 0)
+            .fold(0, |mask, core| mask | (1 << core))
+    }
+
+    /// Get the linear addresses of the blocks `core` ran, in increasing order
+    pub fn blocks(&self, core: Core) -> impl Iterator<Item = u16> + '_ {
+        self.lane(core)
+            .iter()
+            .enumerate()
+            .flat_map(|(word, &bits)| {
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| word * 64 + bit)
+            })
+            .map(|address| u16::try_from(address).expect("The bitmap covers 16-bit addresses"))
+    }
+
+    /// Get the number of blocks `core` ran
+    pub fn count(&self, core: Core) -> usize {
+        self.lane(core)
+            .iter()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+}
@@ -1,13 +1,97 @@
 #![feature(stdsimd)]
 #![feature(portable_simd)]
 #![feature(concat_idents)]
+use cpu8086::budget::{Budget, Spent};
 use cpu8086::flags::EFlags;
-use std::simd::u16x32;
+use std::simd::{u16x32, u32x16};
+
+mod batch;
+pub use batch::{BatchResults, LaneInput, LaneResult};
+
+mod coverage;
+pub use coverage::BlockCoverage;
+
+mod status;
+pub use status::{Fault, LaneStatus};
 
 const LANES: u8 = 32;
 
+/// Number of bytes of 8086 memory available to each lane
+pub const MEMORY_SIZE: usize = 64 * 1024;
+
+/// Distance in bytes between the start of two lanes' memories.
+///
+/// The padding keeps a dword gather at the last address of a lane inside that lane and
+/// avoids every lane mapping to the same cache sets.
+pub const LANE_STRIDE: usize = MEMORY_SIZE + 64;
+
+/// Number of vector registers the JIT can spill into [`JitEmulatorState`]
+pub const SPILL_SLOTS: usize = 8;
+
+/// The independent 8086 memories of all lanes, stored one lane after another
+///
+/// The memory of lane `i` starts at byte `i * LANE_STRIDE`, so a dword gather with the
+/// per-lane indices `i * LANE_STRIDE + address` reads the word at `address` of each lane.
+///
+/// Unlike the 1MB address space of the 8086, each lane only has 64K of memory. The linear
+/// address `segment * 16 + offset` is computed in 16 bits and wraps at 64K, so segments
+/// 0x1000 apart alias the same bytes (DS=0x1000 addresses the same memory as DS=0).
+#[derive(Clone)]
+pub struct LaneMemory {
+    bytes: Vec<u8>,
+}
+
+impl Default for LaneMemory {
+    fn default() -> Self {
+        Self {
+            bytes: vec![0; usize::from(LANES) * LANE_STRIDE],
+        }
+    }
+}
+
+impl std::fmt::Debug for LaneMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LaneMemory")
+            .field("lanes", &LANES)
+            .field("size", &MEMORY_SIZE)
+            .finish()
+    }
+}
+
+impl LaneMemory {
+    /// Get the memory of the given [`Core`]
+    pub fn lane(&self, core: Core) -> &[u8] {
+        let start = usize::from(*core) * LANE_STRIDE;
+        &self.bytes[start..start + MEMORY_SIZE]
+    }
+
+    /// Get the mutable memory of the given [`Core`]
+    pub fn lane_mut(&mut self, core: Core) -> &mut [u8] {
+        let start = usize::from(*core) * LANE_STRIDE;
+        &mut self.bytes[start..start + MEMORY_SIZE]
+    }
+
+    /// Copy `data` to the start of every lane's memory
+    pub fn load(&mut self, data: &[u8]) {
+        assert!(
+            data.len() <= MEMORY_SIZE,
+            "{} bytes do not fit in the {MEMORY_SIZE} bytes of lane memory",
+            data.len()
+        );
+
+        for core in 0..LANES {
+            self.lane_mut(Core(core))[..data.len()].copy_from_slice(data);
+        }
+    }
+
+    /// Get the pointer to the start of the memory of the first lane
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.bytes.as_mut_ptr()
+    }
+}
+
 /// An vectorized emulator state of 32 simulated 8086 processors
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct JitEmulatorState {
     pub ax: u16x32,
     pub bx: u16x32,
@@ -19,6 +103,75 @@ pub struct JitEmulatorState {
     pub bp: u16x32,
     pub ip: u16x32,
     pub flags: u16x32,
+
+    /// Segment registers of each lane. Their linear addresses wrap at the 64K of the
+    /// [`LaneMemory`], so only the low 12 bits of a segment select where it starts.
+    pub es: u16x32,
+    pub cs: u16x32,
+    pub ss: u16x32,
+    pub ds: u16x32,
+
+    /// Lanes executing the current JIT code. Only the registers and memory of these lanes
+    /// are updated.
+    pub exec_mask: u32,
+
+    /// Execution status of each lane
+    pub status: [LaneStatus; LANES as usize],
+
+    /// Limits on the instructions and cycles each lane runs, checked before each block
+    pub budget: Budget,
+
+    /// Instructions and cycles run by each lane so far
+    pub spent: [Spent; LANES as usize],
+
+    /// Blocks run by each lane so far
+    pub coverage: BlockCoverage,
+
+    /// Byte offset of each lane's memory in [`LaneMemory`] for lanes 0-15 and 16-31, used
+    /// as the gather/scatter indices by the JIT
+    lane_offsets: [u32x16; 2],
+
+    /// Scratch area the JIT spills vector registers into when it runs out of registers
+    spill: [u16x32; SPILL_SLOTS],
+
+    /// The memory of each lane
+    pub memory: LaneMemory,
+}
+
+impl Default for JitEmulatorState {
+    fn default() -> Self {
+        #[allow(clippy::cast_possible_truncation)]
+        let lane_offsets = [0, 16].map(|first: u32| {
+            u32x16::from_array(std::array::from_fn(|i| {
+                (first + i as u32) * LANE_STRIDE as u32
+            }))
+        });
+
+        Self {
+            ax: u16x32::default(),
+            bx: u16x32::default(),
+            cx: u16x32::default(),
+            dx: u16x32::default(),
+            si: u16x32::default(),
+            di: u16x32::default(),
+            sp: u16x32::default(),
+            bp: u16x32::default(),
+            ip: u16x32::default(),
+            flags: u16x32::default(),
+            es: u16x32::default(),
+            cs: u16x32::default(),
+            ss: u16x32::default(),
+            ds: u16x32::default(),
+            exec_mask: u32::MAX,
+            status: [LaneStatus::Running; LANES as usize],
+            budget: Budget::default(),
+            spent: [Spent::default(); LANES as usize],
+            coverage: BlockCoverage::default(),
+            lane_offsets,
+            spill: [u16x32::default(); SPILL_SLOTS],
+            memory: LaneMemory::default(),
+        }
+    }
 }
 
 /*
@@ -58,9 +211,31 @@ impl JitEmulatorState {
             bp: self.bp.as_array()[cpu],
             ip: self.ip.as_array()[cpu],
             flags: self.flags.as_array()[cpu],
+            es: self.es.as_array()[cpu],
+            cs: self.cs.as_array()[cpu],
+            ss: self.ss.as_array()[cpu],
+            ds: self.ds.as_array()[cpu],
         }
     }
 
+    /// Set the state of a single core
+    pub fn set_cpu_state(&mut self, cpu: Core, state: &CpuState) {
+        self.set_ax_in(cpu, state.ax);
+        self.set_bx_in(cpu, state.bx);
+        self.set_cx_in(cpu, state.cx);
+        self.set_dx_in(cpu, state.dx);
+        self.set_si_in(cpu, state.si);
+        self.set_di_in(cpu, state.di);
+        self.set_sp_in(cpu, state.sp);
+        self.set_bp_in(cpu, state.bp);
+        self.set_ip_in(cpu, state.ip);
+        self.set_flags_in(cpu, state.flags);
+        self.set_es_in(cpu, state.es);
+        self.set_cs_in(cpu, state.cs);
+        self.set_ss_in(cpu, state.ss);
+        self.set_ds_in(cpu, state.ds);
+    }
+
     /// Print the CPU state for the given [`Core`]
     pub fn print_cpu_state(&self, core: Core) {
         // Get the CPU state for this core
@@ -75,6 +250,10 @@ impl JitEmulatorState {
             bp,
             ip,
             flags,
+            es,
+            cs,
+            ss,
+            ds,
         } = self.get_cpu_state(core);
 
         // Create the FLAGS string
@@ -97,6 +276,8 @@ impl JitEmulatorState {
         println!("    IP: {ip:04x} FLAGS: {flags:04x} {eflags}");
         println!("    AX: {ax:04x} BX: {bx:04x} CX: {cx:04x} DX: {dx:04x}");
         println!("    SP: {sp:04x} BP: {bp:04x} SI: {si:04x} DI: {di:04x}");
+        println!("    ES: {es:04x} CS: {cs:04x} SS: {ss:04x} DS: {ds:04x}");
+        println!("    Status: {}", self.status(core));
     }
 
     /// Print all CPU states in the emulator
@@ -125,6 +306,22 @@ macro_rules! impl_offset {
             }
         }
     };
+    ($field:ident: $ty:ty, $func:ident) => {
+        impl JitEmulatorState {
+            #[allow(non_upper_case_globals)]
+            pub const fn $func() -> isize {
+                // Get the address of the base of the EmulatorState struct
+                const BASE: *const JitEmulatorState =
+                    core::mem::MaybeUninit::<JitEmulatorState>::uninit().as_ptr();
+
+                // Get the address to a struct field
+                const $field: *const $ty = unsafe { core::ptr::addr_of!((*BASE).$field) };
+
+                // Return the offset of the struct field from the base address
+                unsafe { $field.cast::<u8>().offset_from(BASE.cast::<u8>()) }
+            }
+        }
+    };
     (host $field:ident, $func:ident) => {
         impl HostState {
             pub const fn $func() -> isize {
@@ -152,6 +349,13 @@ impl_offset!(8086 sp, sp_offset);
 impl_offset!(8086 bp, bp_offset);
 impl_offset!(8086 ip, ip_offset);
 impl_offset!(8086 flags, flags_offset);
+impl_offset!(8086 es, es_offset);
+impl_offset!(8086 cs, cs_offset);
+impl_offset!(8086 ss, ss_offset);
+impl_offset!(8086 ds, ds_offset);
+impl_offset!(exec_mask: u32, exec_mask_offset);
+impl_offset!(lane_offsets: [u32x16; 2], lane_offsets_offset);
+impl_offset!(spill: [u16x32; SPILL_SLOTS], spill_offset);
 
 // impl_offset!(host rax, rax_offset);
 // impl_offset!(host rbx, rbx_offset);
@@ -161,7 +365,7 @@ impl_offset!(8086 flags, flags_offset);
 // impl_offset!(host rdi, rdi_offset);
 
 /// A single lane's CPU state
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct CpuState {
     pub ax: u16,
     pub bx: u16,
@@ -173,6 +377,10 @@ pub struct CpuState {
     pub bp: u16,
     pub ip: u16,
     pub flags: u16,
+    pub es: u16,
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -220,3 +428,7 @@ impl_reg!(di, set_di, set_di_in);
 impl_reg!(si, set_si, set_si_in);
 impl_reg!(ip, set_ip, set_ip_in);
 impl_reg!(flags, set_flags, set_flags_in);
+impl_reg!(es, set_es, set_es_in);
+impl_reg!(cs, set_cs, set_cs_in);
+impl_reg!(ss, set_ss, set_ss_in);
+impl_reg!(ds, set_ds, set_ds_in);
@@ -0,0 +1,87 @@
+//! Why each lane stopped running
+//!
+//! Every lane starts out [`LaneStatus::Running`]. The lane stops once it halts, leaves the
+//! program, faults or runs out of its budget, and its status records which of these it
+//! was. Stopped lanes are left out of the following runs.
+
+use std::fmt;
+
+use crate::{Core, JitEmulatorState, LANES};
+
+/// A fault stopping a lane
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// An instruction that can't be decoded or has no translation
+    UnsupportedInstruction,
+
+    /// An instruction fetched past the end of the program, whose bytes run off the end of
+    /// the 64K of memory holding it
+    OutOfBounds,
+}
+
+/// The execution status of a lane
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum LaneStatus {
+    /// The lane still has instructions to run
+    #[default]
+    Running,
+
+    /// The lane executed `hlt`
+    Halted,
+
+    /// The CS:IP of the lane left the program
+    Exited,
+
+    /// The lane faulted at its CS:IP, which is left at the faulting instruction
+    Faulted(Fault),
+
+    /// The lane ran out of its instruction budget
+    BudgetExhausted,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Fault::UnsupportedInstruction => "unsupported instruction",
+            Fault::OutOfBounds => "out of bounds",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl fmt::Display for LaneStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LaneStatus::Running => f.write_str("running"),
+            LaneStatus::Halted => f.write_str("halted"),
+            LaneStatus::Exited => f.write_str("exited"),
+            LaneStatus::Faulted(fault) => write!(f, "fault: {fault}"),
+            LaneStatus::BudgetExhausted => f.write_str("budget exhausted"),
+        }
+    }
+}
+
+impl JitEmulatorState {
+    /// Get the status of `core`
+    pub fn status(&self, core: Core) -> LaneStatus {
+        self.status[usize::from(*core)]
+    }
+
+    /// Set the status of every lane in `mask`
+    pub fn set_status(&mut self, mask: u32, status: LaneStatus) {
+        for lane in 0..usize::from(LANES) {
+            if mask & (1 << lane) != 0 {
+                self.status[lane] = status;
+            }
+        }
+    }
+
+    /// Get the mask of the lanes that are still running
+    pub fn running_mask(&self) -> u32 {
+        self.status
+            .iter()
+            .enumerate()
+            .filter(|(_, status)| **status == LaneStatus::Running)
+            .fold(0, |mask, (lane, _)| mask | (1 << lane))
+    }
+}
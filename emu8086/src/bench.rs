@@ -0,0 +1,405 @@
+//! Throughput benchmarks of every execution engine over a set of 8086 programs
+//!
+//! Each program is run by each engine under a [`RepetitionTester`] until the fastest run
+//! stops improving. Engines keep their caches and translated blocks between runs, so the
+//! fastest run measures the steady state rather than the first translation.
+//!
+//! Before an engine runs a program, every instruction of the program is decoded and
+//! checked against what the engine can execute. Programs an engine can't run, or that run
+//! out of their [`Budget`], are recorded as skipped for that engine.
+
+use anyhow::{ensure, Result};
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use cpu8086::budget::Budget;
+use cpu8086::decode_cache::DecodeCache;
+use cpu8086::decoder::decode_instruction;
+use cpu8086::emu::Emulator;
+use cpu8086::instruction::Instruction;
+use cpu8086::register::SegmentRegister;
+use cpu8086::threaded::ThreadedEngine;
+use profiler::{RepetitionResults, RepetitionTester};
+
+#[cfg(feature = "vecemu")]
+use jit::JitProgram;
+#[cfg(feature = "vecemu")]
+use cpu8086::budget::BudgetExhausted;
+#[cfg(feature = "vecemu")]
+use jit_emu::{JitEmulatorState, LaneInput, LaneStatus};
+
+/// The emulator used by the scalar engines
+type Emu = Emulator<{ 64 * 1024 }>;
+
+/// Number of lanes run by the JIT
+#[cfg(feature = "vecemu")]
+const LANES: u64 = 32;
+
+/// An engine being benchmarked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum BenchEngine {
+    /// Decode and execute one instruction at a time
+    Interpreter,
+
+    /// The interpreter reusing decoded instructions from a [`DecodeCache`]
+    Cached,
+
+    /// Execute blocks of pre-resolved handlers with a [`ThreadedEngine`]
+    Threaded,
+
+    /// Run every lane of the JIT at once
+    #[cfg(feature = "vecemu")]
+    Jit,
+}
+
+impl BenchEngine {
+    /// Every engine in the order they are reported
+    const ALL: &'static [BenchEngine] = &[
+        BenchEngine::Interpreter,
+        BenchEngine::Cached,
+        BenchEngine::Threaded,
+        #[cfg(feature = "vecemu")]
+        BenchEngine::Jit,
+    ];
+
+    /// Printable name of the engine
+    fn name(self) -> &'static str {
+        match self {
+            BenchEngine::Interpreter => "interpreter",
+            BenchEngine::Cached => "cached",
+            BenchEngine::Threaded => "threaded",
+            #[cfg(feature = "vecemu")]
+            BenchEngine::Jit => "jit",
+        }
+    }
+}
+
+/// The outcome of benchmarking one program on one engine
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    /// Name of the program
+    pub program: String,
+
+    /// Name of the engine
+    pub engine: &'static str,
+
+    /// Timings of the runs, with the emulated instructions of each run as the work, or
+    /// the reason the program was skipped on this engine
+    pub results: Result<RepetitionResults, String>,
+
+    /// Fraction of the lane slots of the executed instructions doing useful work, which
+    /// is always 1 for the scalar engines
+    pub lane_utilization: f64,
+}
+
+/// Repeat runs of a program until the fastest one stops improving
+///
+/// `setup` creates the state of a run outside of the timed region, and `run` executes it,
+/// returning the number of emulated instructions and the number of lane slots they took.
+fn repeat<S>(
+    try_for: Duration,
+    frequency: u64,
+    mut setup: impl FnMut() -> Result<S>,
+    mut run: impl FnMut(&mut S) -> Result<(u64, u64)>,
+) -> Result<(RepetitionResults, f64)> {
+    let mut tester = RepetitionTester::new(try_for, frequency);
+    let mut utilization = 1.0;
+
+    while tester.is_testing() {
+        let mut state = setup()?;
+
+        tester.begin();
+        let res = run(&mut state);
+        tester.end();
+
+        let (instructions, slots) = res?;
+        tester.count_work(instructions);
+
+        #[allow(clippy::cast_precision_loss)]
+        {
+            utilization = instructions as f64 / slots.max(1) as f64;
+        }
+    }
+
+    Ok((*tester.results(), utilization))
+}
+
+/// Interpret `emu` until its IP leaves the program, returning the instructions executed
+fn interpret(emu: &mut Emu, mut cache: Option<&mut DecodeCache>) -> Result<u64> {
+    let mut executed = 0;
+
+    while usize::from(emu.ip()) < emu.memory.length {
+        let ip = emu.ip();
+        let instr = match cache.as_mut() {
+            Some(cache) => {
+                let cs = emu.segments[SegmentRegister::Cs as usize];
+                cache.decode(cs, &mut emu.registers, &emu.memory)?
+            }
+            None => cpu8086::decoder::decode_instruction(&mut emu.registers, &emu.memory)?,
+        };
+        emu.step(ip, &instr)?;
+        executed += 1;
+
+        // Drop cached instructions overwritten by this instruction
+        let written = emu.memory.take_dirty();
+        if let Some(cache) = cache.as_mut() {
+            for written in &written {
+                cache.invalidate(written);
+            }
+        }
+    }
+
+    Ok(executed)
+}
+
+/// Run `emu` a block at a time on `engine`, returning the instructions executed
+fn run_threaded(emu: &mut Emu, engine: &mut ThreadedEngine<{ 64 * 1024 }>) -> Result<u64> {
+    let mut executed = 0;
+
+    while usize::from(emu.ip()) < emu.memory.length {
+        engine.translate(emu)?;
+        executed += engine.run(emu)?;
+
+        // Drop translated blocks overwritten by this block
+        for written in emu.memory.take_dirty() {
+            engine.invalidate(&written);
+        }
+    }
+
+    Ok(executed)
+}
+
+/// Decode every instruction of the program loaded in `emu`, failing on the first one that
+/// `supports` rejects
+fn check_program(emu: &Emu, supports: fn(&Instruction) -> bool) -> Result<()> {
+    let mut registers = emu.registers.clone();
+
+    while usize::from(registers.ip()) < emu.memory.length {
+        let ip = registers.ip();
+        let instr = decode_instruction(&mut registers, &emu.memory)?;
+        ensure!(supports(&instr), "Cannot execute {instr} at {ip:#x}");
+    }
+
+    Ok(())
+}
+
+/// Check that `engine` can run every instruction of the program at `path`
+fn check_engine(engine: BenchEngine, path: &Path) -> Result<()> {
+    let emu = Emu::with_memory(path)?;
+
+    match engine {
+        BenchEngine::Interpreter | BenchEngine::Cached | BenchEngine::Threaded => {
+            check_program(&emu, Emu::supports)
+        }
+        #[cfg(feature = "vecemu")]
+        BenchEngine::Jit => check_program(&emu, jit::translatable),
+    }
+}
+
+/// Benchmark the program at `path` on `engine`, stopping each run after `budget`
+#[cfg_attr(not(feature = "vecemu"), allow(unused_variables))]
+fn bench_engine(
+    engine: BenchEngine,
+    path: &Path,
+    backend: jit::Backend,
+    budget: Budget,
+    try_for: Duration,
+    frequency: u64,
+) -> Result<(RepetitionResults, f64)> {
+    let emu = || {
+        let mut emu = Box::new(Emu::with_memory(path)?);
+        emu.budget = budget;
+        Ok(emu)
+    };
+
+    match engine {
+        BenchEngine::Interpreter => repeat(try_for, frequency, emu, |emu| {
+            let executed = interpret(emu, None)?;
+            Ok((executed, executed))
+        }),
+        // Each repetition decodes into a new cache, since the memory it was decoded from is
+        // reloaded
+        BenchEngine::Cached => repeat(
+            try_for,
+            frequency,
+            || Ok((emu()?, DecodeCache::new(64 * 1024))),
+            |(emu, cache)| {
+                let executed = interpret(emu, Some(cache))?;
+                Ok((executed, executed))
+            },
+        ),
+        BenchEngine::Threaded => repeat(
+            try_for,
+            frequency,
+            || Ok((emu()?, ThreadedEngine::new())),
+            |(emu, threaded)| {
+                let executed = run_threaded(emu, threaded)?;
+                Ok((executed, executed))
+            },
+        ),
+        #[cfg(feature = "vecemu")]
+        BenchEngine::Jit => {
+            let input = std::fs::read(path)?;
+            let setup = || {
+                let mut state = Box::new(JitEmulatorState::with_inputs(&input, |_| {
+                    LaneInput::default()
+                }));
+                state.budget = budget;
+                let program = JitProgram::<{ 1024 * 1024 }>::new(&input)?;
+                Ok((state, program))
+            };
+
+            repeat(try_for, frequency, setup, |(state, program)| {
+                let executed = program.run_on(state, backend)?;
+                let lane_instructions = program.stats.lane_instructions;
+
+                // A lane running out of its budget ends the run like the scalar engines
+                if let Some(lane) = state
+                    .status
+                    .iter()
+                    .position(|status| *status == LaneStatus::BudgetExhausted)
+                {
+                    return Err(BudgetExhausted(state.spent[lane]).into());
+                }
+
+                Ok((lane_instructions, executed * LANES))
+            })
+        }
+    }
+}
+
+/// Benchmark every program in `programs` on every engine, spending about `try_for` past
+/// the fastest run of each. The JIT runs on `backend`, and every run is stopped after
+/// `budget`.
+pub fn bench(
+    programs: &[PathBuf],
+    backend: jit::Backend,
+    budget: Budget,
+    try_for: Duration,
+    frequency: u64,
+) -> Vec<BenchResult> {
+    let mut results = Vec::new();
+
+    for path in programs {
+        let program = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().to_string(),
+        );
+
+        for &engine in BenchEngine::ALL {
+            // Skip the engines that can't execute every instruction of this program or
+            // that run out of the budget
+            let res = check_engine(engine, path)
+                .and_then(|()| bench_engine(engine, path, backend, budget, try_for, frequency));
+
+            let (results_or_err, lane_utilization) = match res {
+                Ok((res, utilization)) => (Ok(res), utilization),
+                Err(err) => (Err(err.to_string()), 0.0),
+            };
+
+            results.push(BenchResult {
+                program: program.clone(),
+                engine: engine.name(),
+                results: results_or_err,
+                lane_utilization,
+            });
+        }
+    }
+
+    results
+}
+
+/// Format the benchmark results as a table with a row per program and engine
+pub fn table(results: &[BenchResult]) -> String {
+    let mut out = String::new();
+
+    let width = results
+        .iter()
+        .map(|res| res.program.len())
+        .max()
+        .unwrap_or_default()
+        .max("Program".len());
+
+    let _ = writeln!(
+        out,
+        "{:width$} {:11} {:>6} {:>8} {:>10} {:>10} {:>10} {:>8} {:>12} {:>12} {:>6}",
+        "Program",
+        "Engine",
+        "Runs",
+        "Instrs",
+        "Min cyc",
+        "Avg cyc",
+        "Max cyc",
+        "Cyc/ins",
+        "Min ins/s",
+        "Avg ins/s",
+        "Lanes"
+    );
+
+    for res in results {
+        let _ = write!(out, "{:width$} {:11} ", res.program, res.engine);
+
+        let timings = match &res.results {
+            Ok(timings) => timings,
+            Err(err) => {
+                let err = err.lines().next().unwrap_or_default();
+                let _ = writeln!(out, "skipped: {err}");
+                continue;
+            }
+        };
+
+        let _ = writeln!(
+            out,
+            "{:>6} {:>8} {:>10} {:>10.0} {:>10} {:>8.2} {:>12.0} {:>12.0} {:>5.1}%",
+            timings.count,
+            timings.min_work,
+            timings.min_cycles,
+            timings.avg_cycles(),
+            timings.max_cycles,
+            timings.min_cycles_per_work(),
+            timings.min_work_per_second(),
+            timings.avg_work_per_second(),
+            res.lane_utilization * 100.
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu8086::budget::BudgetExhausted;
+
+    /// Load `code` at address 0 of a new [`Emu`]
+    fn emu(code: &[u8]) -> Box<Emu> {
+        let mut emu = Box::new(Emu::new());
+        emu.memory.memory[..code.len()].copy_from_slice(code);
+        emu.memory.length = code.len();
+        emu
+    }
+
+    #[test]
+    fn test_check_program() {
+        // mov cx, 3; add ax, 5; loop -5
+        let supported = emu(b"\xb9\x03\x00\x05\x05\x00\xe2\xfb");
+        assert!(check_program(&supported, Emu::supports).is_ok());
+
+        // mov cx, 3; hlt
+        let unsupported = emu(b"\xb9\x03\x00\xf4");
+        let err = check_program(&unsupported, Emu::supports).unwrap_err();
+        assert!(err.to_string().contains("0x3"), "{err}");
+    }
+
+    #[test]
+    fn test_interpret_budget() {
+        // mov cx, 0xffff; loop -2
+        let mut emu = emu(b"\xb9\xff\xff\xe2\xfe");
+        emu.budget.instructions = Some(100);
+
+        let err = interpret(&mut emu, None).unwrap_err();
+        assert!(err.downcast_ref::<BudgetExhausted>().is_some(), "{err}");
+    }
+}
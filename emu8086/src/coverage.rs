@@ -0,0 +1,109 @@
+//! Listing of a program annotated with the coverage of a run
+
+use std::fmt::Write as _;
+
+use cpu8086::coverage::Coverage;
+use cpu8086::decoder::decode_instruction;
+use cpu8086::emu::RegisterState;
+use cpu8086::memory::Memory;
+
+/// Render the listing of the program in `memory` with the number of times each instruction
+/// was executed and the IPs each branch continued at, from `coverage`.
+///
+/// If given, `lanes` gets the mask of the JIT lanes that ran the block starting at an IP,
+/// which is listed in its own column.
+pub fn listing(
+    coverage: &Coverage,
+    memory: &Memory<{ 64 * 1024 }>,
+    lanes: Option<&dyn Fn(u16) -> u32>,
+) -> String {
+    let mut lines = String::new();
+    let mut cpu = RegisterState::default();
+    let mut instructions = 0;
+
+    while usize::from(cpu.ip()) < memory.length {
+        let ip = cpu.ip();
+        let Ok(instr) = decode_instruction(&mut cpu, memory) else {
+            let _ = writeln!(lines, "; Undecodable bytes from {ip:04x}");
+            break;
+        };
+        instructions += 1;
+
+        let hits = match coverage.hits(ip) {
+            0 => "-".to_string(),
+            hits => hits.to_string(),
+        };
+        let _ = write!(lines, "{hits:>8}  {ip:04x}  ");
+
+        if let Some(lanes) = lanes {
+            match lanes(ip) {
+                0 => lines.push_str("            "),
+                mask => {
+                    let _ = write!(lines, "{mask:#010x}  ");
+                }
+            }
+        }
+
+        let instr = instr.to_string();
+        let edges: Vec<String> = coverage
+            .edges_from(ip)
+            .map(|(to, count)| format!("{to:04x} x{count}"))
+            .collect();
+        if edges.is_empty() {
+            let _ = writeln!(lines, "{instr}");
+        } else {
+            let _ = writeln!(lines, "{instr:<24} ; -> {}", edges.join(", "));
+        }
+    }
+
+    let mut header = format!(
+        "; {} of {instructions} instructions executed, {} branch edges taken\n",
+        coverage.instructions(),
+        coverage.edges()
+    );
+    header.push_str(if lanes.is_some() {
+        ";   hits  ip    jit lanes   instruction\n"
+    } else {
+        ";   hits  ip    instruction\n"
+    });
+
+    header + &lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu8086::emu::Emulator;
+
+    #[test]
+    fn test_listing() {
+        // mov cx, 2; add ax, 5; loop -5; hlt
+        let code = b"\xb9\x02\x00\x05\x05\x00\xe2\xfb\xf4";
+
+        let mut emu = Emulator::<{ 64 * 1024 }>::new();
+        emu.memory.memory[..code.len()].copy_from_slice(code);
+        emu.memory.length = code.len();
+        emu.coverage = Some(Coverage::new());
+
+        // Stop before the `hlt`, leaving it uncovered
+        while emu.ip() < 8 {
+            let ip = emu.ip();
+            let instr = decode_instruction(&mut emu.registers, &emu.memory).unwrap();
+            emu.step(ip, &instr).unwrap();
+        }
+
+        let coverage = emu.coverage.as_ref().unwrap();
+        let listing = listing(coverage, &emu.memory, Some(&|ip| u32::from(ip == 3)));
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "; 3 of 4 instructions executed, 2 branch edges taken"
+        );
+        assert!(lines[2].starts_with("       1  0000              mov cx"));
+        assert!(lines[3].starts_with("       2  0003  0x00000001  add ax"));
+        assert!(lines[4].starts_with("       2  0006              loop"));
+        assert!(lines[4].ends_with("; -> 0003 x1, 0008 x1"));
+        assert_eq!(lines[5], "       -  0008              hlt");
+    }
+}
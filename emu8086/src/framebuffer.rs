@@ -0,0 +1,315 @@
+//! Export a region of the emulator memory as an image
+
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+
+use std::ops::Range;
+use std::path::Path;
+
+/// Layout of a single pixel in the emulator memory
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel: red, green, blue, alpha
+    Rgba,
+
+    /// 4 bytes per pixel: blue, green, red, alpha
+    Bgra,
+
+    /// 3 bytes per pixel: red, green, blue
+    Rgb,
+
+    /// 1 byte per pixel: grayscale intensity
+    Gray,
+}
+
+impl PixelFormat {
+    /// Number of bytes a single pixel occupies in memory
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+            PixelFormat::Rgb => 3,
+            PixelFormat::Gray => 1,
+        }
+    }
+
+    /// Convert the pixel starting at `bytes` into `[red, green, blue, alpha]`
+    pub fn to_rgba(self, bytes: &[u8]) -> [u8; 4] {
+        match self {
+            PixelFormat::Rgba => [bytes[0], bytes[1], bytes[2], bytes[3]],
+            PixelFormat::Bgra => [bytes[2], bytes[1], bytes[0], bytes[3]],
+            PixelFormat::Rgb => [bytes[0], bytes[1], bytes[2], 0xff],
+            PixelFormat::Gray => [bytes[0], bytes[0], bytes[0], 0xff],
+        }
+    }
+}
+
+/// Image file format used when writing a framebuffer to disk
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    /// Portable Network Graphics (keeps the alpha channel)
+    Png,
+
+    /// Portable PixMap (binary P6, alpha channel is dropped)
+    Ppm,
+}
+
+impl ImageFormat {
+    /// File extension used for this image format
+    pub const fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// A region of emulator memory interpreted as a linear framebuffer
+#[derive(Debug, Copy, Clone)]
+pub struct Framebuffer {
+    /// Offset in memory of the first pixel
+    pub offset: usize,
+
+    /// Width of the framebuffer in pixels
+    pub width: usize,
+
+    /// Height of the framebuffer in pixels
+    pub height: usize,
+
+    /// Layout of each pixel in memory
+    pub format: PixelFormat,
+}
+
+impl Framebuffer {
+    /// Number of bytes covered by this framebuffer in memory
+    pub fn size(&self) -> Result<usize> {
+        ensure!(
+            self.width > 0 && self.height > 0,
+            "Framebuffer {}x{} is empty",
+            self.width,
+            self.height
+        );
+
+        self.width
+            .checked_mul(self.height)
+            .and_then(|pixels| pixels.checked_mul(self.format.bytes_per_pixel()))
+            .with_context(|| {
+                format!(
+                    "Framebuffer {}x{} ({:?}) is too large",
+                    self.width, self.height, self.format
+                )
+            })
+    }
+
+    /// Range of memory covered by this framebuffer
+    pub fn range(&self) -> Result<Range<usize>> {
+        let end = self.offset.checked_add(self.size()?).with_context(|| {
+            format!(
+                "Framebuffer at {:#x} extends past the end of the address space",
+                self.offset
+            )
+        })?;
+
+        Ok(self.offset..end)
+    }
+
+    /// Get the framebuffer bytes from the given emulator memory
+    pub fn bytes<'a>(&self, memory: &'a [u8]) -> Result<&'a [u8]> {
+        let range = self.range()?;
+        ensure!(
+            range.end <= memory.len(),
+            "Framebuffer {:#x}..{:#x} is outside of memory (size {:#x})",
+            range.start,
+            range.end,
+            memory.len()
+        );
+
+        Ok(&memory[range])
+    }
+
+    /// Get the pixel at (`x`, `y`) from the framebuffer bytes as `[r, g, b, a]`
+    pub fn pixel(&self, bytes: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let bpp = self.format.bytes_per_pixel();
+        let start = (y * self.width + x) * bpp;
+        self.format.to_rgba(&bytes[start..start + bpp])
+    }
+
+    /// Write the framebuffer found in `memory` to `path` using the given [`ImageFormat`]
+    pub fn write(&self, memory: &[u8], image_format: ImageFormat, path: &Path) -> Result<()> {
+        let bytes = self.bytes(memory)?;
+
+        let image = match image_format {
+            ImageFormat::Png => self.encode_png(bytes)?,
+            ImageFormat::Ppm => self.encode_ppm(bytes),
+        };
+
+        std::fs::write(path, image)?;
+        Ok(())
+    }
+
+    /// Encode the framebuffer as a binary (P6) PPM
+    fn encode_ppm(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let [r, g, b, _a] = self.pixel(bytes, x, y);
+                image.extend([r, g, b]);
+            }
+        }
+
+        image
+    }
+
+    /// Encode the framebuffer as an 8-bit RGBA PNG
+    fn encode_png(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let width = u32::try_from(self.width).context("Framebuffer too wide for a PNG")?;
+        let height = u32::try_from(self.height).context("Framebuffer too tall for a PNG")?;
+
+        // Each scanline is prefixed with its filter type (0 => None)
+        let mut raw = Vec::with_capacity(self.height * (1 + self.width * 4));
+        for y in 0..self.height {
+            raw.push(0);
+            for x in 0..self.width {
+                raw.extend(self.pixel(bytes, x, y));
+            }
+        }
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend(width.to_be_bytes());
+        ihdr.extend(height.to_be_bytes());
+
+        // Bit depth 8, color type 6 (RGBA), default compression/filter, no interlace
+        ihdr.extend([8, 6, 0, 0, 0]);
+
+        let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_png_chunk(&mut image, b"IHDR", &ihdr);
+        write_png_chunk(&mut image, b"IDAT", &zlib_stored(&raw));
+        write_png_chunk(&mut image, b"IEND", &[]);
+        Ok(image)
+    }
+}
+
+/// Append a PNG chunk (length, type, data, crc) to `image`
+fn write_png_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend(u32::try_from(data.len()).unwrap().to_be_bytes());
+
+    let crc_start = image.len();
+    image.extend(kind);
+    image.extend(data);
+
+    let crc = crc32(&image[crc_start..]);
+    image.extend(crc.to_be_bytes());
+}
+
+/// Wrap `data` in a zlib stream made of uncompressed (stored) deflate blocks
+///
+/// The framebuffers are tiny, so skipping compression keeps the encoder trivial
+/// while still producing a valid PNG.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xffff;
+
+    // CMF: deflate with 32K window, FLG: no dictionary, fastest compression
+    let mut res = vec![0x78, 0x01];
+
+    let mut chunks = data.chunks(MAX_BLOCK).peekable();
+
+    // An empty input still needs a single final block
+    if chunks.peek().is_none() {
+        res.extend([1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(chunk) = chunks.next() {
+        let is_final = u8::from(chunks.peek().is_none());
+        let len = u16::try_from(chunk.len()).unwrap();
+
+        res.push(is_final);
+        res.extend(len.to_le_bytes());
+        res.extend((!len).to_le_bytes());
+        res.extend(chunk);
+    }
+
+    res.extend(adler32(data).to_be_bytes());
+    res
+}
+
+/// CRC-32 (ISO-HDLC) as used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Adler-32 checksum as used by zlib streams
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for byte in data {
+        a = (a + u32::from(*byte)) % MOD;
+        b = (b + a) % MOD;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_encode_ppm() {
+        let fb = Framebuffer {
+            offset: 2,
+            width: 2,
+            height: 1,
+            format: PixelFormat::Bgra,
+        };
+
+        let memory = [0, 0, 1, 2, 3, 0xff, 4, 5, 6, 0xff];
+        let ppm = fb.encode_ppm(fb.bytes(&memory).unwrap());
+        assert_eq!(ppm, b"P6\n2 1\n255\n\x03\x02\x01\x06\x05\x04");
+    }
+
+    #[test]
+    fn test_overflowing_region() {
+        let memory = [0; 16];
+
+        let huge = Framebuffer {
+            offset: 0,
+            width: usize::MAX,
+            height: 2,
+            format: PixelFormat::Rgba,
+        };
+        assert!(huge.size().is_err());
+        assert!(huge.bytes(&memory).is_err());
+
+        let wrapping = Framebuffer {
+            offset: usize::MAX - 1,
+            width: 1,
+            height: 1,
+            format: PixelFormat::Rgba,
+        };
+        assert!(wrapping.range().is_err());
+        assert!(wrapping.bytes(&memory).is_err());
+
+        let empty = Framebuffer {
+            offset: 0,
+            width: 0,
+            height: 4,
+            format: PixelFormat::Gray,
+        };
+        assert!(empty.size().is_err());
+    }
+}
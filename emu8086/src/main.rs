@@ -2,42 +2,331 @@
 
 #![deny(missing_docs)]
 #![feature(stdsimd)]
-#![feature(variant_count)]
 #![feature(generic_const_exprs)]
 #![allow(incomplete_features)]
 
 use anyhow::Result;
+use clap::{Parser, ValueEnum};
 
 #[cfg(feature = "vecemu")]
-use jit::JitBuffer;
+use jit::JitProgram;
 #[cfg(feature = "vecemu")]
-use jit_emu::{Core, JitEmulatorState};
+use jit_emu::{Core, CpuState, JitEmulatorState, LaneInput};
 
-use std::arch::asm;
+use std::fmt::Write as _;
 use std::fs::File;
 use std::io::Write;
-use std::path::Path;
-use std::time::{Duration, Instant};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use cpu8086::budget::{Budget, BudgetExhausted};
+use cpu8086::coverage::Coverage;
+use cpu8086::decode_cache::{DecodeCache, DecodeCacheStats};
 use cpu8086::emu::Emulator;
 use cpu8086::instruction::Instruction;
+use cpu8086::register::SegmentRegister;
+use cpu8086::threaded::{ThreadedEngine, ThreadedStats};
+use profiler::{time, Profiler, Report, ReportFormat, Zones};
+
+mod bench;
+mod coverage;
+
+mod framebuffer;
+use framebuffer::{Framebuffer, ImageFormat, PixelFormat};
+
+mod viewer;
+use viewer::{every_hit, Viewer};
+
+/// Execute an 8086 binary
+#[derive(Parser, Debug)]
+struct Args {
+    /// The 8086 binary to decode and execute
+    #[arg(required_unless_present_any = ["fuzz", "bench"])]
+    input: Option<PathBuf>,
+
+    /// Write the framebuffer region of memory as an image after execution
+    #[arg(long)]
+    framebuffer: bool,
+
+    /// Offset in memory of the first framebuffer pixel
+    #[arg(long, default_value_t = 256)]
+    fb_offset: usize,
+
+    /// Width of the framebuffer in pixels
+    #[arg(long, default_value_t = 64)]
+    fb_width: usize,
+
+    /// Height of the framebuffer in pixels
+    #[arg(long, default_value_t = 64)]
+    fb_height: usize,
+
+    /// Layout of each framebuffer pixel in memory
+    #[arg(long, value_enum, default_value_t = PixelFormat::Rgba)]
+    fb_pixel_format: PixelFormat,
+
+    /// Image format of the written framebuffer
+    #[arg(long, value_enum, default_value_t = ImageFormat::Png)]
+    fb_image_format: ImageFormat,
+
+    /// Additionally write an animation frame of the framebuffer every N instructions
+    #[arg(long)]
+    fb_every: Option<u64>,
+
+    /// Render the framebuffer region in the terminal while the program runs
+    #[arg(long)]
+    live: bool,
+
+    /// Redraw the live view every N instructions
+    #[arg(long)]
+    live_every: Option<u64>,
+
+    /// Redraw the live view whenever an instruction writes into the framebuffer region
+    #[arg(long)]
+    live_on_write: bool,
+
+    /// Milliseconds to pause after each live frame
+    #[arg(long, default_value_t = 0)]
+    live_delay_ms: u64,
+
+    /// Number of times to run the program to average the performance stats
+    #[arg(long, default_value_t = 1)]
+    iterations: usize,
+
+    /// Format of the performance stats: text, json or csv
+    #[arg(long, default_value = "text")]
+    stats_format: ReportFormat,
+
+    /// Write the performance stats to this file instead of stdout
+    #[arg(long)]
+    stats_output: Option<PathBuf>,
+
+    /// Reuse decoded instructions from a cache keyed by CS:IP
+    #[arg(long, value_enum, default_value_t = DecodeCacheMode::On)]
+    decode_cache: DecodeCacheMode,
+
+    /// Execution engine used to run the program
+    #[arg(long, value_enum, default_value_t = Engine::Interpreter)]
+    engine: Engine,
+
+    /// Stop the program after this many instructions, on every engine and in each JIT lane
+    #[arg(long)]
+    max_instructions: Option<u64>,
+
+    /// Stop the program after this many estimated 8086 clock cycles, on every engine and in
+    /// each JIT lane
+    #[arg(long)]
+    max_cycles: Option<u64>,
+
+    /// Write the listing of the program annotated with the number of times each instruction
+    /// was executed, the edges taken by each branch and the JIT lanes that ran each block
+    #[arg(long)]
+    coverage: bool,
+
+    /// Instead of running a binary, fuzz the JIT against the interpreter for N random
+    /// programs and report the first lane that differs
+    #[arg(long)]
+    fuzz: Option<u64>,
+
+    /// Seed of the first fuzz case, in decimal or 0x-prefixed hex. A random seed is used
+    /// if not given.
+    #[arg(long, value_parser = parse_seed)]
+    fuzz_seed: Option<u64>,
+
+    /// Instead of running a binary once, benchmark the throughput of every engine on it, or
+    /// on every course listing in `tests` if no binary is given
+    #[arg(long)]
+    bench: bool,
+
+    /// Seconds to keep repeating each benchmark after its fastest run
+    #[arg(long, default_value_t = 1.0)]
+    bench_seconds: f64,
+
+    /// Evaluate the JIT code in a software model instead of natively while fuzzing. This
+    /// is the default on hosts without AVX512BW or AVX2.
+    #[arg(long)]
+    fuzz_model: bool,
+
+    /// How the JIT runs the program, or the fuzz cases. Defaults to native on hosts with
+    /// AVX512BW, to avx2 on hosts with only AVX2, and to simd when running a program (or
+    /// model when fuzzing) otherwise.
+    #[arg(long, value_enum)]
+    jit_backend: Option<JitBackend>,
+
+    /// Print the IL of each block translated by the JIT before and after each optimization
+    /// pass
+    #[arg(long)]
+    dump_il: bool,
+
+    /// Format of the listing of the JIT code translated from each instruction
+    #[arg(long, value_enum, default_value_t = ListingFormat::Text)]
+    listing_format: ListingFormat,
+
+    /// Write the listing of the JIT code to this file instead of stdout
+    #[arg(long)]
+    listing_output: Option<PathBuf>,
+
+    /// File with the initial registers of each JIT lane, one line per lane such as
+    /// `ax=0x10 cx=3`. Registers and lanes not given start at zero.
+    #[arg(long)]
+    lane_registers: Option<PathBuf>,
+
+    /// Memory image loaded into each JIT lane after the program, as `PATH[@ADDRESS]`. Any
+    /// `{lane}` in the path is replaced by the lane number to give each lane its own image.
+    #[arg(long)]
+    lane_memory: Vec<String>,
+
+    /// Range of the memory of each JIT lane collected into the results table, as
+    /// `START..END`
+    #[arg(long, value_parser = parse_memory_range)]
+    results_memory: Option<Range<usize>>,
+
+    /// Format of the table of the final registers and memory of each JIT lane, printed
+    /// when the lanes are given inputs or a results option is used
+    #[arg(long, value_enum, default_value_t = ResultsFormat::Text)]
+    results_format: ResultsFormat,
+
+    /// Write the results table to this file instead of stdout
+    #[arg(long)]
+    results_output: Option<PathBuf>,
+}
 
-#[derive(Debug)]
-enum Stats {
-    CreateEmuFromInput,
-    Decode,
-    Execute,
-    WriteDecode,
-    #[cfg(feature = "vecemu")]
-    BuildJit,
-    #[cfg(feature = "vecemu")]
-    ExecJit,
+#[cfg(feature = "vecemu")]
+impl Args {
+    /// Returns `true` if the JIT lanes run a batch of inputs whose results are collected
+    fn batch(&self) -> bool {
+        self.lane_registers.is_some()
+            || !self.lane_memory.is_empty()
+            || self.results_memory.is_some()
+            || self.results_output.is_some()
+    }
 }
 
-/// Read the time stamp using rdtscp to ensure previous instructions have been executed
-fn rdtsc() -> u64 {
-    let mut x = 0;
-    unsafe { std::arch::x86_64::__rdtscp(&mut x) }
+/// Engine used to execute the 8086 program
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    /// Decode and execute one instruction at a time
+    Interpreter,
+
+    /// Translate basic blocks into pre-resolved handlers and execute a block at a time
+    Threaded,
+}
+
+/// How the JIT code is executed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum JitBackend {
+    /// Run the JIT code on the host CPU, which must support AVX512BW
+    Native,
+
+    /// Evaluate the JIT code in a software model of its instructions
+    Model,
+
+    /// Interpret the IL of each block over `std::simd` vectors
+    Simd,
+
+    /// Run AVX2 code generated from the IL of each block on the host CPU
+    Avx2,
+}
+
+impl From<JitBackend> for jit::Backend {
+    fn from(backend: JitBackend) -> jit::Backend {
+        match backend {
+            JitBackend::Native => jit::Backend::Native,
+            JitBackend::Model => jit::Backend::Model,
+            JitBackend::Simd => jit::Backend::Simd,
+            JitBackend::Avx2 => jit::Backend::Avx2,
+        }
+    }
+}
+
+/// Format of the JIT code listing
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum ListingFormat {
+    /// The 8086 and host code side by side in aligned columns
+    Text,
+
+    /// A Markdown table
+    Markdown,
+
+    /// An HTML table
+    Html,
+}
+
+impl From<ListingFormat> for jit::ListingFormat {
+    fn from(format: ListingFormat) -> jit::ListingFormat {
+        match format {
+            ListingFormat::Text => jit::ListingFormat::Text,
+            ListingFormat::Markdown => jit::ListingFormat::Markdown,
+            ListingFormat::Html => jit::ListingFormat::Html,
+        }
+    }
+}
+
+/// Format of the JIT results table
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum ResultsFormat {
+    /// Aligned columns with a row per lane
+    Text,
+
+    /// Comma separated values with a row per lane
+    Csv,
+}
+
+/// How the interpreter uses the decoded instruction cache
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum DecodeCacheMode {
+    /// Decode every instruction each time it is executed
+    Off,
+
+    /// Decode each instruction once and reuse it until its bytes are written
+    On,
+
+    /// Run every iteration without and then with the cache to compare the two
+    Compare,
+}
+
+profiler::zones! {
+    /// Profiling zones of the emulator
+    enum Stats {
+        CreateEmuFromInput,
+        Decode,
+        Execute,
+        CachedDecode,
+        CachedExecute,
+        TranslateBlock,
+        ExecuteBlock,
+        WriteDecode,
+        ExecJit,
+    }
+}
+
+/// Get the value of a run of the program, or `None` after printing how far it got if it
+/// ran out of its budget
+fn stop_on_budget<T>(res: Result<T>) -> Result<Option<T>> {
+    match res {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => match err.downcast::<BudgetExhausted>() {
+            Ok(exhausted) => {
+                println!("{exhausted}");
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Describe how the cycles of the given decode and execute zones were split, if both were hit
+#[allow(clippy::cast_precision_loss)]
+fn decode_execute_split(report: &Report, decode: Stats, execute: Stats) -> Option<String> {
+    let decode = report.zone(decode.name())?.inclusive_cycles;
+    let execute = report.zone(execute.name())?.inclusive_cycles;
+    let total = (decode + execute).max(1) as f64;
+
+    Some(format!(
+        "decode {:6.2}% ({decode} cycles) | execute {:6.2}% ({execute} cycles)",
+        decode as f64 / total * 100.,
+        execute as f64 / total * 100.
+    ))
 }
 
 // Attempt to write the CPU speed if we know about it
@@ -54,69 +343,267 @@ fn print_cpu_speed() {
     }
 }
 
-/// Number of iterations to measure the test with
-const ITERS: usize = 0x1;
+/// Parse a decimal or `0x` prefixed hex seed
+fn parse_seed(seed: &str) -> Result<u64, std::num::ParseIntError> {
+    match seed.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => seed.parse(),
+    }
+}
 
-#[allow(clippy::too_many_lines)]
-fn main() -> Result<()> {
-    // Print CPU speed of the processor running the emulator
-    print_cpu_speed();
+/// Parse a memory range such as `0x100..0x140`
+fn parse_memory_range(range: &str) -> Result<Range<usize>, String> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("Expected START..END, found {range:?}"))?;
+    let parse = |value: &str| {
+        parse_seed(value)
+            .ok()
+            .and_then(|value| usize::try_from(value).ok())
+            .ok_or_else(|| format!("Invalid address {value:?}"))
+    };
+
+    Ok(parse(start)?..parse(end)?)
+}
 
-    let term_width = 40;
+/// Parse the registers of each lane from the lines of `text`, such as `ax=0x10 cx=3`
+///
+/// Line `n` holds the registers of lane `n - 1`, and anything after a `#` is a comment.
+#[cfg(feature = "vecemu")]
+fn parse_lane_registers(text: &str) -> Result<Vec<CpuState>> {
+    let mut lanes = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut cpu = CpuState::default();
+
+        for field in line.split_whitespace() {
+            let Some((reg, value)) = field.split_once('=') else {
+                anyhow::bail!("Line {}: expected REG=VALUE, found {field:?}", i + 1);
+            };
+            let Some(value) = parse_seed(value)
+                .ok()
+                .and_then(|value| u16::try_from(value).ok())
+            else {
+                anyhow::bail!("Line {}: invalid value {value:?} for {reg}", i + 1);
+            };
+
+            let slot = match reg.to_ascii_lowercase().as_str() {
+                "ax" => &mut cpu.ax,
+                "bx" => &mut cpu.bx,
+                "cx" => &mut cpu.cx,
+                "dx" => &mut cpu.dx,
+                "si" => &mut cpu.si,
+                "di" => &mut cpu.di,
+                "sp" => &mut cpu.sp,
+                "bp" => &mut cpu.bp,
+                "ip" => &mut cpu.ip,
+                "flags" => &mut cpu.flags,
+                "es" => &mut cpu.es,
+                "cs" => &mut cpu.cs,
+                "ss" => &mut cpu.ss,
+                "ds" => &mut cpu.ds,
+                _ => anyhow::bail!("Line {}: unknown register {reg:?}", i + 1),
+            };
+            *slot = value;
+        }
 
-    // Read the input file to decode
-    let input_file = std::env::args()
-        .nth(1)
-        .expect("USAGE: ./emu8086 <8086_File>");
+        lanes.push(cpu);
+    }
 
-    // Set the output file
-    let output_file = Path::new(&input_file).with_extension("rebuilt.decoded.asm");
+    anyhow::ensure!(
+        lanes.len() <= 32,
+        "Registers given for {} lanes, but there are 32 lanes",
+        lanes.len()
+    );
+    Ok(lanes)
+}
+
+/// Read the memory images of `core` from `images`, each given as `PATH[@ADDRESS]`
+#[cfg(feature = "vecemu")]
+fn lane_memory(images: &[String], core: u8) -> Result<Vec<(u16, Vec<u8>)>> {
+    images
+        .iter()
+        .map(|image| {
+            let (path, address) = match image.rsplit_once('@') {
+                Some((path, address)) => {
+                    let address = parse_seed(address)
+                        .ok()
+                        .and_then(|address| u16::try_from(address).ok());
+                    let Some(address) = address else {
+                        anyhow::bail!("Invalid address in the memory image {image:?}");
+                    };
+                    (path, address)
+                }
+                None => (image.as_str(), 0),
+            };
+
+            let path = path.replace("{lane}", &core.to_string());
+            let bytes = std::fs::read(&path)?;
+            anyhow::ensure!(
+                usize::from(address) + bytes.len() <= jit_emu::MEMORY_SIZE,
+                "The {} bytes of {path} do not fit at {address:#x}",
+                bytes.len()
+            );
+            Ok((address, bytes))
+        })
+        .collect()
+}
 
-    // Init statistics for this performance check
-    let mut stats = [0u64; std::mem::variant_count::<Stats>()];
-    let mut stats_time = [Duration::from_secs(0); std::mem::variant_count::<Stats>()];
+/// Get the JIT backend used to run a program, which is `backend` if given
+///
+/// The model is only meant for checking the generated code, so hosts that can't run it
+/// natively default to the simd backend.
+fn run_backend(backend: Option<JitBackend>) -> jit::Backend {
+    match backend {
+        Some(backend) => backend.into(),
+        None => match jit::Backend::detect() {
+            jit::Backend::Model => jit::Backend::Simd,
+            backend => backend,
+        },
+    }
+}
 
-    let mut best_stats = [u64::MAX; std::mem::variant_count::<Stats>()];
-    let mut best_stats_time = [Duration::from_secs(u64::MAX); std::mem::variant_count::<Stats>()];
+/// Benchmark every engine on `input`, or on every course listing if not given
+fn run_bench(
+    input: Option<&Path>,
+    backend: jit::Backend,
+    budget: Budget,
+    seconds: f64,
+) -> Result<()> {
+    let programs = match input {
+        Some(input) => vec![input.to_path_buf()],
+        None => {
+            let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+            let mut programs = std::fs::read_dir(tests)?
+                .map(|entry| Ok(entry?.path()))
+                .filter(|path| {
+                    path.as_ref()
+                        .map_or(true, |path: &PathBuf| path.extension().is_none())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            programs.sort();
+            programs
+        }
+    };
 
-    println!("Number of iterations: {ITERS:#x} {ITERS}");
+    let frequency = profiler::estimate_timer_frequency(Duration::from_millis(100));
+    println!(
+        "Benchmarking {} programs for {seconds}s past each fastest run, JIT on the {backend:?} backend",
+        programs.len()
+    );
 
-    let total_start = rdtsc();
+    let results = bench::bench(
+        &programs,
+        backend,
+        budget,
+        Duration::from_secs_f64(seconds),
+        frequency,
+    );
+    print!("{}", bench::table(&results));
 
-    /// Macro used for timing work
-    macro_rules! time {
-        ($stat:ident, $work:expr) => {{
-            // Start the timer
-            let start_time = Instant::now();
-            let start = rdtsc();
+    Ok(())
+}
 
-            // Perform the work being timed
-            let res = $work;
+/// Fuzz the JIT against the interpreter for `cases` random programs starting at `seed`
+fn fuzz(cases: u64, seed: Option<u64>, model: bool, backend: Option<JitBackend>) -> Result<()> {
+    let seed = seed.unwrap_or_else(|| jit::Rng::new().next());
+    let backend = match backend {
+        _ if model => jit::Backend::Model,
+        Some(backend) => backend.into(),
+        None => jit::Backend::detect(),
+    };
+
+    println!("Fuzzing {cases} cases from seed {seed:#x} on the {backend:?} backend");
+
+    match jit::fuzz(seed, cases, backend)? {
+        Some(mismatch) => {
+            print!("{mismatch}");
+            anyhow::bail!(
+                "JIT differs from the interpreter in case {:#x}",
+                mismatch.seed
+            )
+        }
+        None => println!("No differences found"),
+    }
 
-            // Add the elapsed time this work took
-            let curr_stats_cycle = rdtsc() - start;
+    Ok(())
+}
 
-            // Update the clock time stats
-            let curr_stats_time = start_time.elapsed();
+#[allow(clippy::too_many_lines)]
+fn main() -> Result<()> {
+    // Print CPU speed of the processor running the emulator
+    print_cpu_speed();
 
-            stats[Stats::$stat as usize] += curr_stats_cycle;
+    let term_width = 40;
 
-            // If this is the best stats time, update it.
-            if best_stats[Stats::$stat as usize] > curr_stats_cycle {
-                best_stats[Stats::$stat as usize] = curr_stats_cycle;
-            }
+    // Parse the command line arguments
+    let args = Args::parse();
 
-            stats_time[Stats::$stat as usize] += curr_stats_time;
+    if let Some(cases) = args.fuzz {
+        return fuzz(cases, args.fuzz_seed, args.fuzz_model, args.jit_backend);
+    }
 
-            // If this is the fastest clock time seen, update it
-            if best_stats_time[Stats::$stat as usize] > curr_stats_time {
-                best_stats_time[Stats::$stat as usize] = curr_stats_time;
-            }
+    if args.bench {
+        return run_bench(
+            args.input.as_deref(),
+            run_backend(args.jit_backend),
+            Budget {
+                instructions: args.max_instructions,
+                cycles: args.max_cycles,
+            },
+            args.bench_seconds,
+        );
+    }
 
-            // Return the result from the work
-            res
-        }};
+    // Read the input file to decode
+    let input = args
+        .input
+        .clone()
+        .expect("Input is required when not fuzzing");
+    let input_file = input.display().to_string();
+
+    // Framebuffer region in memory used for image dumps and the live view
+    let fb_region = Framebuffer {
+        offset: args.fb_offset,
+        width: args.fb_width,
+        height: args.fb_height,
+        format: args.fb_pixel_format,
+    };
+
+    // Framebuffer region to export, if requested
+    let framebuffer = (args.framebuffer || args.fb_every.is_some()).then_some(fb_region);
+
+    // Reject empty framebuffers or ones whose region doesn't fit in the address space
+    if framebuffer.is_some() || args.live {
+        fb_region.range()?;
     }
+    let image_ext = args.fb_image_format.extension();
+
+    // Set the output file
+    let output_file = Path::new(&input_file).with_extension("rebuilt.decoded.asm");
+
+    // Compare mode runs each iteration once without and once with the decode cache
+    let passes =
+        if args.engine == Engine::Interpreter && args.decode_cache == DecodeCacheMode::Compare {
+            2
+        } else {
+            1
+        };
+
+    let iterations = args.iterations;
+    println!("Number of iterations: {iterations:#x} {iterations}");
+
+    // Size of the input, used for the throughput of loading it
+    let input_size = std::fs::metadata(&input)?.len();
+
+    // Measure the timer frequency up front so it isn't counted in the total time
+    let timer_frequency = profiler::estimate_timer_frequency(Duration::from_millis(100));
+
+    // Init the profiler for this performance check
+    let prof = Profiler::<Stats>::new();
+    let mut cache_stats = DecodeCacheStats::default();
+    let mut threaded_stats = ThreadedStats::default();
 
     // Run the tests over a number of iterations in order to average the time
     let mut file = File::create(&output_file)?;
@@ -124,155 +611,343 @@ fn main() -> Result<()> {
     file.write_all(format!("; Decoded from {input_file}\n").as_bytes())?;
     file.write_all(b"bits 16\n")?;
 
-    let debug_on = false;
+    let budget = Budget {
+        instructions: args.max_instructions,
+        cycles: args.max_cycles,
+    };
 
     // Main iteration loop
-    for iteration in 0..ITERS {
+    for iteration in 0..iterations * passes {
+        let use_cache = match args.decode_cache {
+            DecodeCacheMode::Off => false,
+            DecodeCacheMode::On => true,
+            DecodeCacheMode::Compare => iteration % 2 == 1,
+        };
+
+        let (decode_zone, execute_zone) = if use_cache {
+            (Stats::CachedDecode, Stats::CachedExecute)
+        } else {
+            (Stats::Decode, Stats::Execute)
+        };
+
         // Init the emulator
         let mut emu = time!(
-            CreateEmuFromInput,
+            prof,
+            Stats::CreateEmuFromInput,
+            bytes = input_size,
             Emulator::<{ 64 * 1024 }>::with_memory(Path::new(&input_file))?
         );
 
-        #[cfg(feature = "vecemu")]
-        let mut jit = JitBuffer::<{ 1024 * 1024 }>::new();
+        emu.budget = budget;
 
-        for iter in 0.. {
-            // If we've read past the end of the emulator, return..
-            if emu.registers.ip() as usize >= emu.memory.length {
-                break;
-            }
+        // Only record the coverage of the first iteration
+        if args.coverage && iteration == 0 {
+            emu.coverage = Some(Coverage::new());
+        }
 
-            // println!("BEFORE");
-            // emu.print_context();
+        let mut cache = use_cache.then(|| DecodeCache::new(emu.memory.memory.len()));
+
+        // Only watch the first iteration live
+        let mut viewer = (args.live && iteration == 0).then(|| {
+            Viewer::new(
+                fb_region,
+                args.live_every,
+                args.live_on_write,
+                Duration::from_millis(args.live_delay_ms),
+            )
+        });
+
+        if args.engine == Engine::Threaded {
+            let mut engine = ThreadedEngine::new();
+            let mut executed = 0;
+
+            while usize::from(emu.ip()) < emu.memory.length {
+                // Translate the block at the current IP if it hasn't been seen yet
+                time!(prof, Stats::TranslateBlock, engine.translate(&emu)?);
+
+                // Execute the whole block
+                let res = time!(prof, Stats::ExecuteBlock, engine.run(&mut emu));
+                let Some(count) = stop_on_budget(res)? else {
+                    break;
+                };
+                let steps = executed..executed + count;
+                executed += count;
+
+                // Drop translated blocks overwritten by this block
+                let written = emu.memory.take_dirty();
+                for written in &written {
+                    engine.invalidate(written);
+                }
 
-            // Decode the input byte stream
-            let decoded_instr = time!(
-                Decode,
-                cpu8086::decoder::decode_instruction(&mut emu.registers, &emu.memory)?
-            );
+                // Redraw the live view if any instruction in the block requires it
+                if let Some(viewer) = viewer.as_mut() {
+                    viewer.step(steps.clone(), &emu.memory.memory, &written)?;
+                }
 
-            // println!("INSTR: {decoded_instr}");
+                // Write an animation frame if the block crossed a multiple of `fb_every`
+                if let (Some(fb), Some(frame), 0) =
+                    (framebuffer, every_hit(&steps, args.fb_every), iteration)
+                {
+                    let frame = format!("{input_file}.frame_{frame:06}.{image_ext}");
+                    fb.write(&emu.memory.memory, args.fb_image_format, Path::new(&frame))?;
+                }
+            }
 
-            // Execute the decoded instruction
-            time!(Execute, emu.execute(&decoded_instr)?);
+            threaded_stats.blocks_translated += engine.stats.blocks_translated;
+            threaded_stats.instructions_translated += engine.stats.instructions_translated;
+            threaded_stats.blocks_executed += engine.stats.blocks_executed;
+            threaded_stats.invalidations += engine.stats.invalidations;
+        } else {
+            for iter in 0.. {
+                // If we've read past the end of the emulator, return..
+                if emu.registers.ip() as usize >= emu.memory.length {
+                    break;
+                }
 
-            // println!("AFTER");
-            // emu.print_context();
-            // println!("");
+                // println!("BEFORE");
+                // emu.print_context();
 
-            // Print the decoded instructions
-            if iteration == 0 {
-                time!(WriteDecode, {
-                    if matches!(decoded_instr, Instruction::Lock) {
-                        file.write_all(format!("{decoded_instr}").as_bytes())?;
+                // Decode the input byte stream
+                let ip = emu.ip();
+                let decoded_instr = time!(prof, decode_zone, {
+                    if let Some(cache) = cache.as_mut() {
+                        let cs = emu.segments[SegmentRegister::Cs as usize];
+                        cache.decode(cs, &mut emu.registers, &emu.memory)?
                     } else {
-                        file.write_all(format!("{decoded_instr}\n").as_bytes())?;
+                        cpu8086::decoder::decode_instruction(&mut emu.registers, &emu.memory)?
                     }
                 });
-            }
 
-            // JIT the decoded instruction to the JIT stream
-            // Debug print the JIT assembly for the decoded instruction
-            #[cfg(feature = "vecemu")]
-            {
-                // Cache the starting offset for the next JIT instructions
-                let curr_offset = jit.offset;
+                // println!("INSTR: {decoded_instr}");
 
-                // Cache the formatted decoded instruction string
-                let decoded_instr_str = format!("{decoded_instr}");
+                // Execute the decoded instruction, unless the budget is used up
+                let res = time!(prof, execute_zone, emu.step(ip, &decoded_instr));
+                if stop_on_budget(res)?.is_none() {
+                    break;
+                }
 
-                time!(BuildJit, {
-                    // Get the JIT instruction for the decoded 8086 instruction
-                    jit.write_instr(decoded_instr);
-                });
+                // println!("AFTER");
+                // emu.print_context();
+                // println!("");
+
+                // Drop cached instructions overwritten by this instruction
+                let written = emu.memory.take_dirty();
+                if let Some(cache) = cache.as_mut() {
+                    for written in &written {
+                        cache.invalidate(written);
+                    }
+                }
+
+                // Redraw the live view if this instruction requires it
+                if let Some(viewer) = viewer.as_mut() {
+                    viewer.step(iter..iter + 1, &emu.memory.memory, &written)?;
+                }
 
-                let ip = emu.registers.ip;
+                // Write an animation frame of the framebuffer every `fb_every` instructions
+                if let (Some(fb), Some(frame), 0) = (
+                    framebuffer,
+                    every_hit(&(iter..iter + 1), args.fb_every),
+                    iteration,
+                ) {
+                    let frame = format!("{input_file}.frame_{frame:06}.{image_ext}");
+                    fb.write(&emu.memory.memory, args.fb_image_format, Path::new(&frame))?;
+                }
 
-                let jit_instr = jit.get_disassembly_between(curr_offset, jit.offset);
-                for (i, line) in jit_instr.iter().enumerate() {
-                    if i == 0 {
-                        println!("{ip:#05x} {decoded_instr_str:20} | {line}");
+                // Print the decoded instructions
+                if iteration == 0 {
+                    let line = if matches!(decoded_instr, Instruction::Lock) {
+                        format!("{decoded_instr}")
                     } else {
-                        println!("{:26} | {line}", "");
-                    }
+                        format!("{decoded_instr}\n")
+                    };
+
+                    time!(
+                        prof,
+                        Stats::WriteDecode,
+                        bytes = line.len(),
+                        file.write_all(line.as_bytes())?
+                    );
                 }
-                println!("{}", "-".repeat(60));
             }
         }
 
-        // Initialize the JIT emulator
+        // Draw the final state of the live view
+        if let Some(mut viewer) = viewer.take() {
+            viewer.draw(&emu.memory.memory)?;
+        }
+
+        // Blocks run by each JIT lane, listed along with the coverage
+        #[cfg(feature = "vecemu")]
+        let jit_coverage;
+
+        // Run the program on every lane of the JIT emulator
         #[cfg(feature = "vecemu")]
         {
-            let jit_emu = JitEmulatorState::default();
+            let input = std::fs::read(&input)?;
+            let mut program = JitProgram::<{ 1024 * 1024 }>::new(&input)?;
+
+            // Start each lane from its own inputs, if any
+            let registers = match &args.lane_registers {
+                Some(path) => parse_lane_registers(&std::fs::read_to_string(path)?)?,
+                None => Vec::new(),
+            };
+            let inputs = (0..32)
+                .map(|core| {
+                    Ok(LaneInput {
+                        cpu: registers
+                            .get(usize::from(core))
+                            .copied()
+                            .unwrap_or_default(),
+                        memory: lane_memory(&args.lane_memory, core)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut jit_emu =
+                JitEmulatorState::with_inputs(&input, |core| inputs[usize::from(*core)].clone());
+            jit_emu.budget = budget;
+            if args.dump_il {
+                program.enable_il_dump();
+            }
 
             #[allow(clippy::cast_possible_truncation)]
-            let core = rdtsc() as u8 % 20 + 1;
+            let core = profiler::read_timer() as u8 % 20 + 1;
 
             println!("+{:-^width$}+", " CPU Before ", width = term_width - 2);
 
             jit_emu.print_cpu_state(Core(core));
 
-            // Execute the JIT buffer
-            time!(ExecJit, {
-                unsafe {
-                    asm!(include_str!("../.tmp_files/findme.rs"),
-                        in("r13") usize::from(debug_on),
-                        in("r14") jit.buffer() as usize,
-                        in("r15") &jit_emu,
-                    );
-                }
-            });
+            // Translate and execute the program
+            let backend = run_backend(args.jit_backend);
+            let executed = time!(prof, Stats::ExecJit, program.run_on(&mut jit_emu, backend))?;
+
+            // List the JIT assembly of each translated instruction
+            let listing = program.listing().render(args.listing_format.into());
+            match &args.listing_output {
+                Some(listing_output) => std::fs::write(listing_output, listing)?,
+                None => print!("{listing}"),
+            }
+
+            for line in program.il_dump() {
+                println!("{line}");
+            }
 
             println!("+{:-^width$}+", " CPU After ", width = term_width - 2);
             jit_emu.print_cpu_state(Core(core));
+
+            let stats = program.stats;
+            println!(
+                "JIT: {} blocks translated ({} instructions) | {} blocks executed | {executed} instructions executed | {} blocks invalidated | {} flushes",
+                stats.blocks_translated,
+                stats.instructions_translated,
+                stats.blocks_executed,
+                stats.blocks_invalidated,
+                stats.flushes
+            );
+
+            // Collect the final state of each lane of the batch
+            if args.batch() {
+                let results = jit_emu.results(args.results_memory.clone().unwrap_or(0..0));
+                let table = match args.results_format {
+                    ResultsFormat::Text => results.table(),
+                    ResultsFormat::Csv => results.csv(),
+                };
+                match &args.results_output {
+                    Some(results_output) => std::fs::write(results_output, table)?,
+                    None => print!("{table}"),
+                }
+            }
+
+            jit_coverage = jit_emu.coverage;
+        }
+
+        if let Some(cache) = cache {
+            cache_stats.hits += cache.stats.hits;
+            cache_stats.misses += cache.stats.misses;
+            cache_stats.invalidations += cache.stats.invalidations;
         }
 
         if iteration == 0 {
             let output_file = format!("{input_file}.memory.data");
             // Write the memory
             std::fs::write(output_file, &emu.memory.memory[..1000 + 64 * 64 * 4])?;
+
+            // Write the final framebuffer image
+            if let Some(fb) = framebuffer {
+                let image = format!("{input_file}.framebuffer.{image_ext}");
+                fb.write(&emu.memory.memory, args.fb_image_format, Path::new(&image))?;
+                println!("Framebuffer written to {image}");
+            }
+
+            // Write the listing annotated with the coverage of the run
+            if let Some(coverage) = &emu.coverage {
+                #[cfg(feature = "vecemu")]
+                let lanes = |ip| jit_coverage.lanes(ip);
+                #[cfg(feature = "vecemu")]
+                let lanes: Option<&dyn Fn(u16) -> u32> = Some(&lanes);
+                #[cfg(not(feature = "vecemu"))]
+                let lanes = None;
+
+                let listing_file = format!("{input_file}.coverage.txt");
+                std::fs::write(
+                    &listing_file,
+                    coverage::listing(coverage, &emu.memory, lanes),
+                )?;
+                println!("Coverage listing written to {listing_file}");
+            }
         }
     }
 
-    // Stop the clock on the entire work load
-    let total_elapsed = rdtsc() - total_start;
-
-    /// Macro used to pretty print the statistics
-    macro_rules! print_stat {
-        ($stat:ident) => {{
-            let curr_stat = stats[Stats::$stat as usize];
-            let curr_stat_time = stats_time[Stats::$stat as usize];
-            let best_stat = best_stats[Stats::$stat as usize];
-            let best_stat_time = best_stats_time[Stats::$stat as usize];
-
-            eprintln!(
-                // "{:12} \n  Best {:<8.2?} \n  Avg  {:<.2?}/iter \n  Best {:<.2?} cycles/iter \n  Avg  {:<.2?} cycles/iter \n  % of total time: {:5.2}%",
-                "{:20} | Best {:>8.2?} | Avg {:>8.2?}/iter | Best {:>8.2?} cycles/iter | Avg {:>10.2?} cycles/iter | % of total time: {:5.2}%",
-                format!("{:?}", Stats::$stat),
-                best_stat_time,
-                Duration::from_nanos((curr_stat_time.as_nanos() as f64 / ITERS as f64) as u64),
-                best_stat,
-                curr_stat as f64 / ITERS as f64,
-                curr_stat as f64 / total_elapsed as f64 * 100.
-            );
-        }};
-    }
+    // Convert the measured cycles into time using the measured timer frequency
+    let report = prof.report(timer_frequency);
+    let mut output = report.format(args.stats_format);
 
-    println!(
-        "+{:-^width$}+",
-        " Performance Stats ",
-        width = term_width - 2
-    );
+    // Summarize how decoding compares to executing with and without the decode cache
+    if args.stats_format == ReportFormat::Text {
+        if let Some(split) = decode_execute_split(&report, Stats::Decode, Stats::Execute) {
+            writeln!(output, "Uncached: {split}")?;
+        }
 
-    print_stat!(CreateEmuFromInput);
-    print_stat!(Decode);
-    print_stat!(Execute);
-    print_stat!(WriteDecode);
-    #[cfg(feature = "vecemu")]
-    {
-        print_stat!(BuildJit);
-        print_stat!(ExecJit);
+        if let Some(split) =
+            decode_execute_split(&report, Stats::CachedDecode, Stats::CachedExecute)
+        {
+            let DecodeCacheStats {
+                hits,
+                misses,
+                invalidations,
+            } = cache_stats;
+
+            writeln!(output, "Cached:   {split}")?;
+            writeln!(
+                output,
+                "Decode cache: {hits} hits | {misses} misses | {invalidations} invalidations"
+            )?;
+        }
+
+        if args.engine == Engine::Threaded {
+            let ThreadedStats {
+                blocks_translated,
+                instructions_translated,
+                blocks_executed,
+                invalidations,
+            } = threaded_stats;
+
+            writeln!(
+                output,
+                "Threaded: {blocks_translated} blocks translated ({instructions_translated} instructions) | {blocks_executed} blocks executed | {invalidations} invalidations"
+            )?;
+        }
+    }
+
+    if let Some(stats_output) = &args.stats_output {
+        std::fs::write(stats_output, output)?;
+        println!("Performance stats written to {}", stats_output.display());
+    } else {
+        println!(
+            "+{:-^width$}+",
+            " Performance Stats ",
+            width = term_width - 2
+        );
+        print!("{output}");
     }
 
     Ok(())
@@ -284,7 +959,25 @@ mod tests {
     use cpu8086::flags::EFlags;
     use cpu8086::instruction::Operand;
     use cpu8086::register::Register;
-    use std::arch::asm;
+    use jit::{JitBuffer, JitIL};
+    use jit_emu::{Core, JitEmulatorState};
+
+    #[test]
+    #[cfg(feature = "vecemu")]
+    fn test_parse_lane_registers() {
+        let lanes =
+            parse_lane_registers("ax=0x10 CX=3\n\n# lane 2\nflags=0x44 DS=0x20 # ZF\n").unwrap();
+        assert_eq!(lanes.len(), 4);
+        assert_eq!((lanes[0].ax, lanes[0].cx), (0x10, 3));
+        assert_eq!(lanes[1], CpuState::default());
+        assert_eq!(lanes[2], CpuState::default());
+        assert_eq!((lanes[3].flags, lanes[3].ds), (0x44, 0x20));
+
+        assert!(parse_lane_registers("ax=0x10000").is_err());
+        assert!(parse_lane_registers("ex=1").is_err());
+        assert!(parse_lane_registers("ax").is_err());
+        assert_eq!(parse_memory_range("0x100..0x140"), Ok(0x100..0x140));
+    }
 
     #[test]
     #[allow(clippy::too_many_lines)]
@@ -341,7 +1034,13 @@ mod tests {
                     dest: Operand::Register(Register::Ax),
                     src: Operand::Immediate(-1),
                 },
-                vec![(Register::Ax, 3), (Register::Flags, 0)],
+                vec![
+                    (Register::Ax, 3),
+                    (
+                        Register::Flags,
+                        EFlags::Carry as u16 | EFlags::Parity as u16 | EFlags::Auxillary as u16,
+                    ),
+                ],
             ),
             (
                 // sub ax, ax
@@ -349,7 +1048,10 @@ mod tests {
                     dest: Operand::Register(Register::Ax),
                     src: Operand::Register(Register::Ax),
                 },
-                vec![(Register::Ax, 0), (Register::Flags, EFlags::Zero as u16)],
+                vec![
+                    (Register::Ax, 0),
+                    (Register::Flags, EFlags::Zero as u16 | EFlags::Parity as u16),
+                ],
             ),
             (
                 // cmp ax, ax
@@ -357,7 +1059,10 @@ mod tests {
                     left: Operand::Register(Register::Ax),
                     right: Operand::Register(Register::Ax),
                 },
-                vec![(Register::Ax, 4), (Register::Flags, EFlags::Zero as u16)],
+                vec![
+                    (Register::Ax, 4),
+                    (Register::Flags, EFlags::Zero as u16 | EFlags::Parity as u16),
+                ],
             ),
             (
                 // cmp bx, ax
@@ -368,27 +1073,30 @@ mod tests {
                 vec![
                     (Register::Bx, 3),
                     (Register::Ax, 4),
-                    (Register::Flags, EFlags::Sign as u16),
+                    (
+                        Register::Flags,
+                        EFlags::Carry as u16
+                            | EFlags::Parity as u16
+                            | EFlags::Auxillary as u16
+                            | EFlags::Sign as u16,
+                    ),
                 ],
             ),
         ] {
             // Copy the default CPU state
-            let jit_emu = clean_jit_emu.clone();
+            let mut jit_emu = clean_jit_emu.clone();
 
             // Get the current offset in the JIT where this instruction will be written
             let offset = jit.offset;
 
-            // Write this instruction
-            jit.write_instr(instr.clone());
+            // Write this instruction as its own block, since it is run on its own
+            let il = JitIL::from(instr.clone());
+            jit.begin_block(&[il]);
+            jit.write_instr(il);
 
-            // Execute the JIT buffer
-            let debug_on = false;
+            // Execute only the JIT code for this instruction
             unsafe {
-                asm!(include_str!("../.tmp_files/findme.rs"),
-                    in("r13") usize::from(debug_on),
-                    in("r14") jit.buffer().offset(offset) as usize,
-                    in("r15") &jit_emu,
-                );
+                jit.run_from(offset, &mut jit_emu);
             }
 
             // Debug print the JIT assembly for the decoded instruction
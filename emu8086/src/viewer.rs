@@ -0,0 +1,142 @@
+//! Live rendering of a framebuffer region in the terminal using truecolor half-blocks
+
+use anyhow::Result;
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::framebuffer::Framebuffer;
+
+/// Upper half block: the foreground colors the top pixel, the background the bottom pixel
+const HALF_BLOCK: char = '\u{2580}';
+
+/// Renders a [`Framebuffer`] into the terminal while a program is running
+pub struct Viewer {
+    /// The memory region being rendered
+    framebuffer: Framebuffer,
+
+    /// Redraw every N executed instructions
+    every: Option<u64>,
+
+    /// Redraw whenever an instruction writes into the framebuffer region
+    on_write: bool,
+
+    /// Time to sleep after each drawn frame so the drawing can be followed
+    delay: Duration,
+
+    /// Reused output buffer for a single frame
+    frame: String,
+
+    /// Number of frames drawn so far
+    pub frames: u64,
+}
+
+impl Viewer {
+    /// Create a new [`Viewer`] and prepare the terminal for drawing
+    pub fn new(
+        framebuffer: Framebuffer,
+        every: Option<u64>,
+        on_write: bool,
+        delay: Duration,
+    ) -> Self {
+        // Clear the screen and hide the cursor while drawing
+        print!("\x1b[2J\x1b[?25l");
+
+        Viewer {
+            framebuffer,
+            every,
+            on_write,
+            delay,
+            frame: String::new(),
+            frames: 0,
+        }
+    }
+
+    /// Redraw the framebuffer if needed after executing the instruction numbers in `executed`.
+    ///
+    /// `written` are the ranges of memory written by those instructions.
+    pub fn step(
+        &mut self,
+        executed: Range<u64>,
+        memory: &[u8],
+        written: &[Range<usize>],
+    ) -> Result<()> {
+        let fb_range = self.framebuffer.range()?;
+
+        let hit_every = every_hit(&executed, self.every).is_some();
+        let hit_write = self.on_write
+            && written
+                .iter()
+                .any(|w| w.start < fb_range.end && fb_range.start < w.end);
+
+        if hit_every || hit_write {
+            self.draw(memory)?;
+        }
+
+        Ok(())
+    }
+
+    /// Draw the current framebuffer contents at the top left of the terminal
+    pub fn draw(&mut self, memory: &[u8]) -> Result<()> {
+        let fb = self.framebuffer;
+        let bytes = fb.bytes(memory)?;
+
+        self.frame.clear();
+
+        // Move the cursor home to draw over the previous frame
+        self.frame.push_str("\x1b[H");
+
+        // Each terminal row holds two pixel rows
+        for y in (0..fb.height).step_by(2) {
+            for x in 0..fb.width {
+                let [tr, tg, tb, _] = fb.pixel(bytes, x, y);
+                let [br, bg, bb, _] = if y + 1 < fb.height {
+                    fb.pixel(bytes, x, y + 1)
+                } else {
+                    [0, 0, 0, 0]
+                };
+
+                write!(
+                    self.frame,
+                    "\x1b[38;2;{tr};{tg};{tb}m\x1b[48;2;{br};{bg};{bb}m{HALF_BLOCK}"
+                )?;
+            }
+
+            self.frame.push_str("\x1b[0m\n");
+        }
+
+        writeln!(self.frame, "Frame {}", self.frames)?;
+
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(self.frame.as_bytes())?;
+        stdout.flush()?;
+
+        self.frames += 1;
+
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the last multiple of `every` among the instruction numbers in `executed`, returning
+/// which multiple it is
+pub fn every_hit(executed: &Range<u64>, every: Option<u64>) -> Option<u64> {
+    let every = every.filter(|every| *every > 0)?;
+    let last = executed.end.checked_sub(1)?;
+    let hit = last / every;
+
+    (hit * every >= executed.start).then_some(hit)
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        // Reset the colors and show the cursor again
+        print!("\x1b[0m\x1b[?25h");
+        let _ = std::io::stdout().flush();
+    }
+}
@@ -0,0 +1,375 @@
+//! A small instrumentation profiler shared by the course projects
+//!
+//! Zones are declared with [`zones!`] and timed with [`time!`]. Zones can be nested: each
+//! zone tracks its hit count, inclusive cycles (including child zones), exclusive cycles
+//! (excluding child zones), the best single hit and the number of bytes it processed.
+//!
+//! ```ignore
+//! profiler::zones! {
+//!     pub enum Stats {
+//!         ReadInput,
+//!         Parse,
+//!     }
+//! }
+//!
+//! let prof = profiler::Profiler::<Stats>::new();
+//! let data = profiler::time!(prof, Stats::ReadInput, bytes = 4096, read_input());
+//! profiler::time!(prof, Stats::Parse, parse(&data));
+//!
+//! let report = prof.report(profiler::estimate_timer_frequency(Duration::from_millis(100)));
+//! report.print();
+//! println!("{}", report.to_json());
+//! ```
+
+#![deny(missing_docs)]
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+mod report;
+pub use report::{Report, ReportFormat, ZoneReport};
+
+mod repetition;
+pub use repetition::{RepetitionResults, RepetitionTester};
+
+/// A set of profiling zones, usually declared using [`zones!`]
+pub trait Zones: Copy + 'static {
+    /// Every zone in declaration order
+    const ALL: &'static [Self];
+
+    /// Index of this zone in [`Zones::ALL`]
+    fn index(self) -> usize;
+
+    /// Printable name of this zone
+    fn name(self) -> &'static str;
+}
+
+/// Declare an enum of profiling zones implementing [`Zones`]
+#[macro_export]
+macro_rules! zones {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$variant_meta:meta])* $variant:ident),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        $vis enum $name {
+            $($(#[$variant_meta])* $variant),*
+        }
+
+        impl $crate::Zones for $name {
+            const ALL: &'static [Self] = &[$($name::$variant),*];
+
+            fn index(self) -> usize {
+                self as usize
+            }
+
+            fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => stringify!($variant)),*
+                }
+            }
+        }
+    };
+}
+
+/// Time the given work in a zone of the given [`Profiler`]
+///
+/// The zone is exited by a [`ZoneGuard`], so work returning early (via `?` or `return`)
+/// still closes it.
+#[macro_export]
+macro_rules! time {
+    ($profiler:expr, $zone:expr, bytes = $bytes:expr, $work:expr) => {{
+        let _zone = $profiler.zone_bytes($zone, $bytes as u64);
+        $work
+    }};
+    ($profiler:expr, $zone:expr, $work:expr) => {{
+        let _zone = $profiler.zone($zone);
+        $work
+    }};
+}
+
+/// Read the current value of the timer used by the profiler
+///
+/// On `x86_64` this is the time stamp counter via `rdtscp`, which waits for previous
+/// instructions to execute. Elsewhere it falls back to nanoseconds since first use.
+#[inline(always)]
+pub fn read_timer() -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mut aux = 0;
+        unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        static EPOCH: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+        u64::try_from(EPOCH.get_or_init(Instant::now).elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+}
+
+/// Estimate the frequency of [`read_timer`] in ticks per second by spinning for `wait`
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn estimate_timer_frequency(wait: Duration) -> u64 {
+    let start = Instant::now();
+    let timer_start = read_timer();
+    while start.elapsed() < wait {}
+    let timer_end = read_timer();
+
+    ((timer_end - timer_start) as f64 / start.elapsed().as_secs_f64()) as u64
+}
+
+/// Accumulated statistics for a single zone
+#[derive(Debug, Default, Copy, Clone)]
+struct Anchor {
+    /// Number of times this zone was entered
+    hits: u64,
+
+    /// Cycles spent in this zone including child zones
+    inclusive: u64,
+
+    /// Cycles spent in this zone excluding child zones
+    exclusive: u64,
+
+    /// Fewest inclusive cycles seen for a single hit
+    best: u64,
+
+    /// Bytes processed by this zone
+    bytes: u64,
+}
+
+/// A zone that has been entered but not yet exited
+#[derive(Debug, Copy, Clone)]
+struct OpenZone {
+    /// Index of the zone's anchor
+    index: usize,
+
+    /// Timer value when the zone was entered
+    start: u64,
+
+    /// Inclusive cycles of the anchor when the zone was entered. Restored on exit so
+    /// that recursive entries of the same zone are not counted twice.
+    old_inclusive: u64,
+
+    /// Cycles spent in child zones so far
+    children: u64,
+}
+
+/// An instrumentation profiler over the zones in `Z`
+///
+/// Zones are entered through a shared reference, so the work timed in a zone can time its
+/// own nested zones with the same profiler.
+pub struct Profiler<Z: Zones> {
+    /// Statistics per zone, indexed by [`Zones::index`]
+    anchors: RefCell<Vec<Anchor>>,
+
+    /// Currently open zones, innermost last
+    stack: RefCell<Vec<OpenZone>>,
+
+    /// Timer value when the profiler was created
+    start: u64,
+
+    /// Phantom for the zone type
+    _zones: PhantomData<Z>,
+}
+
+impl<Z: Zones> Default for Profiler<Z> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Z: Zones> Profiler<Z> {
+    /// Create a new profiler and start its total time
+    pub fn new() -> Self {
+        Profiler {
+            anchors: RefCell::new(vec![
+                Anchor {
+                    best: u64::MAX,
+                    ..Anchor::default()
+                };
+                Z::ALL.len()
+            ]),
+            stack: RefCell::new(Vec::new()),
+            start: read_timer(),
+            _zones: PhantomData,
+        }
+    }
+
+    /// Enter the given zone
+    #[inline(always)]
+    pub fn begin(&self, zone: Z) {
+        self.begin_bytes(zone, 0);
+    }
+
+    /// Enter the given zone which processes `bytes` bytes
+    #[inline(always)]
+    pub fn begin_bytes(&self, zone: Z, bytes: u64) {
+        self.begin_at(zone.index(), bytes, read_timer());
+    }
+
+    /// Exit the most recently entered zone
+    #[inline(always)]
+    pub fn end(&self) {
+        self.end_at(read_timer());
+    }
+
+    /// Enter the given zone until the returned [`ZoneGuard`] is dropped
+    #[inline(always)]
+    #[must_use = "the zone is exited as soon as the guard is dropped"]
+    pub fn zone(&self, zone: Z) -> ZoneGuard<'_, Z> {
+        self.zone_bytes(zone, 0)
+    }
+
+    /// Enter the given zone which processes `bytes` bytes until the returned
+    /// [`ZoneGuard`] is dropped
+    #[inline(always)]
+    #[must_use = "the zone is exited as soon as the guard is dropped"]
+    pub fn zone_bytes(&self, zone: Z, bytes: u64) -> ZoneGuard<'_, Z> {
+        self.begin_bytes(zone, bytes);
+        ZoneGuard { profiler: self }
+    }
+
+    /// Enter the zone at `index` at the given timer value
+    fn begin_at(&self, index: usize, bytes: u64, now: u64) {
+        let mut anchors = self.anchors.borrow_mut();
+        let anchor = &mut anchors[index];
+        anchor.bytes += bytes;
+
+        self.stack.borrow_mut().push(OpenZone {
+            index,
+            start: now,
+            old_inclusive: anchor.inclusive,
+            children: 0,
+        });
+    }
+
+    /// Exit the innermost open zone at the given timer value
+    fn end_at(&self, now: u64) {
+        let mut stack = self.stack.borrow_mut();
+        let open = stack
+            .pop()
+            .expect("Profiler::end called without a matching begin");
+
+        let elapsed = now.saturating_sub(open.start);
+
+        // Attribute the elapsed time to the parent as child time
+        if let Some(parent) = stack.last_mut() {
+            parent.children += elapsed;
+        }
+
+        let mut anchors = self.anchors.borrow_mut();
+        let anchor = &mut anchors[open.index];
+        anchor.hits += 1;
+        anchor.inclusive = open.old_inclusive + elapsed;
+        anchor.exclusive += elapsed.saturating_sub(open.children);
+        anchor.best = anchor.best.min(elapsed);
+    }
+
+    /// Build a [`Report`] of all zones hit so far using the given timer `frequency`
+    pub fn report(&self, frequency: u64) -> Report {
+        self.report_at(frequency, read_timer())
+    }
+
+    /// Build a [`Report`] with the total time ending at the given timer value
+    fn report_at(&self, frequency: u64, now: u64) -> Report {
+        let anchors = self.anchors.borrow();
+        let zones = Z::ALL
+            .iter()
+            .map(|zone| (zone.name(), anchors[zone.index()]))
+            .filter(|(_, anchor)| anchor.hits > 0)
+            .map(|(name, anchor)| ZoneReport {
+                name,
+                hits: anchor.hits,
+                inclusive_cycles: anchor.inclusive,
+                exclusive_cycles: anchor.exclusive,
+                best_cycles: anchor.best,
+                bytes: anchor.bytes,
+            })
+            .collect();
+
+        Report {
+            total_cycles: now.saturating_sub(self.start),
+            frequency,
+            zones,
+        }
+    }
+}
+
+/// An entered zone of a [`Profiler`], exited when dropped
+pub struct ZoneGuard<'a, Z: Zones> {
+    /// The profiler the zone was entered in
+    profiler: &'a Profiler<Z>,
+}
+
+impl<Z: Zones> Drop for ZoneGuard<'_, Z> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        self.profiler.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    zones! {
+        enum TestZones {
+            Outer,
+            Inner,
+            Unused,
+        }
+    }
+
+    #[test]
+    fn test_nested_zones() {
+        let mut prof = Profiler::<TestZones>::new();
+        prof.start = 0;
+
+        // Outer [0, 100) contains Inner [10, 40) and a recursive Outer [50, 70)
+        prof.begin_at(TestZones::Outer.index(), 0, 0);
+        prof.begin_at(TestZones::Inner.index(), 64, 10);
+        prof.end_at(40);
+        prof.begin_at(TestZones::Outer.index(), 0, 50);
+        prof.end_at(70);
+        prof.end_at(100);
+
+        let report = prof.report_at(1000, 200);
+        assert_eq!(report.total_cycles, 200);
+        assert_eq!(report.zones.len(), 2);
+
+        let outer = &report.zones[0];
+        assert_eq!(outer.name, "Outer");
+        assert_eq!(outer.hits, 2);
+        assert_eq!(outer.inclusive_cycles, 100);
+        assert_eq!(outer.exclusive_cycles, 70);
+        assert_eq!(outer.best_cycles, 20);
+
+        let inner = &report.zones[1];
+        assert_eq!(inner.name, "Inner");
+        assert_eq!(inner.hits, 1);
+        assert_eq!(inner.inclusive_cycles, 30);
+        assert_eq!(inner.exclusive_cycles, 30);
+        assert_eq!(inner.bytes, 64);
+    }
+
+    #[test]
+    fn test_early_return() {
+        fn fallible(prof: &Profiler<TestZones>) -> Result<(), ()> {
+            time!(prof, TestZones::Inner, Err(())?);
+            Ok(())
+        }
+
+        let prof = Profiler::<TestZones>::new();
+        time!(prof, TestZones::Outer, assert!(fallible(&prof).is_err()));
+
+        // The inner zone was closed by the early return, so it nests inside the outer one
+        assert!(prof.stack.borrow().is_empty());
+        let report = prof.report(1000);
+        assert_eq!(report.zones.len(), 2);
+        assert!(report.zones.iter().all(|zone| zone.hits == 1));
+    }
+}
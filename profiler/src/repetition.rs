@@ -0,0 +1,283 @@
+//! Repetition testing: run the same work until its fastest time stops improving
+//!
+//! A single timing is noisy (cold caches, page faults, interrupts), so the work is
+//! repeated until no new minimum has been seen for a given time. The minimum is the best
+//! estimate of what the work costs, and the average and maximum show how noisy it was.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::read_timer;
+
+/// Timings gathered by a [`RepetitionTester`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RepetitionResults {
+    /// Number of repetitions
+    pub count: u64,
+
+    /// Timer ticks per second used to convert cycles into time
+    pub frequency: u64,
+
+    /// Cycles of every repetition added together
+    pub total_cycles: u64,
+
+    /// Cycles of the fastest repetition
+    pub min_cycles: u64,
+
+    /// Cycles of the slowest repetition
+    pub max_cycles: u64,
+
+    /// Work (bytes, instructions, ...) of every repetition added together
+    pub total_work: u64,
+
+    /// Work done by the fastest repetition
+    pub min_work: u64,
+
+    /// Work done by the slowest repetition
+    pub max_work: u64,
+}
+
+impl RepetitionResults {
+    /// Average cycles of a repetition
+    #[allow(clippy::cast_precision_loss)]
+    pub fn avg_cycles(&self) -> f64 {
+        self.total_cycles as f64 / self.count.max(1) as f64
+    }
+
+    /// Average work of a repetition
+    #[allow(clippy::cast_precision_loss)]
+    pub fn avg_work(&self) -> f64 {
+        self.total_work as f64 / self.count.max(1) as f64
+    }
+
+    /// Convert a number of cycles into seconds
+    #[allow(clippy::cast_precision_loss)]
+    pub fn seconds(&self, cycles: f64) -> f64 {
+        if self.frequency == 0 {
+            return 0.0;
+        }
+
+        cycles / self.frequency as f64
+    }
+
+    /// Work per second of the fastest repetition
+    #[allow(clippy::cast_precision_loss)]
+    pub fn min_work_per_second(&self) -> f64 {
+        let seconds = self.seconds(self.min_cycles as f64);
+        if seconds == 0.0 {
+            return 0.0;
+        }
+
+        self.min_work as f64 / seconds
+    }
+
+    /// Work per second over every repetition
+    #[allow(clippy::cast_precision_loss)]
+    pub fn avg_work_per_second(&self) -> f64 {
+        let seconds = self.seconds(self.total_cycles as f64);
+        if seconds == 0.0 {
+            return 0.0;
+        }
+
+        self.total_work as f64 / seconds
+    }
+
+    /// Cycles per unit of work of the fastest repetition
+    #[allow(clippy::cast_precision_loss)]
+    pub fn min_cycles_per_work(&self) -> f64 {
+        self.min_cycles as f64 / self.min_work.max(1) as f64
+    }
+
+    /// Format the min, max and average of the repetitions, one per line
+    #[allow(clippy::cast_precision_loss)]
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        let lines = [
+            ("Min", self.min_cycles as f64, self.min_work as f64),
+            ("Max", self.max_cycles as f64, self.max_work as f64),
+            ("Avg", self.avg_cycles(), self.avg_work()),
+        ];
+
+        for (name, cycles, work) in lines {
+            let seconds = self.seconds(cycles);
+            let _ = write!(out, "{name}: {cycles:.0} cycles ({:.3}ms)", seconds * 1000.);
+            if work > 0.0 && seconds > 0.0 {
+                let _ = write!(out, " {:.0} work/s", work / seconds);
+            }
+            let _ = writeln!(out);
+        }
+
+        out
+    }
+}
+
+/// Repeats timed work until its fastest repetition hasn't improved for a while
+///
+/// ```ignore
+/// let mut tester = profiler::RepetitionTester::new(Duration::from_secs(2), frequency);
+/// while tester.is_testing() {
+///     tester.begin();
+///     let bytes = work();
+///     tester.end();
+///     tester.count_work(bytes);
+/// }
+/// print!("{}", tester.results().to_text());
+/// ```
+#[derive(Debug, Clone)]
+pub struct RepetitionTester {
+    /// Cycles without a new minimum after which testing stops
+    try_for: u64,
+
+    /// Timer value when testing started or the last new minimum was seen
+    last_min: u64,
+
+    /// Timer value when the current repetition began, if it is running
+    open: Option<u64>,
+
+    /// Work of the latest repetition, counted with [`RepetitionTester::count_work`]
+    work: u64,
+
+    /// Whether the latest repetition was a new minimum
+    new_min: bool,
+
+    /// Whether the latest repetition was a new maximum
+    new_max: bool,
+
+    /// Timings of the repetitions so far
+    results: RepetitionResults,
+}
+
+impl RepetitionTester {
+    /// Create a tester that stops after `try_for` without a new fastest repetition, using
+    /// the given timer `frequency` (see [`crate::estimate_timer_frequency`])
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn new(try_for: Duration, frequency: u64) -> Self {
+        RepetitionTester {
+            try_for: (try_for.as_secs_f64() * frequency as f64) as u64,
+            last_min: read_timer(),
+            open: None,
+            work: 0,
+            new_min: false,
+            new_max: false,
+            results: RepetitionResults {
+                frequency,
+                min_cycles: u64::MAX,
+                ..RepetitionResults::default()
+            },
+        }
+    }
+
+    /// Returns `true` while another repetition should be run
+    pub fn is_testing(&self) -> bool {
+        self.is_testing_at(read_timer())
+    }
+
+    /// Begin timing a repetition
+    #[inline(always)]
+    pub fn begin(&mut self) {
+        self.begin_at(read_timer());
+    }
+
+    /// End timing the current repetition
+    #[inline(always)]
+    pub fn end(&mut self) {
+        self.end_at(read_timer());
+    }
+
+    /// Count `work` done by the latest repetition, used for its throughput
+    pub fn count_work(&mut self, work: u64) {
+        self.work += work;
+        self.results.total_work += work;
+        if self.new_min {
+            self.results.min_work = self.work;
+        }
+        if self.new_max {
+            self.results.max_work = self.work;
+        }
+    }
+
+    /// Get the timings of the repetitions so far
+    pub fn results(&self) -> &RepetitionResults {
+        &self.results
+    }
+
+    /// Returns `true` if a new minimum was seen within `try_for` of `now`
+    fn is_testing_at(&self, now: u64) -> bool {
+        self.results.count == 0 || now.saturating_sub(self.last_min) < self.try_for
+    }
+
+    /// Begin a repetition at the given timer value
+    fn begin_at(&mut self, now: u64) {
+        assert!(
+            self.open.is_none(),
+            "RepetitionTester::begin called twice without an end"
+        );
+        self.open = Some(now);
+        self.work = 0;
+        self.new_min = false;
+        self.new_max = false;
+    }
+
+    /// End the current repetition at the given timer value
+    fn end_at(&mut self, now: u64) {
+        let start = self
+            .open
+            .take()
+            .expect("RepetitionTester::end called without a matching begin");
+        let cycles = now.saturating_sub(start);
+
+        let results = &mut self.results;
+        results.count += 1;
+        results.total_cycles += cycles;
+
+        if cycles >= results.max_cycles {
+            results.max_cycles = cycles;
+            results.max_work = self.work;
+            self.new_max = true;
+        }
+
+        if cycles < results.min_cycles {
+            results.min_cycles = cycles;
+            results.min_work = self.work;
+            self.new_min = true;
+            self.last_min = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repetitions() {
+        let mut tester = RepetitionTester::new(Duration::from_secs(1), 1000);
+        tester.last_min = 0;
+        assert!(tester.is_testing_at(5000));
+
+        // Repetitions of 100, 50 and 150 cycles, each doing 10 units of work
+        for (start, end) in [(0, 100), (200, 250), (300, 450)] {
+            tester.begin_at(start);
+            tester.end_at(end);
+            tester.count_work(10);
+        }
+
+        let results = *tester.results();
+        assert_eq!(results.count, 3);
+        assert_eq!(results.total_cycles, 300);
+        assert_eq!(results.min_cycles, 50);
+        assert_eq!(results.max_cycles, 150);
+        assert_eq!(results.total_work, 30);
+        assert_eq!(results.min_work, 10);
+        assert_eq!(results.max_work, 10);
+        assert!((results.avg_cycles() - 100.0).abs() < f64::EPSILON);
+        assert!((results.min_work_per_second() - 200.0).abs() < f64::EPSILON);
+        assert!((results.avg_work_per_second() - 100.0).abs() < f64::EPSILON);
+        assert!((results.min_cycles_per_work() - 5.0).abs() < f64::EPSILON);
+
+        // Testing stops a second (1000 cycles) after the minimum at 250
+        assert!(tester.is_testing_at(1249));
+        assert!(!tester.is_testing_at(1250));
+    }
+}
@@ -0,0 +1,211 @@
+//! Human and machine readable profiling reports
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Output format of a [`Report`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Aligned table for humans
+    Text,
+
+    /// A single JSON object
+    Json,
+
+    /// Comma separated values with a header row
+    Csv,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(format!(
+                "Unknown report format: {s} (expected text, json or csv)"
+            )),
+        }
+    }
+}
+
+/// Statistics gathered for a single zone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneReport {
+    /// Name of the zone
+    pub name: &'static str,
+
+    /// Number of times the zone was entered
+    pub hits: u64,
+
+    /// Cycles spent in the zone including child zones
+    pub inclusive_cycles: u64,
+
+    /// Cycles spent in the zone excluding child zones
+    pub exclusive_cycles: u64,
+
+    /// Fewest inclusive cycles seen for a single hit
+    pub best_cycles: u64,
+
+    /// Bytes processed by the zone
+    pub bytes: u64,
+}
+
+/// A profiling report for all zones that were hit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Cycles elapsed since the profiler was created
+    pub total_cycles: u64,
+
+    /// Timer ticks per second used to convert cycles into time
+    pub frequency: u64,
+
+    /// Statistics per zone, in declaration order
+    pub zones: Vec<ZoneReport>,
+}
+
+impl Report {
+    /// Convert a number of cycles into wall clock time
+    #[allow(clippy::cast_precision_loss)]
+    pub fn cycles_to_duration(&self, cycles: u64) -> Duration {
+        if self.frequency == 0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(cycles as f64 / self.frequency as f64)
+    }
+
+    /// Get the statistics of the zone with the given name, if it was hit
+    pub fn zone(&self, name: &str) -> Option<&ZoneReport> {
+        self.zones.iter().find(|zone| zone.name == name)
+    }
+
+    /// Percentage of the total time represented by `cycles`
+    #[allow(clippy::cast_precision_loss)]
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.total_cycles == 0 {
+            return 0.0;
+        }
+
+        cycles as f64 / self.total_cycles as f64 * 100.
+    }
+
+    /// Throughput of the given zone in gigabytes per second, if it processed any bytes
+    #[allow(clippy::cast_precision_loss)]
+    pub fn gigabytes_per_second(&self, zone: &ZoneReport) -> Option<f64> {
+        let seconds = self.cycles_to_duration(zone.inclusive_cycles).as_secs_f64();
+        if zone.bytes == 0 || seconds == 0.0 {
+            return None;
+        }
+
+        Some(zone.bytes as f64 / seconds / (1024. * 1024. * 1024.))
+    }
+
+    /// Format this report in the given [`ReportFormat`]
+    pub fn format(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Text => self.to_text(),
+            ReportFormat::Json => self.to_json(),
+            ReportFormat::Csv => self.to_csv(),
+        }
+    }
+
+    /// Print this report as a human readable table
+    pub fn print(&self) {
+        print!("{}", self.to_text());
+    }
+
+    /// Format this report as a human readable table
+    #[allow(clippy::cast_precision_loss)]
+    pub fn to_text(&self) -> String {
+        let mut res = String::new();
+
+        let _ = writeln!(
+            res,
+            "Total time: {:.2?} ({} cycles @ {:.2} GHz)",
+            self.cycles_to_duration(self.total_cycles),
+            self.total_cycles,
+            self.frequency as f64 / 1e9
+        );
+
+        for zone in &self.zones {
+            let _ = write!(
+                res,
+                "{:20} | Hits {:>8} | Excl {:>12} cycles {:6.2}% | Incl {:>12} cycles {:6.2}% | Best {:>10} cycles | {:>10.2?}",
+                zone.name,
+                zone.hits,
+                zone.exclusive_cycles,
+                self.percent(zone.exclusive_cycles),
+                zone.inclusive_cycles,
+                self.percent(zone.inclusive_cycles),
+                zone.best_cycles,
+                self.cycles_to_duration(zone.inclusive_cycles),
+            );
+
+            if let Some(gbs) = self.gigabytes_per_second(zone) {
+                let _ = write!(res, " | {} bytes at {gbs:.4} GB/s", zone.bytes);
+            }
+
+            res.push('\n');
+        }
+
+        res
+    }
+
+    /// Format this report as a JSON object
+    pub fn to_json(&self) -> String {
+        let mut res = String::new();
+
+        let _ = write!(
+            res,
+            "{{\"total_cycles\":{},\"frequency\":{},\"zones\":[",
+            self.total_cycles, self.frequency
+        );
+
+        for (i, zone) in self.zones.iter().enumerate() {
+            if i > 0 {
+                res.push(',');
+            }
+
+            let _ = write!(
+                res,
+                "{{\"name\":\"{}\",\"hits\":{},\"inclusive_cycles\":{},\"exclusive_cycles\":{},\"best_cycles\":{},\"bytes\":{},\"inclusive_seconds\":{:e}}}",
+                zone.name,
+                zone.hits,
+                zone.inclusive_cycles,
+                zone.exclusive_cycles,
+                zone.best_cycles,
+                zone.bytes,
+                self.cycles_to_duration(zone.inclusive_cycles).as_secs_f64(),
+            );
+        }
+
+        res.push_str("]}\n");
+        res
+    }
+
+    /// Format this report as CSV with a header row
+    pub fn to_csv(&self) -> String {
+        let mut res = String::from(
+            "name,hits,inclusive_cycles,exclusive_cycles,best_cycles,bytes,inclusive_seconds\n",
+        );
+
+        for zone in &self.zones {
+            let _ = writeln!(
+                res,
+                "{},{},{},{},{},{},{:e}",
+                zone.name,
+                zone.hits,
+                zone.inclusive_cycles,
+                zone.exclusive_cycles,
+                zone.best_cycles,
+                zone.bytes,
+                self.cycles_to_duration(zone.inclusive_cycles).as_secs_f64(),
+            );
+        }
+
+        res
+    }
+}